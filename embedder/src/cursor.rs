@@ -1,5 +1,11 @@
+use std::collections::HashMap;
 use std::{io::Read, time::Duration};
 
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::renderer::element::texture::TextureBuffer;
+use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
+use smithay::input::pointer::CursorIcon;
+use smithay::utils::{Physical, Point, Transform};
 use tracing::warn;
 use xcursor::{
     parser::{parse_xcursor, Image},
@@ -15,16 +21,25 @@ pub struct Cursor {
 
 impl Cursor {
     pub fn load() -> Cursor {
+        Self::load_icon(CursorIcon::Default)
+    }
+
+    /// Falls back to the default cursor of the theme when it doesn't have the icon.
+    pub fn load_icon(icon: CursorIcon) -> Cursor {
         let name = std::env::var("XCURSOR_THEME")
             .ok()
             .unwrap_or_else(|| "default".into());
-        let size = std::env::var("XCURSOR_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(24);
+        let size = xcursor_size();
 
         let theme = CursorTheme::load(&name);
-        let icons = load_icon(&theme)
+        let icons = load_icon(&theme, icon)
+            .or_else(|err| match icon {
+                CursorIcon::Default => Err(err),
+                _ => {
+                    warn!(?err, ?icon, "Unable to load xcursor, using default cursor");
+                    load_icon(&theme, CursorIcon::Default)
+                }
+            })
             .map_err(|err| warn!(?err, "Unable to load xcursor, using fallback cursor"))
             .unwrap_or_else(|_| {
                 vec![Image {
@@ -48,6 +63,60 @@ impl Cursor {
     }
 }
 
+/// The nominal size of the cursors, in pixels.
+pub fn xcursor_size() -> u32 {
+    std::env::var("XCURSOR_SIZE")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(24)
+}
+
+/// Textures of the xcursor theme images, loaded the first time a client or the shell asks for them.
+#[derive(Default)]
+pub struct CursorTextures {
+    cursors: HashMap<CursorIcon, Cursor>,
+    textures: Vec<(Image, TextureBuffer<GlesTexture>)>,
+}
+
+impl CursorTextures {
+    /// The image of the cursor at `time`, for animated cursors, and its hotspot.
+    pub fn get(
+        &mut self,
+        gles_renderer: &mut GlesRenderer,
+        icon: CursorIcon,
+        time: Duration,
+    ) -> (TextureBuffer<GlesTexture>, Point<i32, Physical>) {
+        let image = self
+            .cursors
+            .entry(icon)
+            .or_insert_with(|| Cursor::load_icon(icon))
+            .get_image(1, time);
+        let hotspot = Point::from((image.xhot as i32, image.yhot as i32));
+
+        let texture = self
+            .textures
+            .iter()
+            .find_map(|(cached_image, texture)| (*cached_image == image).then(|| texture.clone()))
+            .unwrap_or_else(|| {
+                let texture = TextureBuffer::from_memory(
+                    gles_renderer,
+                    &image.pixels_rgba,
+                    Fourcc::Abgr8888,
+                    (image.width as i32, image.height as i32),
+                    false,
+                    1,
+                    Transform::Normal,
+                    None,
+                )
+                .expect("Failed to import cursor bitmap");
+                self.textures.push((image, texture.clone()));
+                texture
+            });
+
+        (texture, hotspot)
+    }
+}
+
 fn nearest_images(size: u32, images: &[Image]) -> impl Iterator<Item = &Image> {
     // Follow the nominal size of the cursor to choose the nearest
     let nearest_image = images
@@ -76,18 +145,70 @@ fn frame(mut millis: u32, size: u32, images: &[Image]) -> Image {
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
-    #[error("Theme has no default cursor")]
-    NoDefaultCursor,
+    #[error("Theme has no {0} cursor")]
+    MissingIcon(&'static str),
     #[error("Error opening xcursor file: {0}")]
     File(#[from] std::io::Error),
     #[error("Failed to parse XCursor file")]
     Parse,
 }
 
-fn load_icon(theme: &CursorTheme) -> Result<Vec<Image>, Error> {
-    let icon_path = theme.load_icon("default").ok_or(Error::NoDefaultCursor)?;
+/// Themes name their images after the CSS cursors, older ones use the X11 names.
+fn load_icon(theme: &CursorTheme, icon: CursorIcon) -> Result<Vec<Image>, Error> {
+    let icon_path = std::iter::once(icon.name())
+        .chain(icon.alt_names().iter().copied())
+        .find_map(|name| theme.load_icon(name))
+        .ok_or(Error::MissingIcon(icon.name()))?;
     let mut cursor_file = std::fs::File::open(icon_path)?;
     let mut cursor_data = Vec::new();
     cursor_file.read_to_end(&mut cursor_data)?;
     parse_xcursor(&cursor_data).ok_or(Error::Parse)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: u32, delay: u32) -> Image {
+        Image {
+            size,
+            width: size,
+            height: size,
+            xhot: 0,
+            yhot: 0,
+            delay,
+            pixels_rgba: vec![],
+            pixels_argb: vec![],
+        }
+    }
+
+    #[test]
+    fn icons_always_have_an_image() {
+        // Whatever themes are installed, the fallback cursor is there.
+        for icon in [CursorIcon::Default, CursorIcon::Wait, CursorIcon::Text] {
+            let image = Cursor::load_icon(icon).get_image(1, Duration::ZERO);
+            assert!(image.width > 0 && image.height > 0);
+            assert!(image.xhot < image.width && image.yhot < image.height);
+            assert_eq!(
+                image.pixels_rgba.len(),
+                (image.width * image.height * 4) as usize
+            );
+        }
+    }
+
+    #[test]
+    fn the_nearest_size_is_chosen() {
+        let images = [image(24, 1), image(32, 1), image(48, 1)];
+        assert_eq!(frame(0, 30, &images).size, 32);
+        assert_eq!(frame(0, 64, &images).size, 48);
+        assert_eq!(frame(0, 1, &images).size, 24);
+    }
+
+    #[test]
+    fn animations_loop() {
+        let images = [image(24, 100), image(24, 50)];
+        assert_eq!(frame(0, 24, &images).delay, 100);
+        assert_eq!(frame(120, 24, &images).delay, 50);
+        assert_eq!(frame(160, 24, &images).delay, 100);
+    }
+}
@@ -1,42 +1,51 @@
 use std::collections::HashMap;
 use std::path::Path;
 use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use rustix::fs::OFlags;
+use serde_json::json;
 use smithay::backend::allocator::dmabuf::{AnyError, AsDmabuf, Dmabuf, DmabufAllocator};
 use smithay::backend::allocator::gbm::GbmDevice;
 use smithay::backend::allocator::gbm::{GbmAllocator, GbmBufferFlags};
 use smithay::backend::allocator::{Allocator, Fourcc, Slot, Swapchain};
-use smithay::backend::drm::compositor::DrmCompositor;
+use smithay::backend::drm::compositor::{DrmCompositor, PrimaryPlaneElement};
 use smithay::backend::drm::{
     CreateDrmNodeError, DrmDevice, DrmDeviceFd, DrmError, DrmEvent, DrmNode, NodeType,
 };
 use smithay::backend::egl;
 use smithay::backend::egl::{EGLContext, EGLDevice, EGLDisplay};
+use smithay::backend::input::InputEvent;
 use smithay::backend::libinput::{LibinputInputBackend, LibinputSessionInterface};
-use smithay::backend::renderer::element::texture::{TextureBuffer, TextureRenderElement};
-use smithay::backend::renderer::element::Kind;
+use smithay::backend::renderer::element::surface::{
+    render_elements_from_surface_tree, WaylandSurfaceRenderElement,
+};
+use smithay::backend::renderer::element::texture::TextureRenderElement;
+use smithay::backend::renderer::element::{Element, Id, Kind};
 use smithay::backend::renderer::gles::ffi::Gles2;
 use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
-use smithay::backend::renderer::{ImportDma, ImportEgl};
+use smithay::backend::renderer::utils::DamageBag;
+use smithay::backend::renderer::{ImportDma, ImportEgl, Renderer, Texture};
 use smithay::backend::session::libseat::LibSeatSession;
-use smithay::backend::session::{libseat, Session};
+use smithay::backend::session::{libseat, Session, SessionEvent};
 use smithay::backend::udev::{all_gpus, primary_gpu, UdevBackend, UdevEvent};
 use smithay::desktop::utils::OutputPresentationFeedback;
 use smithay::desktop::{Space, Window};
+use smithay::input::pointer::{CursorIcon, CursorImageStatus};
 use smithay::output::Mode;
 use smithay::output::{Output, PhysicalProperties, Subpixel};
 use smithay::reexports::calloop::channel::Event;
+use smithay::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay::reexports::calloop::EventLoop;
 use smithay::reexports::calloop::RegistrationToken;
-use smithay::reexports::drm::control::{connector, crtc, Device, ModeTypeFlags};
+use smithay::reexports::drm::control::{self, connector, crtc, Device, ModeTypeFlags};
 use smithay::reexports::drm::Device as _;
-use smithay::reexports::input::Libinput;
+use smithay::reexports::input::{self, Libinput};
 use smithay::reexports::wayland_server::backend::GlobalId;
 use smithay::reexports::wayland_server::protocol::wl_shm;
 use smithay::reexports::wayland_server::Display;
-use smithay::reexports::wayland_server::DisplayHandle;
-use smithay::utils::{Coordinate, DeviceFd, Point, Rectangle, Transform};
+use smithay::reexports::wayland_server::{DisplayHandle, Resource};
+use smithay::utils::{Buffer, DeviceFd, Logical, Point, Rectangle, Transform};
 use smithay::wayland::dmabuf::{DmabufFeedbackBuilder, DmabufState};
 use smithay::wayland::drm_lease::DrmLease;
 use tracing::{error, info, warn};
@@ -46,17 +55,33 @@ use smithay_drm_extras::edid::EdidInfo;
 
 use crate::flutter_engine::platform_channels::binary_messenger::BinaryMessenger;
 use crate::flutter_engine::FlutterEngine;
+use crate::gles_framebuffer_importer::{Framebuffer, FramebufferSource};
+use crate::input_device_configuration::{
+    input_device_id, input_device_message, InputDeviceConfiguration, InputDeviceSettings,
+};
 use crate::input_handling::handle_input;
-use crate::{flutter_engine::EmbedderChannels, send_frames_surface_tree, Backend, ServerState};
+use crate::monitor_configuration::{MonitorConfiguration, MonitorTransform};
+use crate::persistence::persistence_path;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::render_elements::{
+    cursor_render_elements, flutter_frame_rect_in_texture, OutputRenderElement,
+    FLUTTER_FRAME_TRANSFORM,
+};
+use crate::server::{arrange_monitors, monitors_bounding_box, scale_from_f64};
+use crate::{flutter_engine::EmbedderChannels, Backend, ServerState};
 
 pub struct DrmBackend {
     pub space: Space<Window>,
     pub session: LibSeatSession,
     gpus: HashMap<DrmNode, GpuData>,
     primary_gpu: DrmNode,
-    pointer_images: Vec<(xcursor::parser::Image, TextureBuffer<GlesTexture>)>,
-    pointer_image: crate::cursor::Cursor,
     highest_hz_crtc: Option<(i32, crtc::Handle)>,
+    /// Stands in for the VBLANK of `highest_hz_crtc` when nothing changed on its monitor.
+    idle_frame_timer: Option<RegistrationToken>,
+    /// Monitors that are plugged in but turned off by the shell.
+    disabled_outputs: Vec<Output>,
+    /// libinput devices, the shell refers to them by `input_device_id`.
+    input_devices: Vec<input::Device>,
 }
 
 impl DrmBackend {
@@ -85,6 +110,53 @@ impl Backend for DrmBackend {
     fn get_monitor_layout(&self) -> Vec<Output> {
         self.space.outputs().cloned().collect::<Vec<_>>()
     }
+
+    fn update_monitor_layout(state: &mut ServerState<Self>) {
+        state.update_monitor_layout();
+    }
+
+    fn configure_monitor(
+        state: &mut ServerState<Self>,
+        configuration: MonitorConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        state.configure_monitor(configuration)
+    }
+
+    fn supports_direct_scanout(&self) -> bool {
+        true
+    }
+
+    fn can_change_vt(&self) -> bool {
+        true
+    }
+
+    fn change_vt(&mut self, vt: i32) {
+        if let Err(err) = self.session.change_vt(vt) {
+            error!(vt, "Failed to switch VT: {err}");
+        }
+    }
+
+    fn send_input_devices(state: &mut ServerState<Self>) {
+        for device in state.backend_data.input_devices.clone() {
+            state.invoke_platform_method("input_device_added", input_device_message(&device));
+        }
+    }
+
+    fn configure_input_device(
+        state: &mut ServerState<Self>,
+        configuration: InputDeviceConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        state.configure_input_device(configuration)
+    }
+
+    fn last_flutter_frame(state: &mut ServerState<Self>) -> Option<GlesTexture> {
+        let gpu_data = state
+            .backend_data
+            .gpus
+            .get(&state.backend_data.primary_gpu)?;
+        let slot = gpu_data.last_rendered_slot.as_ref()?;
+        import_flutter_texture(state.gles_renderer.as_mut()?, slot)
+    }
 }
 
 impl DrmBackend {
@@ -97,10 +169,11 @@ impl DrmBackend {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct UdevOutputId {
     device_id: DrmNode,
     crtc: crtc::Handle,
+    connector: connector::Handle,
 }
 
 // we cannot simply pick the first supported format of the intersection of *all* formats, because:
@@ -117,12 +190,16 @@ const SUPPORTED_FORMATS: &[Fourcc] = &[
 ];
 const SUPPORTED_FORMATS_8BIT_ONLY: &[Fourcc] = &[Fourcc::Abgr8888, Fourcc::Argb8888];
 
+/// Number of Flutter frames whose damage is kept.
+/// Scanout buffers older than that are repainted entirely.
+const MAX_FLUTTER_DAMAGE_AGE: usize = 4;
+
 pub fn run_drm_backend() {
     let mut event_loop = EventLoop::try_new().unwrap();
     let display: Display<ServerState<DrmBackend>> = Display::new().unwrap();
     let mut display_handle = display.handle();
 
-    let (session, _notifier) = match LibSeatSession::new() {
+    let (session, notifier) = match LibSeatSession::new() {
         Ok(ret) => ret,
         Err(err) => {
             error!("Could not initialize a session: {}", err);
@@ -172,9 +249,10 @@ pub fn run_drm_backend() {
             session,
             gpus: HashMap::new(),
             primary_gpu,
-            pointer_images: vec![],
-            pointer_image: crate::cursor::Cursor::load(),
             highest_hz_crtc: None,
+            idle_frame_timer: None,
+            disabled_outputs: vec![],
+            input_devices: vec![],
         },
         None,
     );
@@ -230,11 +308,33 @@ pub fn run_drm_backend() {
     event_loop
         .handle()
         .insert_source(libinput_backend, move |event, _, data| {
-            let _dh = data.display_handle.clone();
+            match &event {
+                InputEvent::DeviceAdded { device } => data.input_device_added(device.clone()),
+                InputEvent::DeviceRemoved { device } => data.input_device_removed(device),
+                _ => {}
+            }
             handle_input::<DrmBackend>(&event, data);
         })
         .unwrap();
 
+    event_loop
+        .handle()
+        .insert_source(notifier, move |event, _, data| match event {
+            SessionEvent::PauseSession => {
+                info!("Pausing the session");
+                libinput_context.suspend();
+                data.pause_session();
+            }
+            SessionEvent::ActivateSession => {
+                info!("Resuming the session");
+                if let Err(err) = libinput_context.resume() {
+                    error!("Failed to resume libinput: {:?}", err);
+                }
+                data.activate_session();
+            }
+        })
+        .unwrap();
+
     event_loop
         .handle()
         .insert_source(udev_backend, move |event, _, data| {
@@ -266,22 +366,47 @@ pub fn run_drm_backend() {
         .insert_source(rx_request_fbo, move |_, _, data| {
             let gpu_data = data.backend_data.get_gpu_data_mut();
             let slot = gpu_data.swapchain.acquire().ok().flatten().unwrap();
-            let dmabuf = slot.export().unwrap();
+            let framebuffer = Framebuffer {
+                source: FramebufferSource::Dmabuf(slot.export().unwrap()),
+                age: slot.age(),
+            };
             gpu_data.current_slot = Some(slot);
-            data.tx_fbo.as_ref().unwrap().send(Some(dmabuf)).unwrap();
+            data.tx_fbo
+                .as_ref()
+                .unwrap()
+                .send(Some(framebuffer))
+                .unwrap();
         })
         .unwrap();
 
     event_loop
         .handle()
-        .insert_source(rx_present, move |_, _, data| {
+        .insert_source(rx_present, move |event, _, data| {
+            let Event::Msg(damage) = event else {
+                return;
+            };
+
             let gpu_data = data.backend_data.get_gpu_data_mut();
             gpu_data.last_rendered_slot = gpu_data.current_slot.take();
+            let Some(ref slot) = gpu_data.last_rendered_slot else {
+                return;
+            };
+            gpu_data.swapchain.submitted(slot);
 
-            let gpu_data = data.backend_data.get_gpu_data_mut();
-            if let Some(ref slot) = gpu_data.last_rendered_slot {
-                gpu_data.swapchain.submitted(slot);
-            }
+            let gles_renderer = data.gles_renderer.as_mut().unwrap();
+            let Some(flutter_texture) = import_flutter_texture(gles_renderer, slot) else {
+                return;
+            };
+            let texture_size = flutter_texture.size();
+            gpu_data.flutter_damage.add(damage.iter().map(|rect| {
+                let rect = Rectangle::<i32, Buffer>::from_loc_and_size(
+                    (rect.loc.x, rect.loc.y),
+                    (rect.size.w, rect.size.h),
+                );
+                flutter_frame_rect_in_texture(rect, texture_size)
+            }));
+
+            data.flutter_frame_presented(&damage);
         })
         .unwrap();
 
@@ -301,11 +426,212 @@ pub fn run_drm_backend() {
 }
 
 impl ServerState<DrmBackend> {
+    /// Renders the monitors and lets Flutter and the clients draw their next frame.
+    /// Only the VBLANK of the monitor with the highest refresh rate paces the frames.
+    fn frame_finished(&mut self, node: DrmNode, crtc: crtc::Handle) {
+        let (mhz, highest_hz_crtc) = match self.backend_data.highest_hz_crtc {
+            Some(highest_hz_crtc) => highest_hz_crtc,
+            None => return,
+        };
+
+        if highest_hz_crtc != crtc || !self.backend_data.session.is_active() {
+            return;
+        }
+
+        // A VBLANK can come while the idle timer is still pending, only one of them must pace.
+        if let Some(token) = self.backend_data.idle_frame_timer.take() {
+            self.loop_handle.remove(token);
+        }
+
+        let crtcs = match self.backend_data.gpus.get(&node) {
+            Some(gpu_data) => gpu_data.surfaces.keys().cloned().collect::<Vec<_>>(),
+            None => return,
+        };
+        for crtc in crtcs {
+            self.update_crtc_planes(crtc);
+        }
+        self.schedule_idle_frame();
+
+        let drained: Vec<_> = self.batons.drain(..).collect(); // Mutable borrow ends here
+
+        for baton in drained {
+            self.flutter_engine().on_vsync(baton, mhz as u32).unwrap();
+        }
+        self.send_frame_callbacks();
+    }
+
+    /// The monitor pacing the frames doesn't get a VBLANK when nothing changed on it.
+    /// Wait as long as a refresh would take instead, so Flutter and the clients keep drawing.
+    fn schedule_idle_frame(&mut self) {
+        let (mhz, crtc) = match self.backend_data.highest_hz_crtc {
+            Some(highest_hz_crtc) => highest_hz_crtc,
+            None => return,
+        };
+        if !self.backend_data.session.is_active() {
+            return;
+        }
+        let node = self.backend_data.primary_gpu;
+
+        let is_frame_pending = self
+            .backend_data
+            .gpus
+            .get(&node)
+            .and_then(|gpu_data| gpu_data.surfaces.get(&crtc))
+            .map(|surface| surface.frame_pending)
+            .unwrap_or(false);
+        if is_frame_pending {
+            return;
+        }
+
+        if let Some(token) = self.backend_data.idle_frame_timer.take() {
+            self.loop_handle.remove(token);
+        }
+
+        let frame_duration = Duration::from_nanos(1_000_000_000_000 / mhz as u64);
+        let token = self
+            .loop_handle
+            .insert_source(Timer::from_duration(frame_duration), move |_, _, data| {
+                data.backend_data.idle_frame_timer = None;
+                data.frame_finished(node, crtc);
+                TimeoutAction::Drop
+            })
+            .unwrap();
+        self.backend_data.idle_frame_timer = Some(token);
+    }
+
+    fn input_device_added(&mut self, mut device: input::Device) {
+        if let Some(settings) = InputDeviceSettings::load(device.name()) {
+            // Settings saved for another device with the same name may not all apply,
+            // the others still do.
+            for setting in settings.split() {
+                let result = setting
+                    .validate(&device)
+                    .and_then(|()| setting.apply(&mut device));
+                if let Err(err) = result {
+                    warn!(device = device.name(), "Skipping a saved setting: {err}");
+                }
+            }
+        }
+
+        self.invoke_platform_method("input_device_added", input_device_message(&device));
+        self.backend_data.input_devices.push(device);
+    }
+
+    fn input_device_removed(&mut self, device: &input::Device) {
+        self.backend_data
+            .input_devices
+            .retain(|input_device| input_device != device);
+        self.invoke_platform_method(
+            "input_device_removed",
+            json!({ "id": input_device_id(device) }),
+        );
+    }
+
+    fn configure_input_device(
+        &mut self,
+        configuration: InputDeviceConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        let Some(device) = self
+            .backend_data
+            .input_devices
+            .iter_mut()
+            .find(|device| input_device_id(device) == configuration.device_id)
+        else {
+            return Err(PlatformChannelError::InputDeviceDoesntExist(
+                configuration.device_id,
+            ));
+        };
+
+        configuration.settings.validate(device)?;
+        configuration.settings.apply(device)?;
+
+        if let Err(err) = configuration.settings.save(device.name()) {
+            warn!(device = device.name(), "Failed to save the settings: {err}");
+        }
+
+        let message = input_device_message(device);
+        self.invoke_platform_method("input_device_changed", message);
+        Ok(())
+    }
+
+    /// Another VT took over the seat, the DRM devices can't be used until it gives them back.
+    fn pause_session(&mut self) {
+        if let Some(token) = self.backend_data.idle_frame_timer.take() {
+            self.loop_handle.remove(token);
+        }
+        for gpu_data in self.backend_data.gpus.values_mut() {
+            gpu_data.drm_device.pause();
+        }
+
+        // The key releases go to the other VT.
+        self.release_all_keys();
+        self.invoke_platform_method("session_active_changed", json!({ "active": false }));
+    }
+
+    /// The seat is back, the planes have to be restored and the frames scheduled again.
+    fn activate_session(&mut self) {
+        let nodes = self.backend_data.gpus.keys().copied().collect::<Vec<_>>();
+        for node in &nodes {
+            let gpu_data = self.backend_data.gpus.get_mut(node).unwrap();
+            if let Err(err) = gpu_data.drm_device.activate(false) {
+                error!("Failed to activate drm device {}: {}", node, err);
+            }
+            for surface in gpu_data.surfaces.values_mut() {
+                if let Err(err) = surface.compositor.surface().reset_state() {
+                    warn!("Failed to reset drm surface state: {}", err);
+                }
+                // Damage tracking doesn't know what the other VT left on the planes.
+                surface.compositor.reset_buffers();
+                // The VBLANKs of the page flips queued before the switch never arrive.
+                surface.frame_pending = false;
+            }
+        }
+
+        // Monitors may have been plugged or unplugged in the meantime.
+        for node in nodes {
+            self.device_changed(node);
+        }
+        self.schedule_idle_frame();
+
+        // The shell may want to lock the screen.
+        self.invoke_platform_method("session_active_changed", json!({ "active": true }));
+    }
+
     // TODO: I don't think this method should be here.
     // It should probably be in GpuData or SurfaceData.
+    /// Renders the Flutter frame and the cursor on the monitor of the CRTC.
+    /// The page flip is skipped when nothing changed on the monitor.
     pub fn update_crtc_planes(&mut self, crtc: crtc::Handle) {
+        // The client destroyed its cursor surface without setting another cursor.
+        if let CursorImageStatus::Surface(surface) = &self.client_cursor_image_status {
+            if !surface.alive() {
+                self.client_cursor_image_status = CursorImageStatus::Named(CursorIcon::Default);
+            }
+        }
+        let cursor_image_status = self.cursor_image_status();
+
+        let layout_size = monitors_bounding_box(&self.backend_data.get_monitor_layout()).size;
+
         // TODO: Ideally, there shouldn't be a "primary gpu" and we should handle multi-gpu setups.
         let primary_gpu = self.backend_data.primary_gpu;
+        let output = self
+            .backend_data
+            .space
+            .outputs()
+            .find(|output| {
+                output
+                    .user_data()
+                    .get::<UdevOutputId>()
+                    .map(|id| id.device_id == primary_gpu && id.crtc == crtc)
+                    .unwrap_or(false)
+            })
+            .cloned();
+        // A fullscreen client the shell wants on a plane goes above the Flutter frame.
+        // The DRM compositor puts its buffer on the primary or an overlay plane when it can.
+        let direct_scanout_surface = output
+            .as_ref()
+            .and_then(|output| self.direct_scanout_surface(output));
+
         let gpu_data = self.backend_data.gpus.get_mut(&primary_gpu);
         let gpu_data = if let Some(gpu_data) = gpu_data {
             gpu_data
@@ -318,11 +644,19 @@ impl ServerState<DrmBackend> {
             None => return,
         };
 
+        if surface.frame_pending {
+            // The monitor catches up on the next frame.
+            return;
+        }
+
         let gles_renderer = self.gles_renderer.as_mut().unwrap();
-        let last_rendered_slot = gpu_data.last_rendered_slot.as_mut();
+        let flutter_texture = gpu_data
+            .last_rendered_slot
+            .as_ref()
+            .and_then(|slot| import_flutter_texture(gles_renderer, slot));
 
-        let slot = if let Some(ref slot) = last_rendered_slot {
-            slot
+        let flutter_texture = if let Some(flutter_texture) = flutter_texture {
+            flutter_texture
         } else {
             // Flutter hasn't rendered anything yet. Render a solid color to schedule the next VBLANK.
             surface
@@ -335,18 +669,11 @@ impl ServerState<DrmBackend> {
                 .unwrap();
             surface.compositor.queue_frame(None).unwrap();
             surface.compositor.reset_buffers();
+            surface.frame_pending = true;
             return;
         };
 
-        let output = self.backend_data.space.outputs().find(|output| {
-            output
-                .user_data()
-                .get::<UdevOutputId>()
-                .map(|id| id.device_id == surface.device_id && id.crtc == surface.crtc)
-                .unwrap_or(false)
-        });
-
-        let output = match output {
+        let output = match &output {
             Some(output) => output,
             None => return,
         };
@@ -358,82 +685,114 @@ impl ServerState<DrmBackend> {
 
         let scale = output.current_scale();
 
-        let flutter_texture = gles_renderer
-            .import_dmabuf(&slot.export().unwrap(), None)
-            .unwrap();
-        let flutter_texture_buffer = TextureBuffer::from_texture(
-            gles_renderer,
+        // The Flutter view covers all the monitors, it is shifted so that the part under this one
+        // lands on the screen. Elements are placed the way the user sees the monitor,
+        // the DRM compositor applies the output transform to the whole frame.
+        // The element keeps the same id across frames and carries the damage reported by Flutter,
+        // so the DRM compositor only repaints what changed on this monitor.
+        let flutter_texture_element = TextureRenderElement::from_texture_with_damage(
+            gpu_data.flutter_element_id.clone(),
+            gles_renderer.id(),
+            Point::<f64, Logical>::from((-geometry.loc.x, -geometry.loc.y))
+                .to_physical(scale.fractional_scale()),
             flutter_texture,
             1,
-            Transform::Flipped180,
+            FLUTTER_FRAME_TRANSFORM,
             None,
-        );
-        let flutter_texture_element = TextureRenderElement::from_texture_buffer(
-            Point::from((0.0, 0.0)),
-            &flutter_texture_buffer,
             None,
-            // TODO: I don't know why it has to be like this instead of just `geometry`.
-            Some(Rectangle::from_loc_and_size(
-                (geometry.loc.x, geometry.size.h - geometry.loc.y),
-                geometry.size,
-            )),
+            Some(layout_size),
             None,
+            gpu_data.flutter_damage.snapshot(),
             Kind::Unspecified,
         );
 
-        let pointer_frame = self
-            .backend_data
-            .pointer_image
-            .get_image(1, self.clock.now().into());
-
-        let cursor_position = Point::from(self.mouse_position)
-            - Point::from((pointer_frame.xhot as f64, pointer_frame.yhot as f64))
-            - geometry.loc.to_physical(scale.fractional_scale());
+        let cursor_elements = cursor_render_elements(
+            gles_renderer,
+            &mut self.cursor_textures,
+            cursor_image_status,
+            Point::from(self.mouse_position) - geometry.loc,
+            scale.fractional_scale(),
+            self.clock.now().into(),
+        );
 
-        let pointer_images = &mut self.backend_data.pointer_images;
-        let pointer_image = pointer_images
-            .iter()
-            .find_map(|(image, texture)| {
-                if image == &pointer_frame {
-                    Some(texture.clone())
-                } else {
-                    None
-                }
-            })
-            .unwrap_or_else(|| {
-                let texture = TextureBuffer::from_memory(
+        let direct_scanout_elements = match direct_scanout_surface {
+            Some((_, ref wl_surface)) => {
+                render_elements_from_surface_tree::<_, WaylandSurfaceRenderElement<GlesRenderer>>(
                     gles_renderer,
-                    &pointer_frame.pixels_rgba,
-                    Fourcc::Abgr8888,
-                    (pointer_frame.width as i32, pointer_frame.height as i32),
-                    false,
-                    1,
-                    Transform::Normal,
-                    None,
+                    wl_surface,
+                    (0, 0),
+                    scale.fractional_scale(),
+                    1.0,
+                    Kind::ScanoutCandidate,
                 )
-                .expect("Failed to import cursor bitmap");
-                pointer_images.push((pointer_frame, texture.clone()));
-                texture
-            });
+            }
+            None => vec![],
+        };
+        let direct_scanout_ids = direct_scanout_elements
+            .iter()
+            .map(|element| element.id().clone())
+            .collect::<Vec<_>>();
 
-        let cursor_element = TextureRenderElement::from_texture_buffer(
-            cursor_position,
-            &pointer_image,
-            None,
-            None,
-            None,
-            Kind::Cursor,
-        );
+        let elements = cursor_elements
+            .into_iter()
+            .chain(
+                direct_scanout_elements
+                    .into_iter()
+                    .map(OutputRenderElement::from),
+            )
+            .chain(std::iter::once(OutputRenderElement::from(
+                flutter_texture_element,
+            )))
+            .collect::<Vec<_>>();
 
-        surface
+        let rendered = surface
             .compositor
-            .render_frame::<GlesRenderer, TextureRenderElement<GlesTexture>>(
+            .render_frame::<GlesRenderer, OutputRenderElement>(
                 gles_renderer,
-                &[cursor_element, flutter_texture_element],
+                &elements,
                 [0.0, 0.0, 0.0, 0.0],
             )
             .unwrap();
-        surface.compositor.queue_frame(None).unwrap();
+        let is_on_plane = |id: &Id| {
+            matches!(rendered.primary_element, PrimaryPlaneElement::Element(element) if element.id() == id)
+                || rendered
+                    .overlay_elements
+                    .iter()
+                    .any(|element| element.id() == id)
+        };
+        let is_scanned_out =
+            !direct_scanout_ids.is_empty() && direct_scanout_ids.iter().all(is_on_plane);
+
+        // Nothing changed on this monitor when the frame is empty, it keeps showing the current one.
+        if !rendered.is_empty {
+            surface.compositor.queue_frame(None).unwrap();
+            surface.frame_pending = true;
+        }
+
+        if let Some((surface_id, _)) = direct_scanout_surface {
+            self.set_direct_scanout_active(surface_id, is_scanned_out);
+        }
+    }
+
+    fn update_monitor_layout(&mut self) {
+        let outputs = self.backend_data.get_monitor_layout();
+        arrange_monitors(&outputs);
+        for output in &outputs {
+            self.backend_data
+                .space
+                .map_output(output, output.current_location());
+        }
+
+        let size = self.update_window_metrics();
+        let primary_gpu = self.backend_data.primary_gpu;
+        if let Some(gpu_data) = self.backend_data.gpus.get_mut(&primary_gpu) {
+            gpu_data.swapchain.resize(size.w as u32, size.h as u32);
+        }
+
+        self.backend_data.determine_highest_hz_crtc();
+        // The monitor pacing the frames may have changed to one without a page flip in flight.
+        self.schedule_idle_frame();
+        self.monitor_layout_changed();
     }
 
     fn monitor_layout_changed(&mut self) {
@@ -456,6 +815,10 @@ struct GpuData {
     swapchain: Swapchain<Box<dyn Allocator<Buffer = Dmabuf, Error = AnyError> + 'static>>,
     current_slot: Option<Slot<Dmabuf>>,
     last_rendered_slot: Option<Slot<Dmabuf>>,
+    /// Stays the same across frames so the DRM compositors can track the Flutter frame.
+    flutter_element_id: Id,
+    /// Damage of the Flutter frames, in buffer coordinates.
+    flutter_damage: DamageBag<i32, Buffer>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -498,47 +861,11 @@ impl ServerState<DrmBackend> {
                         let gpu_data = data.backend_data.gpus.get_mut(&node).unwrap();
 
                         if let Some(surface) = gpu_data.surfaces.get_mut(&crtc) {
+                            surface.frame_pending = false;
                             let _ = surface.compositor.frame_submitted();
                         }
 
-                        let (mhz, highest_hz_crtc) = match data.backend_data.highest_hz_crtc {
-                            Some(highest_hz_crtc) => highest_hz_crtc,
-                            None => return,
-                        };
-
-                        if highest_hz_crtc != crtc {
-                            return;
-                        }
-
-                        let crtcs = gpu_data.surfaces.keys().cloned().collect::<Vec<_>>();
-                        for crtc in crtcs {
-                            data.update_crtc_planes(crtc);
-                        }
-
-                        let drained: Vec<_> = data.batons.drain(..).collect(); // Mutable borrow ends here
-
-                        for baton in drained {
-                            data.flutter_engine().on_vsync(baton, mhz as u32).unwrap();
-                        }
-                        let start_time = std::time::Instant::now();
-                        for surface in data.xdg_shell_state.toplevel_surfaces() {
-                            send_frames_surface_tree(
-                                surface.wl_surface(),
-                                start_time.elapsed().as_millis() as u32,
-                            );
-                        }
-                        for surface in data.xdg_popups.values() {
-                            send_frames_surface_tree(
-                                surface.wl_surface(),
-                                start_time.elapsed().as_millis() as u32,
-                            );
-                        }
-                        for surface in data.x11_surface_per_wl_surface.keys() {
-                            send_frames_surface_tree(
-                                surface,
-                                start_time.elapsed().as_millis() as u32,
-                            );
-                        }
+                        data.frame_finished(node, crtc);
                     }
                     DrmEvent::Error(error) => {
                         error!("{:?}", error);
@@ -602,6 +929,8 @@ impl ServerState<DrmBackend> {
                 swapchain,
                 current_slot: None,
                 last_rendered_slot: None,
+                flutter_element_id: Id::new(),
+                flutter_damage: DamageBag::new(MAX_FLUTTER_DAMAGE_AGE),
             },
         );
 
@@ -662,32 +991,30 @@ impl ServerState<DrmBackend> {
         // check if there is a file in xdgConfigHome/veshell/persistence/Monitor/<output_name>.json and if so, get mode from there
         // if not, get the preferred mode from the connector
         info!("output_name: {}", output_name);
-        let mode_id = get_mode_id_for_monitor_from_file(&output_name).unwrap_or_else(|| {
-            connector
-                .modes()
-                .iter()
-                .position(|mode| mode.mode_type().contains(ModeTypeFlags::PREFERRED))
-                .unwrap_or(0)
-        });
+        let drm_modes = distinct_modes(&connector);
+        let preferred_mode = connector
+            .modes()
+            .iter()
+            .find(|mode| mode.mode_type().contains(ModeTypeFlags::PREFERRED))
+            .map(|mode| Mode::from(*mode));
+        let mode_id = get_mode_id_for_monitor_from_file(&output_name)
+            .filter(|mode_id| *mode_id < drm_modes.len())
+            .unwrap_or_else(|| {
+                drm_modes
+                    .iter()
+                    .position(|mode| Some(Mode::from(*mode)) == preferred_mode)
+                    .unwrap_or(0)
+            });
         info!("using mode_id: {}", mode_id);
 
         // log all modes
-        for (i, mode) in connector.modes().iter().enumerate() {
+        for (i, mode) in drm_modes.iter().enumerate() {
             info!("mode {}: {:?}", i, mode);
         }
 
-        let drm_mode = connector.modes()[mode_id];
-        let wl_mode = Mode::from(drm_mode);
-
-        let surface = match device
-            .drm_device
-            .create_surface(crtc, drm_mode, &[connector.handle()])
-        {
-            Ok(surface) => surface,
-            Err(err) => {
-                warn!("Failed to create drm surface: {}", err);
-                return;
-            }
+        let Some(&drm_mode) = drm_modes.get(mode_id) else {
+            warn!("Connector {} doesn't have any mode", output_name);
+            return;
         };
 
         let (phys_w, phys_h) = connector.size().unwrap_or((0, 0));
@@ -700,23 +1027,147 @@ impl ServerState<DrmBackend> {
                 model,
             },
         );
-        let global = output.create_global::<ServerState<DrmBackend>>(&self.display_handle);
-
-        // Put the new output at the right of the last one.
-        let x = self.backend_data.space.outputs().fold(0, |acc, o| {
-            acc + self.backend_data.space.output_geometry(o).unwrap().size.w
-        });
-        let position = (x, 0).into();
-
-        output.set_preferred(wl_mode);
-        output.change_current_state(Some(wl_mode), None, None, Some(position));
-        self.backend_data.space.map_output(&output, position);
 
         output.user_data().insert_if_missing(|| UdevOutputId {
             crtc,
             device_id: node,
+            connector: connector.handle(),
         });
 
+        // The shell picks modes by their index in this list.
+        for mode in &drm_modes {
+            output.add_mode(Mode::from(*mode));
+        }
+        if let Some(preferred_mode) = preferred_mode {
+            output.set_preferred(preferred_mode);
+        }
+
+        // Put the new output at the right of the other ones.
+        let bounding_box = monitors_bounding_box(&self.backend_data.get_monitor_layout());
+        let position = (bounding_box.loc.x + bounding_box.size.w, 0).into();
+
+        let scale = get_scale_for_monitor_from_file(&output.name()).map(scale_from_f64);
+        let transform = get_transform_for_monitor_from_file(&output.name()).map(Transform::from);
+
+        output.change_current_state(Some(Mode::from(drm_mode)), transform, scale, Some(position));
+
+        if let Err(err) = self.enable_output(&output, drm_mode) {
+            warn!("Failed to enable output {}: {}", output.name(), err);
+        }
+
+        self.update_monitor_layout();
+    }
+
+    /// Lights up the CRTC of a disabled output with the given mode and shows the output to clients.
+    /// The output stays disabled when that fails.
+    fn enable_output(&mut self, output: &Output, drm_mode: control::Mode) -> Result<(), String> {
+        let UdevOutputId {
+            device_id, crtc, ..
+        } = *output.user_data().get::<UdevOutputId>().unwrap();
+
+        let compositor = self
+            .create_drm_compositor(output, drm_mode)
+            .and_then(|mut compositor| {
+                // Start first frame with a solid color. This will trigger the first VBLank event.
+                compositor
+                    .render_frame::<_, TextureRenderElement<_>>(
+                        self.gles_renderer.as_mut().unwrap(),
+                        &[],
+                        [0.0, 0.0, 0.0, 0.0],
+                    )
+                    .map_err(|err| format!("Failed to render the first frame: {}", err))?;
+                compositor
+                    .queue_frame(None)
+                    .map_err(|err| format!("Failed to queue the first frame: {}", err))?;
+                compositor.reset_buffers();
+                Ok(compositor)
+            });
+        let compositor = match compositor {
+            Ok(compositor) => compositor,
+            Err(err) => {
+                self.disable_output(output);
+                return Err(err);
+            }
+        };
+
+        let global = output.create_global::<ServerState<DrmBackend>>(&self.display_handle);
+        let device = self.backend_data.gpus.get_mut(&device_id).unwrap();
+        device.surfaces.insert(
+            crtc,
+            SurfaceData {
+                dh: self.display_handle.clone(),
+                device_id,
+                crtc,
+                render_node: device.render_node,
+                global: Some(global),
+                compositor,
+                frame_pending: true,
+            },
+        );
+
+        self.backend_data
+            .disabled_outputs
+            .retain(|disabled_output| disabled_output != output);
+        self.backend_data
+            .space
+            .map_output(output, output.current_location());
+
+        Ok(())
+    }
+
+    /// Switches an enabled output to another mode.
+    /// The mode is tested first, the output is left as it was when the hardware refuses it.
+    /// Clients keep the same wl_output across mode changes.
+    fn change_output_mode(
+        &mut self,
+        output: &Output,
+        drm_mode: control::Mode,
+    ) -> Result<(), String> {
+        let id = output.user_data().get::<UdevOutputId>().unwrap();
+        let surface = self
+            .backend_data
+            .gpus
+            .get_mut(&id.device_id)
+            .and_then(|device| device.surfaces.get_mut(&id.crtc))
+            .ok_or_else(|| format!("Monitor {} isn't lit up", output.name()))?;
+        surface
+            .compositor
+            .use_mode(drm_mode)
+            .map_err(|err| format!("Failed to change the mode: {}", err))
+    }
+
+    /// Turns the CRTC of the output off and hides the output from clients.
+    /// The output can still be enabled again with `configure_monitor`.
+    fn disable_output(&mut self, output: &Output) {
+        let id = output.user_data().get::<UdevOutputId>().unwrap();
+        if let Some(device) = self.backend_data.gpus.get_mut(&id.device_id) {
+            // Dropping the surface also removes the wl_output global.
+            device.surfaces.remove(&id.crtc);
+        }
+
+        self.backend_data.space.unmap_output(output);
+        if !self.backend_data.disabled_outputs.contains(output) {
+            self.backend_data.disabled_outputs.push(output.clone());
+        }
+    }
+
+    fn create_drm_compositor(
+        &mut self,
+        output: &Output,
+        drm_mode: control::Mode,
+    ) -> Result<GbmDrmCompositor, String> {
+        let id = output.user_data().get::<UdevOutputId>().unwrap();
+        let device = self
+            .backend_data
+            .gpus
+            .get_mut(&id.device_id)
+            .ok_or_else(|| format!("Device {} is gone", id.device_id))?;
+
+        let surface = device
+            .drm_device
+            .create_surface(id.crtc, drm_mode, &[id.connector])
+            .map_err(|err| format!("Failed to create drm surface: {}", err))?;
+
         let color_formats = if std::env::var("ANVIL_DISABLE_10BIT").is_ok() {
             SUPPORTED_FORMATS_8BIT_ONLY
         } else {
@@ -731,13 +1182,10 @@ impl ServerState<DrmBackend> {
             .dmabuf_render_formats()
             .clone();
 
-        let driver = match device.drm_device.get_driver() {
-            Ok(driver) => driver,
-            Err(err) => {
-                warn!("Failed to query drm driver: {}", err);
-                return;
-            }
-        };
+        let driver = device
+            .drm_device
+            .get_driver()
+            .map_err(|err| format!("Failed to query drm driver: {}", err))?;
 
         let mut planes = surface.planes().clone();
 
@@ -756,8 +1204,8 @@ impl ServerState<DrmBackend> {
             planes.overlay = vec![];
         }
 
-        let compositor = match DrmCompositor::new(
-            &output,
+        DrmCompositor::new(
+            output,
             surface,
             Some(planes),
             device.gbm_allocator.clone(),
@@ -766,54 +1214,91 @@ impl ServerState<DrmBackend> {
             render_formats,
             device.drm_device.cursor_size(),
             Some(device.gbm_device.clone()),
-        ) {
-            Ok(compositor) => compositor,
-            Err(err) => {
-                warn!("Failed to create drm compositor: {}", err);
-                return;
-            }
-        };
-
-        let mut surface = SurfaceData {
-            dh: self.display_handle.clone(),
-            device_id: node,
-            crtc,
-            render_node: device.render_node,
-            global: Some(global),
-            compositor,
-        };
+        )
+        .map_err(|err| format!("Failed to create drm compositor: {}", err))
+    }
 
-        // Start first frame with a solid color. This will trigger the first VBLank event.
-        surface
-            .compositor
-            .render_frame::<_, TextureRenderElement<_>>(
-                self.gles_renderer.as_mut().unwrap(),
-                &[],
-                [0.0, 0.0, 0.0, 0.0],
-            )
-            .unwrap();
-        surface.compositor.queue_frame(None).unwrap();
-        surface.compositor.reset_buffers();
+    /// The modes of the connector of the output, in the same order as `Output::modes`.
+    fn connector_modes(&self, output: &Output) -> Vec<control::Mode> {
+        let id = output.user_data().get::<UdevOutputId>().unwrap();
+        self.backend_data
+            .gpus
+            .get(&id.device_id)
+            .and_then(|device| device.drm_device.get_connector(id.connector, false).ok())
+            .map(|connector| distinct_modes(&connector))
+            .unwrap_or_default()
+    }
 
-        device.surfaces.insert(crtc, surface);
+    fn configure_monitor(
+        &mut self,
+        configuration: MonitorConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        configuration.validate()?;
 
-        let bounding_box = self
+        let name = configuration.monitor_name.clone();
+        let enabled_output = self
             .backend_data
             .space
             .outputs()
-            .map(|output| self.backend_data.space.output_geometry(output).unwrap())
-            .reduce(|first, second| first.merge(second))
-            .unwrap();
+            .find(|output| output.name() == name)
+            .cloned();
+        let is_enabled = enabled_output.is_some();
+        let output = enabled_output
+            .or_else(|| {
+                self.backend_data
+                    .disabled_outputs
+                    .iter()
+                    .find(|output| output.name() == name)
+                    .cloned()
+            })
+            .ok_or_else(|| PlatformChannelError::MonitorDoesntExist(name.clone()))?;
+
+        let drm_modes = self.connector_modes(&output);
+        let new_mode = match configuration.mode_index {
+            Some(mode_index) => Some(
+                *drm_modes
+                    .get(mode_index)
+                    .ok_or_else(|| PlatformChannelError::InvalidMode(name.clone(), mode_index))?,
+            ),
+            None => None,
+        };
 
-        device
-            .swapchain
-            .resize(bounding_box.size.w as u32, bounding_box.size.h as u32);
-        self.flutter_engine()
-            .send_window_metrics((bounding_box.size.w as u32, bounding_box.size.h as u32).into())
-            .unwrap();
+        let enable = configuration.enabled.unwrap_or(is_enabled);
+        if is_enabled && !enable && self.backend_data.space.outputs().count() == 1 {
+            return Err(PlatformChannelError::LastMonitor);
+        }
 
-        self.backend_data.determine_highest_hz_crtc();
-        self.monitor_layout_changed();
+        // The hardware goes first, so the monitor stays as it was when it refuses the change.
+        if !enable {
+            if is_enabled {
+                self.disable_output(&output);
+            }
+        } else if !is_enabled {
+            let current_mode = output.current_mode();
+            let drm_mode = new_mode
+                .or_else(|| {
+                    drm_modes
+                        .iter()
+                        .copied()
+                        .find(|mode| Some(Mode::from(*mode)) == current_mode)
+                })
+                .or_else(|| drm_modes.first().copied())
+                .ok_or_else(|| {
+                    PlatformChannelError::Backend(format!("Monitor {} doesn't have any mode", name))
+                })?;
+            self.enable_output(&output, drm_mode)
+                .map_err(PlatformChannelError::Backend)?;
+        } else if let Some(drm_mode) = new_mode {
+            self.change_output_mode(&output, drm_mode)
+                .map_err(PlatformChannelError::Backend)?;
+        }
+
+        if let Some(drm_mode) = new_mode {
+            output.change_current_state(Some(Mode::from(drm_mode)), None, None, None);
+        }
+        configuration.apply_to_output(&output);
+        self.update_monitor_layout();
+        Ok(())
     }
 
     fn connector_disconnected(
@@ -838,39 +1323,28 @@ impl ServerState<DrmBackend> {
             device.surfaces.remove(&crtc);
         }
 
+        let is_output = |o: &&Output| {
+            o.user_data()
+                .get::<UdevOutputId>()
+                .map(|id| id.device_id == node && id.crtc == crtc)
+                .unwrap_or(false)
+        };
         let output = self
             .backend_data
             .space
             .outputs()
-            .find(|o| {
-                o.user_data()
-                    .get::<UdevOutputId>()
-                    .map(|id| id.device_id == node && id.crtc == crtc)
-                    .unwrap_or(false)
-            })
+            .chain(self.backend_data.disabled_outputs.iter())
+            .find(is_output)
             .cloned();
 
         if let Some(output) = output {
             self.backend_data.space.unmap_output(&output);
+            self.backend_data
+                .disabled_outputs
+                .retain(|disabled_output| disabled_output != &output);
         }
 
-        let bounding_box = self
-            .backend_data
-            .space
-            .outputs()
-            .map(|output| self.backend_data.space.output_geometry(output).unwrap())
-            .reduce(|first, second| first.merge(second))
-            .unwrap_or(Rectangle::default());
-
-        device
-            .swapchain
-            .resize(bounding_box.size.w as u32, bounding_box.size.h as u32);
-        self.flutter_engine()
-            .send_window_metrics((bounding_box.size.w as u32, bounding_box.size.h as u32).into())
-            .unwrap();
-
-        self.backend_data.determine_highest_hz_crtc();
-        self.monitor_layout_changed();
+        self.update_monitor_layout();
     }
 
     fn device_changed(&mut self, node: DrmNode) {
@@ -904,6 +1378,16 @@ struct SurfaceData {
     render_node: DrmNode,
     global: Option<GlobalId>,
     compositor: GbmDrmCompositor,
+    /// A page flip was queued and its VBLANK didn't arrive yet.
+    frame_pending: bool,
+}
+
+impl Drop for SurfaceData {
+    fn drop(&mut self) {
+        if let Some(global) = self.global.take() {
+            self.dh.remove_global::<ServerState<DrmBackend>>(global);
+        }
+    }
 }
 
 pub type GbmDrmCompositor = DrmCompositor<
@@ -913,14 +1397,66 @@ pub type GbmDrmCompositor = DrmCompositor<
     DrmDeviceFd,
 >;
 
+/// The Flutter frame in the slot as a texture. The dmabuf is only imported the first time.
+fn import_flutter_texture(
+    gles_renderer: &mut GlesRenderer,
+    slot: &Slot<Dmabuf>,
+) -> Option<GlesTexture> {
+    if let Some(texture) = slot.userdata().get::<GlesTexture>() {
+        return Some(texture.clone());
+    }
+    let texture = gles_renderer
+        .import_dmabuf(&slot.export().ok()?, None)
+        .map_err(|err| warn!("Failed to import the Flutter frame: {}", err))
+        .ok()?;
+    slot.userdata().insert_if_missing(|| texture.clone());
+    Some(texture)
+}
+
+/// The modes of the connector, without the ones Wayland can't tell apart.
+fn distinct_modes(connector: &connector::Info) -> Vec<control::Mode> {
+    let mut modes: Vec<control::Mode> = vec![];
+    for mode in connector.modes() {
+        if !modes
+            .iter()
+            .any(|known_mode| Mode::from(*known_mode) == Mode::from(*mode))
+        {
+            modes.push(*mode);
+        }
+    }
+    modes
+}
+
 fn get_mode_id_for_monitor_from_file(output_name: &str) -> Option<usize> {
-    let path = std::env::var("XDG_CONFIG_HOME")
-        .unwrap_or_else(|_| std::env::var("HOME").unwrap() + "/.config")
-        + "/veshell/persistence/Monitor/"
-        + output_name
-        + ".json";
+    let json = read_monitor_persistence_file(output_name)?;
+    let mode = json["selectedMode"].as_u64();
+
+    match mode {
+        Some(mode) => Some(mode as usize),
+        None => {
+            error!("selectedMode not found in JSON");
+            None
+        }
+    }
+}
+
+fn get_scale_for_monitor_from_file(output_name: &str) -> Option<f64> {
+    let json = read_monitor_persistence_file(output_name)?;
+    // Older files don't have a scale, the monitor just keeps the default one.
+    json["scale"].as_f64().filter(|scale| *scale > 0.0)
+}
+
+fn get_transform_for_monitor_from_file(output_name: &str) -> Option<MonitorTransform> {
+    let json = read_monitor_persistence_file(output_name)?;
+    serde_json::from_value(json["transform"].clone()).ok()
+}
 
-    info!("path: {}", path);
+/// Reads the monitor settings saved by the shell in
+/// `$XDG_CONFIG_HOME/veshell/persistence/Monitor/<output_name>.json`.
+fn read_monitor_persistence_file(output_name: &str) -> Option<serde_json::Value> {
+    let path = persistence_path(&format!("Monitor/{output_name}.json"));
+
+    info!("path: {}", path.display());
 
     let file = match std::fs::File::open(path) {
         Ok(file) => file,
@@ -938,13 +1474,5 @@ fn get_mode_id_for_monitor_from_file(output_name: &str) -> Option<usize> {
         }
     };
     info!("json: {:?}", json);
-    let mode = json["selectedMode"].as_u64();
-
-    match mode {
-        Some(mode) => Some(mode as usize),
-        None => {
-            error!("selectedMode not found in JSON");
-            None
-        }
-    }
+    Some(json)
 }
@@ -1,6 +1,6 @@
 use serde_json::json;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{c_int, CString};
 use std::mem::{size_of, MaybeUninit};
 use std::ops::DerefMut;
@@ -18,7 +18,6 @@ use smithay::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay::reexports::calloop::{Dispatcher, LoopHandle};
 use smithay::{
     backend::{
-        allocator::dmabuf::Dmabuf,
         egl::{
             self,
             context::{GlAttributes, GlProfile, PixelFormatRequirements},
@@ -27,7 +26,7 @@ use smithay::{
         renderer::gles::ffi::Gles2,
     },
     reexports::calloop::channel,
-    utils::{Physical, Size},
+    utils::{Physical, Rectangle, Size},
 };
 
 use crate::flutter_engine::callbacks::{
@@ -40,21 +39,24 @@ use crate::flutter_engine::embedder::{
     FlutterEngineAOTDataSource__bindgen_ty_1, FlutterEngineCreateAOTData, FlutterEngineInitialize,
     FlutterEngineMarkExternalTextureFrameAvailable, FlutterEngineRegisterExternalTexture,
     FlutterEngineRunInitialized, FlutterEngineRunTask, FlutterEngineSendPointerEvent,
-    FlutterPointerEvent, FlutterTaskRunnerDescription,
+    FlutterPointerEvent, FlutterRect, FlutterTaskRunnerDescription,
 };
+use crate::flutter_engine::mouse_cursor::mouse_cursor_channel_method_call_handler;
 use crate::flutter_engine::platform_channel_callbacks::platform_channel_method_handler;
 use crate::flutter_engine::platform_channels::basic_message_channel::BasicMessageChannel;
 use crate::flutter_engine::platform_channels::binary_messenger_impl::BinaryMessengerImpl;
+use crate::flutter_engine::platform_channels::encodable_value::EncodableValue;
 use crate::flutter_engine::platform_channels::json_message_codec::JsonMessageCodec;
 use crate::flutter_engine::platform_channels::json_method_codec::JsonMethodCodec;
 use crate::flutter_engine::platform_channels::message_codec::MessageCodec;
 use crate::flutter_engine::platform_channels::method_call::MethodCall;
 use crate::flutter_engine::platform_channels::method_channel::MethodChannel;
 use crate::flutter_engine::platform_channels::method_result::MethodResult;
+use crate::flutter_engine::platform_channels::standard_method_codec::StandardMethodCodec;
 use crate::flutter_engine::task_runner::TaskRunner;
 use crate::flutter_engine::text_input::{text_input_channel_method_call_handler, TextInput};
 use crate::flutter_engine::wayland_messages::{EnvironmentVariables, MonitorsMessage, MyOutput};
-use crate::gles_framebuffer_importer::GlesFramebufferImporter;
+use crate::gles_framebuffer_importer::{Framebuffer, GlesFramebufferImporter};
 use crate::keyboard::KeyEvent;
 use crate::mouse_button_tracker::MouseButtonTracker;
 use crate::{
@@ -80,6 +82,7 @@ use {
 
 mod callbacks;
 pub mod embedder;
+pub mod mouse_cursor;
 pub mod platform_channel_callbacks;
 pub mod platform_channels;
 pub mod task_runner;
@@ -100,6 +103,7 @@ pub struct FlutterEngine<BackendData: Backend + 'static> {
     pub platform_method_channel: MethodChannel<serde_json::Value>,
     pub key_event_channel: BasicMessageChannel<serde_json::Value>,
     pub text_input: TextInput,
+    pub mouse_cursor_channel: MethodChannel<EncodableValue>,
     rx_request_external_texture_name_registration_token: calloop::RegistrationToken,
 }
 
@@ -111,9 +115,9 @@ impl<BackendData: Backend + 'static> FlutterEngine<BackendData> {
     pub fn new(
         server_state: &mut ServerState<BackendData>,
     ) -> Result<(Box<Self>, EmbedderChannels), Box<dyn std::error::Error>> {
-        let (tx_present, rx_present) = channel::channel::<()>();
+        let (tx_present, rx_present) = channel::channel::<Vec<Rectangle<i32, Physical>>>();
         let (tx_request_fbo, rx_request_fbo) = channel::channel::<()>();
-        let (tx_fbo, rx_fbo) = channel::channel::<Option<Dmabuf>>();
+        let (tx_fbo, rx_fbo) = channel::channel::<Option<Framebuffer>>();
         let (tx_output_height, rx_output_height) = channel::channel::<u16>();
         let (tx_baton, rx_baton) = channel::channel::<Baton>();
         let (tx_reschedule_task_runner_timer, rx_reschedule_task_runner_timer) =
@@ -339,6 +343,28 @@ impl<BackendData: Backend + 'static> FlutterEngine<BackendData> {
             )
             .unwrap();
 
+        let codec = Rc::new(StandardMethodCodec::new());
+        let mut mouse_cursor_channel = MethodChannel::<EncodableValue>::new(
+            binary_messenger.clone(),
+            "flutter/mousecursor".to_string(),
+            codec,
+        );
+
+        let (tx_mouse_cursor_message, rx_mouse_cursor_message) = channel::channel::<(
+            MethodCall<EncodableValue>,
+            Box<dyn MethodResult<EncodableValue>>,
+        )>();
+
+        mouse_cursor_channel.set_method_call_mpsc_channel(Some(tx_mouse_cursor_message));
+
+        server_state
+            .loop_handle
+            .insert_source(
+                rx_mouse_cursor_message,
+                mouse_cursor_channel_method_call_handler,
+            )
+            .unwrap();
+
         let task_runner_timer_dispatcher = Dispatcher::new(
             Timer::immediate(),
             move |deadline, _, data: &mut ServerState<BackendData>| {
@@ -401,6 +427,7 @@ impl<BackendData: Backend + 'static> FlutterEngine<BackendData> {
             platform_method_channel,
             key_event_channel,
             text_input: TextInput::new(text_input_channel),
+            mouse_cursor_channel,
             rx_request_external_texture_name_registration_token,
         });
 
@@ -430,12 +457,13 @@ impl<BackendData: Backend + 'static> FlutterEngine<BackendData> {
     pub fn send_window_metrics(
         &self,
         size: Size<u32, Physical>,
+        pixel_ratio: f64,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let event = FlutterWindowMetricsEvent {
             struct_size: size_of::<FlutterWindowMetricsEvent>(),
             width: size.w as usize,
             height: size.h as usize,
-            pixel_ratio: 1.0,
+            pixel_ratio,
             left: 0,
             top: 0,
             physical_view_inset_top: 0.0,
@@ -490,7 +518,7 @@ impl<BackendData: Backend + 'static> FlutterEngine<BackendData> {
             &json!({
                 "keymap": "linux",
                 "toolkit": "glfw",
-                "keyCode": get_glfw_keycode(key_event.key_code),
+                "keyCode": get_glfw_keycode(key_event.key_code, key_event.latin_keysym),
                 "scanCode": key_event.key_code + 8,
                 "modifiers": get_glfw_modifiers(key_event.mods),
                 "unicodeScalarValues": key_event.codepoint.map(|c| c as u32),
@@ -589,6 +617,12 @@ struct FlutterEngineData {
     output_height: Option<u16>,
     channels: FlutterEngineChannels,
     framebuffer_importer: GlesFramebufferImporter,
+    /// Age of the buffer behind each framebuffer object, see [Framebuffer::age].
+    framebuffer_ages: HashMap<u32, u8>,
+    /// Damage of the last presented frames, newest first.
+    damage_history: VecDeque<Vec<FlutterRect>>,
+    /// Keeps the rects returned by `populate_existing_damage` alive until Flutter reads them.
+    existing_damage: Vec<FlutterRect>,
 }
 
 // Ironically, EGLContext which contains EGLDisplay is Send, but EGLDisplay is not.
@@ -629,14 +663,17 @@ impl FlutterEngineData {
             output_height: None,
             channels,
             framebuffer_importer: unsafe { GlesFramebufferImporter::new(egl_display.clone())? },
+            framebuffer_ages: HashMap::new(),
+            damage_history: VecDeque::new(),
+            existing_damage: vec![],
         })
     }
 }
 
 pub struct FlutterEngineChannels {
-    tx_present: channel::Sender<()>,
+    tx_present: channel::Sender<Vec<Rectangle<i32, Physical>>>,
     tx_request_fbo: channel::Sender<()>,
-    rx_fbo: channel::Channel<Option<Dmabuf>>,
+    rx_fbo: channel::Channel<Option<Framebuffer>>,
     rx_output_height: channel::Channel<u16>,
     tx_baton: channel::Sender<Baton>,
     tx_request_external_texture_name: channel::Sender<i64>,
@@ -644,9 +681,10 @@ pub struct FlutterEngineChannels {
 }
 
 pub struct EmbedderChannels {
-    pub rx_present: channel::Channel<()>,
+    /// Receives the damage of each frame Flutter presents, in pixels of the Flutter view.
+    pub rx_present: channel::Channel<Vec<Rectangle<i32, Physical>>>,
     pub rx_request_fbo: channel::Channel<()>,
-    pub tx_fbo: channel::Sender<Option<Dmabuf>>,
+    pub tx_fbo: channel::Sender<Option<Framebuffer>>,
     pub tx_output_height: channel::Sender<u16>,
     pub rx_baton: channel::Channel<Baton>,
 }
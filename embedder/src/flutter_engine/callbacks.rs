@@ -1,7 +1,9 @@
+use std::collections::VecDeque;
 use std::ffi::c_void;
 use std::ptr::null_mut;
 
 use smithay::backend::renderer::gles::ffi;
+use smithay::utils::{Physical, Rectangle};
 use tracing::error;
 
 use crate::flutter_engine::embedder::{
@@ -10,8 +12,21 @@ use crate::flutter_engine::embedder::{
 };
 use crate::flutter_engine::platform_channels::binary_messenger::BinaryMessenger;
 use crate::flutter_engine::{Baton, FlutterEngine};
+use crate::gles_framebuffer_importer::{Framebuffer, FramebufferSource};
 use crate::Backend;
 
+/// Buffers older than this many frames are repainted entirely.
+/// Swapchains never have more buffers than that.
+const MAX_BUFFER_AGE: usize = 4;
+
+/// Damage covering the whole view, Flutter clips it to the size of the view.
+static FULL_DAMAGE: FlutterRect = FlutterRect {
+    left: 0.0,
+    top: 0.0,
+    right: 10000.0,
+    bottom: 10000.0,
+};
+
 pub unsafe extern "C" fn make_current<BackendData>(user_data: *mut c_void) -> bool
 where
     BackendData: Backend + 'static,
@@ -68,53 +83,107 @@ where
     {
         return 0;
     }
-    if let Ok(Some(dmabuf)) = flutter_engine.data.channels.rx_fbo.recv() {
-        flutter_engine
-            .data
+    let data = &mut flutter_engine.data;
+    let Ok(Some(Framebuffer { source, age })) = data.channels.rx_fbo.recv() else {
+        return 0;
+    };
+    let fbo = match source {
+        FramebufferSource::Dmabuf(dmabuf) => data
             .framebuffer_importer
-            .import_framebuffer(&flutter_engine.data.main_egl_context, dmabuf)
-            .unwrap_or(0)
-    } else {
-        0
-    }
+            .import_framebuffer(&data.main_egl_context, dmabuf)
+            .unwrap_or(0),
+        FramebufferSource::Texture(texture) => data
+            .framebuffer_importer
+            .import_texture(&data.main_egl_context, texture)
+            .unwrap_or(0),
+    };
+    data.framebuffer_ages.insert(fbo, age);
+    fbo
 }
 
 pub unsafe extern "C" fn present_with_info<BackendData>(
     user_data: *mut c_void,
-    _frame_present_info: *const FlutterPresentInfo,
+    frame_present_info: *const FlutterPresentInfo,
 ) -> bool
 where
     BackendData: Backend + 'static,
 {
     let flutter_engine = &mut *(user_data as *mut FlutterEngine<BackendData>);
-    flutter_engine.data.gl.Finish();
-    flutter_engine.data.channels.tx_present.send(()).is_ok()
+    let data = &mut flutter_engine.data;
+    data.gl.Finish();
+
+    let frame_damage = damage_rects(&(*frame_present_info).frame_damage).to_vec();
+    let damage = frame_damage.iter().map(to_rectangle).collect();
+    data.damage_history.push_front(frame_damage);
+    data.damage_history.truncate(MAX_BUFFER_AGE);
+
+    data.channels.tx_present.send(damage).is_ok()
 }
 
+/// Tells Flutter which parts of the buffer are outdated so it doesn't repaint the rest.
 pub unsafe extern "C" fn populate_existing_damage<BackendData>(
-    _user_data: *mut c_void,
-    _fbo_id: isize,
+    user_data: *mut c_void,
+    fbo_id: isize,
     existing_damage: *mut FlutterDamage,
 ) where
     BackendData: Backend + 'static,
 {
+    let flutter_engine = &mut *(user_data as *mut FlutterEngine<BackendData>);
+    let data = &mut flutter_engine.data;
+
+    let age = data
+        .framebuffer_ages
+        .get(&(fbo_id as u32))
+        .copied()
+        .unwrap_or(0) as usize;
+
+    data.existing_damage = existing_damage_for_age(age, &data.damage_history);
+
     let existing_damage = &mut *existing_damage;
     existing_damage.struct_size = std::mem::size_of::<FlutterDamage>();
-    existing_damage.num_rects = 1;
+    existing_damage.num_rects = data.existing_damage.len();
+    existing_damage.damage = data.existing_damage.as_mut_ptr();
+}
 
-    // TODO: Implement proper damage tracking.
+/// The parts of a buffer of `age` that differ from the last presented frame.
+/// `damage_history` holds the damage of the last presented frames, newest first.
+fn existing_damage_for_age(
+    age: usize,
+    damage_history: &VecDeque<Vec<FlutterRect>>,
+) -> Vec<FlutterRect> {
     // The Flutter engine docs says that if this callback is not implemented,
     // it will repaint the entire screen every frame, but it's false.
-    // It's probably a bug in the Flutter engine, but until I implement proper damage tracking
-    // we manually set the damage to the entire screen.
-    static FLUTTER_RECT: FlutterRect = FlutterRect {
-        left: 0.0,
-        top: 0.0,
-        right: 10000.0,
-        bottom: 10000.0,
-    };
+    // We have to report the entire screen ourselves when the buffer content is unknown.
+    if age == 0 || age > damage_history.len() + 1 {
+        vec![FULL_DAMAGE]
+    } else {
+        // The buffer misses the frames presented after it.
+        damage_history
+            .iter()
+            .take(age - 1)
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
 
-    existing_damage.damage = &FLUTTER_RECT as *const _ as *mut _;
+unsafe fn damage_rects(damage: &FlutterDamage) -> &[FlutterRect] {
+    if damage.damage.is_null() {
+        return &[];
+    }
+    std::slice::from_raw_parts(damage.damage, damage.num_rects)
+}
+
+fn to_rectangle(rect: &FlutterRect) -> Rectangle<i32, Physical> {
+    let left = rect.left.floor() as i32;
+    let top = rect.top.floor() as i32;
+    Rectangle::from_loc_and_size(
+        (left, top),
+        (
+            rect.right.ceil() as i32 - left,
+            rect.bottom.ceil() as i32 - top,
+        ),
+    )
 }
 
 pub unsafe extern "C" fn surface_transformation<BackendData>(
@@ -242,3 +311,75 @@ where
 
     texture_name != 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(left: f64, top: f64, right: f64, bottom: f64) -> FlutterRect {
+        FlutterRect {
+            left,
+            top,
+            right,
+            bottom,
+        }
+    }
+
+    fn lefts(damage: &[FlutterRect]) -> Vec<f64> {
+        damage.iter().map(|rect| rect.left).collect()
+    }
+
+    #[test]
+    fn buffers_miss_the_damage_of_the_frames_presented_after_them() {
+        // The frame presented last damaged the rect starting at 1.
+        let damage_history = VecDeque::from([
+            vec![rect(1.0, 0.0, 2.0, 2.0)],
+            vec![rect(2.0, 0.0, 3.0, 3.0), rect(3.0, 0.0, 4.0, 4.0)],
+            vec![rect(4.0, 0.0, 5.0, 5.0)],
+        ]);
+
+        // The buffer of the last frame is up to date.
+        assert!(existing_damage_for_age(1, &damage_history).is_empty());
+        assert_eq!(lefts(&existing_damage_for_age(2, &damage_history)), [1.0]);
+        assert_eq!(
+            lefts(&existing_damage_for_age(3, &damage_history)),
+            [1.0, 2.0, 3.0]
+        );
+        assert_eq!(
+            lefts(&existing_damage_for_age(4, &damage_history)),
+            [1.0, 2.0, 3.0, 4.0]
+        );
+    }
+
+    #[test]
+    fn unknown_buffers_are_repainted_entirely() {
+        let damage_history = VecDeque::from([vec![rect(1.0, 0.0, 2.0, 2.0)]]);
+        let full_damage = [FULL_DAMAGE.left];
+
+        // New buffers.
+        assert_eq!(
+            lefts(&existing_damage_for_age(0, &damage_history)),
+            full_damage
+        );
+        // Older than the history.
+        assert_eq!(
+            lefts(&existing_damage_for_age(3, &damage_history)),
+            full_damage
+        );
+        assert_eq!(
+            lefts(&existing_damage_for_age(
+                MAX_BUFFER_AGE + 1,
+                &VecDeque::new()
+            )),
+            full_damage
+        );
+    }
+
+    #[test]
+    fn damage_covers_partial_pixels() {
+        assert_eq!(
+            to_rectangle(&rect(1.5, 2.25, 10.5, 20.0)),
+            Rectangle::from_loc_and_size((1, 2), (10, 18))
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! https://api.flutter.dev/flutter/services/SystemChannels/mouseCursor-constant.html
+
+use smithay::input::pointer::{CursorIcon, CursorImageStatus};
+use smithay::reexports::calloop::channel::Event;
+
+use crate::flutter_engine::platform_channels::encodable_value::EncodableValue;
+use crate::flutter_engine::platform_channels::method_call::MethodCall;
+use crate::flutter_engine::platform_channels::method_result::MethodResult;
+use crate::server::ServerState;
+use crate::Backend;
+
+pub fn mouse_cursor_channel_method_call_handler<BackendData: Backend + 'static>(
+    event: Event<(
+        MethodCall<EncodableValue>,
+        Box<dyn MethodResult<EncodableValue>>,
+    )>,
+    _: &mut (),
+    data: &mut ServerState<BackendData>,
+) {
+    if let Event::Msg((method_call, mut result)) = event {
+        match method_call.method() {
+            "activateSystemCursor" => {
+                let kind = match method_call.arguments() {
+                    Some(EncodableValue::Map(arguments)) => {
+                        arguments
+                            .iter()
+                            .find_map(|(key, value)| match (key, value) {
+                                (EncodableValue::String(key), EncodableValue::String(kind))
+                                    if key == "kind" =>
+                                {
+                                    Some(kind.as_str())
+                                }
+                                _ => None,
+                            })
+                    }
+                    _ => None,
+                };
+
+                match kind {
+                    Some(kind) => {
+                        data.flutter_cursor_image_status = cursor_image_status_from_kind(kind);
+                        result.success(None);
+                    }
+                    None => result.error(
+                        "invalid_arguments".to_string(),
+                        "Missing cursor kind".to_string(),
+                        None,
+                    ),
+                }
+            }
+            _ => result.not_implemented(),
+        }
+    }
+}
+
+/// Maps the kinds of `SystemMouseCursors` to the CSS cursor names used by xcursor themes.
+pub fn cursor_image_status_from_kind(kind: &str) -> CursorImageStatus {
+    let icon = match kind {
+        "none" => return CursorImageStatus::Hidden,
+        "click" => CursorIcon::Pointer,
+        "forbidden" => CursorIcon::NotAllowed,
+        "wait" => CursorIcon::Wait,
+        "progress" => CursorIcon::Progress,
+        "contextMenu" => CursorIcon::ContextMenu,
+        "help" => CursorIcon::Help,
+        "text" => CursorIcon::Text,
+        "verticalText" => CursorIcon::VerticalText,
+        "cell" => CursorIcon::Cell,
+        "precise" => CursorIcon::Crosshair,
+        "move" => CursorIcon::Move,
+        "grab" => CursorIcon::Grab,
+        "grabbing" => CursorIcon::Grabbing,
+        "noDrop" => CursorIcon::NoDrop,
+        "alias" => CursorIcon::Alias,
+        "copy" => CursorIcon::Copy,
+        "allScroll" => CursorIcon::AllScroll,
+        "resizeLeftRight" => CursorIcon::EwResize,
+        "resizeUpDown" => CursorIcon::NsResize,
+        "resizeUpLeftDownRight" => CursorIcon::NwseResize,
+        "resizeUpRightDownLeft" => CursorIcon::NeswResize,
+        "resizeUp" => CursorIcon::NResize,
+        "resizeDown" => CursorIcon::SResize,
+        "resizeLeft" => CursorIcon::WResize,
+        "resizeRight" => CursorIcon::EResize,
+        "resizeUpLeft" => CursorIcon::NwResize,
+        "resizeUpRight" => CursorIcon::NeResize,
+        "resizeDownLeft" => CursorIcon::SwResize,
+        "resizeDownRight" => CursorIcon::SeResize,
+        "resizeColumn" => CursorIcon::ColResize,
+        "resizeRow" => CursorIcon::RowResize,
+        "zoomIn" => CursorIcon::ZoomIn,
+        "zoomOut" => CursorIcon::ZoomOut,
+        // "basic" and kinds without an equivalent.
+        _ => CursorIcon::Default,
+    };
+    CursorImageStatus::Named(icon)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flutter_cursor_kinds_map_to_xcursor_names() {
+        assert!(matches!(
+            cursor_image_status_from_kind("none"),
+            CursorImageStatus::Hidden
+        ));
+        assert!(matches!(
+            cursor_image_status_from_kind("click"),
+            CursorImageStatus::Named(CursorIcon::Pointer)
+        ));
+        assert!(matches!(
+            cursor_image_status_from_kind("resizeUpLeftDownRight"),
+            CursorImageStatus::Named(CursorIcon::NwseResize)
+        ));
+        assert!(matches!(
+            cursor_image_status_from_kind("basic"),
+            CursorImageStatus::Named(CursorIcon::Default)
+        ));
+        assert!(matches!(
+            cursor_image_status_from_kind("notAFlutterCursor"),
+            CursorImageStatus::Named(CursorIcon::Default)
+        ));
+    }
+}
@@ -7,20 +7,32 @@ use serde_json::json;
 use smithay::backend::input::ButtonState;
 use smithay::backend::x11;
 use smithay::input::pointer::{ButtonEvent, MotionEvent};
+use smithay::input::touch::{DownEvent, MotionEvent as TouchMotionEvent, UpEvent};
 use smithay::reexports::calloop::channel::Event;
 use smithay::reexports::calloop::channel::Event::Msg;
 use smithay::reexports::wayland_protocols::xdg::shell::server::xdg_toplevel;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
 use smithay::utils::SERIAL_COUNTER;
 use smithay::wayland::compositor::with_states;
+use smithay::wayland::shell::wlr_layer::{
+    KeyboardInteractivity, LayerSurfaceCachedState, LAYER_SURFACE_ROLE,
+};
 use smithay::wayland::shell::xdg;
 use smithay::wayland::xwayland_shell::XWAYLAND_SHELL_ROLE;
 use smithay::xwayland::xwm;
 
 use crate::flutter_engine::platform_channels::method_call::MethodCall;
 use crate::flutter_engine::platform_channels::method_result::MethodResult;
-use crate::flutter_engine::wayland_messages::NewX11Surface;
+use crate::flutter_engine::wayland_messages::{DecorationMode, NewX11Surface};
 use crate::focus::{KeyboardFocusTarget, PointerFocusTarget};
+use crate::input_device_configuration::InputDeviceConfiguration;
+use crate::input_handling::{self, touch_slot};
+use crate::keyboard::repeat_settings::KeyRepeatSettings;
+use crate::monitor_configuration::MonitorConfiguration;
 use crate::mouse_button_tracker::FLUTTER_TO_LINUX_MOUSE_BUTTONS;
+use crate::server::key_bindings::KeyBindingAction;
+use crate::server::screenshot::CaptureScreenshotPayload;
+use crate::server::window_state::SetWindowStatePayload;
 use crate::server::{get_surface_id, ServerState};
 use crate::Backend;
 
@@ -37,10 +49,36 @@ pub fn platform_channel_method_handler<BackendData: Backend + 'static>(
             "pointer_hover" => pointer_hover(method_call, result, data),
             "pointer_exit" => pointer_exit(method_call, result, data),
             "mouse_buttons_event" => mouse_buttons_event(method_call, result, data),
+            "touch_down" => touch_down(method_call, result, data),
+            "touch_motion" => touch_motion(method_call, result, data),
+            "touch_up" => touch_up(method_call, result, data),
+            "touch_cancel" => touch_cancel(method_call, result, data),
+            "forward_gesture" => forward_gesture(method_call, result, data),
+            "tablet_tool_hover" => tablet_tool_hover(method_call, result, data),
+            "tablet_tool_exit" => tablet_tool_exit(method_call, result, data),
             "activate_window" => activate_window(method_call, result, data),
             "resize_window" => resize_window(method_call, result, data),
             "close_window" => close_window(method_call, result, data),
+            "set_window_state" => set_window_state(method_call, result, data),
+            "set_decoration_mode" => set_decoration_mode(method_call, result, data),
             "get_monitor_layout" => get_monitor_layout(method_call, result, data),
+            "configure_monitor" => configure_monitor(method_call, result, data),
+            "set_direct_scanout" => set_direct_scanout(method_call, result, data),
+            "get_keyboard_layouts" => get_keyboard_layouts(method_call, result, data),
+            "set_keyboard_layout" => set_keyboard_layout(method_call, result, data),
+            "next_layout" => next_layout(method_call, result, data),
+            "get_keyboard_repeat" => get_keyboard_repeat(method_call, result, data),
+            "set_keyboard_repeat" => set_keyboard_repeat(method_call, result, data),
+            "get_input_devices" => get_input_devices(method_call, result, data),
+            "configure_input_device" => configure_input_device(method_call, result, data),
+            "register_key_binding" => register_key_binding(method_call, result, data),
+            "unregister_key_binding" => unregister_key_binding(method_call, result, data),
+            "capture_screenshot" => capture_screenshot(method_call, result, data),
+            "answer_screen_capture_permission" => {
+                answer_screen_capture_permission(method_call, result, data)
+            }
+            "set_foreign_toplevel_state" => set_foreign_toplevel_state(method_call, result, data),
+            "create_activation_token" => create_activation_token(method_call, result, data),
             "get_environment_variables" => get_environment_variables(method_call, result, data),
             "shell_ready" => on_shell_ready(method_call, result, data),
             _ => result.error(
@@ -165,6 +203,190 @@ pub fn mouse_buttons_event<BackendData: Backend + 'static>(
     result.success(None);
 }
 
+/// A touch point the shell routes to a surface.
+/// `device` is the id of the touch point in the Flutter pointer events.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TouchPayload {
+    surface_id: u64,
+    device: i32,
+    x: f64,
+    y: f64,
+}
+
+pub fn touch_down<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: TouchPayload = serde_json::from_value(args).unwrap();
+
+    let Some(surface) = data.surfaces.get(&payload.surface_id).cloned() else {
+        result.error(
+            "surface_doesnt_exist".to_string(),
+            format!("Surface {} doesn't exist", payload.surface_id),
+            None,
+        );
+        return;
+    };
+
+    let now = Duration::from(data.clock.now()).as_millis() as u32;
+    let touch = data.touch.clone();
+
+    if let Some(x11_surface) = data.x11_surface_per_wl_surface.get(&surface).cloned() {
+        let _ = data.x11_wm.as_mut().unwrap().raise_window(&x11_surface);
+    }
+
+    touch.down(
+        data,
+        Some((PointerFocusTarget::from(surface), Default::default())),
+        &DownEvent {
+            slot: touch_slot(payload.device),
+            location: (payload.x, payload.y).into(),
+            serial: SERIAL_COUNTER.next_serial(),
+            time: now,
+        },
+    );
+    touch.frame(data);
+    result.success(None);
+}
+
+pub fn touch_motion<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: TouchPayload = serde_json::from_value(args).unwrap();
+
+    let Some(surface) = data.surfaces.get(&payload.surface_id).cloned() else {
+        result.error(
+            "surface_doesnt_exist".to_string(),
+            format!("Surface {} doesn't exist", payload.surface_id),
+            None,
+        );
+        return;
+    };
+
+    let now = Duration::from(data.clock.now()).as_millis() as u32;
+    let touch = data.touch.clone();
+
+    touch.motion(
+        data,
+        Some((PointerFocusTarget::from(surface), Default::default())),
+        &TouchMotionEvent {
+            slot: touch_slot(payload.device),
+            location: (payload.x, payload.y).into(),
+            time: now,
+        },
+    );
+    touch.frame(data);
+    result.success(None);
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TouchUpPayload {
+    device: i32,
+}
+
+pub fn touch_up<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: TouchUpPayload = serde_json::from_value(args).unwrap();
+
+    let now = Duration::from(data.clock.now()).as_millis() as u32;
+    let touch = data.touch.clone();
+
+    touch.up(
+        data,
+        &UpEvent {
+            slot: touch_slot(payload.device),
+            serial: SERIAL_COUNTER.next_serial(),
+            time: now,
+        },
+    );
+    touch.frame(data);
+    result.success(None);
+}
+
+/// The shell claimed the touch sequence, clients stop receiving it.
+pub fn touch_cancel<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let touch = data.touch.clone();
+    touch.cancel(data);
+    result.success(None);
+}
+
+/// The shell doesn't use the touchpad gesture in progress,
+/// the client under the pointer gets it instead.
+pub fn forward_gesture<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let now = Duration::from(data.clock.now()).as_millis() as u32;
+    input_handling::forward_gesture(data, now);
+    result.success(None);
+}
+
+/// A tablet tool the shell routes to a surface.
+/// `device` is the id of the tool in the Flutter pointer events.
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabletToolHoverPayload {
+    surface_id: u64,
+    device: i32,
+    x: f64,
+    y: f64,
+}
+
+pub fn tablet_tool_hover<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: TabletToolHoverPayload = serde_json::from_value(args).unwrap();
+
+    let Some(surface) = data.surfaces.get(&payload.surface_id).cloned() else {
+        result.error(
+            "surface_doesnt_exist".to_string(),
+            format!("Surface {} doesn't exist", payload.surface_id),
+            None,
+        );
+        return;
+    };
+
+    input_handling::move_tablet_tool(data, payload.device, (payload.x, payload.y), Some(surface));
+    result.success(None);
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabletToolExitPayload {
+    device: i32,
+}
+
+pub fn tablet_tool_exit<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: TabletToolExitPayload = serde_json::from_value(args).unwrap();
+
+    input_handling::move_tablet_tool(data, payload.device, (0.0, 0.0), None);
+    result.success(None);
+}
+
 #[derive(Debug, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ActivateWindowPayload {
@@ -256,6 +478,34 @@ pub fn activate_window<BackendData: Backend + 'static>(
 
             result.success(None);
         }
+        Some(LAYER_SURFACE_ROLE) => {
+            if !data.layer_surfaces.contains_key(&payload.surface_id) {
+                result.error(
+                    "layer_surface_doesnt_exist".to_string(),
+                    format!("Layer surface {} doesn't exist", payload.surface_id),
+                    None,
+                );
+                return;
+            }
+
+            let keyboard_interactivity = with_states(&wl_surface, |states| {
+                states
+                    .cached_state
+                    .current::<LayerSurfaceCachedState>()
+                    .keyboard_interactivity
+            });
+
+            // Panels and wallpapers never take the keyboard focus.
+            if payload.activate && keyboard_interactivity != KeyboardInteractivity::None {
+                keyboard.set_focus(
+                    data,
+                    Some(KeyboardFocusTarget::WlSurface(wl_surface)),
+                    serial,
+                );
+            }
+
+            result.success(None);
+        }
         _ => {
             result.error(
                 "invalid_surface_role".to_string(),
@@ -341,6 +591,23 @@ pub fn resize_window<BackendData: Backend + 'static>(
 
             result.success(None);
         }
+        Some(LAYER_SURFACE_ROLE) => {
+            let Some(layer_surface) = data.layer_surfaces.get(&payload.surface_id) else {
+                result.error(
+                    "layer_surface_doesnt_exist".to_string(),
+                    format!("Layer surface {} doesn't exist", payload.surface_id),
+                    None,
+                );
+                return;
+            };
+
+            layer_surface.with_pending_state(|state| {
+                state.size = Some((payload.width, payload.height).into());
+            });
+            layer_surface.send_pending_configure();
+
+            result.success(None);
+        }
         _ => {
             result.error(
                 "invalid_surface_role".to_string(),
@@ -405,6 +672,19 @@ pub fn close_window<BackendData: Backend + 'static>(
             let _ = x11_surface.close();
             result.success(None);
         }
+        Some(LAYER_SURFACE_ROLE) => {
+            let Some(layer_surface) = data.layer_surfaces.get(&payload.surface_id) else {
+                result.error(
+                    "layer_surface_doesnt_exist".to_string(),
+                    format!("Layer surface {} doesn't exist", payload.surface_id),
+                    None,
+                );
+                return;
+            };
+
+            layer_surface.send_close();
+            result.success(None);
+        }
         _ => {
             result.error(
                 "invalid_surface_role".to_string(),
@@ -416,6 +696,51 @@ pub fn close_window<BackendData: Backend + 'static>(
     }
 }
 
+/// Answers `maximize_request`, `fullscreen_request` and the like, or changes the state on the shell's own accord.
+pub fn set_window_state<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: SetWindowStatePayload = serde_json::from_value(args).unwrap();
+
+    match data.set_window_state(&payload) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetDecorationModePayload {
+    surface_id: u64,
+    mode: DecorationMode,
+}
+
+pub fn set_decoration_mode<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: SetDecorationModePayload = serde_json::from_value(args).unwrap();
+
+    if !data.xdg_toplevels.contains_key(&payload.surface_id)
+        && !data.kde_decorations.contains_key(&payload.surface_id)
+    {
+        result.error(
+            "toplevel_doesnt_exist".to_string(),
+            format!("Toplevel {} doesn't exist", payload.surface_id),
+            None,
+        );
+        return;
+    }
+
+    data.set_decoration_mode(payload.surface_id, payload.mode);
+    result.success(None);
+}
+
 pub fn get_monitor_layout<BackendData: Backend + 'static>(
     _method_call: MethodCall<serde_json::Value>,
     mut result: Box<dyn MethodResult<serde_json::Value>>,
@@ -426,6 +751,249 @@ pub fn get_monitor_layout<BackendData: Backend + 'static>(
     result.success(None);
 }
 
+pub fn configure_monitor<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let configuration: MonitorConfiguration = serde_json::from_value(args).unwrap();
+
+    match BackendData::configure_monitor(data, configuration) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetDirectScanoutPayload {
+    surface_id: u64,
+    /// The surface goes back through Flutter when missing.
+    monitor_name: Option<String>,
+}
+
+pub fn set_direct_scanout<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: SetDirectScanoutPayload = serde_json::from_value(args).unwrap();
+
+    match data.set_direct_scanout(payload.surface_id, payload.monitor_name) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+pub fn get_keyboard_layouts<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    data.send_keyboard_layouts();
+    result.success(None);
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetKeyboardLayoutPayload {
+    index: u32,
+}
+
+pub fn set_keyboard_layout<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: SetKeyboardLayoutPayload = serde_json::from_value(args).unwrap();
+
+    match data.set_keyboard_layout(payload.index) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+pub fn next_layout<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    data.next_keyboard_layout();
+    result.success(None);
+}
+
+pub fn get_keyboard_repeat<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    data.send_keyboard_repeat_info();
+    result.success(None);
+}
+
+pub fn set_keyboard_repeat<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: KeyRepeatSettings = serde_json::from_value(args).unwrap();
+
+    match data.change_keyboard_repeat_info(payload) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+pub fn get_input_devices<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    BackendData::send_input_devices(data);
+    result.success(None);
+}
+
+pub fn configure_input_device<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let configuration: InputDeviceConfiguration = serde_json::from_value(args).unwrap();
+
+    match BackendData::configure_input_device(data, configuration) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegisterKeyBindingPayload {
+    /// Like `Ctrl+Alt+T`.
+    keys: String,
+    action: KeyBindingAction,
+}
+
+pub fn register_key_binding<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: RegisterKeyBindingPayload = serde_json::from_value(args).unwrap();
+
+    match data.register_key_binding(&payload.keys, payload.action) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UnregisterKeyBindingPayload {
+    keys: String,
+}
+
+pub fn unregister_key_binding<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: UnregisterKeyBindingPayload = serde_json::from_value(args).unwrap();
+
+    match data.unregister_key_binding(&payload.keys) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+pub fn capture_screenshot<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: CaptureScreenshotPayload = serde_json::from_value(args).unwrap();
+
+    match data.save_screenshot(&payload.path, &payload.target) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AnswerScreenCapturePermissionPayload {
+    request_id: u64,
+    allowed: bool,
+}
+
+/// Answers a `screen_capture_permission_request`, the client keeps the answer until it disconnects.
+pub fn answer_screen_capture_permission<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: AnswerScreenCapturePermissionPayload = serde_json::from_value(args).unwrap();
+
+    match data.answer_screen_capture_permission(payload.request_id, payload.allowed) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetForeignToplevelStatePayload {
+    surface_id: u64,
+    #[serde(default)]
+    activated: bool,
+    #[serde(default)]
+    minimized: bool,
+    /// Names of the monitors showing the window, all of them when missing.
+    monitors: Option<Vec<String>>,
+}
+
+/// Tells taskbars whether a window is activated or minimized and where it is.
+/// Maximized and fullscreen come from `set_window_state`.
+pub fn set_foreign_toplevel_state<BackendData: Backend + 'static>(
+    method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let args = method_call.arguments().unwrap().clone();
+    let payload: SetForeignToplevelStatePayload = serde_json::from_value(args).unwrap();
+
+    match data.set_foreign_toplevel_state(
+        payload.surface_id,
+        payload.activated,
+        payload.minimized,
+        payload.monitors,
+    ) {
+        Ok(()) => result.success(None),
+        Err(err) => result.error(err.code().to_string(), err.to_string(), None),
+    }
+}
+
+/// A token the shell's launcher gives to the app it starts, so that its first window gets the focus.
+pub fn create_activation_token<BackendData: Backend + 'static>(
+    _method_call: MethodCall<serde_json::Value>,
+    mut result: Box<dyn MethodResult<serde_json::Value>>,
+    data: &mut ServerState<BackendData>,
+) {
+    let token = data.create_activation_token();
+    result.success(Some(json!({
+        "token": token,
+    })));
+}
+
 pub fn get_environment_variables<BackendData: Backend + 'static>(
     _method_call: MethodCall<serde_json::Value>,
     mut result: Box<dyn MethodResult<serde_json::Value>>,
@@ -460,28 +1028,30 @@ pub fn on_shell_ready<BackendData: Backend + 'static>(
     println!("on_shell_ready");
     // Send new_surface for all existing surface
     for surface_id in surfaces.keys() {
-        let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        data.invoke_platform_method(
             "new_surface",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
     let toplevels = data.xdg_toplevels.clone();
     for surface_id in toplevels.keys() {
-        let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        data.invoke_platform_method(
             "new_toplevel",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
+    // Before the popups, panels can open some.
+    let layer_surfaces = data.layer_surfaces.clone();
+    for layer_surface in layer_surfaces.values() {
+        data.send_new_layer_surface(layer_surface.wl_surface());
+    }
+
     let popups = data.xdg_popups.clone();
     for surface_id in popups.keys() {
         if let Some(wl_surface) = surfaces.get(surface_id) {
@@ -492,15 +1062,13 @@ pub fn on_shell_ready<BackendData: Backend + 'static>(
                     popup_message.unwrap().position,
                 )
             };
-            let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-            platform_method_channel.invoke_method(
+            data.invoke_platform_method(
                 "new_popup",
-                Some(Box::new(json!({
+                json!({
                     "surfaceId": surface_id,
                     "parent": parent,
                     "position": position,
-                }))),
-                None,
+                }),
             );
         }
     }
@@ -511,15 +1079,12 @@ pub fn on_shell_ready<BackendData: Backend + 'static>(
             let subsurface_message = ServerState::<BackendData>::construct_subsurface_role_message(
                 wl_surface.clone().borrow(),
             );
-            let platform_method_channel: &mut crate::flutter_engine::platform_channels::method_channel::MethodChannel<serde_json::Value> = &mut data.flutter_engine_mut().platform_method_channel;
-
-            platform_method_channel.invoke_method(
+            data.invoke_platform_method(
                 "new_subsurface",
-                Some(Box::new(json!({
+                json!({
                     "surfaceId": surface_id,
                     "parentId": subsurface_message.parent,
-                }))),
-                None,
+                }),
             );
         }
     }
@@ -527,23 +1092,17 @@ pub fn on_shell_ready<BackendData: Backend + 'static>(
     let x11_surfaces = data.x11_surface_per_x11_window.clone();
 
     for x11_surface in x11_surfaces.values() {
-        let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        data.invoke_platform_method(
             "new_x11_surface",
-            Some(Box::new(json!(NewX11Surface {
+            json!(NewX11Surface {
                 x11_surface_id: ServerState::<BackendData>::get_x11_surface_id(&x11_surface),
                 override_redirect: x11_surface.is_override_redirect(),
-            }))),
-            None,
+            }),
         );
     }
 
     for x11_surface in x11_surfaces.values() {
-        let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-
-        platform_method_channel.invoke_method(
-            "x11_properties_changed",
-            Some(Box::new(json!({
+        data.invoke_platform_method("x11_properties_changed", json!({
                 "x11SurfaceId": ServerState::<BackendData>::get_x11_surface_id(&x11_surface),
                 "title": if !x11_surface.title().is_empty() { Some(x11_surface.title()) } else { None },
                 "windowClass": x11_surface.class(),
@@ -553,32 +1112,26 @@ pub fn on_shell_ready<BackendData: Backend + 'static>(
                     None
                 },
                 "startupId": x11_surface.startup_id(),
-            }))),
-            None,
-        );
+            }));
         if (x11_surface.is_mapped()) {
-            platform_method_channel.invoke_method(
+            data.invoke_platform_method(
                 "surface_associated",
-                Some(Box::new(json!({
+                json!({
                     "surfaceId":  get_surface_id(x11_surface.wl_surface().unwrap().borrow()),
                     "x11SurfaceId": ServerState::<BackendData>::get_x11_surface_id(&x11_surface),
-                }))),
-                None,
+                }),
             );
             data.map_x11_surface(x11_surface.clone());
         }
     }
 
+    data.send_keyboard_layouts();
+
     // send commited_state for all existing surface
     for surface_id in surfaces.keys() {
         if let Some(wl_surface) = surfaces.get(surface_id) {
             let surface_message = data.construct_surface_message(wl_surface.clone().borrow());
-            let platform_method_channel = &mut data.flutter_engine_mut().platform_method_channel;
-            platform_method_channel.invoke_method(
-                "commit_surface",
-                Some(Box::new(json!(surface_message))),
-                None,
-            );
+            data.invoke_platform_method("commit_surface", json!(surface_message));
         }
     }
     result.success(None);
@@ -1,9 +1,11 @@
 use serde::ser::SerializeStruct;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use smithay::output::{Mode, Output, PhysicalProperties};
 use smithay::utils::{Buffer as BufferCoords, Logical, Point, Rectangle, Size};
 use std::collections::HashMap;
 
+use crate::monitor_configuration::MonitorTransform;
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SurfaceMessage {
@@ -13,6 +15,8 @@ pub struct SurfaceMessage {
     pub buffer_delta: Option<MyPoint<i32, Logical>>,
     pub buffer_size: Option<MySize<i32, BufferCoords>>,
     pub scale: i32,
+    pub viewport_source: Option<MyRectangle<f64, Logical>>,
+    pub viewport_destination: Option<MySize<i32, Logical>>,
     pub input_region: MyRectangle<i32, Logical>,
     pub subsurfaces_below: Vec<u64>,
     pub subsurfaces_above: Vec<u64>,
@@ -25,6 +29,7 @@ pub enum SurfaceRole {
     XdgSurface(XdgSurfaceMessage),
     Subsurface(SubsurfaceMessage),
     X11Surface,
+    LayerSurface(LayerSurfaceMessage),
 }
 
 #[derive(Debug, Serialize)]
@@ -55,6 +60,15 @@ pub struct ToplevelMessage {
     pub parent_surface_id: Option<u64>,
     pub app_id: Option<String>,
     pub title: Option<String>,
+    /// The decoration mode the client asked for, `None` if it has no preference.
+    pub decoration_mode: Option<DecorationMode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DecorationMode {
+    ClientSide,
+    ServerSide,
 }
 
 #[derive(Debug, Serialize)]
@@ -64,6 +78,50 @@ pub struct PopupMessage {
     pub position: MyPoint<i32, Logical>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerSurfaceMessage {
+    /// One of `background`, `bottom`, `top` or `overlay`.
+    pub layer: &'static str,
+    pub anchor: LayerAnchorMessage,
+    /// Same meaning as in the protocol: positive values reserve space, 0 means the surface
+    /// must not overlap exclusive zones, -1 means the surface ignores them.
+    pub exclusive_zone: i32,
+    pub margin: LayerMarginMessage,
+    /// One of `none`, `exclusive` or `onDemand`.
+    pub keyboard_interactivity: &'static str,
+    /// The size requested by the client, 0 on an axis means the compositor decides.
+    pub desired_size: MySize<i32, Logical>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerAnchorMessage {
+    pub top: bool,
+    pub bottom: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerMarginMessage {
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+    pub left: i32,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewLayerSurfaceMessage {
+    pub surface_id: u64,
+    pub namespace: String,
+    pub layer: &'static str,
+    /// Name of the monitor requested by the client, if any.
+    pub output: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MonitorsMessage {
@@ -178,7 +236,7 @@ impl Serialize for MyOutput {
         S: serde::Serializer,
     {
         let output = &self.0;
-        let mut state = serializer.serialize_struct("Output", 7)?;
+        let mut state = serializer.serialize_struct("Output", 9)?;
         state.serialize_field("name", &output.name())?;
         state.serialize_field("description", &output.description())?;
         state.serialize_field(
@@ -187,6 +245,10 @@ impl Serialize for MyOutput {
         )?;
         state.serialize_field("scale", &output.current_scale().fractional_scale())?;
         state.serialize_field("location", &MyPoint(output.current_location()))?;
+        state.serialize_field(
+            "transform",
+            &MonitorTransform::from(output.current_transform()),
+        )?;
         state.serialize_field(
             "currentMode",
             &output.current_mode().map(|mode| MyMode(mode)),
@@ -259,3 +321,28 @@ impl Serialize for MyPhysicalProperties {
 pub struct EnvironmentVariables<'k, 'v> {
     pub environment_variables: HashMap<&'k str, Option<&'v str>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use smithay::output::Subpixel;
+    use smithay::utils::Transform;
+
+    use super::*;
+
+    #[test]
+    fn monitor_message_contains_the_transform() {
+        let output = Output::new(
+            "first".to_string(),
+            PhysicalProperties {
+                size: (0, 0).into(),
+                subpixel: Subpixel::Unknown,
+                make: "Veshell".into(),
+                model: "test".into(),
+            },
+        );
+        output.change_current_state(None, Some(Transform::Flipped270), None, None);
+
+        let message = serde_json::to_value(MyOutput(output)).unwrap();
+        assert_eq!(message["transform"], "flipped270");
+    }
+}
@@ -1,5 +1,6 @@
 //! Converts dmabufs into OpenGL framebuffer objects and caches them.
 //! Destroys the framebuffers when their source dmabuf fd is closed.
+//! Shared GL textures can also be wrapped into framebuffers for backends without dmabufs.
 //!
 //! Code was copied from GlesRenderer from Smithay because unfortunately buffer importing
 //! and rendering are merged together, and I only wanted the buffer importing part. Smithay's code
@@ -24,12 +25,32 @@ use smithay::backend::{
 };
 use tracing::{info, trace};
 
+/// A buffer the Flutter render thread can draw into.
+pub enum FramebufferSource {
+    Dmabuf(Dmabuf),
+    /// Name of a texture living in an EGL context shared with Flutter.
+    /// The backend owns the texture and must keep it alive until Flutter presents the frame.
+    Texture(ffi::types::GLuint),
+}
+
+/// Backends handing out textures cycle through this many of them.
+pub const TEXTURE_SWAPCHAIN_LENGTH: usize = 3;
+
+/// A buffer handed to Flutter for the next frame.
+pub struct Framebuffer {
+    pub source: FramebufferSource,
+    /// Number of frames presented since this buffer was last presented, like `EGL_EXT_buffer_age`.
+    /// 0 means its content is undefined and Flutter has to repaint everything.
+    pub age: u8,
+}
+
 pub struct GlesFramebufferImporter {
     gl: ffi::Gles2,
     egl_display: EGLDisplay,
 
     // caches
     buffers: Vec<GlesFramebuffer>,
+    texture_buffers: Vec<GlesTextureFramebuffer>,
 }
 
 #[derive(Debug, Clone)]
@@ -40,6 +61,12 @@ struct GlesFramebuffer {
     fbo: ffi::types::GLuint,
 }
 
+#[derive(Debug, Clone)]
+struct GlesTextureFramebuffer {
+    texture: ffi::types::GLuint,
+    fbo: ffi::types::GLuint,
+}
+
 impl GlesFramebufferImporter {
     pub unsafe fn new(egl_display: EGLDisplay) -> Result<Self, GlesError> {
         let gl = ffi::Gles2::load_with(|s| egl::get_proc_address(s) as *const _);
@@ -62,6 +89,7 @@ impl GlesFramebufferImporter {
             gl,
             egl_display,
             buffers: vec![],
+            texture_buffers: vec![],
         })
     }
 
@@ -132,6 +160,66 @@ impl GlesFramebufferImporter {
         Ok(fbo)
     }
 
+    pub fn import_texture(
+        &mut self,
+        egl_context: &EGLContext,
+        texture: ffi::types::GLuint,
+    ) -> Result<u32, GlesError> {
+        self.make_current(egl_context)?;
+
+        if let Some(buffer) = self
+            .texture_buffers
+            .iter()
+            .find(|buffer| buffer.texture == texture)
+        {
+            // The backend may have deleted the texture and created a new one with the same name.
+            // Attach it again so the framebuffer doesn't keep rendering into the old one.
+            unsafe {
+                self.gl.BindFramebuffer(ffi::FRAMEBUFFER, buffer.fbo);
+                self.gl.FramebufferTexture2D(
+                    ffi::FRAMEBUFFER,
+                    ffi::COLOR_ATTACHMENT0,
+                    ffi::TEXTURE_2D,
+                    texture,
+                    0,
+                );
+                self.gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+            }
+            return Ok(buffer.fbo);
+        }
+
+        trace!("Creating framebuffer for texture: {}", texture);
+        unsafe {
+            let mut fbo = 0;
+            self.gl.GenFramebuffers(1, &mut fbo as *mut _);
+            self.gl.BindFramebuffer(ffi::FRAMEBUFFER, fbo);
+            self.gl.FramebufferTexture2D(
+                ffi::FRAMEBUFFER,
+                ffi::COLOR_ATTACHMENT0,
+                ffi::TEXTURE_2D,
+                texture,
+                0,
+            );
+            let status = self.gl.CheckFramebufferStatus(ffi::FRAMEBUFFER);
+            self.gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+
+            if status != ffi::FRAMEBUFFER_COMPLETE {
+                self.gl.DeleteFramebuffers(1, &fbo as *const _);
+                return Err(GlesError::FramebufferBindingError);
+            }
+
+            // The textures of the swapchain before the last resize are gone.
+            if self.texture_buffers.len() == TEXTURE_SWAPCHAIN_LENGTH {
+                let oldest = self.texture_buffers.remove(0);
+                self.gl.DeleteFramebuffers(1, &oldest.fbo as *const _);
+            }
+            self.texture_buffers
+                .push(GlesTextureFramebuffer { texture, fbo });
+
+            Ok(fbo)
+        }
+    }
+
     pub fn make_current(&mut self, egl_context: &EGLContext) -> Result<(), MakeCurrentError> {
         unsafe { egl_context.make_current()? };
         // delayed destruction until the next frame rendering.
@@ -167,5 +255,10 @@ impl Drop for GlesFramebufferImporter {
         while let Some(buffer) = self.buffers.pop() {
             self.drop_buffer(buffer);
         }
+        while let Some(buffer) = self.texture_buffers.pop() {
+            unsafe {
+                self.gl.DeleteFramebuffers(1, &buffer.fbo as *const _);
+            }
+        }
     }
 }
@@ -0,0 +1,400 @@
+//! Runs the compositor without any display hardware.
+//!
+//! Outputs are virtual and rendering happens on a software EGL device (Mesa's llvmpipe),
+//! so this backend works in containers and CI machines that don't have a GPU.
+//! Flutter renders into plain GL textures instead of dmabufs.
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::egl::{self, EGLContext, EGLDevice, EGLDisplay};
+use smithay::backend::renderer::gles::ffi::Gles2;
+use smithay::backend::renderer::gles::{GlesError, GlesRenderer, GlesTexture};
+use smithay::backend::renderer::{Offscreen, Texture};
+use smithay::output::{Mode, Output, PhysicalProperties, Subpixel};
+use smithay::reexports::calloop::channel::{self, Event};
+use smithay::reexports::calloop::timer::{TimeoutAction, Timer};
+use smithay::reexports::calloop::EventLoop;
+use smithay::reexports::wayland_server::protocol::wl_shm;
+use smithay::reexports::wayland_server::Display;
+use smithay::utils::{Physical, Size};
+use tracing::{error, info};
+
+use crate::flutter_engine::{EmbedderChannels, FlutterEngine};
+use crate::gles_framebuffer_importer::{Framebuffer, FramebufferSource, TEXTURE_SWAPCHAIN_LENGTH};
+use crate::input_device_configuration::InputDeviceConfiguration;
+use crate::monitor_configuration::MonitorConfiguration;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::server::{arrange_monitors, scale_from_f64};
+use crate::{Backend, ServerState};
+
+/// Refresh rate of the virtual outputs in millihertz.
+const REFRESH_RATE: i32 = 60_000;
+
+pub struct HeadlessBackend {
+    pub outputs: Vec<Output>,
+    swapchain: Vec<GlesTexture>,
+    /// Age of each texture of the swapchain, see [Framebuffer::age].
+    buffer_ages: Vec<u8>,
+    /// Textures Flutter may still be rendering into after a resize.
+    old_swapchain: Vec<GlesTexture>,
+    current_slot: Option<usize>,
+    pub last_rendered_slot: Option<usize>,
+    tx_output_height: Option<channel::Sender<u16>>,
+}
+
+impl HeadlessBackend {
+    pub fn new(outputs: Vec<Output>) -> Self {
+        Self {
+            outputs,
+            swapchain: vec![],
+            buffer_ages: vec![],
+            old_swapchain: vec![],
+            current_slot: None,
+            last_rendered_slot: None,
+            tx_output_height: None,
+        }
+    }
+
+    /// Flutter gets no framebuffer until the next resize when the textures can't be created.
+    fn resize_swapchain(
+        &mut self,
+        gles_renderer: &mut GlesRenderer,
+        size: Size<i32, Physical>,
+    ) -> Result<(), GlesError> {
+        let current_size = self.swapchain.first().map(|texture| texture.size());
+        if current_size == Some((size.w, size.h).into()) {
+            return Ok(());
+        }
+
+        self.old_swapchain = std::mem::take(&mut self.swapchain);
+        self.buffer_ages.clear();
+        self.current_slot = None;
+        self.last_rendered_slot = None;
+
+        if size.is_empty() {
+            return Ok(());
+        }
+
+        self.swapchain = (0..TEXTURE_SWAPCHAIN_LENGTH)
+            .map(|_| {
+                Offscreen::<GlesTexture>::create_buffer(
+                    gles_renderer,
+                    Fourcc::Abgr8888,
+                    (size.w, size.h).into(),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        self.buffer_ages = vec![0; TEXTURE_SWAPCHAIN_LENGTH];
+        Ok(())
+    }
+
+    pub fn last_rendered_texture(&self) -> Option<&GlesTexture> {
+        self.last_rendered_slot
+            .and_then(|slot| self.swapchain.get(slot))
+    }
+
+    fn acquire(&mut self) -> Option<Framebuffer> {
+        // Never hand out the texture holding the last complete frame.
+        let slot = (0..self.swapchain.len()).find(|slot| Some(*slot) != self.last_rendered_slot)?;
+        self.current_slot = Some(slot);
+        Some(Framebuffer {
+            source: FramebufferSource::Texture(self.swapchain[slot].tex_id()),
+            age: self.buffer_ages[slot],
+        })
+    }
+
+    fn submitted(&mut self, slot: usize) {
+        for age in self.buffer_ages.iter_mut().filter(|age| **age > 0) {
+            *age = age.saturating_add(1);
+        }
+        self.buffer_ages[slot] = 1;
+        self.last_rendered_slot = Some(slot);
+    }
+}
+
+impl Backend for HeadlessBackend {
+    fn seat_name(&self) -> String {
+        "headless".to_string()
+    }
+
+    fn get_monitor_layout(&self) -> Vec<Output> {
+        self.outputs.clone()
+    }
+
+    fn update_monitor_layout(state: &mut ServerState<Self>) {
+        arrange_monitors(&state.backend_data.outputs);
+
+        let size = state.update_window_metrics();
+        if let Some(gles_renderer) = state.gles_renderer.as_mut() {
+            if let Err(err) = state.backend_data.resize_swapchain(gles_renderer, size) {
+                error!(
+                    ?size,
+                    "Failed to create the textures Flutter renders into: {err}"
+                );
+            }
+        }
+        if let Some(tx_output_height) = state.backend_data.tx_output_height.as_ref() {
+            let _ = tx_output_height.send(size.h as u16);
+        }
+
+        if let Some(flutter_engine) = state.flutter_engine.as_mut() {
+            flutter_engine.monitor_layout_changed(state.backend_data.outputs.clone());
+        }
+    }
+
+    fn configure_monitor(
+        state: &mut ServerState<Self>,
+        configuration: MonitorConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        configuration.validate()?;
+        let output = state
+            .backend_data
+            .outputs
+            .iter()
+            .find(|output| output.name() == configuration.monitor_name)
+            .cloned()
+            .ok_or_else(|| {
+                PlatformChannelError::MonitorDoesntExist(configuration.monitor_name.clone())
+            })?;
+
+        if configuration.enabled == Some(false) {
+            return Err(PlatformChannelError::Unsupported(
+                "Virtual outputs can't be disabled",
+            ));
+        }
+
+        if let Some(mode_index) = configuration.mode_index {
+            let mode = output
+                .modes()
+                .get(mode_index)
+                .copied()
+                .ok_or_else(|| PlatformChannelError::InvalidMode(output.name(), mode_index))?;
+            output.change_current_state(Some(mode), None, None, None);
+        }
+
+        configuration.apply_to_output(&output);
+        Self::update_monitor_layout(state);
+        Ok(())
+    }
+
+    fn supports_direct_scanout(&self) -> bool {
+        false
+    }
+
+    fn can_change_vt(&self) -> bool {
+        false
+    }
+
+    fn change_vt(&mut self, _vt: i32) {}
+
+    fn send_input_devices(_state: &mut ServerState<Self>) {}
+
+    fn configure_input_device(
+        _state: &mut ServerState<Self>,
+        _configuration: InputDeviceConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        Err(PlatformChannelError::Unsupported(
+            "Input devices can only be configured with libinput",
+        ))
+    }
+
+    fn last_flutter_frame(state: &mut ServerState<Self>) -> Option<GlesTexture> {
+        state.backend_data.last_rendered_texture().cloned()
+    }
+}
+
+/// Reads the virtual output sizes from `VESHELL_HEADLESS_OUTPUTS`,
+/// a comma separated list like `1920x1080,1280x720@1.5`, where the optional suffix is the scale.
+/// Outputs are placed from left to right.
+fn create_virtual_outputs() -> Vec<Output> {
+    let sizes = std::env::var("VESHELL_HEADLESS_OUTPUTS").unwrap_or_else(|_| "1920x1080".into());
+
+    let outputs: Vec<Output> = sizes
+        .split(',')
+        .filter_map(|size| {
+            let (size, scale) = match size.trim().split_once('@') {
+                Some((size, scale)) => (size, scale.parse::<f64>().ok()),
+                None => (size.trim(), Some(1.0)),
+            };
+            let (w, h) = size.split_once('x')?;
+            match (w.parse::<i32>(), h.parse::<i32>(), scale) {
+                (Ok(w), Ok(h), Some(scale)) if w > 0 && h > 0 && scale > 0.0 => Some((w, h, scale)),
+                _ => {
+                    error!("Invalid headless output size: {}", size);
+                    None
+                }
+            }
+        })
+        .enumerate()
+        .map(|(i, (w, h, scale))| {
+            let output = Output::new(
+                format!("HEADLESS-{}", i + 1),
+                PhysicalProperties {
+                    size: (0, 0).into(),
+                    subpixel: Subpixel::Unknown,
+                    make: "Veshell".into(),
+                    model: "headless".into(),
+                },
+            );
+            let mode = Mode {
+                size: (w, h).into(),
+                refresh: REFRESH_RATE,
+            };
+            output.set_preferred(mode);
+            output.change_current_state(Some(mode), None, Some(scale_from_f64(scale)), None);
+            output
+        })
+        .collect();
+
+    arrange_monitors(&outputs);
+    outputs
+}
+
+pub fn run_headless_backend() {
+    let mut event_loop = EventLoop::try_new().unwrap();
+    let display: Display<ServerState<HeadlessBackend>> = Display::new().unwrap();
+    let mut display_handle = display.handle();
+
+    let egl_device = EGLDevice::enumerate()
+        .expect("Failed to enumerate EGL devices")
+        .find(|device| device.is_software())
+        .expect("No software EGL device found, is Mesa's llvmpipe installed?");
+    let egl_display = unsafe { EGLDisplay::new(egl_device) }.expect("Failed to create EGLDisplay");
+    let egl_context = EGLContext::new(&egl_display).expect("Failed to create EGLContext");
+    let gles_renderer =
+        unsafe { GlesRenderer::new(egl_context) }.expect("Failed to initialize GLES");
+
+    let outputs = create_virtual_outputs();
+    for output in &outputs {
+        let _global = output.create_global::<ServerState<HeadlessBackend>>(&display_handle);
+    }
+
+    // Clients can't share buffers with us without a GPU, so there is no dmabuf global.
+    let mut state = ServerState::new(
+        display,
+        event_loop.handle(),
+        HeadlessBackend::new(outputs),
+        None,
+    );
+
+    state.gles_renderer = Some(gles_renderer);
+    state.gl = Some(Gles2::load_with(
+        |s| unsafe { egl::get_proc_address(s) } as *const _
+    ));
+
+    let (
+        flutter_engine,
+        EmbedderChannels {
+            rx_present,
+            rx_request_fbo,
+            tx_fbo,
+            tx_output_height,
+            rx_baton,
+        },
+    ) = FlutterEngine::new(&mut state).unwrap();
+    state.tx_fbo = Some(tx_fbo.clone());
+    state.flutter_engine = Some(flutter_engine);
+    state.backend_data.tx_output_height = Some(tx_output_height);
+
+    // Creates the textures Flutter renders into.
+    HeadlessBackend::update_monitor_layout(&mut state);
+
+    // Mandatory formats by the Wayland spec.
+    // TODO: Add more formats based on the GLES version.
+    state
+        .shm_state
+        .update_formats([wl_shm::Format::Argb8888, wl_shm::Format::Xrgb8888]);
+
+    // There is no display to synchronize with, so a timer plays the role of the VBLANK.
+    let frame_duration = Duration::from_nanos(1_000_000_000_000 / REFRESH_RATE as u64);
+    event_loop
+        .handle()
+        .insert_source(Timer::from_duration(frame_duration), move |_, _, data| {
+            let drained: Vec<_> = data.batons.drain(..).collect(); // Mutable borrow ends here
+
+            for baton in drained {
+                data.flutter_engine()
+                    .on_vsync(baton, REFRESH_RATE as u32)
+                    .unwrap();
+            }
+            data.send_frame_callbacks();
+            TimeoutAction::ToDuration(frame_duration)
+        })
+        .unwrap();
+
+    event_loop
+        .handle()
+        .insert_source(rx_baton, move |baton, _, data| {
+            if let Event::Msg(baton) = baton {
+                data.batons.push(baton);
+            }
+        })
+        .unwrap();
+
+    event_loop
+        .handle()
+        .insert_source(rx_request_fbo, move |_, _, data| {
+            let framebuffer = data.backend_data.acquire();
+            let _ = data.tx_fbo.as_ref().unwrap().send(framebuffer);
+        })
+        .unwrap();
+
+    event_loop
+        .handle()
+        .insert_source(rx_present, move |event, _, data| {
+            let Event::Msg(damage) = event else {
+                return;
+            };
+
+            let backend_data = &mut data.backend_data;
+            if let Some(slot) = backend_data.current_slot.take() {
+                backend_data.submitted(slot);
+            }
+            backend_data.old_swapchain.clear();
+
+            data.flutter_frame_presented(&damage);
+        })
+        .unwrap();
+
+    state.start_xwayland();
+
+    info!(
+        "Running headless with {} output(s)",
+        state.backend_data.outputs.len()
+    );
+
+    while state.running.load(Ordering::SeqCst) {
+        let result = event_loop.dispatch(None, &mut state);
+        if result.is_err() {
+            state.running.store(false, Ordering::SeqCst);
+        } else {
+            display_handle.flush_clients().unwrap();
+        }
+    }
+
+    // Avoid indefinite hang in the Flutter render thread waiting for a new texture.
+    drop(tx_fbo);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_age_with_every_presented_frame() {
+        let mut backend = HeadlessBackend::new(vec![]);
+        backend.buffer_ages = vec![0; TEXTURE_SWAPCHAIN_LENGTH];
+
+        backend.submitted(0);
+        assert_eq!(backend.buffer_ages, [1, 0, 0]);
+        backend.submitted(1);
+        assert_eq!(backend.buffer_ages, [2, 1, 0]);
+        backend.submitted(2);
+        assert_eq!(backend.buffer_ages, [3, 2, 1]);
+        backend.submitted(0);
+        assert_eq!(backend.buffer_ages, [1, 3, 2]);
+        assert_eq!(backend.last_rendered_slot, Some(0));
+    }
+}
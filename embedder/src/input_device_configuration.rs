@@ -0,0 +1,483 @@
+//! libinput settings of mice and touchpads, changed by the shell through `configure_input_device`.
+//!
+//! Settings are saved per device name in
+//! `$XDG_CONFIG_HOME/veshell/persistence/InputDevice/<device_name>.json`
+//! and applied again when a device with the same name is plugged in.
+//! Only the libinput of the DRM backend can be configured.
+
+use serde_json::json;
+use smithay::reexports::input::{
+    AccelProfile, ClickMethod, Device, DeviceCapability, DeviceConfigError, ScrollMethod,
+};
+use tracing::warn;
+
+use crate::persistence::persistence_path;
+use crate::platform_channel_error::PlatformChannelError;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDeviceConfiguration {
+    /// The `id` of the device sent to the shell.
+    pub device_id: String,
+    #[serde(flatten)]
+    pub settings: InputDeviceSettings,
+}
+
+/// `None` leaves a setting as it is.
+/// In the settings sent to the shell, `None` means the device doesn't support the setting.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputDeviceSettings {
+    pub accel_profile: Option<InputAccelProfile>,
+    /// Between -1 (slowest) and 1 (fastest).
+    pub accel_speed: Option<f64>,
+    pub tap_to_click: Option<bool>,
+    pub tap_drag: Option<bool>,
+    pub natural_scroll: Option<bool>,
+    pub scroll_method: Option<InputScrollMethod>,
+    pub disable_while_typing: Option<bool>,
+    pub left_handed: Option<bool>,
+    pub middle_emulation: Option<bool>,
+    pub click_method: Option<InputClickMethod>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputAccelProfile {
+    Flat,
+    Adaptive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputScrollMethod {
+    NoScroll,
+    TwoFinger,
+    Edge,
+    OnButtonDown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InputClickMethod {
+    ButtonAreas,
+    ClickFinger,
+}
+
+impl From<InputAccelProfile> for AccelProfile {
+    fn from(profile: InputAccelProfile) -> Self {
+        match profile {
+            InputAccelProfile::Flat => AccelProfile::Flat,
+            InputAccelProfile::Adaptive => AccelProfile::Adaptive,
+        }
+    }
+}
+
+impl From<InputScrollMethod> for ScrollMethod {
+    fn from(method: InputScrollMethod) -> Self {
+        match method {
+            InputScrollMethod::NoScroll => ScrollMethod::NoScroll,
+            InputScrollMethod::TwoFinger => ScrollMethod::TwoFinger,
+            InputScrollMethod::Edge => ScrollMethod::Edge,
+            InputScrollMethod::OnButtonDown => ScrollMethod::OnButtonDown,
+        }
+    }
+}
+
+impl From<InputClickMethod> for ClickMethod {
+    fn from(method: InputClickMethod) -> Self {
+        match method {
+            InputClickMethod::ButtonAreas => ClickMethod::ButtonAreas,
+            InputClickMethod::ClickFinger => ClickMethod::Clickfinger,
+        }
+    }
+}
+
+/// Id of the device in the messages to the shell, like `event4`.
+/// Unlike the name, it is unique.
+pub fn input_device_id(device: &Device) -> String {
+    device.sysname().to_string()
+}
+
+/// Describes the device to the shell, with its current settings.
+pub fn input_device_message(device: &Device) -> serde_json::Value {
+    let capabilities = [
+        (DeviceCapability::Keyboard, "keyboard"),
+        (DeviceCapability::Pointer, "pointer"),
+        (DeviceCapability::Touch, "touch"),
+        (DeviceCapability::TabletTool, "tabletTool"),
+        (DeviceCapability::TabletPad, "tabletPad"),
+        (DeviceCapability::Gesture, "gesture"),
+        (DeviceCapability::Switch, "switch"),
+    ]
+    .into_iter()
+    .filter(|(capability, _)| device.has_capability(*capability))
+    .map(|(_, name)| name)
+    .collect::<Vec<_>>();
+
+    json!({
+        "id": input_device_id(device),
+        "name": device.name(),
+        "capabilities": capabilities,
+        "settings": InputDeviceSettings::current(device),
+    })
+}
+
+impl InputDeviceSettings {
+    /// The settings of the device, leaving out the ones it doesn't support.
+    pub fn current(device: &Device) -> Self {
+        Self {
+            accel_profile: device
+                .config_accel_is_available()
+                .then(|| device.config_accel_profile())
+                .flatten()
+                .and_then(|profile| match profile {
+                    AccelProfile::Flat => Some(InputAccelProfile::Flat),
+                    AccelProfile::Adaptive => Some(InputAccelProfile::Adaptive),
+                    _ => None,
+                }),
+            accel_speed: device
+                .config_accel_is_available()
+                .then(|| device.config_accel_speed()),
+            tap_to_click: (device.config_tap_finger_count() > 0)
+                .then(|| device.config_tap_enabled()),
+            tap_drag: (device.config_tap_finger_count() > 0)
+                .then(|| device.config_tap_drag_enabled()),
+            natural_scroll: device
+                .config_scroll_has_natural_scroll()
+                .then(|| device.config_scroll_natural_scroll_enabled()),
+            scroll_method: device
+                .config_scroll_method()
+                .filter(|_| !device.config_scroll_methods().is_empty())
+                .and_then(|method| match method {
+                    ScrollMethod::NoScroll => Some(InputScrollMethod::NoScroll),
+                    ScrollMethod::TwoFinger => Some(InputScrollMethod::TwoFinger),
+                    ScrollMethod::Edge => Some(InputScrollMethod::Edge),
+                    ScrollMethod::OnButtonDown => Some(InputScrollMethod::OnButtonDown),
+                    _ => None,
+                }),
+            disable_while_typing: device
+                .config_dwt_is_available()
+                .then(|| device.config_dwt_enabled()),
+            left_handed: device
+                .config_left_handed_is_available()
+                .then(|| device.config_left_handed()),
+            middle_emulation: device
+                .config_middle_emulation_is_available()
+                .then(|| device.config_middle_emulation_enabled()),
+            click_method: device
+                .config_click_method()
+                .filter(|_| !device.config_click_methods().is_empty())
+                .and_then(|method| match method {
+                    ClickMethod::ButtonAreas => Some(InputClickMethod::ButtonAreas),
+                    ClickMethod::Clickfinger => Some(InputClickMethod::ClickFinger),
+                    _ => None,
+                }),
+        }
+    }
+
+    /// Catches settings the device doesn't support before anything changes.
+    pub fn validate(&self, device: &Device) -> Result<(), PlatformChannelError> {
+        use PlatformChannelError::{InvalidValue, UnsupportedSetting};
+
+        if let Some(profile) = self.accel_profile {
+            if !device.config_accel_profiles().contains(&profile.into()) {
+                return Err(UnsupportedSetting("this acceleration profile"));
+            }
+        }
+        if let Some(speed) = self.accel_speed {
+            if !device.config_accel_is_available() {
+                return Err(UnsupportedSetting("pointer acceleration"));
+            }
+            if !(-1.0..=1.0).contains(&speed) {
+                return Err(InvalidValue("accelSpeed"));
+            }
+        }
+        if (self.tap_to_click.is_some() || self.tap_drag.is_some())
+            && device.config_tap_finger_count() == 0
+        {
+            return Err(UnsupportedSetting("tapping"));
+        }
+        if self.natural_scroll.is_some() && !device.config_scroll_has_natural_scroll() {
+            return Err(UnsupportedSetting("natural scrolling"));
+        }
+        if let Some(method) = self.scroll_method {
+            if !device.config_scroll_methods().contains(&method.into()) {
+                return Err(UnsupportedSetting("this scroll method"));
+            }
+        }
+        if self.disable_while_typing.is_some() && !device.config_dwt_is_available() {
+            return Err(UnsupportedSetting("disable-while-typing"));
+        }
+        if self.left_handed.is_some() && !device.config_left_handed_is_available() {
+            return Err(UnsupportedSetting("left-handed mode"));
+        }
+        if self.middle_emulation.is_some() && !device.config_middle_emulation_is_available() {
+            return Err(UnsupportedSetting("middle button emulation"));
+        }
+        if let Some(method) = self.click_method {
+            if !device.config_click_methods().contains(&method.into()) {
+                return Err(UnsupportedSetting("this click method"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the settings that are set, call `validate` first.
+    pub fn apply(&self, device: &mut Device) -> Result<(), PlatformChannelError> {
+        fn check(
+            result: Result<(), DeviceConfigError>,
+            setting: &'static str,
+        ) -> Result<(), PlatformChannelError> {
+            match result {
+                Ok(()) => Ok(()),
+                Err(DeviceConfigError::Unsupported) => {
+                    Err(PlatformChannelError::UnsupportedSetting(setting))
+                }
+                Err(DeviceConfigError::Invalid) => Err(PlatformChannelError::InvalidValue(setting)),
+            }
+        }
+
+        if let Some(profile) = self.accel_profile {
+            check(
+                device.config_accel_set_profile(profile.into()),
+                "accelProfile",
+            )?;
+        }
+        if let Some(speed) = self.accel_speed {
+            check(device.config_accel_set_speed(speed), "accelSpeed")?;
+        }
+        if let Some(enabled) = self.tap_to_click {
+            check(device.config_tap_set_enabled(enabled), "tapToClick")?;
+        }
+        if let Some(enabled) = self.tap_drag {
+            check(device.config_tap_set_drag_enabled(enabled), "tapDrag")?;
+        }
+        if let Some(enabled) = self.natural_scroll {
+            check(
+                device.config_scroll_set_natural_scroll_enabled(enabled),
+                "naturalScroll",
+            )?;
+        }
+        if let Some(method) = self.scroll_method {
+            check(
+                device.config_scroll_set_method(method.into()),
+                "scrollMethod",
+            )?;
+        }
+        if let Some(enabled) = self.disable_while_typing {
+            check(device.config_dwt_set_enabled(enabled), "disableWhileTyping")?;
+        }
+        if let Some(enabled) = self.left_handed {
+            check(device.config_left_handed_set(enabled), "leftHanded")?;
+        }
+        if let Some(enabled) = self.middle_emulation {
+            check(
+                device.config_middle_emulation_set_enabled(enabled),
+                "middleEmulation",
+            )?;
+        }
+        if let Some(method) = self.click_method {
+            check(device.config_click_set_method(method.into()), "clickMethod")?;
+        }
+        Ok(())
+    }
+
+    /// Settings set in `other` replace the ones of `self`.
+    pub fn merge(&mut self, other: &InputDeviceSettings) {
+        fn merge_setting<T: Copy>(setting: &mut Option<T>, other: Option<T>) {
+            if other.is_some() {
+                *setting = other;
+            }
+        }
+
+        merge_setting(&mut self.accel_profile, other.accel_profile);
+        merge_setting(&mut self.accel_speed, other.accel_speed);
+        merge_setting(&mut self.tap_to_click, other.tap_to_click);
+        merge_setting(&mut self.tap_drag, other.tap_drag);
+        merge_setting(&mut self.natural_scroll, other.natural_scroll);
+        merge_setting(&mut self.scroll_method, other.scroll_method);
+        merge_setting(&mut self.disable_while_typing, other.disable_while_typing);
+        merge_setting(&mut self.left_handed, other.left_handed);
+        merge_setting(&mut self.middle_emulation, other.middle_emulation);
+        merge_setting(&mut self.click_method, other.click_method);
+    }
+
+    /// One settings per setting that is set, to apply them one at a time.
+    pub fn split(&self) -> Vec<InputDeviceSettings> {
+        let default = InputDeviceSettings::default;
+        [
+            self.accel_profile.map(|accel_profile| Self {
+                accel_profile: Some(accel_profile),
+                ..default()
+            }),
+            self.accel_speed.map(|accel_speed| Self {
+                accel_speed: Some(accel_speed),
+                ..default()
+            }),
+            self.tap_to_click.map(|tap_to_click| Self {
+                tap_to_click: Some(tap_to_click),
+                ..default()
+            }),
+            self.tap_drag.map(|tap_drag| Self {
+                tap_drag: Some(tap_drag),
+                ..default()
+            }),
+            self.natural_scroll.map(|natural_scroll| Self {
+                natural_scroll: Some(natural_scroll),
+                ..default()
+            }),
+            self.scroll_method.map(|scroll_method| Self {
+                scroll_method: Some(scroll_method),
+                ..default()
+            }),
+            self.disable_while_typing.map(|disable_while_typing| Self {
+                disable_while_typing: Some(disable_while_typing),
+                ..default()
+            }),
+            self.left_handed.map(|left_handed| Self {
+                left_handed: Some(left_handed),
+                ..default()
+            }),
+            self.middle_emulation.map(|middle_emulation| Self {
+                middle_emulation: Some(middle_emulation),
+                ..default()
+            }),
+            self.click_method.map(|click_method| Self {
+                click_method: Some(click_method),
+                ..default()
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// The settings saved for devices with this name, if any.
+    pub fn load(device_name: &str) -> Option<Self> {
+        let path = persistence_path(&persistence_file(device_name));
+        let json = std::fs::read_to_string(&path).ok()?;
+        serde_json::from_str(&json)
+            .map_err(|err| warn!(path = %path.display(), "Invalid input device settings: {err}"))
+            .ok()
+    }
+
+    /// Adds the settings to the ones saved for devices with this name.
+    pub fn save(&self, device_name: &str) -> std::io::Result<()> {
+        let mut settings = Self::load(device_name).unwrap_or_default();
+        settings.merge(self);
+
+        let path = persistence_path(&persistence_file(device_name));
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&settings)?)
+    }
+}
+
+fn persistence_file(device_name: &str) -> String {
+    // Device names are free text, keep them from escaping the directory.
+    format!("InputDevice/{}.json", device_name.replace('/', "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_the_settings_missing_from_the_other() {
+        let mut settings = InputDeviceSettings {
+            accel_speed: Some(0.5),
+            tap_to_click: Some(true),
+            ..Default::default()
+        };
+        settings.merge(&InputDeviceSettings {
+            tap_to_click: Some(false),
+            scroll_method: Some(InputScrollMethod::Edge),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            settings,
+            InputDeviceSettings {
+                accel_speed: Some(0.5),
+                tap_to_click: Some(false),
+                scroll_method: Some(InputScrollMethod::Edge),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn split_gives_one_setting_each() {
+        let settings = InputDeviceSettings {
+            accel_profile: Some(InputAccelProfile::Flat),
+            natural_scroll: Some(true),
+            click_method: Some(InputClickMethod::ClickFinger),
+            ..Default::default()
+        };
+
+        let split = settings.split();
+        assert_eq!(split.len(), 3);
+        let mut merged = InputDeviceSettings::default();
+        for setting in &split {
+            merged.merge(setting);
+        }
+        assert_eq!(merged, settings);
+        assert!(InputDeviceSettings::default().split().is_empty());
+    }
+
+    #[test]
+    fn settings_survive_a_round_trip_through_json() {
+        let settings = InputDeviceSettings {
+            accel_profile: Some(InputAccelProfile::Adaptive),
+            accel_speed: Some(-0.25),
+            tap_to_click: Some(true),
+            tap_drag: Some(false),
+            natural_scroll: Some(true),
+            scroll_method: Some(InputScrollMethod::TwoFinger),
+            disable_while_typing: Some(true),
+            left_handed: Some(false),
+            middle_emulation: Some(true),
+            click_method: Some(InputClickMethod::ButtonAreas),
+        };
+
+        let json = serde_json::to_value(&settings).unwrap();
+        assert_eq!(json["accelProfile"], "adaptive");
+        assert_eq!(json["scrollMethod"], "twoFinger");
+        assert_eq!(json["clickMethod"], "buttonAreas");
+        let parsed: InputDeviceSettings = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, settings);
+
+        // Files saved by older versions may miss settings.
+        let parsed: InputDeviceSettings =
+            serde_json::from_str(r#"{ "naturalScroll": true }"#).unwrap();
+        assert_eq!(
+            parsed,
+            InputDeviceSettings {
+                natural_scroll: Some(true),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn configuration_flattens_the_settings() {
+        let configuration: InputDeviceConfiguration =
+            serde_json::from_str(r#"{ "deviceId": "event4", "leftHanded": true }"#).unwrap();
+        assert_eq!(configuration.device_id, "event4");
+        assert_eq!(configuration.settings.left_handed, Some(true));
+    }
+
+    #[test]
+    fn persistence_file_stays_in_its_directory() {
+        assert_eq!(
+            persistence_file("Logitech USB Receiver"),
+            "InputDevice/Logitech USB Receiver.json"
+        );
+        assert_eq!(
+            persistence_file("../../evil/device"),
+            "InputDevice/.._.._evil_device.json"
+        );
+    }
+}
@@ -1,24 +1,53 @@
-use std::collections::HashSet;
 use std::mem::size_of;
-use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use input_linux::sys::{KEY_ESC, KEY_LEFTALT};
+use input_linux::sys::{BTN_STYLUS, BTN_STYLUS2};
+use serde_json::json;
 use smithay::backend::input::{
-    AbsolutePositionEvent, Axis, AxisRelativeDirection, ButtonState, Event, InputBackend,
-    InputEvent, KeyboardKeyEvent, PointerAxisEvent, PointerButtonEvent, PointerMotionEvent,
+    AbsolutePositionEvent, Axis, AxisRelativeDirection, ButtonState, Device, DeviceCapability,
+    Event, GestureBeginEvent, GestureEndEvent, GesturePinchUpdateEvent as _,
+    GestureSwipeUpdateEvent as _, InputBackend, InputEvent, KeyboardKeyEvent, PointerAxisEvent,
+    PointerButtonEvent, PointerMotionEvent, ProximityState, TabletToolButtonEvent,
+    TabletToolDescriptor, TabletToolEvent, TabletToolProximityEvent, TabletToolTipEvent,
+    TabletToolTipState, TouchEvent, TouchSlot,
 };
-use smithay::input::pointer::AxisFrame;
+use smithay::input::pointer::{
+    AxisFrame, GestureHoldBeginEvent, GestureHoldEndEvent, GesturePinchBeginEvent,
+    GesturePinchEndEvent, GesturePinchUpdateEvent, GestureSwipeBeginEvent, GestureSwipeEndEvent,
+    GestureSwipeUpdateEvent,
+};
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::{Logical, Point, Rectangle, Size, Transform, SERIAL_COUNTER};
+use smithay::wayland::tablet_manager::{TabletDescriptor, TabletSeatTrait};
 
 use crate::flutter_engine::embedder::{
-    FlutterPointerDeviceKind_kFlutterPointerDeviceKindMouse, FlutterPointerEvent,
-    FlutterPointerPhase_kDown, FlutterPointerPhase_kHover, FlutterPointerPhase_kMove,
-    FlutterPointerPhase_kUp, FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
+    FlutterPointerDeviceKind_kFlutterPointerDeviceKindMouse,
+    FlutterPointerDeviceKind_kFlutterPointerDeviceKindStylus,
+    FlutterPointerDeviceKind_kFlutterPointerDeviceKindTouch,
+    FlutterPointerDeviceKind_kFlutterPointerDeviceKindTrackpad, FlutterPointerEvent,
+    FlutterPointerPhase, FlutterPointerPhase_kCancel, FlutterPointerPhase_kDown,
+    FlutterPointerPhase_kHover, FlutterPointerPhase_kMove, FlutterPointerPhase_kPanZoomEnd,
+    FlutterPointerPhase_kPanZoomStart, FlutterPointerPhase_kPanZoomUpdate,
+    FlutterPointerPhase_kRemove, FlutterPointerPhase_kUp,
+    FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
     FlutterPointerSignalKind_kFlutterPointerSignalKindScroll,
 };
 use crate::flutter_engine::FlutterEngine;
-use crate::server::ServerState;
+use crate::server::{monitors_bounding_box, ServerState};
 use crate::Backend;
 
+/// Device id of the mouse, touch points get the ids after the touchpad.
+const MOUSE_DEVICE_ID: i32 = 0;
+/// Device id of the pan/zoom events of touchpad gestures.
+const TOUCHPAD_DEVICE_ID: i32 = 1;
+/// Tablet tools get device ids far above the ones of touch points.
+pub const FIRST_TABLET_TOOL_DEVICE_ID: i32 = 0x10000;
+
+/// Flutter button bits of a stylus.
+const FLUTTER_STYLUS_CONTACT: i64 = 0x01;
+const FLUTTER_PRIMARY_STYLUS_BUTTON: i64 = 0x02;
+const FLUTTER_SECONDARY_STYLUS_BUTTON: i64 = 0x04;
+
 pub fn handle_input<BackendData>(
     event: &InputEvent<impl InputBackend>,
     data: &mut ServerState<BackendData>,
@@ -26,16 +55,40 @@ pub fn handle_input<BackendData>(
     BackendData: Backend + 'static,
 {
     match event {
-        InputEvent::DeviceAdded { .. } => {}
-        InputEvent::DeviceRemoved { .. } => {}
+        InputEvent::DeviceAdded { device } => {
+            if device.has_capability(DeviceCapability::TabletTool) {
+                data.seat
+                    .tablet_seat()
+                    .add_tablet::<ServerState<BackendData>>(
+                        &data.display_handle,
+                        &TabletDescriptor::from(device),
+                    );
+            }
+        }
+        InputEvent::DeviceRemoved { device } => {
+            if device.has_capability(DeviceCapability::TabletTool) {
+                let tablet = TabletDescriptor::from(device);
+                let tablet_seat = data.seat.tablet_seat();
+                tablet_seat.remove_tablet(&tablet);
+                if tablet_seat.count_tablets() == 0 {
+                    tablet_seat.clear_tools();
+                }
+                forget_tablet_tools(data, &tablet);
+            }
+        }
         InputEvent::PointerMotion { event } => {
+            let old_position = data.mouse_position;
             data.mouse_position.0 += event.delta_x();
             data.mouse_position.1 += event.delta_y();
             send_motion_event(data);
+            data.cursor_moved(old_position);
         }
         InputEvent::PointerMotionAbsolute { event } => {
-            data.mouse_position = (event.x(), event.y());
+            let old_position = data.mouse_position;
+            // Absolute positions are in pixels, like the Flutter view.
+            data.mouse_position = (event.x() / data.pixel_ratio, event.y() / data.pixel_ratio);
             send_motion_event(data);
+            data.cursor_moved(old_position);
         }
         InputEvent::PointerButton { event } => {
             let phase = if event.state() == ButtonState::Pressed {
@@ -73,9 +126,9 @@ pub fn handle_input<BackendData>(
                     struct_size: size_of::<FlutterPointerEvent>(),
                     phase,
                     timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
-                    x: data.mouse_position.0,
-                    y: data.mouse_position.1,
-                    device: 0,
+                    x: data.mouse_position.0 * data.pixel_ratio,
+                    y: data.mouse_position.1 * data.pixel_ratio,
+                    device: MOUSE_DEVICE_ID,
                     signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
                     scroll_delta_x: 0.0,
                     scroll_delta_y: 0.0,
@@ -142,9 +195,9 @@ pub fn handle_input<BackendData>(
                         FlutterPointerPhase_kDown
                     },
                     timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
-                    x: data.mouse_position.0,
-                    y: data.mouse_position.1,
-                    device: 0,
+                    x: data.mouse_position.0 * data.pixel_ratio,
+                    y: data.mouse_position.1 * data.pixel_ratio,
+                    device: MOUSE_DEVICE_ID,
                     signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindScroll,
                     scroll_delta_x: horizontal,
                     scroll_delta_y: vertical,
@@ -162,38 +215,196 @@ pub fn handle_input<BackendData>(
         }
         InputEvent::Keyboard { event } => {
             data.handle_key_event(event.key_code(), event.state(), event.time_msec());
+        }
+        InputEvent::GestureSwipeBegin { event } => {
+            begin_gesture(data, GestureKind::Swipe, event.fingers(), event.time_msec());
+        }
+        InputEvent::GestureSwipeUpdate { event } => {
+            update_gesture(
+                data,
+                (event.delta_x(), event.delta_y()),
+                None,
+                0.0,
+                event.time_msec(),
+            );
+        }
+        InputEvent::GestureSwipeEnd { event } => {
+            end_gesture(data, event.cancelled(), event.time_msec());
+        }
+        InputEvent::GesturePinchBegin { event } => {
+            begin_gesture(data, GestureKind::Pinch, event.fingers(), event.time_msec());
+        }
+        InputEvent::GesturePinchUpdate { event } => {
+            update_gesture(
+                data,
+                (event.delta_x(), event.delta_y()),
+                Some(event.scale()),
+                event.rotation(),
+                event.time_msec(),
+            );
+        }
+        InputEvent::GesturePinchEnd { event } => {
+            end_gesture(data, event.cancelled(), event.time_msec());
+        }
+        InputEvent::GestureHoldBegin { event } => {
+            begin_gesture(data, GestureKind::Hold, event.fingers(), event.time_msec());
+        }
+        InputEvent::GestureHoldEnd { event } => {
+            end_gesture(data, event.cancelled(), event.time_msec());
+        }
+        InputEvent::TouchDown { event } => {
+            let position = touch_position(data, event);
+            data.touch_positions.insert(event.slot(), position);
+            send_touch_event(data, event.slot(), FlutterPointerPhase_kDown);
+        }
+        InputEvent::TouchMotion { event } => {
+            let position = touch_position(data, event);
+            data.touch_positions.insert(event.slot(), position);
+            send_touch_event(data, event.slot(), FlutterPointerPhase_kMove);
+        }
+        InputEvent::TouchUp { event } => {
+            send_touch_event(data, event.slot(), FlutterPointerPhase_kUp);
+            data.touch_positions.remove(&event.slot());
+        }
+        InputEvent::TouchCancel { .. } => {
+            // Every touch point of the device is gone.
+            let slots = data.touch_positions.keys().copied().collect::<Vec<_>>();
+            for slot in slots {
+                send_touch_event(data, slot, FlutterPointerPhase_kCancel);
+            }
+            data.touch_positions.clear();
+        }
+        // Flutter doesn't group touch events in frames.
+        InputEvent::TouchFrame { .. } => {}
+        InputEvent::TabletToolAxis { event } => {
+            let position = tablet_tool_position(data, event);
+            let descriptor = event.tool();
+            let Some(tablet_tool) = data.tablet_tools.get_mut(&descriptor) else {
+                return;
+            };
+            tablet_tool.position = position;
+            tablet_tool.set_axes(event.pressure(), event.tilt());
+            let phase = if tablet_tool.tip_down {
+                FlutterPointerPhase_kMove
+            } else {
+                FlutterPointerPhase_kHover
+            };
+            let device = tablet_tool.device;
+            let focus = tablet_tool.focus.clone();
 
-            let pressed_keys = data
-                .keyboard
-                .pressed_keys()
-                .iter()
-                .map(|key| key.raw())
-                .collect::<HashSet<_>>();
+            // The motion itself reaches the client when the shell routes the tool
+            // to the surface under it.
+            if let Some(tool) = data.seat.tablet_seat().get_tool(&descriptor) {
+                if event.pressure_has_changed() {
+                    tool.pressure(event.pressure());
+                }
+                if event.distance_has_changed() {
+                    tool.distance(event.distance());
+                }
+                if event.tilt_has_changed() {
+                    tool.tilt(event.tilt());
+                }
+                if event.slider_has_changed() {
+                    tool.slider_position(event.slider_position());
+                }
+                if event.rotation_has_changed() {
+                    tool.rotation(event.rotation());
+                }
+                if event.wheel_has_changed() {
+                    tool.wheel(event.wheel_delta(), event.wheel_delta_discrete());
+                }
+            }
+            // Clients get the axis changes with the next frame, which only comes with a motion.
+            if let Some((surface, position)) = focus {
+                move_tablet_tool(data, device, position, Some(surface));
+            }
 
-            if pressed_keys.contains(&(KEY_ESC as u32))
-                && pressed_keys.contains(&(KEY_LEFTALT as u32))
-            {
-                data.running.store(false, Ordering::SeqCst);
+            send_stylus_event(data, &descriptor, phase);
+        }
+        InputEvent::TabletToolProximity { event } => {
+            let descriptor = event.tool();
+            let display_handle = data.display_handle.clone();
+            data.seat
+                .tablet_seat()
+                .add_tool::<ServerState<BackendData>>(data, &display_handle, &descriptor);
+
+            match event.state() {
+                ProximityState::In => {
+                    let position = tablet_tool_position(data, event);
+                    let device = data
+                        .tablet_tools
+                        .get(&descriptor)
+                        .map(|tablet_tool| tablet_tool.device)
+                        .unwrap_or_else(|| next_tablet_tool_device_id(data));
+                    let mut tablet_tool =
+                        TabletTool::new(device, TabletDescriptor::from(&event.device()), position);
+                    tablet_tool.set_axes(event.pressure(), event.tilt());
+                    data.tablet_tools.insert(descriptor.clone(), tablet_tool);
+                    send_stylus_event(data, &descriptor, FlutterPointerPhase_kHover);
+                }
+                ProximityState::Out => {
+                    if let Some(tool) = data.seat.tablet_seat().get_tool(&descriptor) {
+                        tool.proximity_out(event.time_msec());
+                    }
+                    send_stylus_event(data, &descriptor, FlutterPointerPhase_kRemove);
+                }
+            }
+        }
+        InputEvent::TabletToolTip { event } => {
+            let descriptor = event.tool();
+            let Some(tablet_tool) = data.tablet_tools.get_mut(&descriptor) else {
                 return;
+            };
+            tablet_tool.tip_down = event.tip_state() == TabletToolTipState::Down;
+            let phase = if tablet_tool.tip_down {
+                FlutterPointerPhase_kDown
+            } else {
+                FlutterPointerPhase_kUp
+            };
+
+            if let Some(tool) = data.seat.tablet_seat().get_tool(&descriptor) {
+                match event.tip_state() {
+                    TabletToolTipState::Down => {
+                        tool.tip_down(SERIAL_COUNTER.next_serial(), event.time_msec())
+                    }
+                    TabletToolTipState::Up => tool.tip_up(event.time_msec()),
+                }
             }
+
+            send_stylus_event(data, &descriptor, phase);
+        }
+        InputEvent::TabletToolButton { event } => {
+            let descriptor = event.tool();
+            let Some(tablet_tool) = data.tablet_tools.get_mut(&descriptor) else {
+                return;
+            };
+            let flutter_button = match event.button() as i32 {
+                BTN_STYLUS => FLUTTER_PRIMARY_STYLUS_BUTTON,
+                BTN_STYLUS2 => FLUTTER_SECONDARY_STYLUS_BUTTON,
+                _ => 0,
+            };
+            if event.button_state() == ButtonState::Pressed {
+                tablet_tool.buttons |= flutter_button;
+            } else {
+                tablet_tool.buttons &= !flutter_button;
+            }
+            let phase = if tablet_tool.tip_down {
+                FlutterPointerPhase_kMove
+            } else {
+                FlutterPointerPhase_kHover
+            };
+
+            if let Some(tool) = data.seat.tablet_seat().get_tool(&descriptor) {
+                tool.button(
+                    event.button(),
+                    event.button_state(),
+                    SERIAL_COUNTER.next_serial(),
+                    event.time_msec(),
+                );
+            }
+
+            send_stylus_event(data, &descriptor, phase);
         }
-        InputEvent::GestureSwipeBegin { .. } => {}
-        InputEvent::GestureSwipeUpdate { .. } => {}
-        InputEvent::GestureSwipeEnd { .. } => {}
-        InputEvent::GesturePinchBegin { .. } => {}
-        InputEvent::GesturePinchUpdate { .. } => {}
-        InputEvent::GesturePinchEnd { .. } => {}
-        InputEvent::GestureHoldBegin { .. } => {}
-        InputEvent::GestureHoldEnd { .. } => {}
-        InputEvent::TouchDown { .. } => {}
-        InputEvent::TouchMotion { .. } => {}
-        InputEvent::TouchUp { .. } => {}
-        InputEvent::TouchCancel { .. } => {}
-        InputEvent::TouchFrame { .. } => {}
-        InputEvent::TabletToolAxis { .. } => {}
-        InputEvent::TabletToolProximity { .. } => {}
-        InputEvent::TabletToolTip { .. } => {}
-        InputEvent::TabletToolButton { .. } => {}
         InputEvent::Special(_) => {}
         InputEvent::SwitchToggle { .. } => {}
     }
@@ -216,9 +427,9 @@ where
                 FlutterPointerPhase_kHover
             },
             timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
-            x: data.mouse_position.0,
-            y: data.mouse_position.1,
-            device: 0,
+            x: data.mouse_position.0 * data.pixel_ratio,
+            y: data.mouse_position.1 * data.pixel_ratio,
+            device: MOUSE_DEVICE_ID,
             signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
             scroll_delta_x: 0.0,
             scroll_delta_y: 0.0,
@@ -234,3 +445,544 @@ where
         })
         .unwrap();
 }
+
+/// Flutter tracks every touch point as its own pointer device.
+pub fn touch_device_id(slot: TouchSlot) -> i32 {
+    // Single touch devices have no slot, which converts to -1.
+    TOUCHPAD_DEVICE_ID + 2 + i32::from(slot)
+}
+
+/// The touch slot of a Flutter device id returned by [`touch_device_id`].
+pub fn touch_slot(device: i32) -> TouchSlot {
+    u32::try_from(device - TOUCHPAD_DEVICE_ID - 2).ok().into()
+}
+
+/// Maps a position on a touchscreen or a tablet to the monitor layout.
+/// They cover the first monitor of the layout, usually the built-in one, and turn with it.
+/// `position_transformed` gives the position on a device of the given size.
+fn absolute_device_position<BackendData>(
+    data: &ServerState<BackendData>,
+    position_transformed: impl FnOnce(Size<i32, Logical>) -> Point<f64, Logical>,
+) -> (f64, f64)
+where
+    BackendData: Backend + 'static,
+{
+    let Some(monitor) = data.backend_data.get_monitor_layout().into_iter().next() else {
+        return (0.0, 0.0);
+    };
+    let area = monitors_bounding_box(&[monitor.clone()]);
+    transform_absolute_position(area, monitor.current_transform(), position_transformed)
+}
+
+fn transform_absolute_position(
+    area: Rectangle<i32, Logical>,
+    transform: Transform,
+    position_transformed: impl FnOnce(Size<i32, Logical>) -> Point<f64, Logical>,
+) -> (f64, f64) {
+    // Devices report positions on the panel as it is mounted, before the monitor turns.
+    let panel_size = transform.invert().transform_size(area.size);
+    let position =
+        transform.transform_point_in(position_transformed(panel_size), &panel_size.to_f64());
+    (
+        area.loc.x as f64 + position.x,
+        area.loc.y as f64 + position.y,
+    )
+}
+
+/// Position of the touch point in logical coordinates.
+fn touch_position<BackendData, B>(
+    data: &ServerState<BackendData>,
+    event: &impl AbsolutePositionEvent<B>,
+) -> (f64, f64)
+where
+    BackendData: Backend + 'static,
+    B: InputBackend,
+{
+    absolute_device_position(data, |size| {
+        (event.x_transformed(size.w), event.y_transformed(size.h)).into()
+    })
+}
+
+fn send_touch_event<BackendData>(
+    data: &mut ServerState<BackendData>,
+    slot: TouchSlot,
+    phase: FlutterPointerPhase,
+) where
+    BackendData: Backend + 'static,
+{
+    let Some(&(x, y)) = data.touch_positions.get(&slot) else {
+        return;
+    };
+
+    data.flutter_engine()
+        .send_pointer_event(FlutterPointerEvent {
+            struct_size: size_of::<FlutterPointerEvent>(),
+            phase,
+            timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
+            x: x * data.pixel_ratio,
+            y: y * data.pixel_ratio,
+            device: touch_device_id(slot),
+            signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
+            scroll_delta_x: 0.0,
+            scroll_delta_y: 0.0,
+            device_kind: FlutterPointerDeviceKind_kFlutterPointerDeviceKindTouch,
+            buttons: 0,
+            pan_x: 0.0,
+            pan_y: 0.0,
+            scale: 1.0,
+            rotation: 0.0,
+        })
+        .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GestureKind {
+    Swipe,
+    Pinch,
+    Hold,
+}
+
+/// The touchpad gesture in progress.
+/// The shell sees it first, and can hand it to the client under the pointer
+/// if it doesn't use it.
+#[derive(Debug, Clone)]
+pub struct Gesture {
+    kind: GestureKind,
+    fingers: u32,
+    /// Logical distance the fingers moved since the beginning of the gesture.
+    pan: (f64, f64),
+    scale: f64,
+    /// Clockwise rotation in degrees since the beginning of the gesture.
+    rotation: f64,
+    /// The focused client receives the rest of the gesture.
+    forwarded: bool,
+}
+
+fn begin_gesture<BackendData>(
+    data: &mut ServerState<BackendData>,
+    kind: GestureKind,
+    fingers: u32,
+    time: u32,
+) where
+    BackendData: Backend + 'static,
+{
+    if data.gesture.is_some() {
+        // libinput always ends a gesture before beginning another one,
+        // but don't leave a client or the shell with a dangling gesture if it doesn't.
+        end_gesture(data, true, time);
+    }
+
+    let gesture = Gesture {
+        kind,
+        fingers,
+        pan: (0.0, 0.0),
+        scale: 1.0,
+        rotation: 0.0,
+        forwarded: false,
+    };
+    send_pan_zoom_event(data, &gesture, FlutterPointerPhase_kPanZoomStart);
+    data.gesture = Some(gesture);
+}
+
+fn update_gesture<BackendData>(
+    data: &mut ServerState<BackendData>,
+    delta: (f64, f64),
+    scale: Option<f64>,
+    rotation: f64,
+    time: u32,
+) where
+    BackendData: Backend + 'static,
+{
+    let Some(gesture) = data.gesture.as_mut() else {
+        return;
+    };
+    gesture.pan.0 += delta.0;
+    gesture.pan.1 += delta.1;
+    if let Some(scale) = scale {
+        gesture.scale = scale;
+    }
+    gesture.rotation += rotation;
+
+    let gesture = gesture.clone();
+    send_pan_zoom_event(data, &gesture, FlutterPointerPhase_kPanZoomUpdate);
+
+    if gesture.forwarded {
+        let pointer = data.pointer.clone();
+        match gesture.kind {
+            GestureKind::Swipe => pointer.gesture_swipe_update(
+                data,
+                &GestureSwipeUpdateEvent {
+                    time,
+                    delta: delta.into(),
+                },
+            ),
+            GestureKind::Pinch => pointer.gesture_pinch_update(
+                data,
+                &GesturePinchUpdateEvent {
+                    time,
+                    delta: delta.into(),
+                    scale: gesture.scale,
+                    rotation,
+                },
+            ),
+            GestureKind::Hold => {}
+        }
+    }
+}
+
+fn end_gesture<BackendData>(data: &mut ServerState<BackendData>, cancelled: bool, time: u32)
+where
+    BackendData: Backend + 'static,
+{
+    let Some(gesture) = data.gesture.take() else {
+        return;
+    };
+
+    // Flutter has no way to cancel a pan/zoom gesture.
+    send_pan_zoom_event(data, &gesture, FlutterPointerPhase_kPanZoomEnd);
+
+    if gesture.forwarded {
+        let pointer = data.pointer.clone();
+        let serial = SERIAL_COUNTER.next_serial();
+        match gesture.kind {
+            GestureKind::Swipe => pointer.gesture_swipe_end(
+                data,
+                &GestureSwipeEndEvent {
+                    serial,
+                    time,
+                    cancelled,
+                },
+            ),
+            GestureKind::Pinch => pointer.gesture_pinch_end(
+                data,
+                &GesturePinchEndEvent {
+                    serial,
+                    time,
+                    cancelled,
+                },
+            ),
+            GestureKind::Hold => pointer.gesture_hold_end(
+                data,
+                &GestureHoldEndEvent {
+                    serial,
+                    time,
+                    cancelled,
+                },
+            ),
+        }
+    }
+}
+
+/// Sends the gesture in progress to the client under the pointer, starting with a single update
+/// holding everything that happened before the shell decided not to use it.
+/// Does nothing if the gesture already ended.
+pub fn forward_gesture<BackendData>(data: &mut ServerState<BackendData>, time: u32)
+where
+    BackendData: Backend + 'static,
+{
+    let Some(gesture) = data.gesture.as_mut() else {
+        return;
+    };
+    if gesture.forwarded {
+        return;
+    }
+    gesture.forwarded = true;
+    let gesture = gesture.clone();
+
+    let pointer = data.pointer.clone();
+    let serial = SERIAL_COUNTER.next_serial();
+    match gesture.kind {
+        GestureKind::Swipe => {
+            pointer.gesture_swipe_begin(
+                data,
+                &GestureSwipeBeginEvent {
+                    serial,
+                    time,
+                    fingers: gesture.fingers,
+                },
+            );
+            pointer.gesture_swipe_update(
+                data,
+                &GestureSwipeUpdateEvent {
+                    time,
+                    delta: gesture.pan.into(),
+                },
+            );
+        }
+        GestureKind::Pinch => {
+            pointer.gesture_pinch_begin(
+                data,
+                &GesturePinchBeginEvent {
+                    serial,
+                    time,
+                    fingers: gesture.fingers,
+                },
+            );
+            pointer.gesture_pinch_update(
+                data,
+                &GesturePinchUpdateEvent {
+                    time,
+                    delta: gesture.pan.into(),
+                    scale: gesture.scale,
+                    rotation: gesture.rotation,
+                },
+            );
+        }
+        GestureKind::Hold => {
+            pointer.gesture_hold_begin(
+                data,
+                &GestureHoldBeginEvent {
+                    serial,
+                    time,
+                    fingers: gesture.fingers,
+                },
+            );
+        }
+    }
+}
+
+fn send_pan_zoom_event<BackendData>(
+    data: &mut ServerState<BackendData>,
+    gesture: &Gesture,
+    phase: FlutterPointerPhase,
+) where
+    BackendData: Backend + 'static,
+{
+    data.flutter_engine()
+        .send_pointer_event(FlutterPointerEvent {
+            struct_size: size_of::<FlutterPointerEvent>(),
+            phase,
+            timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
+            x: data.mouse_position.0 * data.pixel_ratio,
+            y: data.mouse_position.1 * data.pixel_ratio,
+            device: TOUCHPAD_DEVICE_ID,
+            signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
+            scroll_delta_x: 0.0,
+            scroll_delta_y: 0.0,
+            device_kind: FlutterPointerDeviceKind_kFlutterPointerDeviceKindTrackpad,
+            buttons: 0,
+            pan_x: gesture.pan.0 * data.pixel_ratio,
+            pan_y: gesture.pan.1 * data.pixel_ratio,
+            scale: gesture.scale,
+            rotation: gesture.rotation.to_radians(),
+        })
+        .unwrap();
+}
+
+/// A pen or eraser that came close to a tablet at least once.
+#[derive(Debug, Clone)]
+pub struct TabletTool {
+    /// Flutter device id of the tool, kept when the tool leaves and comes back.
+    pub device: i32,
+    /// The tablet the tool was last seen on.
+    pub tablet: TabletDescriptor,
+    /// Logical position of the tool.
+    position: (f64, f64),
+    tip_down: bool,
+    /// Flutter bitmask of the buttons on the side of the tool.
+    buttons: i64,
+    /// Between 0 and 1.
+    pressure: f64,
+    /// In degrees, away from the perpendicular to the tablet.
+    tilt: (f64, f64),
+    /// The shell doesn't know the current pressure and tilt yet.
+    axes_changed: bool,
+    /// The surface the shell routes the tool to, and the position on it.
+    focus: Option<(WlSurface, (f64, f64))>,
+}
+
+impl TabletTool {
+    pub fn new(device: i32, tablet: TabletDescriptor, position: (f64, f64)) -> Self {
+        Self {
+            device,
+            tablet,
+            position,
+            tip_down: false,
+            buttons: 0,
+            pressure: 0.0,
+            tilt: (0.0, 0.0),
+            axes_changed: true,
+            focus: None,
+        }
+    }
+
+    pub fn set_axes(&mut self, pressure: f64, tilt: (f64, f64)) {
+        if (pressure, tilt) != (self.pressure, self.tilt) {
+            self.pressure = pressure;
+            self.tilt = tilt;
+            self.axes_changed = true;
+        }
+    }
+}
+
+/// Ids of tools that are gone aren't reused, the shell may still track them.
+pub fn next_tablet_tool_device_id<BackendData>(data: &mut ServerState<BackendData>) -> i32
+where
+    BackendData: Backend + 'static,
+{
+    let device = data.next_tablet_tool_device_id;
+    data.next_tablet_tool_device_id += 1;
+    device
+}
+
+/// The tools last seen on a tablet that was unplugged.
+pub fn forget_tablet_tools<BackendData>(
+    data: &mut ServerState<BackendData>,
+    tablet: &TabletDescriptor,
+) where
+    BackendData: Backend + 'static,
+{
+    data.tablet_tools
+        .retain(|_, tablet_tool| tablet_tool.tablet != *tablet);
+}
+
+/// Moves the tool to a position local to the surface,
+/// entering or leaving surfaces when the focus changes.
+pub fn move_tablet_tool<BackendData>(
+    data: &mut ServerState<BackendData>,
+    device: i32,
+    position: (f64, f64),
+    surface: Option<WlSurface>,
+) where
+    BackendData: Backend + 'static,
+{
+    // The tool may have left the tablet before the shell answered.
+    let Some((descriptor, tablet_tool)) = data
+        .tablet_tools
+        .iter_mut()
+        .find(|(_, tablet_tool)| tablet_tool.device == device)
+    else {
+        return;
+    };
+    tablet_tool.focus = surface.clone().map(|surface| (surface, position));
+
+    let tablet_seat = data.seat.tablet_seat();
+    let (Some(tool), Some(tablet)) = (
+        tablet_seat.get_tool(descriptor),
+        tablet_seat.get_tablet(&tablet_tool.tablet),
+    ) else {
+        return;
+    };
+
+    let now = Duration::from(data.clock.now()).as_millis() as u32;
+    tool.motion(
+        position.into(),
+        surface.map(|surface| (surface, Default::default())),
+        &tablet,
+        SERIAL_COUNTER.next_serial(),
+        now,
+    );
+}
+
+/// Position of the tablet tool in logical coordinates.
+fn tablet_tool_position<BackendData, B>(
+    data: &ServerState<BackendData>,
+    event: &impl TabletToolEvent<B>,
+) -> (f64, f64)
+where
+    BackendData: Backend + 'static,
+    B: InputBackend,
+{
+    absolute_device_position(data, |size| {
+        (event.x_transformed(size.w), event.y_transformed(size.h)).into()
+    })
+}
+
+/// The embedder API has no pressure or tilt, the shell gets them in `tablet_tool_axes_changed`
+/// right before the pointer event. Clients get the whole state of the tool through tablet-v2.
+fn send_stylus_event<BackendData>(
+    data: &mut ServerState<BackendData>,
+    descriptor: &TabletToolDescriptor,
+    phase: FlutterPointerPhase,
+) where
+    BackendData: Backend + 'static,
+{
+    send_stylus_axes(data, descriptor);
+
+    let Some(tablet_tool) = data.tablet_tools.get(descriptor) else {
+        return;
+    };
+
+    let contact = if tablet_tool.tip_down {
+        FLUTTER_STYLUS_CONTACT
+    } else {
+        0
+    };
+
+    data.flutter_engine()
+        .send_pointer_event(FlutterPointerEvent {
+            struct_size: size_of::<FlutterPointerEvent>(),
+            phase,
+            timestamp: FlutterEngine::<BackendData>::current_time_us() as usize,
+            x: tablet_tool.position.0 * data.pixel_ratio,
+            y: tablet_tool.position.1 * data.pixel_ratio,
+            device: tablet_tool.device,
+            signal_kind: FlutterPointerSignalKind_kFlutterPointerSignalKindNone,
+            scroll_delta_x: 0.0,
+            scroll_delta_y: 0.0,
+            device_kind: FlutterPointerDeviceKind_kFlutterPointerDeviceKindStylus,
+            buttons: contact | tablet_tool.buttons,
+            pan_x: 0.0,
+            pan_y: 0.0,
+            scale: 1.0,
+            rotation: 0.0,
+        })
+        .unwrap();
+}
+
+/// Tells the shell the pressure and tilt of the tool when they changed.
+pub fn send_stylus_axes<BackendData>(
+    data: &mut ServerState<BackendData>,
+    descriptor: &TabletToolDescriptor,
+) where
+    BackendData: Backend + 'static,
+{
+    let Some(tablet_tool) = data.tablet_tools.get_mut(descriptor) else {
+        return;
+    };
+    if !std::mem::take(&mut tablet_tool.axes_changed) {
+        return;
+    }
+
+    let arguments = json!({
+        "device": tablet_tool.device,
+        "pressure": tablet_tool.pressure,
+        "tiltX": tablet_tool.tilt.0,
+        "tiltY": tablet_tool.tilt.1,
+    });
+    data.invoke_platform_method("tablet_tool_axes_changed", arguments);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(transform: Transform, (x, y): (f64, f64)) -> (f64, f64) {
+        // A 1920x1080 panel, at (100, 0) in the layout.
+        let size = transform.transform_size(Size::from((1920, 1080)));
+        let area = Rectangle::from_loc_and_size((100, 0), size);
+        transform_absolute_position(area, transform, |panel_size| {
+            assert_eq!(panel_size, (1920, 1080).into());
+            (x * panel_size.w as f64, y * panel_size.h as f64).into()
+        })
+    }
+
+    #[test]
+    fn touchscreens_turn_with_their_monitor() {
+        assert_eq!(
+            position(Transform::Normal, (0.25, 0.5)),
+            (100.0 + 480.0, 540.0)
+        );
+        // The top left corner of the panel.
+        assert_eq!(position(Transform::_90, (0.0, 0.0)), (100.0 + 1080.0, 0.0));
+        assert_eq!(
+            position(Transform::_180, (0.0, 0.0)),
+            (100.0 + 1920.0, 1080.0)
+        );
+        assert_eq!(position(Transform::_270, (0.0, 0.0)), (100.0, 1920.0));
+        assert_eq!(
+            position(Transform::Flipped, (0.0, 0.0)),
+            (100.0 + 1920.0, 0.0)
+        );
+    }
+}
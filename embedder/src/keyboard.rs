@@ -1,13 +1,17 @@
 use smithay::backend::input::KeyState;
-use smithay::input::keyboard::ModifiersState;
+use smithay::input::keyboard::{Keysym, ModifiersState};
 
 pub mod glfw_key_codes;
 pub mod key_repeater;
+pub mod repeat_settings;
 
 #[derive(Copy, Clone)]
 pub struct KeyEvent {
     pub key_code: u32,
     pub codepoint: Option<char>,
+    /// Unmodified keysym of the key in the active layout,
+    /// or in the first Latin layout when the active one isn't Latin.
+    pub latin_keysym: Option<Keysym>,
     pub state: KeyState,
     pub time: u32,
     pub mods: ModifiersState,
@@ -5,9 +5,21 @@ use std::collections::HashMap;
 
 use input_linux::sys::*;
 use lazy_static::lazy_static;
-use smithay::input::keyboard::ModifiersState;
+use smithay::input::keyboard::{Keysym, ModifiersState};
+
+/// Letters and digits follow the layout, so shortcuts match the labels on the keys,
+/// like Ctrl+Z on the key labeled Z of an AZERTY keyboard.
+/// Other keys, and all keys of non-Latin layouts without a Latin fallback,
+/// keep the code of their position on a US keyboard.
+pub fn get_glfw_keycode(xkb_keycode: u32, latin_keysym: Option<Keysym>) -> u32 {
+    // Latin keysyms have the same value as their ASCII character,
+    // and GLFW key codes of letters and digits are their uppercase ASCII character.
+    if let Some(c) = latin_keysym.and_then(|keysym| char::from_u32(keysym.raw())) {
+        if c.is_ascii_alphanumeric() {
+            return c.to_ascii_uppercase() as u32;
+        }
+    }
 
-pub fn get_glfw_keycode(xkb_keycode: u32) -> u32 {
     keycode_to_glfwkey_map
         .get(&xkb_keycode)
         .copied()
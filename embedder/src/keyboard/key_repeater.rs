@@ -1,3 +1,4 @@
+use crate::keyboard::KeyEvent;
 use crate::server::ServerState;
 use crate::Backend;
 use smithay::reexports::calloop;
@@ -5,7 +6,7 @@ use smithay::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay::reexports::calloop::{timer, LoopHandle, RegistrationToken};
 use std::time::{Duration, Instant};
 
-type Callback<BackendData> = fn(u32, Option<char>, &mut ServerState<BackendData>);
+type Callback<BackendData> = fn(KeyEvent, &mut ServerState<BackendData>);
 
 pub struct KeyRepeater<BackendData: Backend + 'static> {
     loop_handle: LoopHandle<'static, ServerState<BackendData>>,
@@ -27,16 +28,10 @@ impl<BackendData: Backend + 'static> KeyRepeater<BackendData> {
         }
     }
 
-    pub fn down(
-        &mut self,
-        key_code: u32,
-        char_point: Option<char>,
-        repeat_delay: Duration,
-        repeat_rate: Duration,
-    ) {
+    pub fn down(&mut self, key_event: KeyEvent, repeat_delay: Duration, repeat_rate: Duration) {
         // Cancel any existing key repeat, we don't want to repeat multiple keys at once.
         self.cancel();
-        self.repeating_key = Some(key_code);
+        self.repeating_key = Some(key_event.key_code);
 
         let timer = Timer::from_duration(repeat_delay);
 
@@ -44,7 +39,7 @@ impl<BackendData: Backend + 'static> KeyRepeater<BackendData> {
         let token = self
             .loop_handle
             .insert_source(timer, move |_, _, data| {
-                callback(key_code, char_point, data);
+                callback(key_event, data);
                 // Reschedule the timer over and over.
                 TimeoutAction::ToDuration(repeat_rate)
             })
@@ -64,7 +59,7 @@ impl<BackendData: Backend + 'static> KeyRepeater<BackendData> {
         }
     }
 
-    fn cancel(&mut self) {
+    pub fn cancel(&mut self) {
         if let Some(token) = self.timer_token.take() {
             self.loop_handle.remove(token);
         }
@@ -0,0 +1,139 @@
+//! Key repeat settings the shell changes from its settings screen.
+//! They are saved in `$XDG_CONFIG_HOME/veshell/persistence/Keyboard/repeat.json`.
+
+use std::path::Path;
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::persistence::persistence_path;
+use crate::platform_channel_error::PlatformChannelError;
+
+const PERSISTENCE_FILE: &str = "Keyboard/repeat.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRepeatSettings {
+    pub enabled: bool,
+    /// Milliseconds a key is held down before it starts repeating.
+    pub delay: u64,
+    /// Repeats per second.
+    pub rate: u64,
+}
+
+impl Default for KeyRepeatSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: 200,
+            rate: 50,
+        }
+    }
+}
+
+impl KeyRepeatSettings {
+    /// The saved settings, or the default ones if there are none.
+    pub fn load() -> Self {
+        Self::load_from(&persistence_path(PERSISTENCE_FILE))
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        self.save_to(&persistence_path(PERSISTENCE_FILE))
+    }
+
+    pub fn load_from(path: &Path) -> Self {
+        let Ok(json) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        match serde_json::from_str::<Self>(&json) {
+            Ok(settings) if settings.validate().is_ok() => settings,
+            _ => {
+                warn!(path = %path.display(), "Ignoring invalid key repeat settings");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn validate(&self) -> Result<(), PlatformChannelError> {
+        // Clients store the values in 32 bit integers.
+        if self.delay > i32::MAX as u64 {
+            return Err(PlatformChannelError::InvalidRepeatDelay(self.delay));
+        }
+        if self.rate == 0 || self.rate > 1000 {
+            return Err(PlatformChannelError::InvalidRepeatRate(self.rate));
+        }
+        Ok(())
+    }
+
+    /// The rate sent in `wl_keyboard.repeat_info`, where 0 disables repeat.
+    pub fn wayland_rate(&self) -> i32 {
+        if self.enabled {
+            self.rate as i32
+        } else {
+            0
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay)
+    }
+
+    /// Time between two repeats, 20 ms with the default rate.
+    /// Keys handled by Flutter repeat at the same rate as the one sent to clients.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.rate as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_repeat_interval_follows_the_rate() {
+        let settings = KeyRepeatSettings::default();
+        assert_eq!(settings.interval(), Duration::from_millis(20));
+        assert_eq!(settings.wayland_rate(), 50);
+
+        let disabled = KeyRepeatSettings {
+            enabled: false,
+            ..settings
+        };
+        assert_eq!(disabled.wayland_rate(), 0);
+    }
+
+    #[test]
+    fn key_repeat_settings_are_saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!(
+            "veshell-tests-{}/key_repeat_settings_are_saved_and_loaded.json",
+            std::process::id()
+        ));
+        assert_eq!(
+            KeyRepeatSettings::load_from(&path),
+            KeyRepeatSettings::default()
+        );
+
+        let settings = KeyRepeatSettings {
+            enabled: false,
+            delay: 400,
+            rate: 30,
+        };
+        settings.save_to(&path).unwrap();
+        assert_eq!(KeyRepeatSettings::load_from(&path), settings);
+
+        // Broken files fall back to the defaults.
+        std::fs::write(&path, r#"{ "enabled": true, "delay": 300, "rate": 0 }"#).unwrap();
+        assert_eq!(
+            KeyRepeatSettings::load_from(&path),
+            KeyRepeatSettings::default()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,12 @@
 use std::env;
+use std::sync::Mutex;
 
 use log::debug;
 use smithay::output::Output;
 use smithay::reexports::calloop::{channel, EventSource};
 use smithay::{
     backend::allocator::dmabuf::Dmabuf,
+    backend::renderer::gles::GlesTexture,
     reexports::wayland_server::{
         backend::{ClientData, ClientId, DisconnectReason},
         protocol::wl_surface::{self},
@@ -23,7 +25,11 @@ use smithay::{
 
 use crate::flutter_engine::platform_channels::binary_messenger::BinaryMessenger;
 use crate::flutter_engine::FlutterEngine;
+use crate::input_device_configuration::InputDeviceConfiguration;
+use crate::monitor_configuration::MonitorConfiguration;
 use crate::mouse_button_tracker::MouseButtonTracker;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::server::screen_capture::ScreenCapturePermission;
 use crate::server::ServerState;
 
 mod cursor;
@@ -31,9 +37,15 @@ mod drm_backend;
 mod flutter_engine;
 mod focus;
 mod gles_framebuffer_importer;
+mod headless_backend;
+mod input_device_configuration;
 mod input_handling;
 mod keyboard;
+mod monitor_configuration;
 mod mouse_button_tracker;
+mod persistence;
+mod platform_channel_error;
+mod render_elements;
 mod server;
 mod texture_swap_chain;
 mod x11_client;
@@ -48,7 +60,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Fix XWayland crash when too many file descriptors are open.
     let _ = rlimit::increase_nofile_limit(u64::MAX);
 
-    if env::var("DISPLAY").is_ok() || env::var("WAYLAND_DISPLAY").is_ok() {
+    if env::var("VESHELL_BACKEND").as_deref() == Ok("headless") {
+        headless_backend::run_headless_backend();
+    } else if env::var("DISPLAY").is_ok() || env::var("WAYLAND_DISPLAY").is_ok() {
         x11_client::run_x11_client();
     } else {
         drm_backend::run_drm_backend();
@@ -61,6 +75,50 @@ pub trait Backend {
     fn seat_name(&self) -> String;
 
     fn get_monitor_layout(&self) -> Vec<Output>;
+
+    /// Called after the mode or the scale of a monitor changed.
+    /// Places the monitors next to each other again and resizes the Flutter view to cover them.
+    fn update_monitor_layout(state: &mut ServerState<Self>)
+    where
+        Self: Sized + 'static;
+
+    /// Applies the changes the shell asked for to a monitor, then updates the layout.
+    /// Invalid configurations are rejected before anything changes.
+    fn configure_monitor(
+        state: &mut ServerState<Self>,
+        configuration: MonitorConfiguration,
+    ) -> Result<(), PlatformChannelError>
+    where
+        Self: Sized + 'static;
+
+    /// Whether client buffers can be shown directly on a hardware plane, bypassing Flutter.
+    fn supports_direct_scanout(&self) -> bool;
+
+    /// Whether the compositor owns the session and can switch virtual terminals.
+    fn can_change_vt(&self) -> bool;
+
+    /// Switches to another virtual terminal.
+    /// Does nothing when the compositor runs inside another session.
+    fn change_vt(&mut self, vt: i32);
+
+    /// Tells the shell about every input device it can configure.
+    fn send_input_devices(state: &mut ServerState<Self>)
+    where
+        Self: Sized + 'static;
+
+    /// Applies libinput settings to an input device and saves them for its name.
+    fn configure_input_device(
+        state: &mut ServerState<Self>,
+        configuration: InputDeviceConfiguration,
+    ) -> Result<(), PlatformChannelError>
+    where
+        Self: Sized + 'static;
+
+    /// The last complete frame of the Flutter view, covering all the monitors.
+    /// Like everything OpenGL renders, its rows go from bottom to top.
+    fn last_flutter_frame(state: &mut ServerState<Self>) -> Option<GlesTexture>
+    where
+        Self: Sized + 'static;
 }
 
 pub struct FlutterState<BackendData: Backend + 'static> {
@@ -89,9 +147,21 @@ pub fn send_frames_surface_tree(surface: &wl_surface::WlSurface, time: u32) {
     );
 }
 
-#[derive(Default)]
 struct ClientState {
     compositor_state: CompositorClientState,
+    screen_capture_permission: Mutex<ScreenCapturePermission>,
+    /// The server forgets what the client was waiting for, see `ServerState::client_disconnected`.
+    tx_disconnected: Mutex<channel::Sender<ClientId>>,
+}
+
+impl ClientState {
+    fn new(tx_disconnected: channel::Sender<ClientId>) -> Self {
+        Self {
+            compositor_state: Default::default(),
+            screen_capture_permission: Default::default(),
+            tx_disconnected: Mutex::new(tx_disconnected),
+        }
+    }
 }
 
 impl ClientData for ClientState {
@@ -99,7 +169,9 @@ impl ClientData for ClientState {
         debug!("Client initialized {:?}", _client_id);
     }
 
-    fn disconnected(&self, _client_id: ClientId, _reason: DisconnectReason) {
-        debug!("Client disconnected {:?} {:?}", _client_id, _reason);
+    fn disconnected(&self, client_id: ClientId, reason: DisconnectReason) {
+        debug!("Client disconnected {:?} {:?}", client_id, reason);
+        // The event loop is gone when the server shuts down.
+        let _ = self.tx_disconnected.lock().unwrap().send(client_id);
     }
 }
@@ -0,0 +1,118 @@
+//! Changes the shell can apply to a monitor at runtime, through `configure_monitor`.
+//!
+//! Position, scale and transform only live in the `Output` state and are handled the same way
+//! by every backend. The mode and the enabled state need the backend to reprogram the hardware.
+
+use smithay::output::Output;
+use smithay::utils::{Logical, Point, Transform};
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::server::scale_from_f64;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitorConfiguration {
+    pub monitor_name: String,
+    pub position: Option<MonitorPosition>,
+    /// Index in the `modes` list of the monitor sent to the shell.
+    pub mode_index: Option<usize>,
+    pub scale: Option<f64>,
+    pub transform: Option<MonitorTransform>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct MonitorPosition {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MonitorTransform {
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Flipped,
+    Flipped90,
+    Flipped180,
+    Flipped270,
+}
+
+impl From<MonitorTransform> for Transform {
+    fn from(transform: MonitorTransform) -> Self {
+        match transform {
+            MonitorTransform::Normal => Transform::Normal,
+            MonitorTransform::Rotate90 => Transform::_90,
+            MonitorTransform::Rotate180 => Transform::_180,
+            MonitorTransform::Rotate270 => Transform::_270,
+            MonitorTransform::Flipped => Transform::Flipped,
+            MonitorTransform::Flipped90 => Transform::Flipped90,
+            MonitorTransform::Flipped180 => Transform::Flipped180,
+            MonitorTransform::Flipped270 => Transform::Flipped270,
+        }
+    }
+}
+
+impl From<Transform> for MonitorTransform {
+    fn from(transform: Transform) -> Self {
+        match transform {
+            Transform::Normal => MonitorTransform::Normal,
+            Transform::_90 => MonitorTransform::Rotate90,
+            Transform::_180 => MonitorTransform::Rotate180,
+            Transform::_270 => MonitorTransform::Rotate270,
+            Transform::Flipped => MonitorTransform::Flipped,
+            Transform::Flipped90 => MonitorTransform::Flipped90,
+            Transform::Flipped180 => MonitorTransform::Flipped180,
+            Transform::Flipped270 => MonitorTransform::Flipped270,
+        }
+    }
+}
+
+/// Marks the monitors the shell placed itself, `arrange_monitors` leaves them where they are.
+struct ShellPosition;
+
+pub fn has_shell_position(output: &Output) -> bool {
+    output.user_data().get::<ShellPosition>().is_some()
+}
+
+impl MonitorConfiguration {
+    /// Catches invalid values before the backend starts changing anything.
+    pub fn validate(&self) -> Result<(), PlatformChannelError> {
+        match self.scale {
+            Some(scale) if scale <= 0.0 => Err(PlatformChannelError::InvalidScale(scale)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the position, the scale and the transform.
+    /// The backend still has to call `update_monitor_layout` afterwards.
+    pub fn apply_to_output(&self, output: &Output) {
+        let position = self.position.as_ref().map(|position| {
+            output
+                .user_data()
+                .insert_if_missing_threadsafe(|| ShellPosition);
+            Point::<i32, Logical>::from((position.x, position.y))
+        });
+
+        output.change_current_state(
+            None,
+            self.transform.map(Transform::from),
+            self.scale.map(scale_from_f64),
+            position,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_scales_are_advertised_rounded_up() {
+        assert_eq!(scale_from_f64(2.0).integer_scale(), 2);
+        assert_eq!(scale_from_f64(1.25).integer_scale(), 2);
+        assert_eq!(scale_from_f64(1.25).fractional_scale(), 1.25);
+    }
+}
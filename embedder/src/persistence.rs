@@ -0,0 +1,18 @@
+//! Files of veshell in `$XDG_CONFIG_HOME/veshell`.
+//! Settings the user writes live at the top, settings saved between sessions in `persistence`.
+
+use std::path::PathBuf;
+
+/// Path of a configuration file, like `key_bindings.json`.
+pub fn config_path(relative_path: &str) -> PathBuf {
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .unwrap_or_else(|_| std::env::var("HOME").unwrap() + "/.config");
+    PathBuf::from(config_home)
+        .join("veshell")
+        .join(relative_path)
+}
+
+/// Path of a file in the persistence directory, like `Monitor/eDP-1.json`.
+pub fn persistence_path(relative_path: &str) -> PathBuf {
+    config_path("persistence").join(relative_path)
+}
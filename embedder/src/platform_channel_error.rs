@@ -0,0 +1,87 @@
+//! The ways the requests of the shell and of scripts on the control socket fail.
+//!
+//! Every error has a code scripts can match on, and a message meant for humans.
+
+use smithay::backend::renderer::gles::GlesError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlatformChannelError {
+    #[error("Surface {0} doesn't exist")]
+    SurfaceDoesntExist(u64),
+    #[error("Monitor {0} doesn't exist")]
+    MonitorDoesntExist(String),
+    #[error("Input device {0} doesn't exist")]
+    InputDeviceDoesntExist(String),
+    #[error("Keyboard layout {0} doesn't exist")]
+    KeyboardLayoutDoesntExist(u32),
+    #[error("No key binding for {0}")]
+    KeyBindingDoesntExist(String),
+    #[error("No screen capture permission request with id {0}")]
+    UnknownPermissionRequest(u64),
+    #[error("Invalid key combination {0}")]
+    InvalidKeys(String),
+    #[error("Monitor {0} doesn't have a mode at index {1}")]
+    InvalidMode(String, usize),
+    #[error("Scale {0} must be positive")]
+    InvalidScale(f64),
+    #[error("Invalid repeat delay {0}")]
+    InvalidRepeatDelay(u64),
+    #[error("Invalid repeat rate {0}")]
+    InvalidRepeatRate(u64),
+    #[error("Invalid value for {0}")]
+    InvalidValue(&'static str),
+    #[error("The last enabled monitor can't be disabled")]
+    LastMonitor,
+    #[error("Surface {0} has subsurfaces")]
+    HasSubsurfaces(u64),
+    /// What the backend can't do.
+    #[error("{0}")]
+    Unsupported(&'static str),
+    #[error("The device doesn't support {0}")]
+    UnsupportedSetting(&'static str),
+    #[error("Nothing was rendered yet")]
+    NothingRendered,
+    #[error("The region is outside of the screen")]
+    EmptyRegion,
+    #[error("Failed to apply the configuration: {0}")]
+    Backend(String),
+    #[error("Failed to render the screenshot: {0}")]
+    Render(GlesError),
+    #[error("Failed to read the pixels: {0}")]
+    Read(#[from] GlesError),
+    #[error("Failed to write the screenshot: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to encode the screenshot: {0}")]
+    Encode(#[from] png::EncodingError),
+}
+
+impl PlatformChannelError {
+    /// Error code sent back on the platform channel.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PlatformChannelError::SurfaceDoesntExist(_) => "surface_doesnt_exist",
+            PlatformChannelError::MonitorDoesntExist(_) => "monitor_doesnt_exist",
+            PlatformChannelError::InputDeviceDoesntExist(_) => "input_device_doesnt_exist",
+            PlatformChannelError::KeyboardLayoutDoesntExist(_) => "keyboard_layout_doesnt_exist",
+            PlatformChannelError::KeyBindingDoesntExist(_) => "key_binding_doesnt_exist",
+            PlatformChannelError::UnknownPermissionRequest(_) => "unknown_permission_request",
+            PlatformChannelError::InvalidKeys(_) => "invalid_keys",
+            PlatformChannelError::InvalidMode(..) => "invalid_mode",
+            PlatformChannelError::InvalidScale(_) => "invalid_scale",
+            PlatformChannelError::InvalidRepeatDelay(_) => "invalid_repeat_delay",
+            PlatformChannelError::InvalidRepeatRate(_) => "invalid_repeat_rate",
+            PlatformChannelError::InvalidValue(_) => "invalid_value",
+            PlatformChannelError::LastMonitor => "last_monitor",
+            PlatformChannelError::HasSubsurfaces(_) => "has_subsurfaces",
+            PlatformChannelError::Unsupported(_) | PlatformChannelError::UnsupportedSetting(_) => {
+                "unsupported"
+            }
+            PlatformChannelError::NothingRendered => "nothing_rendered",
+            PlatformChannelError::EmptyRegion => "empty_region",
+            PlatformChannelError::Backend(_) => "backend_error",
+            PlatformChannelError::Render(_) => "render_failed",
+            PlatformChannelError::Read(_) => "read_failed",
+            PlatformChannelError::Io(_) | PlatformChannelError::Encode(_) => "write_failed",
+        }
+    }
+}
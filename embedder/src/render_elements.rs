@@ -0,0 +1,89 @@
+//! What the compositor draws itself on top of the Flutter frame,
+//! on the monitors and in screen captures, and how the frame itself is turned the right way up.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use smithay::backend::renderer::element::surface::{
+    render_elements_from_surface_tree, WaylandSurfaceRenderElement,
+};
+use smithay::backend::renderer::element::texture::TextureRenderElement;
+use smithay::backend::renderer::element::Kind;
+use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
+use smithay::input::pointer::{CursorImageAttributes, CursorImageStatus};
+use smithay::utils::{Buffer, Logical, Point, Rectangle, Size, Transform};
+use smithay::wayland::compositor::with_states;
+
+use crate::cursor::CursorTextures;
+
+smithay::backend::renderer::element::render_elements! {
+    pub OutputRenderElement<=GlesRenderer>;
+    Texture=TextureRenderElement<GlesTexture>,
+    Surface=WaylandSurfaceRenderElement<GlesRenderer>,
+}
+
+/// OpenGL stores the rows of the Flutter frame bottom to top,
+/// drawing the frame with this transform puts it the right way up.
+pub const FLUTTER_FRAME_TRANSFORM: Transform = Transform::Flipped180;
+
+/// Where a rectangle of the Flutter frame, as the user sees it, is in the texture Flutter rendered.
+pub fn flutter_frame_rect_in_texture(
+    rect: Rectangle<i32, Buffer>,
+    texture_size: Size<i32, Buffer>,
+) -> Rectangle<i32, Buffer> {
+    FLUTTER_FRAME_TRANSFORM.transform_rect_in(rect, &texture_size)
+}
+
+/// The cursor at `position`, relative to the top left corner of what is rendered.
+pub fn cursor_render_elements(
+    gles_renderer: &mut GlesRenderer,
+    cursor_textures: &mut CursorTextures,
+    cursor_image_status: CursorImageStatus,
+    position: Point<f64, Logical>,
+    scale: f64,
+    time: Duration,
+) -> Vec<OutputRenderElement> {
+    match cursor_image_status {
+        CursorImageStatus::Hidden => vec![],
+        CursorImageStatus::Named(icon) => {
+            let (texture, hotspot) = cursor_textures.get(gles_renderer, icon, time);
+            let position = position.to_physical(scale) - hotspot.to_f64();
+            vec![OutputRenderElement::from(
+                TextureRenderElement::from_texture_buffer(
+                    position,
+                    &texture,
+                    None,
+                    None,
+                    None,
+                    Kind::Cursor,
+                ),
+            )]
+        }
+        CursorImageStatus::Surface(wl_surface) => {
+            let hotspot = with_states(&wl_surface, |states| {
+                states
+                    .data_map
+                    .get::<Mutex<CursorImageAttributes>>()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .hotspot
+            });
+            let position = (position - hotspot.to_f64())
+                .to_physical(scale)
+                .to_i32_round();
+
+            render_elements_from_surface_tree::<_, WaylandSurfaceRenderElement<GlesRenderer>>(
+                gles_renderer,
+                &wl_surface,
+                position,
+                scale,
+                1.0,
+                Kind::Cursor,
+            )
+            .into_iter()
+            .map(OutputRenderElement::from)
+            .collect()
+        }
+    }
+}
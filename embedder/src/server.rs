@@ -1,4 +1,19 @@
+mod control_socket;
+mod decoration;
+mod direct_scanout;
+pub mod foreign_toplevel;
+mod image_copy_capture;
+pub mod key_bindings;
+pub mod keyboard_layout;
+mod layer_shell;
+pub mod screen_capture;
+pub mod screenshot;
+#[cfg(test)]
+mod tests;
+pub mod window_state;
+mod wlr_screencopy;
 mod x11;
+mod xdg_activation;
 
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
@@ -11,34 +26,48 @@ use std::time::{Duration, SystemTime};
 use log::error;
 use serde_json::json;
 use smithay::backend::allocator::dmabuf::Dmabuf;
-use smithay::backend::input::KeyState;
+use smithay::backend::input::{KeyState, TabletToolDescriptor, TouchSlot};
 use smithay::backend::renderer::gles::ffi::Gles2;
-use smithay::backend::renderer::gles::GlesRenderer;
+use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
 use smithay::backend::renderer::{ImportAll, ImportDma, Texture};
+use smithay::backend::renderer::utils::on_commit_buffer_handler;
 use smithay::input::keyboard::KeyboardHandle;
-use smithay::input::pointer::{CursorImageStatus, PointerHandle};
+use smithay::input::pointer::{CursorIcon, CursorImageStatus, PointerHandle, CURSOR_IMAGE_ROLE};
+use smithay::input::touch::TouchHandle;
 use smithay::input::{Seat, SeatHandler, SeatState};
+use smithay::output::{Output, Scale};
 use smithay::reexports::calloop::channel::Event::Msg;
 use smithay::reexports::calloop::generic::Generic;
 use smithay::reexports::calloop::{channel, Interest, LoopHandle, Mode, PostAction};
 use smithay::reexports::wayland_protocols::xdg::shell::server::xdg_toplevel;
+use smithay::reexports::wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration::OrgKdeKwinServerDecoration;
+use smithay::reexports::wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as KdeDecorationMode;
 use smithay::reexports::wayland_server::protocol::wl_buffer;
+use smithay::reexports::wayland_server::protocol::wl_output::WlOutput;
 use smithay::reexports::wayland_server::protocol::wl_seat::WlSeat;
 use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::reexports::wayland_server::backend::ClientId;
 use smithay::reexports::wayland_server::{Client, Display, DisplayHandle, Resource};
 use smithay::reexports::x11rb::protocol::xproto::Window as X11Window;
 use smithay::utils::{
-    Buffer as BufferCoords, Clock, Logical, Monotonic, Point, Rectangle, Serial, Size,
-    SERIAL_COUNTER,
+    Buffer as BufferCoords, Clock, Logical, Monotonic, Physical, Point, Rectangle, Serial, Size,
+    Transform, SERIAL_COUNTER,
 };
 use smithay::wayland::buffer::BufferHandler;
-use smithay::wayland::compositor::{self, get_parent, RectangleKind};
+use smithay::wayland::compositor::{self, get_parent, get_role, RectangleKind};
 use smithay::wayland::compositor::{
     with_states, with_surface_tree_upward, BufferAssignment, CompositorClientState,
-    CompositorHandler, CompositorState, SubsurfaceCachedState, SurfaceAttributes, TraversalAction,
+    CompositorHandler, CompositorState, SubsurfaceCachedState, SurfaceAttributes, SurfaceData,
+    TraversalAction,
 };
+use smithay::wayland::cursor_shape::CursorShapeManagerState;
 use smithay::wayland::dmabuf::{DmabufGlobal, DmabufHandler, DmabufState, ImportNotifier};
+use smithay::wayland::fractional_scale::{
+    with_fractional_scale, FractionalScaleHandler, FractionalScaleManagerState,
+};
 use smithay::wayland::output::OutputHandler;
+use smithay::wayland::pointer_gestures::PointerGesturesState;
+use smithay::wayland::tablet_manager::{TabletManagerState, TabletSeatHandler};
 use smithay::wayland::seat::WaylandFocus;
 use smithay::wayland::selection::data_device::{
     set_data_device_focus, ClientDndGrabHandler, DataDeviceHandler, DataDeviceState,
@@ -49,13 +78,18 @@ use smithay::wayland::selection::primary_selection::{
 };
 use smithay::wayland::selection::wlr_data_control::{DataControlHandler, DataControlState};
 use smithay::wayland::selection::{SelectionHandler, SelectionSource, SelectionTarget};
+use smithay::wayland::shell::kde::decoration::KdeDecorationState;
+use smithay::wayland::shell::wlr_layer::{LayerSurface, WlrLayerShellState, LAYER_SURFACE_ROLE};
 use smithay::wayland::shell::xdg;
+use smithay::wayland::shell::xdg::decoration::XdgDecorationState;
 use smithay::wayland::shell::xdg::{
     PopupSurface, PositionerState, SurfaceCachedState, ToplevelSurface, XdgPopupSurfaceData,
     XdgShellHandler, XdgShellState, XdgToplevelSurfaceData,
 };
 use smithay::wayland::shm::{ShmHandler, ShmState};
 use smithay::wayland::socket::ListeningSocketSource;
+use smithay::wayland::viewporter::{ViewportCachedState, ViewporterState};
+use smithay::wayland::xdg_activation::XdgActivationState;
 use smithay::wayland::xwayland_keyboard_grab::XWaylandKeyboardGrabState;
 use smithay::wayland::xwayland_shell::{
     self, XWaylandShellHandler, XWaylandShellState, XWAYLAND_SHELL_ROLE,
@@ -65,30 +99,44 @@ use smithay::xwayland::{
     xwm, X11Surface, X11Wm, XWayland, XWaylandClientData, XWaylandEvent, XwmHandler,
 };
 use smithay::{
-    delegate_compositor, delegate_data_control, delegate_data_device, delegate_dmabuf,
-    delegate_output, delegate_primary_selection, delegate_seat, delegate_shm, delegate_xdg_shell,
-    delegate_xwayland_shell,
+    delegate_compositor, delegate_cursor_shape, delegate_data_control, delegate_data_device, delegate_dmabuf,
+    delegate_fractional_scale, delegate_output, delegate_pointer_gestures,
+    delegate_primary_selection, delegate_seat, delegate_shm, delegate_tablet_manager,
+    delegate_viewporter, delegate_xdg_shell, delegate_xwayland_shell,
 };
 use tracing::{info, warn};
 
-use crate::cursor::Cursor;
+use crate::cursor::{Cursor, CursorTextures};
 use crate::flutter_engine::wayland_messages::{
     MyPoint, PopupMessage, SubsurfaceMessage, SurfaceMessage, SurfaceRole, ToplevelMessage,
     XdgSurfaceMessage, XdgSurfaceRole,
 };
 use crate::flutter_engine::FlutterEngine;
 use crate::focus::{KeyboardFocusTarget, PointerFocusTarget};
+use crate::gles_framebuffer_importer::Framebuffer;
+use crate::input_handling::{Gesture, TabletTool, FIRST_TABLET_TOOL_DEVICE_ID};
 use crate::keyboard::key_repeater::KeyRepeater;
+use crate::keyboard::repeat_settings::KeyRepeatSettings;
 use crate::keyboard::KeyEvent;
+use crate::monitor_configuration::has_shell_position;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::server::control_socket::{init_control_socket, ControlSocket};
+use crate::server::foreign_toplevel::ForeignToplevelState;
+use crate::server::image_copy_capture::ImageCopyCaptureState;
+use crate::server::key_bindings::{
+    binding_keysym, load_key_bindings, switch_vt_keysym, KeyBinding,
+};
+use crate::server::keyboard_layout::KeyboardLayoutConfig;
+use crate::server::wlr_screencopy::WlrScreencopyState;
 use crate::texture_swap_chain::TextureSwapChain;
-use crate::{flutter_engine, Backend, ClientState};
+use crate::{flutter_engine, send_frames_surface_tree, Backend, ClientState};
 
 pub struct ServerState<BackendData: Backend + 'static> {
     pub running: Arc<AtomicBool>,
     pub display_handle: DisplayHandle,
     pub loop_handle: LoopHandle<'static, ServerState<BackendData>>,
     pub clock: Clock<Monotonic>,
-    pub tx_fbo: Option<channel::Sender<Option<Dmabuf>>>,
+    pub tx_fbo: Option<channel::Sender<Option<Framebuffer>>>,
     pub batons: Vec<flutter_engine::Baton>,
     pub seat: Seat<ServerState<BackendData>>,
     pub seat_state: SeatState<ServerState<BackendData>>,
@@ -97,12 +145,18 @@ pub struct ServerState<BackendData: Backend + 'static> {
     pub primary_selection_state: PrimarySelectionState,
     pub pointer: PointerHandle<ServerState<BackendData>>,
     pub keyboard: KeyboardHandle<ServerState<BackendData>>,
-    pub repeat_delay: u64,
-    pub repeat_rate: u64,
+    /// Index of the active layout of the keymap, the last one the shell heard about.
+    pub active_keyboard_layout: u32,
+    pub touch: TouchHandle<ServerState<BackendData>>,
+    pub key_repeat: KeyRepeatSettings,
+    pub key_bindings: Vec<KeyBinding>,
+    /// Keys handled by the compositor, like key bindings, their release is swallowed too.
+    pub intercepted_keys: HashSet<u32>,
     pub tx_flutter_handled_key_event: channel::Sender<(KeyEvent, bool)>,
     pub key_repeater: KeyRepeater<BackendData>,
     pub x11_wm: Option<X11Wm>,
     pub wayland_socket_name: Option<String>,
+    pub control_socket: Option<ControlSocket>,
     pub xwayland_display: Option<u32>,
 
     pub backend_data: Box<BackendData>,
@@ -113,12 +167,42 @@ pub struct ServerState<BackendData: Backend + 'static> {
 
     pub mouse_position: (f64, f64),
     pub surface_id_under_cursor: Option<u64>,
+    /// The cursor a client asked for with `wl_pointer.set_cursor` or `wp_cursor_shape_device_v1`.
+    pub client_cursor_image_status: CursorImageStatus,
+    /// The cursor the shell asked for on the `flutter/mousecursor` channel.
+    pub flutter_cursor_image_status: CursorImageStatus,
+    pub cursor_textures: CursorTextures,
+    /// Logical position of every finger on the touchscreen.
+    pub touch_positions: HashMap<TouchSlot, (f64, f64)>,
+    pub gesture: Option<Gesture>,
+    pub tablet_tools: HashMap<TabletToolDescriptor, TabletTool>,
+    pub next_tablet_tool_device_id: i32,
     pub is_next_flutter_frame_scheduled: bool,
+    /// Flutter renders all monitors in a single view, so it uses the highest scale among them.
+    /// Monitors with a lower scale display a downscaled image.
+    pub pixel_ratio: f64,
 
     pub compositor_state: CompositorState,
     pub xdg_shell_state: XdgShellState,
+    pub layer_shell_state: WlrLayerShellState,
+    pub xdg_decoration_state: XdgDecorationState,
+    pub kde_decoration_state: KdeDecorationState,
     pub shm_state: ShmState,
     pub dmabuf_state: Option<DmabufState>,
+    pub fractional_scale_manager_state: FractionalScaleManagerState,
+    pub viewporter_state: ViewporterState,
+    pub pointer_gestures_state: PointerGesturesState,
+    pub tablet_manager_state: TabletManagerState,
+    pub cursor_shape_manager_state: CursorShapeManagerState,
+    pub wlr_screencopy_state: WlrScreencopyState,
+    pub image_copy_capture_state: ImageCopyCaptureState,
+    pub foreign_toplevel_state: ForeignToplevelState,
+    pub xdg_activation_state: XdgActivationState,
+    /// Clients waiting for the shell to allow or deny screen captures, by request id.
+    pub screen_capture_permission_requests: HashMap<u64, Client>,
+    pub next_screen_capture_permission_request_id: u64,
+    /// Given to every client, see `ClientState::disconnected`.
+    pub tx_client_disconnected: channel::Sender<ClientId>,
 
     pub imported_dmabufs: Vec<Dmabuf>,
     pub gles_renderer: Option<GlesRenderer>,
@@ -127,12 +211,20 @@ pub struct ServerState<BackendData: Backend + 'static> {
     pub subsurfaces: HashMap<u64, WlSurface>,
     pub xdg_toplevels: HashMap<u64, ToplevelSurface>,
     pub xdg_popups: HashMap<u64, PopupSurface>,
+    pub layer_surfaces: HashMap<u64, LayerSurface>,
+    pub kde_decorations: HashMap<u64, OrgKdeKwinServerDecoration>,
     pub x11_surface_per_x11_window: HashMap<X11Window, X11Surface>,
     pub x11_surface_per_wl_surface: HashMap<WlSurface, X11Surface>,
     pub texture_ids_per_surface_id: HashMap<u64, Vec<(i64, Size<i32, BufferCoords>)>>,
     pub surface_id_per_texture_id: HashMap<i64, u64>,
     pub texture_swapchains: HashMap<i64, TextureSwapChain>,
+    /// Surfaces the backend may show directly on a hardware plane, by surface id.
+    pub direct_scanouts: HashMap<u64, direct_scanout::DirectScanout>,
     pub xwayland_shell_state: xwayland_shell::XWaylandShellState,
+
+    /// Every message sent to the shell, so tests can check them.
+    #[cfg(test)]
+    pub platform_messages: Vec<(String, serde_json::Value)>,
 }
 
 impl<BackendData: Backend + 'static> ServerState<BackendData> {
@@ -154,31 +246,150 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         texture_id
     }
 
+    /// Hands a texture imported from the buffer of the surface to Flutter.
+    /// The texture id only changes with the size of the buffer.
+    pub fn commit_texture(
+        &mut self,
+        surface_data: &SurfaceData,
+        surface_id: u64,
+        texture: GlesTexture,
+    ) -> (i64, Size<i32, BufferCoords>) {
+        unsafe {
+            self.gl.as_ref().unwrap().Finish();
+        }
+
+        let size = texture.size();
+
+        let old_texture_size = surface_data
+            .data_map
+            .get::<RefCell<MySurfaceState>>()
+            .unwrap()
+            .borrow()
+            .old_texture_size;
+        let size_changed = match old_texture_size {
+            Some(old_size) => old_size != size,
+            None => true,
+        };
+
+        surface_data
+            .data_map
+            .get::<RefCell<MySurfaceState>>()
+            .unwrap()
+            .borrow_mut()
+            .old_texture_size = Some(size);
+
+        let texture_id = match size_changed {
+            true => None,
+            false => self
+                .texture_ids_per_surface_id
+                .get(&surface_id)
+                .and_then(|v| v.last().cloned())
+                .map(|(id, _)| id),
+        };
+
+        let texture_id = texture_id.unwrap_or_else(|| {
+            let texture_id = self.get_new_texture_id();
+            while self
+                .texture_ids_per_surface_id
+                .entry(surface_id)
+                .or_default()
+                .len()
+                >= 2
+            {
+                self.texture_ids_per_surface_id
+                    .entry(surface_id)
+                    .or_default()
+                    .remove(0);
+            }
+
+            self.texture_ids_per_surface_id
+                .entry(surface_id)
+                .or_default()
+                .push((texture_id, size));
+            self.surface_id_per_texture_id
+                .insert(texture_id, surface_id);
+            self.flutter_engine_mut()
+                .register_external_texture(texture_id)
+                .unwrap();
+            texture_id
+        });
+
+        let swapchain = self.texture_swapchains.entry(texture_id).or_default();
+        swapchain.commit(texture);
+
+        self.flutter_engine_mut()
+            .mark_external_texture_frame_available(texture_id)
+            .unwrap();
+
+        (texture_id, size)
+    }
+
     pub fn handle_key_event(&mut self, key_code: u32, state: KeyState, time: u32) {
         // Update the state of the keyboard.
         // Every key event must be passed through `glfw_key_codes.input_intercept`
         // so that Smithay knows what keys are pressed.
         let keyboard = self.keyboard.clone();
-        let ((mods, utf32_codepoint), mods_changed) =
-            keyboard.input_intercept::<_, _>(self, key_code, state, |_, mods, keysym_handle| {
-                // After updating the keyboard state,
-                // we get the state of the modifiers and the character that was typed.
-                let utf32_codepoint = keysym_handle.modified_sym().key_char();
-                (*mods, utf32_codepoint)
-            });
+        let (
+            (mods, modified_keysym, utf32_codepoint, latin_keysym, unmodified_keysym),
+            mods_changed,
+        ) = keyboard.input_intercept::<_, _>(self, key_code, state, |_, mods, keysym_handle| {
+            // After updating the keyboard state,
+            // we get the state of the modifiers and the character that was typed.
+            let modified_keysym = keysym_handle.modified_sym();
+            (
+                *mods,
+                modified_keysym,
+                modified_keysym.key_char(),
+                keysym_handle.raw_latin_sym_or_raw_current_sym(),
+                binding_keysym(&keysym_handle),
+            )
+        });
+        self.check_keyboard_layout_changed();
+
+        // VT switching and key bindings come before Flutter and clients,
+        // even when a client grabbed the keyboard.
+        match state {
+            KeyState::Pressed => {
+                // The keymap turns Ctrl+Alt+F1..F12 into these keysyms.
+                // Nested in another session, they go to Flutter and clients like any other key.
+                if let Some(vt) =
+                    switch_vt_keysym(modified_keysym).filter(|_| self.backend_data.can_change_vt())
+                {
+                    self.intercepted_keys.insert(key_code);
+                    // The keys still pressed are released when the session pauses.
+                    self.backend_data.change_vt(vt);
+                    return;
+                }
+                if let Some(action) =
+                    unmodified_keysym.and_then(|keysym| self.find_key_binding(&mods, keysym))
+                {
+                    self.intercepted_keys.insert(key_code);
+                    self.run_key_binding_action(action);
+                    return;
+                }
+            }
+            KeyState::Released => {
+                if self.intercepted_keys.remove(&key_code) {
+                    return;
+                }
+            }
+        }
+
+        let key_event = KeyEvent {
+            key_code,
+            codepoint: utf32_codepoint,
+            latin_keysym,
+            state,
+            time,
+            mods,
+            mods_changed,
+        };
 
         // Forward the key event to Flutter.
-        self.flutter_engine.as_mut().unwrap().send_key_event(
-            self.tx_flutter_handled_key_event.clone(),
-            KeyEvent {
-                key_code,
-                codepoint: utf32_codepoint,
-                state,
-                time,
-                mods,
-                mods_changed,
-            },
-        );
+        self.flutter_engine
+            .as_mut()
+            .unwrap()
+            .send_key_event(self.tx_flutter_handled_key_event.clone(), key_event);
 
         // Initiate key repeat.
         // The callback that gets called repeatedly is defined in the constructor of `ServerState`.
@@ -187,14 +398,14 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         // because asynchronous flows like this one are difficult to follow.
         if !mods_changed {
             match state {
-                KeyState::Pressed => {
+                KeyState::Pressed if self.key_repeat.enabled => {
                     self.key_repeater.down(
-                        key_code,
-                        utf32_codepoint,
-                        Duration::from_millis(self.repeat_delay),
-                        Duration::from_millis(self.repeat_rate),
+                        key_event,
+                        self.key_repeat.delay(),
+                        self.key_repeat.interval(),
                     );
                 }
+                KeyState::Pressed => {}
                 KeyState::Released => {
                     self.key_repeater.up(key_code);
                 }
@@ -202,6 +413,14 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         }
     }
 
+    /// Clients choose the cursor over their surfaces, the shell everywhere else.
+    pub fn cursor_image_status(&self) -> CursorImageStatus {
+        match self.surface_id_under_cursor {
+            Some(_) => self.client_cursor_image_status.clone(),
+            None => self.flutter_cursor_image_status.clone(),
+        }
+    }
+
     pub fn release_all_keys(&mut self) {
         let keyboard = self.keyboard.clone();
         for key_code in keyboard.pressed_keys() {
@@ -217,6 +436,120 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
     pub fn flutter_engine_mut(&mut self) -> &mut FlutterEngine<BackendData> {
         self.flutter_engine.as_mut().unwrap()
     }
+
+    /// Resizes the Flutter view to cover all the monitors and updates the scale clients
+    /// should render at.
+    /// Returns the size in pixels of the buffers Flutter renders into.
+    pub fn update_window_metrics(&mut self) -> Size<i32, Physical> {
+        let monitors = self.backend_data.get_monitor_layout();
+
+        self.pixel_ratio = monitors
+            .iter()
+            .map(|output| output.current_scale().fractional_scale())
+            .reduce(f64::max)
+            .unwrap_or(1.0);
+
+        let size = monitors_bounding_box(&monitors)
+            .size
+            .to_f64()
+            .to_physical(self.pixel_ratio)
+            .to_i32_round();
+
+        if let Some(flutter_engine) = self.flutter_engine.as_ref() {
+            flutter_engine
+                .send_window_metrics((size.w as u32, size.h as u32).into(), self.pixel_ratio)
+                .unwrap();
+        }
+
+        self.update_preferred_scales();
+
+        size
+    }
+
+    /// The scale clients should render the surface at, the one of the monitor showing it.
+    /// Windows spanning several monitors follow the sharpest one,
+    /// like surfaces the shell hasn't placed on a monitor yet.
+    pub fn preferred_scale(&self, surface: &WlSurface) -> f64 {
+        let root = self.window_root_surface(surface);
+        let monitors = self
+            .layer_surface_monitor(&root)
+            .map(|output| vec![output])
+            .or_else(|| self.window_monitors(get_surface_id(&root)))
+            .filter(|monitors| !monitors.is_empty())
+            .unwrap_or_else(|| self.backend_data.get_monitor_layout());
+
+        monitors
+            .iter()
+            .map(|output| output.current_scale().fractional_scale())
+            .reduce(f64::max)
+            .unwrap_or(1.0)
+    }
+
+    /// Sends every surface the scale it should render at, after monitors or windows moved.
+    pub fn update_preferred_scales(&self) {
+        for surface in self.surfaces.values() {
+            let scale = self.preferred_scale(surface);
+            with_states(surface, |surface_data| {
+                with_fractional_scale(surface_data, |fractional_scale| {
+                    fractional_scale.set_preferred_scale(scale);
+                });
+            });
+        }
+    }
+
+    /// The toplevel or layer surface a subsurface or a popup belongs to.
+    fn window_root_surface(&self, surface: &WlSurface) -> WlSurface {
+        let mut root = surface.clone();
+        loop {
+            if let Some(parent) = get_parent(&root) {
+                root = parent;
+            } else if let Some(parent) = self
+                .xdg_popups
+                .get(&get_surface_id(&root))
+                .and_then(|popup| popup.get_parent_surface())
+            {
+                root = parent;
+            } else {
+                return root;
+            }
+        }
+    }
+
+    /// Lets every client draw its next frame, called by the backends when a frame was shown.
+    pub fn send_frame_callbacks(&self) {
+        let time = Duration::from(self.clock.now()).as_millis() as u32;
+        for surface in self.xdg_shell_state.toplevel_surfaces() {
+            send_frames_surface_tree(surface.wl_surface(), time);
+        }
+        for surface in self.xdg_popups.values() {
+            send_frames_surface_tree(surface.wl_surface(), time);
+        }
+        for surface in self.layer_surfaces.values() {
+            send_frames_surface_tree(surface.wl_surface(), time);
+        }
+        for surface in self.x11_surface_per_wl_surface.keys() {
+            send_frames_surface_tree(surface, time);
+        }
+        if let CursorImageStatus::Surface(surface) = &self.client_cursor_image_status {
+            send_frames_surface_tree(surface, time);
+        }
+    }
+
+    /// Sends a message to the shell over the `platform` channel.
+    /// Messages are dropped if the Flutter engine is not running.
+    pub fn invoke_platform_method(&mut self, method: &str, arguments: serde_json::Value) {
+        #[cfg(test)]
+        self.platform_messages
+            .push((method.to_string(), arguments.clone()));
+
+        if let Some(flutter_engine) = self.flutter_engine.as_mut() {
+            flutter_engine.platform_method_channel.invoke_method(
+                method,
+                Some(Box::new(arguments)),
+                None,
+            );
+        }
+    }
 }
 
 // Macros used to delegate protocol handling to types in the app state.
@@ -228,6 +561,11 @@ delegate_output!(@<BackendData: Backend + 'static> ServerState<BackendData>);
 delegate_seat!(@<BackendData: Backend + 'static> ServerState<BackendData>);
 delegate_data_device!(@<BackendData: Backend + 'static> ServerState<BackendData>);
 delegate_xwayland_shell!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_fractional_scale!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_viewporter!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_pointer_gestures!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_tablet_manager!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_cursor_shape!(@<BackendData: Backend + 'static> ServerState<BackendData>);
 
 impl<BackendData: Backend + 'static> ServerState<BackendData> {
     pub fn new(
@@ -240,6 +578,21 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         let clock = Clock::new();
         let compositor_state = CompositorState::new::<Self>(&display_handle);
         let xdg_shell_state = XdgShellState::new::<Self>(&display_handle);
+        let layer_shell_state = WlrLayerShellState::new::<Self>(&display_handle);
+        let fractional_scale_manager_state =
+            FractionalScaleManagerState::new::<Self>(&display_handle);
+        let viewporter_state = ViewporterState::new::<Self>(&display_handle);
+        let pointer_gestures_state = PointerGesturesState::new::<Self>(&display_handle);
+        let tablet_manager_state = TabletManagerState::new::<Self>(&display_handle);
+        let cursor_shape_manager_state = CursorShapeManagerState::new::<Self>(&display_handle);
+        let wlr_screencopy_state = WlrScreencopyState::new::<BackendData>(&display_handle);
+        let image_copy_capture_state = ImageCopyCaptureState::new::<BackendData>(&display_handle);
+        let foreign_toplevel_state = ForeignToplevelState::new::<BackendData>(&display_handle);
+        let xdg_activation_state = XdgActivationState::new::<Self>(&display_handle);
+        let xdg_decoration_state = XdgDecorationState::new::<Self>(&display_handle);
+        // Clients draw their own decorations until the shell decides otherwise.
+        let kde_decoration_state =
+            KdeDecorationState::new::<Self>(&display_handle, KdeDecorationMode::Client);
         let shm_state = ShmState::new::<Self>(&display_handle, vec![]);
 
         // init input
@@ -247,13 +600,29 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         let seat_name = backend_data.seat_name();
         let mut seat = seat_state.new_wl_seat(&display_handle, seat_name.clone());
 
-        let repeat_delay: u64 = 200;
-        let repeat_rate: u64 = 50;
+        let key_repeat = KeyRepeatSettings::load();
+        let keyboard_layout_config = KeyboardLayoutConfig::from_env();
         let keyboard = seat
-            .add_keyboard(Default::default(), repeat_delay as i32, repeat_rate as i32)
+            .add_keyboard(
+                keyboard_layout_config.xkb_config(),
+                key_repeat.delay as i32,
+                key_repeat.wayland_rate(),
+            )
+            .or_else(|err| {
+                warn!(
+                    ?keyboard_layout_config,
+                    "Invalid keymap, using the default one: {err}"
+                );
+                seat.add_keyboard(
+                    Default::default(),
+                    key_repeat.delay as i32,
+                    key_repeat.wayland_rate(),
+                )
+            })
             .unwrap();
 
         let pointer = seat.add_pointer();
+        let touch = seat.add_touch();
 
         let data_device_state = DataDeviceState::new::<Self>(&display_handle);
         let primary_selection_state = PrimarySelectionState::new::<Self>(&display_handle);
@@ -268,10 +637,10 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         let socket_name = source.socket_name().to_string_lossy().into_owned();
         loop_handle
             .insert_source(source, |client_stream, _, data| {
-                if let Err(err) = data
-                    .display_handle
-                    .insert_client(client_stream, Arc::new(ClientState::default()))
-                {
+                if let Err(err) = data.display_handle.insert_client(
+                    client_stream,
+                    Arc::new(ClientState::new(data.tx_client_disconnected.clone())),
+                ) {
                     warn!("Error adding wayland client: {}", err);
                 };
             })
@@ -279,6 +648,11 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
 
         info!(name = socket_name, "Listening on wayland socket");
 
+        let control_socket = init_control_socket(&loop_handle, &socket_name);
+        if let Some(control_socket) = &control_socket {
+            std::env::set_var("VESHELL_SOCKET", control_socket.path());
+        }
+
         std::env::set_var("XDG_SESSION_TYPE", "wayland");
         std::env::set_var("GDK_BACKEND", "wayland"); // Force GTK apps to run on Wayland.
         std::env::set_var("QT_QPA_PLATFORM", "wayland"); // Force QT apps to run on Wayland.
@@ -339,17 +713,24 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             )
             .unwrap();
 
+        let (tx_client_disconnected, rx_client_disconnected) = channel::channel::<ClientId>();
+        loop_handle
+            .insert_source(rx_client_disconnected, |event, _, data| {
+                if let Msg(client_id) = event {
+                    data.client_disconnected(client_id);
+                }
+            })
+            .unwrap();
+
         let key_repeater = KeyRepeater::new(
             loop_handle.clone(),
-            |key_code, code_point, data: &mut ServerState<BackendData>| {
+            |key_event, data: &mut ServerState<BackendData>| {
                 let keyboard = data.keyboard.clone();
 
                 let mods = keyboard.modifier_state();
                 data.flutter_engine.as_mut().unwrap().send_key_event(
                     data.tx_flutter_handled_key_event.clone(),
                     KeyEvent {
-                        key_code,
-                        codepoint: code_point,
                         state: KeyState::Pressed,
                         time: SystemTime::now()
                             .duration_since(SystemTime::UNIX_EPOCH)
@@ -357,6 +738,7 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
                             .as_millis() as u32,
                         mods,
                         mods_changed: false,
+                        ..key_event
                     },
                 );
             },
@@ -375,12 +757,35 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             backend_data: Box::new(backend_data),
             mouse_position: (0.0, 0.0),
             surface_id_under_cursor: None,
+            client_cursor_image_status: CursorImageStatus::Named(CursorIcon::Default),
+            flutter_cursor_image_status: CursorImageStatus::Named(CursorIcon::Default),
+            cursor_textures: CursorTextures::default(),
+            touch_positions: HashMap::new(),
+            gesture: None,
+            tablet_tools: HashMap::new(),
+            next_tablet_tool_device_id: FIRST_TABLET_TOOL_DEVICE_ID,
             is_next_flutter_frame_scheduled: false,
+            pixel_ratio: 1.0,
             compositor_state,
             xdg_shell_state,
+            layer_shell_state,
+            xdg_decoration_state,
+            kde_decoration_state,
             shm_state,
             flutter_engine: None,
             dmabuf_state,
+            fractional_scale_manager_state,
+            viewporter_state,
+            pointer_gestures_state,
+            tablet_manager_state,
+            cursor_shape_manager_state,
+            wlr_screencopy_state,
+            image_copy_capture_state,
+            foreign_toplevel_state,
+            xdg_activation_state,
+            screen_capture_permission_requests: HashMap::new(),
+            next_screen_capture_permission_request_id: 1,
+            tx_client_disconnected,
             seat,
             seat_state,
             data_device_state,
@@ -388,12 +793,16 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             data_control_state,
             pointer,
             keyboard,
-            repeat_delay,
-            repeat_rate,
+            active_keyboard_layout: 0,
+            touch,
+            key_repeat,
+            key_bindings: load_key_bindings(),
+            intercepted_keys: HashSet::new(),
             tx_flutter_handled_key_event,
             key_repeater,
             x11_wm: None,
             wayland_socket_name: Some(socket_name),
+            control_socket,
             xwayland_display: None,
             next_surface_id: 1,
             next_x11_surface_id: 1,
@@ -405,12 +814,17 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             subsurfaces: HashMap::new(),
             xdg_toplevels: HashMap::new(),
             xdg_popups: HashMap::new(),
+            layer_surfaces: HashMap::new(),
+            kde_decorations: HashMap::new(),
             x11_surface_per_x11_window: HashMap::new(),
             x11_surface_per_wl_surface: HashMap::new(),
             texture_ids_per_surface_id: HashMap::new(),
             surface_id_per_texture_id: HashMap::new(),
             texture_swapchains: HashMap::new(),
+            direct_scanouts: HashMap::new(),
             xwayland_shell_state,
+            #[cfg(test)]
+            platform_messages: vec![],
         }
     }
 
@@ -478,24 +892,45 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
         }
     }
 
-    pub fn change_keyboard_repeat_info(&mut self, repeat_delay: u64, repeat_rate: u64) {
-        self.repeat_delay = repeat_delay;
-        self.repeat_rate = repeat_rate;
+    /// Applies the settings to the repeats of the shell and of clients, and saves them.
+    pub fn change_keyboard_repeat_info(
+        &mut self,
+        key_repeat: KeyRepeatSettings,
+    ) -> Result<(), PlatformChannelError> {
+        key_repeat.validate()?;
+
+        self.key_repeat = key_repeat;
+        if !key_repeat.enabled {
+            self.key_repeater.cancel();
+        }
         self.keyboard
-            .change_repeat_info(repeat_delay as i32, repeat_rate as i32);
+            .change_repeat_info(key_repeat.delay as i32, key_repeat.wayland_rate());
+
+        if let Err(err) = key_repeat.save() {
+            warn!("Failed to save the key repeat settings: {err}");
+        }
+        self.send_keyboard_repeat_info();
+        Ok(())
+    }
+
+    pub fn send_keyboard_repeat_info(&mut self) {
+        let key_repeat = self.key_repeat;
+        self.invoke_platform_method("keyboard_repeat_changed", json!(key_repeat));
     }
 
     pub fn construct_surface_message(&self, surface: &WlSurface) -> SurfaceMessage {
         let surface_id = get_surface_id(surface);
         let role = self.construct_surface_role_message(surface);
 
-        let (buffer_delta, buffer_scale, input_region) = with_states(surface, |surface_data| {
-            let surface_state = surface_data.cached_state.current::<SurfaceAttributes>();
-            let buffer_delta = surface_state.buffer_delta;
-            let buffer_scale = surface_state.buffer_scale;
-            let input_region = surface_state.input_region.clone();
-            (buffer_delta, buffer_scale, input_region)
-        });
+        let (buffer_delta, buffer_scale, input_region, viewport) =
+            with_states(surface, |surface_data| {
+                let surface_state = surface_data.cached_state.current::<SurfaceAttributes>();
+                let buffer_delta = surface_state.buffer_delta;
+                let buffer_scale = surface_state.buffer_scale;
+                let input_region = surface_state.input_region.clone();
+                let viewport = *surface_data.cached_state.current::<ViewportCachedState>();
+                (buffer_delta, buffer_scale, input_region, viewport)
+            });
 
         let (texture_id, buffer_size) = self
             .texture_ids_per_surface_id
@@ -518,9 +953,15 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             }
             acc.unwrap_or_default()
         } else {
-            // TODO: Account for DPI scaling.
-            buffer_size
-                .map(|size| Rectangle::from_loc_and_size((0, 0), (size.w, size.h)))
+            // The viewport destination takes precedence over the source,
+            // which takes precedence over the buffer size divided by the buffer scale.
+            viewport
+                .dst
+                .or_else(|| viewport.src.map(|src| src.size.to_i32_round()))
+                .or_else(|| {
+                    buffer_size.map(|size| size.to_logical(buffer_scale, Transform::Normal))
+                })
+                .map(|size| Rectangle::from_loc_and_size((0, 0), size))
                 .unwrap_or_default()
         };
 
@@ -533,6 +974,8 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             buffer_delta: buffer_delta.map(|delta| delta.into()),
             buffer_size: buffer_size.map(|b| b.into()),
             scale: buffer_scale,
+            viewport_source: viewport.src.map(|src| src.into()),
+            viewport_destination: viewport.dst.map(|dst| dst.into()),
             input_region: input_region.into(),
             subsurfaces_below,
             subsurfaces_above,
@@ -551,6 +994,9 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
                 Some(SurfaceRole::Subsurface(subsurface_message))
             }
             Some(XWAYLAND_SHELL_ROLE) => Some(SurfaceRole::X11Surface),
+            Some(LAYER_SURFACE_ROLE) => self
+                .construct_layer_surface_role_message(surface)
+                .map(SurfaceRole::LayerSurface),
             _ => None,
         }
     }
@@ -617,10 +1063,6 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
                 )
             });
 
-        toplevel.with_pending_state(|state| {
-            state.states.set(xdg_toplevel::State::Maximized);
-        });
-
         if !initial_configure_sent {
             toplevel.send_configure();
             return None;
@@ -636,6 +1078,7 @@ impl<BackendData: Backend + 'static> ServerState<BackendData> {
             parent_surface_id: parent_id,
             app_id,
             title,
+            decoration_mode: decoration::get_requested_decoration_mode(surface),
         })
     }
 
@@ -674,7 +1117,7 @@ impl<BackendData: Backend> BufferHandler for ServerState<BackendData> {
     fn buffer_destroyed(&mut self, _buffer: &wl_buffer::WlBuffer) {}
 }
 
-impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
+impl<BackendData: Backend + 'static> XdgShellHandler for ServerState<BackendData> {
     fn xdg_shell_state(&mut self) -> &mut XdgShellState {
         &mut self.xdg_shell_state
     }
@@ -687,13 +1130,11 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
             state.states.set(xdg_toplevel::State::Activated);
         });
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "new_toplevel",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
@@ -725,32 +1166,95 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
 
         self.xdg_popups.insert(surface_id, surface.clone());
 
-        // TODO: Revise this unwrap.
-        // Wayland states that popups without parents can exist but I don't know in what case.
-        let parent = get_surface_id(&parent.unwrap());
+        // Popups of layer surfaces are created without a parent,
+        // they get one later in `WlrLayerShellHandler::new_popup`.
+        let Some(parent) = parent else {
+            return;
+        };
+        let parent = get_surface_id(&parent);
         let position: MyPoint<i32, Logical> = positioner.get_geometry().loc.into();
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "new_popup",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
                 "parent": parent,
                 "position": position,
-            }))),
-            None,
+            }),
         );
     }
 
     fn move_request(&mut self, surface: ToplevelSurface, _seat: WlSeat, _serial: Serial) {
         let surface_id = get_surface_id(surface.wl_surface());
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "interactive_move",
-            Some(Box::new(json!({
+            json!({
                     "surfaceId": surface_id,
-            }))),
-            None,
+            }),
+        );
+    }
+
+    fn maximize_request(&mut self, surface: ToplevelSurface) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        self.invoke_platform_method(
+            "maximize_request",
+            json!({
+                "surfaceId": surface_id,
+            }),
+        );
+        answer_window_state_request(&surface);
+    }
+
+    fn unmaximize_request(&mut self, surface: ToplevelSurface) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        self.invoke_platform_method(
+            "unmaximize_request",
+            json!({
+                "surfaceId": surface_id,
+            }),
+        );
+        answer_window_state_request(&surface);
+    }
+
+    fn fullscreen_request(&mut self, surface: ToplevelSurface, output: Option<WlOutput>) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        // The shell picks a monitor when the client doesn't care.
+        let monitor = output
+            .as_ref()
+            .and_then(Output::from_resource)
+            .map(|output| output.name());
+        // Used when the shell accepts the request without choosing a monitor itself.
+        surface.with_pending_state(|state| {
+            state.fullscreen_output = output;
+        });
+        self.invoke_platform_method(
+            "fullscreen_request",
+            json!({
+                "surfaceId": surface_id,
+                "monitor": monitor,
+            }),
+        );
+        answer_window_state_request(&surface);
+    }
+
+    fn unfullscreen_request(&mut self, surface: ToplevelSurface) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        self.invoke_platform_method(
+            "unfullscreen_request",
+            json!({
+                "surfaceId": surface_id,
+            }),
+        );
+        answer_window_state_request(&surface);
+    }
+
+    fn minimize_request(&mut self, surface: ToplevelSurface) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        self.invoke_platform_method(
+            "minimize_request",
+            json!({
+                "surfaceId": surface_id,
+            }),
         );
     }
 
@@ -762,14 +1266,12 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
         edges: xdg_toplevel::ResizeEdge,
     ) {
         let surface_id = get_surface_id(surface.wl_surface());
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "interactive_resize",
-            Some(Box::new(json!({
+            json!({
                     "surfaceId": surface_id,
                     "edge": edges as i64,
-            }))),
-            None,
+            }),
         );
     }
 
@@ -789,14 +1291,13 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
     fn toplevel_destroyed(&mut self, surface: ToplevelSurface) {
         let surface_id = get_surface_id(surface.wl_surface());
         self.xdg_toplevels.remove(&surface_id);
+        self.close_foreign_toplevel(surface_id);
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "destroy_toplevel",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
@@ -804,13 +1305,11 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
         let surface_id = get_surface_id(surface.wl_surface());
         self.xdg_popups.remove(&surface_id);
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "destroy_popup",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
@@ -828,15 +1327,14 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
                 .clone()
         });
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "app_id_changed",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
                 "appId": app_id,
-            }))),
-            None,
+            }),
         );
+        self.set_foreign_toplevel_app_id(surface_id, app_id);
     }
 
     fn title_changed(&mut self, surface: ToplevelSurface) {
@@ -853,15 +1351,22 @@ impl<BackendData: Backend> XdgShellHandler for ServerState<BackendData> {
                 .clone()
         });
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "title_changed",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
                 "title": title,
-            }))),
-            None,
+            }),
         );
+        self.set_foreign_toplevel_title(surface_id, title);
+    }
+}
+
+/// xdg-shell requires a configure for every maximize and fullscreen request, even refused ones.
+/// The shell sends another one with `set_window_state` when it accepts.
+fn answer_window_state_request(surface: &ToplevelSurface) {
+    if surface.is_initial_configure_sent() {
+        surface.send_configure();
     }
 }
 
@@ -870,6 +1375,67 @@ pub struct MySurfaceState {
     pub old_texture_size: Option<Size<i32, BufferCoords>>,
 }
 
+/// The area covered by all the monitors in the global compositor space.
+pub fn monitors_bounding_box(monitors: &[Output]) -> Rectangle<i32, Logical> {
+    monitors
+        .iter()
+        .filter_map(|output| {
+            let mode = output.current_mode()?;
+            let size = mode
+                .size
+                .to_f64()
+                .to_logical(output.current_scale().fractional_scale())
+                .to_i32_round();
+            let size = output.current_transform().transform_size(size);
+            Some(Rectangle::from_loc_and_size(
+                output.current_location(),
+                size,
+            ))
+        })
+        .reduce(|first, second| first.merge(second))
+        .unwrap_or_default()
+}
+
+/// Places the monitors the shell didn't position to the right of the others, keeping their
+/// current order, then moves the whole layout so it starts at (0, 0) like the Flutter view.
+pub fn arrange_monitors(monitors: &[Output]) {
+    let (mut placed, mut unplaced): (Vec<_>, Vec<_>) =
+        monitors.iter().cloned().partition(has_shell_position);
+    unplaced.sort_by_key(|output| output.current_location().x);
+
+    for output in unplaced {
+        let position = if placed.is_empty() {
+            (0, 0).into()
+        } else {
+            let bounding_box = monitors_bounding_box(&placed);
+            (bounding_box.loc.x + bounding_box.size.w, bounding_box.loc.y).into()
+        };
+        output.change_current_state(None, None, None, Some(position));
+        placed.push(output);
+    }
+
+    let origin = monitors_bounding_box(&placed).loc;
+    if origin != (0, 0).into() {
+        for output in &placed {
+            output.change_current_state(None, None, None, Some(output.current_location() - origin));
+        }
+    }
+}
+
+/// `wl_output` only advertises integer scales, fractional ones are advertised rounded up
+/// so clients without `wp_fractional_scale_v1` render sharp and get downscaled.
+/// The exact scale goes through `wp_fractional_scale_v1`.
+pub fn scale_from_f64(scale: f64) -> Scale {
+    if scale.fract() == 0.0 {
+        Scale::Integer(scale as i32)
+    } else {
+        Scale::Custom {
+            advertised_integer: scale.ceil() as i32,
+            fractional: scale,
+        }
+    }
+}
+
 pub fn get_surface_id(surface: &WlSurface) -> u64 {
     with_states(surface, |surface_data| {
         surface_data
@@ -920,6 +1486,15 @@ fn get_direct_subsurfaces(surface: &WlSurface) -> (Vec<u64>, Vec<u64>) {
     (subsurfaces_below, subsurfaces_above)
 }
 
+/// Whether the surface is a cursor image or one of its subsurfaces.
+fn is_in_cursor_surface_tree(surface: &WlSurface) -> bool {
+    let mut root = surface.clone();
+    while let Some(parent) = get_parent(&root) {
+        root = parent;
+    }
+    get_role(&root) == Some(CURSOR_IMAGE_ROLE)
+}
+
 impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
     fn compositor_state(&mut self) -> &mut CompositorState {
         &mut self.compositor_state
@@ -947,13 +1522,11 @@ impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
         });
         self.surfaces.insert(surface_id, surface.clone());
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "new_surface",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 
@@ -961,18 +1534,27 @@ impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
         let surface_id = get_surface_id(surface);
         let parent = get_surface_id(parent);
         self.subsurfaces.insert(surface_id, surface.clone());
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        // The backend only puts single surfaces on a plane, Flutter draws the whole tree again.
+        if self.has_direct_scanout(parent) {
+            let _ = self.set_direct_scanout(parent, None);
+        }
+        self.invoke_platform_method(
             "new_subsurface",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
                 "parent": parent,
-            }))),
-            None,
+            }),
         );
     }
 
     fn commit(&mut self, surface: &WlSurface) {
+        // The backend draws cursor surfaces and their subsurfaces itself,
+        // Flutter never sees them.
+        if is_in_cursor_surface_tree(surface) {
+            on_commit_buffer_handler::<Self>(surface);
+            return;
+        }
+
         let (subsurfaces_below, subsurfaces_above) = get_direct_subsurfaces(surface);
 
         // Make sure Flutter knows about subsurfaces
@@ -985,92 +1567,33 @@ impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
         }
 
         with_states(surface, |surface_data| {
-            let (surface_id, old_texture_size) = {
-                let my_state = surface_data
-                    .data_map
-                    .get::<RefCell<MySurfaceState>>()
-                    .unwrap()
-                    .borrow();
-                (my_state.surface_id, my_state.old_texture_size)
-            };
+            let surface_id = surface_data
+                .data_map
+                .get::<RefCell<MySurfaceState>>()
+                .unwrap()
+                .borrow()
+                .surface_id;
 
             let attributes = surface_data.cached_state.current::<SurfaceAttributes>();
 
+            // The backend shows the buffer itself, Flutter doesn't need it.
+            let has_direct_scanout = self.has_direct_scanout(surface_id);
+
             let texture = attributes
                 .buffer
                 .as_ref()
+                .filter(|_| !has_direct_scanout)
                 .and_then(|assignment| match assignment {
                     BufferAssignment::NewBuffer(buffer) => self
                         .gles_renderer
-                        .as_mut()
-                        .unwrap()
+                        .as_mut()?
                         .import_buffer(buffer, Some(surface_data), &[])
                         .and_then(|t| t.ok()),
                     _ => None,
                 });
 
             let (texture_id, size) = if let Some(texture) = texture {
-                unsafe {
-                    self.gl.as_ref().unwrap().Finish();
-                }
-
-                let size = texture.size();
-
-                let size_changed = match old_texture_size {
-                    Some(old_size) => old_size != size,
-                    None => true,
-                };
-
-                surface_data
-                    .data_map
-                    .get::<RefCell<MySurfaceState>>()
-                    .unwrap()
-                    .borrow_mut()
-                    .old_texture_size = Some(size);
-
-                let texture_id = match size_changed {
-                    true => None,
-                    false => self
-                        .texture_ids_per_surface_id
-                        .get(&surface_id)
-                        .and_then(|v| v.last().cloned())
-                        .map(|(id, _)| id),
-                };
-
-                let texture_id = texture_id.unwrap_or_else(|| {
-                    let texture_id = self.get_new_texture_id();
-                    while self
-                        .texture_ids_per_surface_id
-                        .entry(surface_id)
-                        .or_default()
-                        .len()
-                        >= 2
-                    {
-                        self.texture_ids_per_surface_id
-                            .entry(surface_id)
-                            .or_default()
-                            .remove(0);
-                    }
-
-                    self.texture_ids_per_surface_id
-                        .entry(surface_id)
-                        .or_default()
-                        .push((texture_id, size));
-                    self.surface_id_per_texture_id
-                        .insert(texture_id, surface_id);
-                    self.flutter_engine_mut()
-                        .register_external_texture(texture_id)
-                        .unwrap();
-                    texture_id
-                });
-
-                let swapchain = self.texture_swapchains.entry(texture_id).or_default();
-                swapchain.commit(texture.clone());
-
-                self.flutter_engine_mut()
-                    .mark_external_texture_frame_available(texture_id)
-                    .unwrap();
-
+                let (texture_id, size) = self.commit_texture(surface_data, surface_id, texture);
                 (texture_id, Some(size))
             } else {
                 (-1, None)
@@ -1086,14 +1609,13 @@ impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
             )
         });
 
+        self.commit_direct_scanout(surface, get_surface_id(surface));
+
         let surface_message = self.construct_surface_message(surface);
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
-            "commit_surface",
-            Some(Box::new(json!(surface_message))),
-            None,
-        );
+        self.invoke_platform_method("commit_surface", json!(surface_message));
+        self.send_initial_layer_surface_configure(surface);
+        self.open_foreign_toplevel_on_map(surface);
     }
 
     fn destroyed(&mut self, _surface: &WlSurface) {
@@ -1106,18 +1628,37 @@ impl<BackendData: Backend> CompositorHandler for ServerState<BackendData> {
                 .surface_id
         });
         self.surfaces.remove(&surface_id);
+        self.subsurfaces.remove(&surface_id);
+        self.kde_decorations.remove(&surface_id);
+        self.direct_scanouts.remove(&surface_id);
+        for (texture_id, _) in self
+            .texture_ids_per_surface_id
+            .remove(&surface_id)
+            .unwrap_or_default()
+        {
+            self.surface_id_per_texture_id.remove(&texture_id);
+        }
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "destroy_surface",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": surface_id,
-            }))),
-            None,
+            }),
         );
     }
 }
 
+impl<BackendData: Backend> FractionalScaleHandler for ServerState<BackendData> {
+    fn new_fractional_scale(&mut self, surface: WlSurface) {
+        let scale = self.preferred_scale(&surface);
+        with_states(&surface, |surface_data| {
+            with_fractional_scale(surface_data, |fractional_scale| {
+                fractional_scale.set_preferred_scale(scale);
+            });
+        });
+    }
+}
+
 impl<BackendData: Backend> ShmHandler for ServerState<BackendData> {
     fn shm_state(&self) -> &ShmState {
         &self.shm_state
@@ -1182,9 +1723,13 @@ impl<BackendData: Backend> SeatHandler for ServerState<BackendData> {
         set_primary_focus(dh, seat, client);
     }
 
-    fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {}
+    fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
+        self.client_cursor_image_status = image;
+    }
 }
 
+impl<BackendData: Backend> TabletSeatHandler for ServerState<BackendData> {}
+
 impl<BackendData: Backend> SelectionHandler for ServerState<BackendData> {
     type SelectionUserData = ();
 
@@ -1227,7 +1772,11 @@ impl<BackendData: Backend> DataDeviceHandler for ServerState<BackendData> {
     }
 }
 
-impl<BackendData: Backend> OutputHandler for ServerState<BackendData> {}
+impl<BackendData: Backend + 'static> OutputHandler for ServerState<BackendData> {
+    fn output_bound(&mut self, output: Output, wl_output: WlOutput) {
+        self.send_foreign_toplevel_output_enter(&output, &wl_output);
+    }
+}
 
 impl<BackendData: Backend> PrimarySelectionHandler for ServerState<BackendData> {
     fn primary_selection_state(&self) -> &PrimarySelectionState {
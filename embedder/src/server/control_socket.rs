@@ -0,0 +1,181 @@
+//! Local control interface for scripts and tools, a Unix socket next to the Wayland socket.
+//!
+//! Its path is in the `VESHELL_SOCKET` environment variable of the clients.
+//! A connection sends one request on a single line, like
+//! `{"method": "capture_screenshot", "arguments": {"path": "/tmp/screen.png", "target": {"type": "all"}}}`,
+//! and receives `{"result": null}` or `{"error": {"code": "...", "message": "..."}}` before it is closed.
+//!
+//! Connections are read without blocking from the event loop,
+//! a slow or silent client never stalls rendering and input.
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use serde_json::json;
+use smithay::reexports::calloop::generic::Generic;
+use smithay::reexports::calloop::{Interest, LoopHandle, Mode, PostAction};
+use tracing::{info, warn};
+
+use crate::Backend;
+
+use super::screenshot::CaptureScreenshotPayload;
+use super::ServerState;
+
+/// Requests are tiny, a connection sending more than this without a newline is dropped.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, serde::Deserialize)]
+struct ControlRequest {
+    method: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+struct ControlError {
+    code: String,
+    message: String,
+}
+
+impl ControlError {
+    fn new(code: &str, message: impl ToString) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Removes the socket file when the compositor exits.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Listens on `$XDG_RUNTIME_DIR/veshell-<wayland socket name>.sock`.
+pub fn init_control_socket<BackendData: Backend + 'static>(
+    loop_handle: &LoopHandle<'static, ServerState<BackendData>>,
+    wayland_socket_name: &str,
+) -> Option<ControlSocket> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    let path = PathBuf::from(runtime_dir).join(format!("veshell-{wayland_socket_name}.sock"));
+
+    // Left behind by a compositor that used the same Wayland socket name, it can't be running anymore.
+    let _ = std::fs::remove_file(&path);
+
+    let listener = UnixListener::bind(&path)
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|err| warn!(path = %path.display(), "Failed to create the control socket: {err}"))
+        .ok()?;
+
+    loop_handle
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            |_, listener, data| {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => data.add_control_connection(stream),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) => {
+                            warn!("Failed to accept a control connection: {err}");
+                            break;
+                        }
+                    }
+                }
+                Ok(PostAction::Continue)
+            },
+        )
+        .expect("Failed to init the control socket source");
+
+    info!(path = %path.display(), "Listening on control socket");
+    Some(ControlSocket { path })
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    fn add_control_connection(&mut self, stream: UnixStream) {
+        if let Err(err) = stream.set_nonblocking(true) {
+            warn!("Failed to set up a control connection: {err}");
+            return;
+        }
+
+        let mut request = Vec::new();
+        let result = self.loop_handle.insert_source(
+            Generic::new(stream, Interest::READ, Mode::Level),
+            move |_, stream, data| {
+                let mut stream: &UnixStream = stream;
+                let mut buffer = [0u8; 4096];
+                let closed = loop {
+                    match stream.read(&mut buffer) {
+                        Ok(0) => break true,
+                        Ok(len) => request.extend_from_slice(&buffer[..len]),
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break false,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(_) => return Ok(PostAction::Remove),
+                    }
+                };
+
+                let line_end = request.iter().position(|&byte| byte == b'\n');
+                if line_end.is_none() && !closed && request.len() <= MAX_REQUEST_SIZE {
+                    // Wait for the rest of the line.
+                    return Ok(PostAction::Continue);
+                }
+                let line = &request[..line_end.unwrap_or(request.len())];
+
+                let response = if request.len() > MAX_REQUEST_SIZE && line_end.is_none() {
+                    Err(ControlError::new(
+                        "invalid_request",
+                        "The request is too long",
+                    ))
+                } else {
+                    match serde_json::from_slice::<ControlRequest>(line) {
+                        Ok(request) => data.handle_control_request(request),
+                        Err(err) => Err(ControlError::new("invalid_request", err)),
+                    }
+                };
+
+                let response = match response {
+                    Ok(()) => json!({ "result": null }),
+                    Err(err) => json!({
+                        "error": {
+                            "code": err.code,
+                            "message": err.message,
+                        },
+                    }),
+                };
+                // The response fits in the empty socket buffer, a client that left doesn't matter.
+                let _ = writeln!(stream, "{response}");
+                Ok(PostAction::Remove)
+            },
+        );
+        if let Err(err) = result {
+            warn!("Failed to listen on a control connection: {}", err.error);
+        }
+    }
+
+    fn handle_control_request(&mut self, request: ControlRequest) -> Result<(), ControlError> {
+        match request.method.as_str() {
+            "capture_screenshot" => {
+                let payload: CaptureScreenshotPayload =
+                    serde_json::from_value(request.arguments)
+                        .map_err(|err| ControlError::new("invalid_arguments", err))?;
+                self.save_screenshot(&payload.path, &payload.target)
+                    .map_err(|err| ControlError::new(err.code(), err))
+            }
+            method => Err(ControlError::new(
+                "unknown_method",
+                format!("Unknown method {method}"),
+            )),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::Mutex;
+
+use smithay::reexports::wayland_protocols::xdg::decoration::zv1::server::zxdg_toplevel_decoration_v1;
+use smithay::reexports::wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration::{
+    self, OrgKdeKwinServerDecoration,
+};
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::reexports::wayland_server::WEnum;
+use smithay::wayland::compositor::with_states;
+use smithay::wayland::shell::kde::decoration::{KdeDecorationHandler, KdeDecorationState};
+use smithay::wayland::shell::xdg::decoration::XdgDecorationHandler;
+use smithay::wayland::shell::xdg::{ToplevelSurface, XdgToplevelSurfaceData};
+use smithay::{delegate_kde_decoration, delegate_xdg_decoration};
+
+use crate::flutter_engine::wayland_messages::DecorationMode;
+use crate::Backend;
+
+use super::{get_surface_id, ServerState};
+
+/// The decoration mode a client asked for through either of the decoration protocols,
+/// and the one the shell chose, which wins over the client's requests once set.
+#[derive(Default)]
+struct DecorationModes {
+    requested: Option<DecorationMode>,
+    chosen: Option<DecorationMode>,
+}
+
+fn with_decoration_modes<T>(surface: &WlSurface, f: impl FnOnce(&mut DecorationModes) -> T) -> T {
+    with_states(surface, |surface_data| {
+        surface_data
+            .data_map
+            .insert_if_missing_threadsafe(Mutex::<DecorationModes>::default);
+        f(&mut surface_data
+            .data_map
+            .get::<Mutex<DecorationModes>>()
+            .unwrap()
+            .lock()
+            .unwrap())
+    })
+}
+
+pub fn get_requested_decoration_mode(surface: &WlSurface) -> Option<DecorationMode> {
+    with_decoration_modes(surface, |modes| modes.requested)
+}
+
+/// Returns the mode chosen by the shell, if any.
+fn set_requested_decoration_mode(
+    surface: &WlSurface,
+    mode: Option<DecorationMode>,
+) -> Option<DecorationMode> {
+    with_decoration_modes(surface, |modes| {
+        modes.requested = mode;
+        modes.chosen
+    })
+}
+
+fn xdg_decoration_mode(mode: DecorationMode) -> zxdg_toplevel_decoration_v1::Mode {
+    match mode {
+        DecorationMode::ClientSide => zxdg_toplevel_decoration_v1::Mode::ClientSide,
+        DecorationMode::ServerSide => zxdg_toplevel_decoration_v1::Mode::ServerSide,
+    }
+}
+
+fn kde_decoration_mode(mode: DecorationMode) -> org_kde_kwin_server_decoration::Mode {
+    match mode {
+        DecorationMode::ClientSide => org_kde_kwin_server_decoration::Mode::Client,
+        DecorationMode::ServerSide => org_kde_kwin_server_decoration::Mode::Server,
+    }
+}
+
+impl<BackendData: Backend> ServerState<BackendData> {
+    /// Tells the client who draws the window decorations.
+    /// Later requests of the client only show up in its `ToplevelMessage`.
+    /// Clients only using one of the two decoration protocols ignore the other.
+    pub fn set_decoration_mode(&self, surface_id: u64, mode: DecorationMode) {
+        if let Some(surface) = self.surfaces.get(&surface_id) {
+            with_decoration_modes(surface, |modes| modes.chosen = Some(mode));
+        }
+
+        if let Some(toplevel) = self.xdg_toplevels.get(&surface_id) {
+            toplevel.with_pending_state(|state| {
+                state.decoration_mode = Some(xdg_decoration_mode(mode));
+            });
+            send_configure_if_mapped(toplevel);
+        }
+
+        if let Some(decoration) = self.kde_decorations.get(&surface_id) {
+            decoration.mode(kde_decoration_mode(mode));
+        }
+    }
+}
+
+/// Configures sent before the initial commit are a protocol error,
+/// the pending state will be part of the initial configure anyway.
+fn send_configure_if_mapped(toplevel: &ToplevelSurface) {
+    let initial_configure_sent = with_states(toplevel.wl_surface(), |surface_data| {
+        surface_data
+            .data_map
+            .get::<XdgToplevelSurfaceData>()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .initial_configure_sent
+    });
+    if initial_configure_sent {
+        toplevel.send_pending_configure();
+    }
+}
+
+impl<BackendData: Backend> XdgDecorationHandler for ServerState<BackendData> {
+    fn new_decoration(&mut self, _toplevel: ToplevelSurface) {}
+
+    fn request_mode(&mut self, toplevel: ToplevelSurface, mode: zxdg_toplevel_decoration_v1::Mode) {
+        let requested_mode = match mode {
+            zxdg_toplevel_decoration_v1::Mode::ServerSide => DecorationMode::ServerSide,
+            _ => DecorationMode::ClientSide,
+        };
+        let chosen_mode =
+            set_requested_decoration_mode(toplevel.wl_surface(), Some(requested_mode));
+
+        // Go with what the client wants until the shell says otherwise.
+        toplevel.with_pending_state(|state| {
+            state.decoration_mode = Some(chosen_mode.map_or(mode, xdg_decoration_mode));
+        });
+        send_configure_if_mapped(&toplevel);
+    }
+
+    fn unset_mode(&mut self, toplevel: ToplevelSurface) {
+        let chosen_mode = set_requested_decoration_mode(toplevel.wl_surface(), None);
+
+        toplevel.with_pending_state(|state| {
+            state.decoration_mode = chosen_mode.map(xdg_decoration_mode);
+        });
+        send_configure_if_mapped(&toplevel);
+    }
+}
+
+impl<BackendData: Backend> KdeDecorationHandler for ServerState<BackendData> {
+    fn kde_decoration_state(&self) -> &KdeDecorationState {
+        &self.kde_decoration_state
+    }
+
+    fn new_decoration(&mut self, surface: &WlSurface, decoration: &OrgKdeKwinServerDecoration) {
+        self.kde_decorations
+            .insert(get_surface_id(surface), decoration.clone());
+    }
+
+    fn request_mode(
+        &mut self,
+        surface: &WlSurface,
+        decoration: &OrgKdeKwinServerDecoration,
+        mode: WEnum<org_kde_kwin_server_decoration::Mode>,
+    ) {
+        let WEnum::Value(mode) = mode else {
+            return;
+        };
+
+        let requested_mode = match mode {
+            org_kde_kwin_server_decoration::Mode::Server => DecorationMode::ServerSide,
+            _ => DecorationMode::ClientSide,
+        };
+        let chosen_mode = set_requested_decoration_mode(surface, Some(requested_mode));
+
+        // Go with what the client wants until the shell says otherwise.
+        decoration.mode(chosen_mode.map_or(mode, kde_decoration_mode));
+    }
+
+    fn release(&mut self, _decoration: &OrgKdeKwinServerDecoration, surface: &WlSurface) {
+        self.kde_decorations.remove(&get_surface_id(surface));
+        set_requested_decoration_mode(surface, None);
+    }
+}
+
+delegate_xdg_decoration!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+delegate_kde_decoration!(@<BackendData: Backend + 'static> ServerState<BackendData>);
@@ -0,0 +1,159 @@
+//! Fullscreen surfaces the shell lets the backend show directly on a hardware plane.
+//!
+//! The buffers of these surfaces don't go through Flutter at all, the backend draws the surface
+//! above the Flutter frame of the monitor. The shell has to turn direct scanout off
+//! to show anything over the surface.
+//! When the buffer can't go on a plane, the backend falls back to compositing it with the GPU.
+//! Surfaces with subsurfaces stay with Flutter, a plane only takes a single buffer.
+
+use serde_json::json;
+use smithay::backend::renderer::utils::{on_commit_buffer_handler, RendererSurfaceStateUserData};
+use smithay::backend::renderer::ImportAll;
+use smithay::output::Output;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::wayland::compositor::with_states;
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::Backend;
+
+use super::{get_direct_subsurfaces, monitors_bounding_box, ServerState};
+
+pub struct DirectScanout {
+    pub monitor_name: String,
+    /// The buffer of the surface is on a plane right now instead of being composited.
+    pub active: bool,
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// Lets the backend show the surface on a plane of the monitor,
+    /// or gives the surface back to Flutter when `monitor_name` is `None`.
+    pub fn set_direct_scanout(
+        &mut self,
+        surface_id: u64,
+        monitor_name: Option<String>,
+    ) -> Result<(), PlatformChannelError> {
+        let Some(surface) = self.surfaces.get(&surface_id).cloned() else {
+            return Err(PlatformChannelError::SurfaceDoesntExist(surface_id));
+        };
+
+        let Some(monitor_name) = monitor_name else {
+            self.set_direct_scanout_active(surface_id, false);
+            if self.direct_scanouts.remove(&surface_id).is_some() {
+                self.give_buffer_back_to_flutter(&surface, surface_id);
+            }
+            return Ok(());
+        };
+
+        if !self.backend_data.supports_direct_scanout() {
+            return Err(PlatformChannelError::Unsupported(
+                "The backend can't scan out client buffers",
+            ));
+        }
+
+        if !self
+            .backend_data
+            .get_monitor_layout()
+            .iter()
+            .any(|output| output.name() == monitor_name)
+        {
+            return Err(PlatformChannelError::MonitorDoesntExist(monitor_name));
+        }
+
+        let (subsurfaces_below, subsurfaces_above) = get_direct_subsurfaces(&surface);
+        if !subsurfaces_below.is_empty() || !subsurfaces_above.is_empty() {
+            return Err(PlatformChannelError::HasSubsurfaces(surface_id));
+        }
+
+        self.set_direct_scanout_active(surface_id, false);
+        self.direct_scanouts.insert(
+            surface_id,
+            DirectScanout {
+                monitor_name,
+                active: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Called by the backend after each frame, tells the shell when the surface
+    /// starts or stops being scanned out.
+    pub fn set_direct_scanout_active(&mut self, surface_id: u64, active: bool) {
+        let Some(direct_scanout) = self.direct_scanouts.get_mut(&surface_id) else {
+            return;
+        };
+        if direct_scanout.active == active {
+            return;
+        }
+        direct_scanout.active = active;
+
+        let monitor_name = direct_scanout.monitor_name.clone();
+        self.invoke_platform_method(
+            "direct_scanout_changed",
+            json!({
+                "surfaceId": surface_id,
+                "monitorName": monitor_name,
+                "active": active,
+            }),
+        );
+    }
+
+    /// Whether the buffers of the surface go to the backend instead of Flutter.
+    pub fn has_direct_scanout(&self, surface_id: u64) -> bool {
+        self.direct_scanouts.contains_key(&surface_id)
+    }
+
+    /// The surface the shell wants on a plane of the monitor, drawn above its Flutter frame.
+    pub fn direct_scanout_surface(&self, output: &Output) -> Option<(u64, WlSurface)> {
+        self.direct_scanouts
+            .iter()
+            .find(|(_, direct_scanout)| direct_scanout.monitor_name == output.name())
+            .and_then(|(surface_id, _)| Some((*surface_id, self.surfaces.get(surface_id)?.clone())))
+    }
+
+    /// Flutter only saw the buffers committed before the direct scanout,
+    /// the one the backend was showing is imported again.
+    fn give_buffer_back_to_flutter(&mut self, surface: &WlSurface, surface_id: u64) {
+        let imported = with_states(surface, |surface_data| {
+            let buffer = surface_data
+                .data_map
+                .get::<RendererSurfaceStateUserData>()?
+                .borrow()
+                .buffer()
+                .cloned()?;
+            // No cached texture, it was last imported by the backend.
+            let texture = self
+                .gles_renderer
+                .as_mut()?
+                .import_buffer(&buffer, None, &[])?
+                .ok()?;
+            self.commit_texture(surface_data, surface_id, texture);
+            Some(())
+        });
+        if imported.is_some() {
+            let surface_message = self.construct_surface_message(surface);
+            self.invoke_platform_method("commit_surface", json!(surface_message));
+        }
+    }
+
+    /// Hands the committed buffers to the renderer of the backend.
+    pub(super) fn commit_direct_scanout(&mut self, surface: &WlSurface, surface_id: u64) {
+        let Some(direct_scanout) = self.direct_scanouts.get(&surface_id) else {
+            return;
+        };
+        on_commit_buffer_handler::<Self>(surface);
+
+        // Flutter doesn't see these buffers, the captures of the monitor are damaged here instead.
+        let Some(output) = self
+            .backend_data
+            .get_monitor_layout()
+            .into_iter()
+            .find(|output| output.name() == direct_scanout.monitor_name)
+        else {
+            return;
+        };
+        let damage = [monitors_bounding_box(&[output])];
+        self.wlr_screencopy_state.add_damage(&damage);
+        self.image_copy_capture_state.add_damage(&damage);
+        self.process_screen_captures();
+    }
+}
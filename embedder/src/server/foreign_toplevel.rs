@@ -0,0 +1,628 @@
+//! Lets taskbars, docks and scripts list the windows and control them,
+//! with wlr-foreign-toplevel-management and ext-foreign-toplevel-list.
+//!
+//! Xdg toplevels and X11 windows are listed once mapped, by the surface id the shell knows them by.
+//! The shell owns the window state, so it reports the state of each window and
+//! receives the requests of the clients as `foreign_toplevel_request` messages.
+
+use std::collections::HashMap;
+
+use serde_json::json;
+use smithay::output::Output;
+use smithay::reexports::wayland_protocols_wlr::foreign_toplevel::v1::server::{
+    zwlr_foreign_toplevel_handle_v1, zwlr_foreign_toplevel_manager_v1,
+};
+use smithay::reexports::wayland_server::backend::ClientId;
+use smithay::reexports::wayland_server::protocol::wl_output::WlOutput;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::reexports::wayland_server::{
+    Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+};
+use wayland_protocols::ext::foreign_toplevel_list::v1::server::{
+    ext_foreign_toplevel_handle_v1, ext_foreign_toplevel_list_v1,
+};
+
+use smithay::wayland::compositor::{with_states, BufferAssignment, SurfaceAttributes};
+use smithay::wayland::shell::xdg::XdgToplevelSurfaceData;
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::Backend;
+
+use super::{get_surface_id, ServerState};
+
+use ext_foreign_toplevel_handle_v1::ExtForeignToplevelHandleV1;
+use ext_foreign_toplevel_list_v1::ExtForeignToplevelListV1;
+use zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1;
+use zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1;
+
+const WLR_VERSION: u32 = 3;
+
+pub struct ForeignToplevelState {
+    wlr_managers: Vec<ZwlrForeignToplevelManagerV1>,
+    ext_lists: Vec<ExtForeignToplevelListV1>,
+    /// By surface id.
+    toplevels: HashMap<u64, ForeignToplevel>,
+}
+
+/// Maximized and fullscreen follow `set_window_state`, the rest is reported by the shell.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ForeignToplevelWindowState {
+    pub activated: bool,
+    pub maximized: bool,
+    pub minimized: bool,
+    pub fullscreen: bool,
+}
+
+#[derive(Default)]
+struct ForeignToplevel {
+    title: Option<String>,
+    app_id: Option<String>,
+    window_state: ForeignToplevelWindowState,
+    /// Names of the monitors showing the window, all of them until the shell tells otherwise.
+    monitors: Option<Vec<String>>,
+    wlr_handles: Vec<ZwlrForeignToplevelHandleV1>,
+    ext_handles: Vec<ExtForeignToplevelHandleV1>,
+}
+
+impl ForeignToplevel {
+    fn shows_on(&self, output: &Output) -> bool {
+        self.monitors
+            .as_ref()
+            .map_or(true, |monitors| monitors.contains(&output.name()))
+    }
+
+    /// The `state` array of wlr handles, fullscreen only exists since version 2.
+    fn wlr_states(&self, version: u32) -> Vec<u8> {
+        let window_state = &self.window_state;
+        [
+            (
+                window_state.maximized,
+                zwlr_foreign_toplevel_handle_v1::State::Maximized,
+            ),
+            (
+                window_state.minimized,
+                zwlr_foreign_toplevel_handle_v1::State::Minimized,
+            ),
+            (
+                window_state.activated,
+                zwlr_foreign_toplevel_handle_v1::State::Activated,
+            ),
+            (
+                window_state.fullscreen && version >= 2,
+                zwlr_foreign_toplevel_handle_v1::State::Fullscreen,
+            ),
+        ]
+        .into_iter()
+        .filter(|(is_set, _)| *is_set)
+        .flat_map(|(_, state)| (state as u32).to_ne_bytes())
+        .collect()
+    }
+
+    fn send_wlr_info(&self, handle: &ZwlrForeignToplevelHandleV1, monitors: &[Output]) {
+        if let Some(title) = &self.title {
+            handle.title(title.clone());
+        }
+        if let Some(app_id) = &self.app_id {
+            handle.app_id(app_id.clone());
+        }
+        if let Some(client) = handle.client() {
+            for output in monitors.iter().filter(|output| self.shows_on(output)) {
+                for wl_output in output.client_outputs(&client) {
+                    handle.output_enter(&wl_output);
+                }
+            }
+        }
+        handle.state(self.wlr_states(handle.version()));
+        handle.done();
+    }
+
+    fn send_ext_info(&self, handle: &ExtForeignToplevelHandleV1, surface_id: u64) {
+        // Surface ids are never reused, scripts can also give it to the shell.
+        handle.identifier(surface_id.to_string());
+        if let Some(title) = &self.title {
+            handle.title(title.clone());
+        }
+        if let Some(app_id) = &self.app_id {
+            handle.app_id(app_id.clone());
+        }
+        handle.done();
+    }
+}
+
+impl ForeignToplevelState {
+    pub fn new<BackendData: Backend + 'static>(
+        display_handle: &DisplayHandle,
+    ) -> ForeignToplevelState {
+        display_handle.create_global::<ServerState<BackendData>, ZwlrForeignToplevelManagerV1, _>(
+            WLR_VERSION,
+            (),
+        );
+        display_handle
+            .create_global::<ServerState<BackendData>, ExtForeignToplevelListV1, _>(1, ());
+        ForeignToplevelState {
+            wlr_managers: vec![],
+            ext_lists: vec![],
+            toplevels: HashMap::new(),
+        }
+    }
+}
+
+fn new_wlr_handle<BackendData: Backend + 'static>(
+    display_handle: &DisplayHandle,
+    manager: &ZwlrForeignToplevelManagerV1,
+    surface_id: u64,
+    toplevel: &ForeignToplevel,
+    monitors: &[Output],
+) -> Option<ZwlrForeignToplevelHandleV1> {
+    let handle = manager
+        .client()?
+        .create_resource::<ZwlrForeignToplevelHandleV1, _, ServerState<BackendData>>(
+            display_handle,
+            manager.version(),
+            surface_id,
+        )
+        .ok()?;
+    manager.toplevel(&handle);
+    toplevel.send_wlr_info(&handle, monitors);
+    Some(handle)
+}
+
+fn new_ext_handle<BackendData: Backend + 'static>(
+    display_handle: &DisplayHandle,
+    list: &ExtForeignToplevelListV1,
+    surface_id: u64,
+    toplevel: &ForeignToplevel,
+) -> Option<ExtForeignToplevelHandleV1> {
+    let handle = list
+        .client()?
+        .create_resource::<ExtForeignToplevelHandleV1, _, ServerState<BackendData>>(
+            display_handle,
+            list.version(),
+            surface_id,
+        )
+        .ok()?;
+    list.toplevel(&handle);
+    toplevel.send_ext_info(&handle, surface_id);
+    Some(handle)
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// Lists a new window. Its title and app id are often only known later.
+    pub fn open_foreign_toplevel(
+        &mut self,
+        surface_id: u64,
+        title: Option<String>,
+        app_id: Option<String>,
+    ) {
+        if self
+            .foreign_toplevel_state
+            .toplevels
+            .contains_key(&surface_id)
+        {
+            return;
+        }
+
+        let monitors = self.backend_data.get_monitor_layout();
+        let state = &mut self.foreign_toplevel_state;
+        let mut toplevel = ForeignToplevel {
+            title,
+            app_id,
+            ..Default::default()
+        };
+        toplevel.wlr_handles = state
+            .wlr_managers
+            .iter()
+            .filter_map(|manager| {
+                new_wlr_handle::<BackendData>(
+                    &self.display_handle,
+                    manager,
+                    surface_id,
+                    &toplevel,
+                    &monitors,
+                )
+            })
+            .collect();
+        toplevel.ext_handles = state
+            .ext_lists
+            .iter()
+            .filter_map(|list| {
+                new_ext_handle::<BackendData>(&self.display_handle, list, surface_id, &toplevel)
+            })
+            .collect();
+        state.toplevels.insert(surface_id, toplevel);
+    }
+
+    /// Lists an xdg toplevel when it commits its first buffer.
+    pub fn open_foreign_toplevel_on_map(&mut self, surface: &WlSurface) {
+        let surface_id = get_surface_id(surface);
+        if !self.xdg_toplevels.contains_key(&surface_id) {
+            return;
+        }
+        let (is_mapped, title, app_id) = with_states(surface, |surface_data| {
+            let is_mapped = matches!(
+                surface_data
+                    .cached_state
+                    .current::<SurfaceAttributes>()
+                    .buffer,
+                Some(BufferAssignment::NewBuffer(_))
+            );
+            let data = surface_data
+                .data_map
+                .get::<XdgToplevelSurfaceData>()
+                .unwrap()
+                .lock()
+                .unwrap();
+            (is_mapped, data.title.clone(), data.app_id.clone())
+        });
+        if is_mapped {
+            self.open_foreign_toplevel(surface_id, title, app_id);
+        }
+    }
+
+    pub fn close_foreign_toplevel(&mut self, surface_id: u64) {
+        let Some(toplevel) = self.foreign_toplevel_state.toplevels.remove(&surface_id) else {
+            return;
+        };
+        for handle in toplevel.wlr_handles {
+            handle.closed();
+        }
+        for handle in toplevel.ext_handles {
+            handle.closed();
+        }
+    }
+
+    pub fn set_foreign_toplevel_title(&mut self, surface_id: u64, title: Option<String>) {
+        let Some(toplevel) = self.foreign_toplevel_state.toplevels.get_mut(&surface_id) else {
+            return;
+        };
+        if toplevel.title == title {
+            return;
+        }
+        toplevel.title = title;
+
+        let Some(title) = &toplevel.title else {
+            return;
+        };
+        for handle in &toplevel.wlr_handles {
+            handle.title(title.clone());
+            handle.done();
+        }
+        for handle in &toplevel.ext_handles {
+            handle.title(title.clone());
+            handle.done();
+        }
+    }
+
+    pub fn set_foreign_toplevel_app_id(&mut self, surface_id: u64, app_id: Option<String>) {
+        let Some(toplevel) = self.foreign_toplevel_state.toplevels.get_mut(&surface_id) else {
+            return;
+        };
+        if toplevel.app_id == app_id {
+            return;
+        }
+        toplevel.app_id = app_id;
+
+        let Some(app_id) = &toplevel.app_id else {
+            return;
+        };
+        for handle in &toplevel.wlr_handles {
+            handle.app_id(app_id.clone());
+            handle.done();
+        }
+        for handle in &toplevel.ext_handles {
+            handle.app_id(app_id.clone());
+            handle.done();
+        }
+    }
+
+    /// Called by `set_window_state`, taskbars see the same maximized and fullscreen states as the window.
+    pub fn change_foreign_toplevel_window_state(
+        &mut self,
+        surface_id: u64,
+        change: impl FnOnce(&mut ForeignToplevelWindowState),
+    ) {
+        let Some(toplevel) = self.foreign_toplevel_state.toplevels.get_mut(&surface_id) else {
+            return;
+        };
+        let old_window_state = toplevel.window_state;
+        change(&mut toplevel.window_state);
+        if toplevel.window_state == old_window_state {
+            return;
+        }
+
+        for handle in &toplevel.wlr_handles {
+            handle.state(toplevel.wlr_states(handle.version()));
+            handle.done();
+        }
+    }
+
+    /// The monitors the shell shows the window on, `None` for surfaces that aren't windows.
+    pub fn window_monitors(&self, surface_id: u64) -> Option<Vec<Output>> {
+        let toplevel = self.foreign_toplevel_state.toplevels.get(&surface_id)?;
+        let monitors = self.backend_data.get_monitor_layout();
+        Some(
+            monitors
+                .into_iter()
+                .filter(|output| toplevel.shows_on(output))
+                .collect(),
+        )
+    }
+
+    /// Called by the shell whenever it activates or minimizes a window or moves it to other monitors.
+    pub fn set_foreign_toplevel_state(
+        &mut self,
+        surface_id: u64,
+        activated: bool,
+        minimized: bool,
+        monitor_names: Option<Vec<String>>,
+    ) -> Result<(), PlatformChannelError> {
+        let monitors = self.backend_data.get_monitor_layout();
+        let toplevel = self
+            .foreign_toplevel_state
+            .toplevels
+            .get_mut(&surface_id)
+            .ok_or(PlatformChannelError::SurfaceDoesntExist(surface_id))?;
+
+        let old_monitors = monitors
+            .iter()
+            .filter(|output| toplevel.shows_on(output))
+            .cloned()
+            .collect::<Vec<_>>();
+        toplevel.window_state.activated = activated;
+        toplevel.window_state.minimized = minimized;
+        toplevel.monitors = monitor_names;
+
+        for handle in &toplevel.wlr_handles {
+            if let Some(client) = handle.client() {
+                for output in &monitors {
+                    let was_shown = old_monitors.contains(output);
+                    let is_shown = toplevel.shows_on(output);
+                    for wl_output in output.client_outputs(&client) {
+                        match (was_shown, is_shown) {
+                            (false, true) => handle.output_enter(&wl_output),
+                            (true, false) => handle.output_leave(&wl_output),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            handle.state(toplevel.wlr_states(handle.version()));
+            handle.done();
+        }
+        // Clients render the window for the monitors it moved to.
+        self.update_preferred_scales();
+        Ok(())
+    }
+
+    /// Tells the taskbars binding a monitor after a window was listed that it shows on it.
+    pub fn send_foreign_toplevel_output_enter(&self, output: &Output, wl_output: &WlOutput) {
+        let Some(client) = wl_output.client() else {
+            return;
+        };
+        for toplevel in self.foreign_toplevel_state.toplevels.values() {
+            if !toplevel.shows_on(output) {
+                continue;
+            }
+            for handle in &toplevel.wlr_handles {
+                if handle.client().map(|c| c.id()) == Some(client.id()) {
+                    handle.output_enter(wl_output);
+                    handle.done();
+                }
+            }
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> GlobalDispatch<ZwlrForeignToplevelManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrForeignToplevelManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let manager = data_init.init(resource, ());
+
+        let monitors = state.backend_data.get_monitor_layout();
+        for (surface_id, toplevel) in &mut state.foreign_toplevel_state.toplevels {
+            if let Some(toplevel_handle) =
+                new_wlr_handle::<BackendData>(handle, &manager, *surface_id, toplevel, &monitors)
+            {
+                toplevel.wlr_handles.push(toplevel_handle);
+            }
+        }
+        state.foreign_toplevel_state.wlr_managers.push(manager);
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ZwlrForeignToplevelManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        manager: &ZwlrForeignToplevelManagerV1,
+        request: zwlr_foreign_toplevel_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            zwlr_foreign_toplevel_manager_v1::Request::Stop => {
+                state
+                    .foreign_toplevel_state
+                    .wlr_managers
+                    .retain(|wlr_manager| wlr_manager != manager);
+                manager.finished();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        manager: &ZwlrForeignToplevelManagerV1,
+        _data: &(),
+    ) {
+        state
+            .foreign_toplevel_state
+            .wlr_managers
+            .retain(|wlr_manager| wlr_manager != manager);
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ZwlrForeignToplevelHandleV1, u64>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        _handle: &ZwlrForeignToplevelHandleV1,
+        request: zwlr_foreign_toplevel_handle_v1::Request,
+        surface_id: &u64,
+        _display_handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (action, monitor) = match request {
+            zwlr_foreign_toplevel_handle_v1::Request::Activate { .. } => ("activate", None),
+            zwlr_foreign_toplevel_handle_v1::Request::Close => ("close", None),
+            zwlr_foreign_toplevel_handle_v1::Request::SetMaximized => ("maximize", None),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMaximized => ("unmaximize", None),
+            zwlr_foreign_toplevel_handle_v1::Request::SetMinimized => ("minimize", None),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMinimized => ("unminimize", None),
+            zwlr_foreign_toplevel_handle_v1::Request::SetFullscreen { output } => (
+                "fullscreen",
+                output
+                    .as_ref()
+                    .and_then(Output::from_resource)
+                    .map(|output| output.name()),
+            ),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetFullscreen => ("unfullscreen", None),
+            // The shell doesn't animate minimized windows towards taskbar buttons.
+            zwlr_foreign_toplevel_handle_v1::Request::SetRectangle { .. } => return,
+            zwlr_foreign_toplevel_handle_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+
+        // The window may be gone while the request was in flight.
+        if !state
+            .foreign_toplevel_state
+            .toplevels
+            .contains_key(surface_id)
+        {
+            return;
+        }
+
+        state.invoke_platform_method(
+            "foreign_toplevel_request",
+            json!({
+                "surfaceId": surface_id,
+                "action": action,
+                "monitor": monitor,
+            }),
+        );
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        handle: &ZwlrForeignToplevelHandleV1,
+        surface_id: &u64,
+    ) {
+        if let Some(toplevel) = state.foreign_toplevel_state.toplevels.get_mut(surface_id) {
+            toplevel
+                .wlr_handles
+                .retain(|toplevel_handle| toplevel_handle != handle);
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> GlobalDispatch<ExtForeignToplevelListV1, ()>
+    for ServerState<BackendData>
+{
+    fn bind(
+        state: &mut Self,
+        handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtForeignToplevelListV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let list = data_init.init(resource, ());
+
+        for (surface_id, toplevel) in &mut state.foreign_toplevel_state.toplevels {
+            if let Some(toplevel_handle) =
+                new_ext_handle::<BackendData>(handle, &list, *surface_id, toplevel)
+            {
+                toplevel.ext_handles.push(toplevel_handle);
+            }
+        }
+        state.foreign_toplevel_state.ext_lists.push(list);
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtForeignToplevelListV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        list: &ExtForeignToplevelListV1,
+        request: ext_foreign_toplevel_list_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_foreign_toplevel_list_v1::Request::Stop => {
+                state
+                    .foreign_toplevel_state
+                    .ext_lists
+                    .retain(|ext_list| ext_list != list);
+                list.finished();
+            }
+            ext_foreign_toplevel_list_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut Self, _client: ClientId, list: &ExtForeignToplevelListV1, _data: &()) {
+        state
+            .foreign_toplevel_state
+            .ext_lists
+            .retain(|ext_list| ext_list != list);
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtForeignToplevelHandleV1, u64>
+    for ServerState<BackendData>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _handle: &ExtForeignToplevelHandleV1,
+        _request: ext_foreign_toplevel_handle_v1::Request,
+        _surface_id: &u64,
+        _display_handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Only destroy.
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        handle: &ExtForeignToplevelHandleV1,
+        surface_id: &u64,
+    ) {
+        if let Some(toplevel) = state.foreign_toplevel_state.toplevels.get_mut(surface_id) {
+            toplevel
+                .ext_handles
+                .retain(|toplevel_handle| toplevel_handle != handle);
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! ext-image-copy-capture with output sources from ext-image-capture-source.
+//!
+//! A session keeps capturing the same monitor and accumulates its damage between frames.
+//! Cursors are painted on the frames of sessions created with `paint_cursors`,
+//! cursor sessions exist but their capture sessions are stopped right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use smithay::output::Output;
+use smithay::reexports::wayland_server::backend::ClientId;
+use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer;
+use smithay::reexports::wayland_server::protocol::wl_output;
+use smithay::reexports::wayland_server::{
+    Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+};
+use smithay::utils::{Logical, Physical, Rectangle, Size};
+use tracing::warn;
+use wayland_protocols::ext::image_capture_source::v1::server::{
+    ext_image_capture_source_v1, ext_output_image_capture_source_manager_v1,
+};
+use wayland_protocols::ext::image_copy_capture::v1::server::{
+    ext_image_copy_capture_cursor_session_v1, ext_image_copy_capture_frame_v1,
+    ext_image_copy_capture_manager_v1, ext_image_copy_capture_session_v1,
+};
+
+use crate::Backend;
+
+use super::screen_capture::{
+    split_timestamp, CaptureError, CaptureRegion, ScreenCapturePermission, SHM_FORMATS,
+};
+use super::ServerState;
+
+use ext_image_capture_source_v1::ExtImageCaptureSourceV1;
+use ext_image_copy_capture_cursor_session_v1::ExtImageCopyCaptureCursorSessionV1;
+use ext_image_copy_capture_frame_v1::{ExtImageCopyCaptureFrameV1, FailureReason};
+use ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1;
+use ext_image_copy_capture_session_v1::ExtImageCopyCaptureSessionV1;
+use ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1;
+
+pub struct ImageCopyCaptureState {
+    sessions: Vec<CaptureSession>,
+}
+
+struct CaptureSession {
+    session: ExtImageCopyCaptureSessionV1,
+    capture: CaptureRegion,
+    /// Sent with the buffer constraints, the client reallocates its buffers when it changes.
+    buffer_size: Size<i32, Physical>,
+    /// What changed since the last ready frame, the first frame is fully damaged.
+    damage: Vec<Rectangle<i32, Logical>>,
+    /// Only one frame at a time, it waits for damage once captured.
+    frame: Option<ExtImageCopyCaptureFrameV1>,
+}
+
+pub struct ImageCaptureSourceData {
+    /// `None` when the monitor was already gone.
+    output: Option<Output>,
+}
+
+#[derive(Default)]
+pub struct CaptureFrameData {
+    session: Option<ExtImageCopyCaptureSessionV1>,
+    buffer: Option<WlBuffer>,
+    captured: bool,
+}
+
+#[derive(Default)]
+pub struct CursorSessionData {
+    has_capture_session: AtomicBool,
+}
+
+impl ImageCopyCaptureState {
+    pub fn new<BackendData: Backend + 'static>(
+        display_handle: &DisplayHandle,
+    ) -> ImageCopyCaptureState {
+        display_handle
+            .create_global::<ServerState<BackendData>, ExtOutputImageCaptureSourceManagerV1, _>(
+                1,
+                (),
+            );
+        display_handle
+            .create_global::<ServerState<BackendData>, ExtImageCopyCaptureManagerV1, _>(1, ());
+        ImageCopyCaptureState { sessions: vec![] }
+    }
+
+    pub fn add_damage(&mut self, damage: &[Rectangle<i32, Logical>]) {
+        for session in &mut self.sessions {
+            session.damage.extend_from_slice(damage);
+        }
+    }
+
+    /// Only sessions painting the cursor change when it moves.
+    pub fn add_cursor_damage(&mut self, damage: &[Rectangle<i32, Logical>]) {
+        for session in &mut self.sessions {
+            if session.capture.paint_cursor {
+                session.damage.extend_from_slice(damage);
+            }
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    pub fn process_image_copy_capture_frames(&mut self) {
+        let sessions = std::mem::take(&mut self.image_copy_capture_state.sessions);
+        self.image_copy_capture_state.sessions = sessions
+            .into_iter()
+            .filter_map(|mut session| self.capture_session_frame(&mut session).then_some(session))
+            .collect();
+    }
+
+    /// Returns `false` when the session is stopped.
+    fn capture_session_frame(&mut self, session: &mut CaptureSession) -> bool {
+        let Some(frame) = session.frame.clone() else {
+            return true;
+        };
+        let Some(buffer) = frame
+            .data::<Mutex<CaptureFrameData>>()
+            .map(|data| data.lock().unwrap())
+            .filter(|data| data.captured)
+            .and_then(|data| data.buffer.clone())
+        else {
+            return true;
+        };
+        let Some(client) = frame.client() else {
+            return true;
+        };
+
+        match self.screen_capture_permission(&client) {
+            ScreenCapturePermission::Unknown | ScreenCapturePermission::Pending => return true,
+            ScreenCapturePermission::Denied => {
+                frame.failed(FailureReason::Stopped);
+                session.session.stopped();
+                return false;
+            }
+            ScreenCapturePermission::Allowed => {}
+        }
+
+        if self.is_capture_gone(&session.capture) {
+            frame.failed(FailureReason::Stopped);
+            session.session.stopped();
+            return false;
+        }
+
+        // The monitor changed its resolution or scale.
+        let buffer_size = session.capture.buffer_size();
+        if buffer_size != session.buffer_size {
+            session.buffer_size = buffer_size;
+            session.damage = vec![session.capture.geometry()];
+            self.send_capture_session_constraints(&session.session, buffer_size);
+            frame.failed(FailureReason::BufferConstraints);
+            session.frame = None;
+            return true;
+        }
+
+        let damage = session.capture.buffer_damage(&session.damage);
+        if damage.is_empty() {
+            return true;
+        }
+
+        match self.render_capture(&session.capture, &buffer) {
+            Ok(()) => {}
+            Err(CaptureError::NothingRendered) => return true,
+            Err(CaptureError::BufferConstraints) => {
+                frame.failed(FailureReason::BufferConstraints);
+                session.frame = None;
+                return true;
+            }
+            Err(err) => {
+                warn!("Failed to capture a frame: {err}");
+                frame.failed(FailureReason::Unknown);
+                session.frame = None;
+                return true;
+            }
+        }
+
+        // Captures are already in the orientation the user sees.
+        frame.transform(wl_output::Transform::Normal);
+        for rect in damage {
+            frame.damage(rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+        }
+        let (tv_sec_hi, tv_sec_lo, tv_nsec) = split_timestamp(self.clock.now().into());
+        frame.presentation_time(tv_sec_hi, tv_sec_lo, tv_nsec);
+        frame.ready();
+
+        session.frame = None;
+        session.damage.clear();
+        true
+    }
+
+    fn send_capture_session_constraints(
+        &mut self,
+        session: &ExtImageCopyCaptureSessionV1,
+        buffer_size: Size<i32, Physical>,
+    ) {
+        session.buffer_size(buffer_size.w as u32, buffer_size.h as u32);
+        for format in SHM_FORMATS {
+            session.shm_format(format);
+        }
+        if let Some(constraints) = self.capture_dmabuf_constraints() {
+            session.dmabuf_device(constraints.device);
+            for (format, modifiers) in constraints.formats {
+                let modifiers = modifiers
+                    .iter()
+                    .flat_map(|modifier| modifier.to_ne_bytes())
+                    .collect();
+                session.dmabuf_format(format as u32, modifiers);
+            }
+        }
+        session.done();
+    }
+}
+
+impl<BackendData: Backend + 'static> GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtOutputImageCaptureSourceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtOutputImageCaptureSourceManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _manager: &ExtOutputImageCaptureSourceManagerV1,
+        request: ext_output_image_capture_source_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_output_image_capture_source_manager_v1::Request::CreateSource {
+                source,
+                output,
+            } => {
+                data_init.init(
+                    source,
+                    ImageCaptureSourceData {
+                        output: Output::from_resource(&output),
+                    },
+                );
+            }
+            ext_output_image_capture_source_manager_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtImageCaptureSourceV1, ImageCaptureSourceData>
+    for ServerState<BackendData>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        _source: &ExtImageCaptureSourceV1,
+        _request: ext_image_capture_source_v1::Request,
+        _data: &ImageCaptureSourceData,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        // Only destroy, sessions keep capturing after their source is destroyed.
+    }
+}
+
+impl<BackendData: Backend + 'static> GlobalDispatch<ExtImageCopyCaptureManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ExtImageCopyCaptureManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtImageCopyCaptureManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        client: &Client,
+        manager: &ExtImageCopyCaptureManagerV1,
+        request: ext_image_copy_capture_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_image_copy_capture_manager_v1::Request::CreateSession {
+                session,
+                source,
+                options,
+            } => {
+                let WEnum::Value(options) = options else {
+                    manager.post_error(
+                        ext_image_copy_capture_manager_v1::Error::InvalidOption,
+                        "Unknown capture options",
+                    );
+                    return;
+                };
+                let session = data_init.init(session, ());
+
+                let capture = source
+                    .data::<ImageCaptureSourceData>()
+                    .and_then(|data| data.output.clone())
+                    .map(|output| CaptureRegion {
+                        output,
+                        region: None,
+                        paint_cursor: options
+                            .contains(ext_image_copy_capture_manager_v1::Options::PaintCursors),
+                    })
+                    .filter(|capture| !state.is_capture_gone(capture));
+                let Some(capture) = capture else {
+                    session.stopped();
+                    return;
+                };
+
+                let buffer_size = capture.buffer_size();
+                state.send_capture_session_constraints(&session, buffer_size);
+                state
+                    .image_copy_capture_state
+                    .sessions
+                    .push(CaptureSession {
+                        session,
+                        damage: vec![capture.geometry()],
+                        capture,
+                        buffer_size,
+                        frame: None,
+                    });
+                // The shell is asked while the client allocates its buffers.
+                state.screen_capture_permission(client);
+            }
+            ext_image_copy_capture_manager_v1::Request::CreatePointerCursorSession {
+                session,
+                ..
+            } => {
+                data_init.init(session, CursorSessionData::default());
+            }
+            ext_image_copy_capture_manager_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtImageCopyCaptureSessionV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        session: &ExtImageCopyCaptureSessionV1,
+        request: ext_image_copy_capture_session_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_image_copy_capture_session_v1::Request::CreateFrame { frame } => {
+                let capture_session = state
+                    .image_copy_capture_state
+                    .sessions
+                    .iter_mut()
+                    .find(|capture_session| capture_session.session == *session);
+
+                if capture_session
+                    .as_ref()
+                    .is_some_and(|capture_session| capture_session.frame.is_some())
+                {
+                    session.post_error(
+                        ext_image_copy_capture_session_v1::Error::DuplicateFrame,
+                        "The session already has a frame",
+                    );
+                    return;
+                }
+
+                let frame = data_init.init(
+                    frame,
+                    Mutex::new(CaptureFrameData {
+                        session: Some(session.clone()),
+                        ..Default::default()
+                    }),
+                );
+                // Frames of a stopped session fail once captured.
+                if let Some(capture_session) = capture_session {
+                    capture_session.frame = Some(frame);
+                }
+            }
+            ext_image_copy_capture_session_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        session: &ExtImageCopyCaptureSessionV1,
+        _data: &(),
+    ) {
+        let sessions = &mut state.image_copy_capture_state.sessions;
+        if let Some(index) = sessions
+            .iter()
+            .position(|capture_session| capture_session.session == *session)
+        {
+            let capture_session = sessions.remove(index);
+            if let Some(frame) = capture_session.frame {
+                if frame
+                    .data::<Mutex<CaptureFrameData>>()
+                    .unwrap()
+                    .lock()
+                    .unwrap()
+                    .captured
+                {
+                    frame.failed(FailureReason::Stopped);
+                }
+            }
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtImageCopyCaptureFrameV1, Mutex<CaptureFrameData>>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        frame: &ExtImageCopyCaptureFrameV1,
+        request: ext_image_copy_capture_frame_v1::Request,
+        data: &Mutex<CaptureFrameData>,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let mut frame_data = data.lock().unwrap();
+        if frame_data.captured
+            && !matches!(request, ext_image_copy_capture_frame_v1::Request::Destroy)
+        {
+            frame.post_error(
+                ext_image_copy_capture_frame_v1::Error::AlreadyCaptured,
+                "The frame was already captured",
+            );
+            return;
+        }
+
+        match request {
+            ext_image_copy_capture_frame_v1::Request::AttachBuffer { buffer } => {
+                frame_data.buffer = Some(buffer);
+            }
+            ext_image_copy_capture_frame_v1::Request::DamageBuffer {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                // The capture is always rendered completely, the damage of the buffer isn't needed.
+                if x < 0 || y < 0 || width <= 0 || height <= 0 {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::InvalidBufferDamage,
+                        "Invalid buffer damage",
+                    );
+                }
+            }
+            ext_image_copy_capture_frame_v1::Request::Capture => {
+                if frame_data.buffer.is_none() {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::NoBuffer,
+                        "No buffer attached to the frame",
+                    );
+                    return;
+                }
+                frame_data.captured = true;
+
+                let is_session_running =
+                    state
+                        .image_copy_capture_state
+                        .sessions
+                        .iter()
+                        .any(|capture_session| {
+                            Some(&capture_session.session) == frame_data.session.as_ref()
+                        });
+                drop(frame_data);
+                if is_session_running {
+                    state.process_image_copy_capture_frames();
+                } else {
+                    frame.failed(FailureReason::Stopped);
+                }
+            }
+            ext_image_copy_capture_frame_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        frame: &ExtImageCopyCaptureFrameV1,
+        _data: &Mutex<CaptureFrameData>,
+    ) {
+        for capture_session in &mut state.image_copy_capture_state.sessions {
+            if capture_session.frame.as_ref() == Some(frame) {
+                capture_session.frame = None;
+            }
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ExtImageCopyCaptureCursorSessionV1, CursorSessionData>
+    for ServerState<BackendData>
+{
+    fn request(
+        _state: &mut Self,
+        _client: &Client,
+        cursor_session: &ExtImageCopyCaptureCursorSessionV1,
+        request: ext_image_copy_capture_cursor_session_v1::Request,
+        data: &CursorSessionData,
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            ext_image_copy_capture_cursor_session_v1::Request::GetCaptureSession { session } => {
+                if data.has_capture_session.swap(true, Ordering::SeqCst) {
+                    cursor_session.post_error(
+                        ext_image_copy_capture_cursor_session_v1::Error::DuplicateSession,
+                        "The cursor session already has a capture session",
+                    );
+                    return;
+                }
+                // The cursor is only captured painted on the frames.
+                let session = data_init.init(session, ());
+                session.stopped();
+            }
+            ext_image_copy_capture_cursor_session_v1::Request::Destroy => {}
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Compositor key bindings, handled before Flutter and clients see the keys.
+//!
+//! They are read from `$XDG_CONFIG_HOME/veshell/key_bindings.json`, a list like
+//! `[{ "keys": "Ctrl+Alt+T", "action": { "type": "spawn", "command": "foot" } }]`,
+//! and the shell can register more over the platform channel.
+//! Without the file, or when it can't be parsed, Alt+Escape exits the compositor.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+
+use serde_json::json;
+use smithay::input::keyboard::{xkb, Keysym, KeysymHandle, ModifiersState};
+use tracing::{error, warn};
+
+use crate::persistence::config_path;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::Backend;
+
+use super::ServerState;
+
+const CONFIG_FILE: &str = "key_bindings.json";
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum KeyBindingAction {
+    Exit,
+    /// Switches to another virtual terminal, like a text console.
+    SwitchVt {
+        vt: i32,
+    },
+    /// Runs the command with `sh -c`, in the environment of Wayland clients.
+    Spawn {
+        command: String,
+    },
+    /// Sent to the shell as `key_binding_action`.
+    Flutter {
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeyCombination {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub logo: bool,
+    pub keysym: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyBinding {
+    pub keys: KeyCombination,
+    pub action: KeyBindingAction,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct KeyBindingConfig {
+    keys: String,
+    action: KeyBindingAction,
+}
+
+impl KeyCombination {
+    /// Parses combinations like `Ctrl+Alt+T` or `Super+Return`.
+    /// The key is an XKB keysym name, case doesn't matter.
+    pub fn parse(keys: &str) -> Result<Self, PlatformChannelError> {
+        let invalid = || PlatformChannelError::InvalidKeys(keys.to_string());
+
+        let mut parts = keys.split('+').map(str::trim).collect::<Vec<_>>();
+        let key = parts
+            .pop()
+            .filter(|key| !key.is_empty())
+            .ok_or_else(invalid)?;
+
+        let mut combination = KeyCombination::default();
+        for modifier in parts {
+            match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => combination.ctrl = true,
+                "alt" => combination.alt = true,
+                "shift" => combination.shift = true,
+                "super" | "logo" | "meta" => combination.logo = true,
+                _ => return Err(invalid()),
+            }
+        }
+
+        let keysym = xkb::keysym_from_name(key, xkb::KEYSYM_CASE_INSENSITIVE);
+        if keysym.raw() == xkb::keysyms::KEY_NoSymbol {
+            return Err(invalid());
+        }
+        combination.keysym = normalize_keysym(keysym.raw());
+        Ok(combination)
+    }
+
+    fn matches(&self, mods: &ModifiersState, keysym: Keysym) -> bool {
+        self.ctrl == mods.ctrl
+            && self.alt == mods.alt
+            && self.shift == mods.shift
+            && self.logo == mods.logo
+            && self.keysym == normalize_keysym(keysym.raw())
+    }
+}
+
+/// Latin letters match whatever their case.
+fn normalize_keysym(keysym: u32) -> u32 {
+    match char::from_u32(keysym) {
+        Some(c) if c.is_ascii_uppercase() => c.to_ascii_lowercase() as u32,
+        _ => keysym,
+    }
+}
+
+/// The keysym bindings are matched against, the one of the key without any modifier applied,
+/// so `Super+Shift+1` matches although Shift turns the key into `exclam`.
+/// Letters of other scripts use the key of the first latin layout, `Ctrl+C` works in Russian.
+pub fn binding_keysym(keysym_handle: &KeysymHandle<'_>) -> Option<Keysym> {
+    let keysym = keysym_handle.raw_syms().first().copied()?;
+    match keysym.key_char() {
+        Some(c) if c.is_alphabetic() && !c.is_ascii() => {
+            keysym_handle.raw_latin_sym_or_raw_current_sym()
+        }
+        _ => Some(keysym),
+    }
+}
+
+/// The VT of the `XF86Switch_VT_1` to `XF86Switch_VT_12` keysyms.
+pub fn switch_vt_keysym(keysym: Keysym) -> Option<i32> {
+    let first = xkb::keysyms::KEY_XF86Switch_VT_1;
+    let last = xkb::keysyms::KEY_XF86Switch_VT_12;
+    (first..=last)
+        .contains(&keysym.raw())
+        .then(|| (keysym.raw() - first + 1) as i32)
+}
+
+/// The bindings of the config file, or the default ones if there is no file.
+pub fn load_key_bindings() -> Vec<KeyBinding> {
+    load_key_bindings_from(&config_path(CONFIG_FILE))
+}
+
+/// A broken file gets the default bindings too, so that the user can still exit.
+pub fn load_key_bindings_from(path: &Path) -> Vec<KeyBinding> {
+    let Ok(json) = std::fs::read_to_string(path) else {
+        return default_key_bindings();
+    };
+
+    let configs: Vec<KeyBindingConfig> = match serde_json::from_str(&json) {
+        Ok(configs) => configs,
+        Err(err) => {
+            error!(path = %path.display(), "Invalid key bindings, using the default ones: {err}");
+            return default_key_bindings();
+        }
+    };
+
+    configs
+        .into_iter()
+        .filter_map(|config| match KeyCombination::parse(&config.keys) {
+            Ok(keys) => Some(KeyBinding {
+                keys,
+                action: config.action,
+            }),
+            Err(err) => {
+                warn!("Ignoring key binding: {err}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn default_key_bindings() -> Vec<KeyBinding> {
+    vec![KeyBinding {
+        keys: KeyCombination::parse("Alt+Escape").unwrap(),
+        action: KeyBindingAction::Exit,
+    }]
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// Replaces the binding of the same key combination, if any.
+    pub fn register_key_binding(
+        &mut self,
+        keys: &str,
+        action: KeyBindingAction,
+    ) -> Result<(), PlatformChannelError> {
+        let keys = KeyCombination::parse(keys)?;
+        self.key_bindings.retain(|binding| binding.keys != keys);
+        self.key_bindings.push(KeyBinding { keys, action });
+        Ok(())
+    }
+
+    pub fn unregister_key_binding(&mut self, keys: &str) -> Result<(), PlatformChannelError> {
+        let combination = KeyCombination::parse(keys)?;
+        let count = self.key_bindings.len();
+        self.key_bindings
+            .retain(|binding| binding.keys != combination);
+        if self.key_bindings.len() == count {
+            return Err(PlatformChannelError::KeyBindingDoesntExist(
+                keys.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(super) fn find_key_binding(
+        &self,
+        mods: &ModifiersState,
+        keysym: Keysym,
+    ) -> Option<KeyBindingAction> {
+        self.key_bindings
+            .iter()
+            .find(|binding| binding.keys.matches(mods, keysym))
+            .map(|binding| binding.action.clone())
+    }
+
+    pub(super) fn run_key_binding_action(&mut self, action: KeyBindingAction) {
+        match action {
+            KeyBindingAction::Exit => self.running.store(false, Ordering::SeqCst),
+            KeyBindingAction::SwitchVt { vt } => self.backend_data.change_vt(vt),
+            KeyBindingAction::Spawn { command } => self.spawn(&command),
+            KeyBindingAction::Flutter { name } => {
+                self.invoke_platform_method("key_binding_action", json!({ "name": name }))
+            }
+        }
+    }
+
+    fn spawn(&self, command: &str) {
+        let mut process = Command::new("/bin/sh");
+        process.arg("-c").arg(command);
+        if let Some(socket_name) = self.wayland_socket_name.as_ref() {
+            process.env("WAYLAND_DISPLAY", socket_name);
+        }
+        if let Some(display) = self.xwayland_display {
+            process.env("DISPLAY", format!(":{display}"));
+        }
+
+        match process.spawn() {
+            // Reap the process when it exits.
+            Ok(mut child) => {
+                std::thread::spawn(move || child.wait());
+            }
+            Err(err) => error!(command, "Failed to spawn: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_vt_keysyms_map_to_their_vt() {
+        let vt = |raw| switch_vt_keysym(Keysym::new(raw));
+        assert_eq!(vt(xkb::keysyms::KEY_XF86Switch_VT_1), Some(1));
+        assert_eq!(vt(xkb::keysyms::KEY_XF86Switch_VT_12), Some(12));
+        assert_eq!(vt(xkb::keysyms::KEY_F1), None);
+    }
+}
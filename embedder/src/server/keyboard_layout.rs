@@ -0,0 +1,107 @@
+//! XKB keymap of the seat, and switching between its layouts at runtime.
+//!
+//! Several layouts are configured with a comma separated list, like `VESHELL_XKB_LAYOUT=us,fr`.
+//! The shell refers to them by their index in this list.
+
+use std::env;
+
+use serde_json::json;
+use smithay::input::keyboard::{Layout, XkbConfig};
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::Backend;
+
+use super::ServerState;
+
+/// XKB names of the keymap, read from the `VESHELL_XKB_*` environment variables.
+/// Missing names are left empty so xkbcommon picks the system default.
+#[derive(Debug, Clone, Default)]
+pub struct KeyboardLayoutConfig {
+    pub rules: String,
+    pub model: String,
+    pub layout: String,
+    pub variant: String,
+    pub options: Option<String>,
+}
+
+impl KeyboardLayoutConfig {
+    pub fn from_env() -> Self {
+        let var = |name: &str| env::var(format!("VESHELL_XKB_{name}")).ok();
+        Self {
+            rules: var("RULES").unwrap_or_default(),
+            model: var("MODEL").unwrap_or_default(),
+            layout: var("LAYOUT").unwrap_or_default(),
+            variant: var("VARIANT").unwrap_or_default(),
+            options: var("OPTIONS"),
+        }
+    }
+
+    pub fn xkb_config(&self) -> XkbConfig<'_> {
+        XkbConfig {
+            rules: &self.rules,
+            model: &self.model,
+            layout: &self.layout,
+            variant: &self.variant,
+            options: self.options.clone(),
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// Names of the layouts of the keymap, like "English (US)", and the index of the active one.
+    pub fn keyboard_layouts(&mut self) -> (Vec<String>, u32) {
+        let keyboard = self.keyboard.clone();
+        keyboard.with_xkb_state(self, |context| {
+            let xkb = context.xkb().lock().unwrap();
+            let names = xkb
+                .layouts()
+                .map(|layout| xkb.layout_name(layout).to_string())
+                .collect();
+            (names, xkb.active_layout().0)
+        })
+    }
+
+    pub fn set_keyboard_layout(&mut self, index: u32) -> Result<(), PlatformChannelError> {
+        let (names, _) = self.keyboard_layouts();
+        if index as usize >= names.len() {
+            return Err(PlatformChannelError::KeyboardLayoutDoesntExist(index));
+        }
+
+        let keyboard = self.keyboard.clone();
+        keyboard.with_xkb_state(self, |mut context| context.set_layout(Layout(index)));
+        self.check_keyboard_layout_changed();
+        Ok(())
+    }
+
+    /// Switches to the next layout, going back to the first one after the last.
+    pub fn next_keyboard_layout(&mut self) {
+        let keyboard = self.keyboard.clone();
+        keyboard.with_xkb_state(self, |mut context| context.cycle_next_layout());
+        self.check_keyboard_layout_changed();
+    }
+
+    /// Tells the shell when the active layout changed,
+    /// which also happens on key presses with XKB options like `grp:alt_shift_toggle`.
+    /// Runs on every key event, the names are only built when the layout changed.
+    pub fn check_keyboard_layout_changed(&mut self) {
+        let keyboard = self.keyboard.clone();
+        let active = keyboard.with_xkb_state(self, |context| {
+            context.xkb().lock().unwrap().active_layout().0
+        });
+        if active != self.active_keyboard_layout {
+            self.active_keyboard_layout = active;
+            self.send_keyboard_layouts();
+        }
+    }
+
+    pub fn send_keyboard_layouts(&mut self) {
+        let (names, active) = self.keyboard_layouts();
+        self.invoke_platform_method(
+            "keyboard_layout_changed",
+            json!({
+                "layouts": names,
+                "active": active,
+            }),
+        );
+    }
+}
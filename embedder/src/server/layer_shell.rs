@@ -0,0 +1,223 @@
+use serde_json::json;
+use smithay::delegate_layer_shell;
+use smithay::output::Output;
+use smithay::reexports::wayland_server::protocol::wl_output::WlOutput;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::utils::{Logical, Size};
+use smithay::wayland::compositor::with_states;
+use smithay::wayland::shell::wlr_layer::{
+    Anchor, ExclusiveZone, KeyboardInteractivity, Layer, LayerSurface, LayerSurfaceCachedState,
+    LayerSurfaceData, WlrLayerShellHandler, WlrLayerShellState,
+};
+use smithay::wayland::shell::xdg::PopupSurface;
+
+use crate::flutter_engine::wayland_messages::{
+    LayerAnchorMessage, LayerMarginMessage, LayerSurfaceMessage, MyPoint, NewLayerSurfaceMessage,
+};
+use crate::Backend;
+
+use super::{get_surface_id, monitors_bounding_box, ServerState};
+
+/// What the client asked for when it created the layer surface.
+struct LayerSurfaceRequest {
+    namespace: String,
+    layer: Layer,
+    output: Option<Output>,
+}
+
+/// Whether the initial configure was sent, the committed state and the requested output.
+fn layer_surface_state(surface: &WlSurface) -> (bool, LayerSurfaceCachedState, Option<Output>) {
+    with_states(surface, |surface_data| {
+        let initial_configure_sent = surface_data
+            .data_map
+            .get::<LayerSurfaceData>()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .initial_configure_sent;
+        let state = *surface_data
+            .cached_state
+            .current::<LayerSurfaceCachedState>();
+        let output = surface_data
+            .data_map
+            .get::<LayerSurfaceRequest>()
+            .and_then(|request| request.output.clone());
+        (initial_configure_sent, state, output)
+    })
+}
+
+impl<BackendData: Backend> ServerState<BackendData> {
+    /// The monitor showing the layer surface, the first one when the client didn't choose.
+    /// `None` for other surfaces.
+    pub fn layer_surface_monitor(&self, surface: &WlSurface) -> Option<Output> {
+        if !self.layer_surfaces.contains_key(&get_surface_id(surface)) {
+            return None;
+        }
+        let (_, _, output) = layer_surface_state(surface);
+        output.or_else(|| self.backend_data.get_monitor_layout().into_iter().next())
+    }
+
+    /// Tells the shell about a layer surface, also when it missed its creation.
+    pub fn send_new_layer_surface(&mut self, surface: &WlSurface) {
+        let message = with_states(surface, |surface_data| {
+            let request = surface_data.data_map.get::<LayerSurfaceRequest>()?;
+            Some(NewLayerSurfaceMessage {
+                surface_id: get_surface_id(surface),
+                namespace: request.namespace.clone(),
+                layer: layer_name(request.layer),
+                output: request.output.as_ref().map(Output::name),
+            })
+        });
+        if let Some(message) = message {
+            self.invoke_platform_method("new_layer_surface", json!(message));
+        }
+    }
+
+    /// Answers the initial commit of a layer surface with its size,
+    /// the shell hears of the surface on the next commit.
+    pub fn send_initial_layer_surface_configure(&self, surface: &WlSurface) {
+        let surface_id = get_surface_id(surface);
+        let Some(layer_surface) = self.layer_surfaces.get(&surface_id) else {
+            return;
+        };
+
+        let (initial_configure_sent, state, _) = layer_surface_state(surface);
+        if initial_configure_sent {
+            return;
+        }
+
+        // The client leaves it to the compositor to pick the size on the axes set to 0,
+        // which only makes sense when the surface is anchored to both edges.
+        let output_size = self
+            .layer_surface_monitor(surface)
+            .map(|output| output_logical_size(&output))
+            .unwrap_or_default();
+        let margin = state.margin;
+        let width = match state.size.w {
+            0 => output_size.w - margin.left - margin.right,
+            w => w,
+        };
+        let height = match state.size.h {
+            0 => output_size.h - margin.top - margin.bottom,
+            h => h,
+        };
+
+        layer_surface.with_pending_state(|pending| {
+            pending.size = Some((width.max(0), height.max(0)).into());
+        });
+        layer_surface.send_configure();
+    }
+
+    pub fn construct_layer_surface_role_message(
+        &self,
+        surface: &WlSurface,
+    ) -> Option<LayerSurfaceMessage> {
+        let surface_id = get_surface_id(surface);
+        if !self.layer_surfaces.contains_key(&surface_id) {
+            return None;
+        }
+
+        let (initial_configure_sent, state, _) = layer_surface_state(surface);
+        if !initial_configure_sent {
+            return None;
+        }
+
+        Some(LayerSurfaceMessage {
+            layer: layer_name(state.layer),
+            anchor: LayerAnchorMessage {
+                top: state.anchor.contains(Anchor::TOP),
+                bottom: state.anchor.contains(Anchor::BOTTOM),
+                left: state.anchor.contains(Anchor::LEFT),
+                right: state.anchor.contains(Anchor::RIGHT),
+            },
+            exclusive_zone: match state.exclusive_zone {
+                ExclusiveZone::Exclusive(zone) => zone as i32,
+                ExclusiveZone::Neutral => 0,
+                ExclusiveZone::DontCare => -1,
+            },
+            margin: LayerMarginMessage {
+                top: state.margin.top,
+                right: state.margin.right,
+                bottom: state.margin.bottom,
+                left: state.margin.left,
+            },
+            keyboard_interactivity: match state.keyboard_interactivity {
+                KeyboardInteractivity::None => "none",
+                KeyboardInteractivity::Exclusive => "exclusive",
+                KeyboardInteractivity::OnDemand => "onDemand",
+            },
+            desired_size: state.size.into(),
+        })
+    }
+}
+
+impl<BackendData: Backend> WlrLayerShellHandler for ServerState<BackendData> {
+    fn shell_state(&mut self) -> &mut WlrLayerShellState {
+        &mut self.layer_shell_state
+    }
+
+    fn new_layer_surface(
+        &mut self,
+        surface: LayerSurface,
+        output: Option<WlOutput>,
+        layer: Layer,
+        namespace: String,
+    ) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        let output = output.as_ref().and_then(Output::from_resource);
+
+        with_states(surface.wl_surface(), |surface_data| {
+            surface_data
+                .data_map
+                .insert_if_missing_threadsafe(|| LayerSurfaceRequest {
+                    namespace,
+                    layer,
+                    output,
+                })
+        });
+        self.layer_surfaces.insert(surface_id, surface.clone());
+        self.send_new_layer_surface(surface.wl_surface());
+    }
+
+    fn new_popup(&mut self, parent: LayerSurface, popup: PopupSurface) {
+        let surface_id = get_surface_id(popup.wl_surface());
+        let position: MyPoint<i32, Logical> =
+            popup.with_pending_state(|state| state.geometry.loc).into();
+
+        self.invoke_platform_method(
+            "new_popup",
+            json!({
+                "surfaceId": surface_id,
+                "parent": get_surface_id(parent.wl_surface()),
+                "position": position,
+            }),
+        );
+    }
+
+    fn layer_destroyed(&mut self, surface: LayerSurface) {
+        let surface_id = get_surface_id(surface.wl_surface());
+        self.layer_surfaces.remove(&surface_id);
+
+        self.invoke_platform_method(
+            "destroy_layer_surface",
+            json!({
+                "surfaceId": surface_id,
+            }),
+        );
+    }
+}
+
+delegate_layer_shell!(@<BackendData: Backend + 'static> ServerState<BackendData>);
+
+fn layer_name(layer: Layer) -> &'static str {
+    match layer {
+        Layer::Background => "background",
+        Layer::Bottom => "bottom",
+        Layer::Top => "top",
+        Layer::Overlay => "overlay",
+    }
+}
+
+fn output_logical_size(output: &Output) -> Size<i32, Logical> {
+    monitors_bounding_box(&[output.clone()]).size
+}
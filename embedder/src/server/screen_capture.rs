@@ -0,0 +1,462 @@
+//! What wlr-screencopy and ext-image-copy-capture have in common: the region of a monitor
+//! being captured, the permission the shell gives to each client, and the rendering of
+//! the last Flutter frame and the cursor into the buffers of the clients.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::json;
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::egl::EGLDevice;
+use smithay::backend::renderer::damage::{self, OutputDamageTracker};
+use smithay::backend::renderer::element::surface::{
+    render_elements_from_surface_tree, WaylandSurfaceRenderElement,
+};
+use smithay::backend::renderer::element::texture::TextureRenderElement;
+use smithay::backend::renderer::element::{Id, Kind};
+use smithay::backend::renderer::gles::{GlesError, GlesRenderer, GlesTexture};
+use smithay::backend::renderer::{Bind, ExportMem, Offscreen, Renderer, TextureMapping, Unbind};
+use smithay::desktop::utils::bbox_from_surface_tree;
+use smithay::input::pointer::{CursorIcon, CursorImageAttributes, CursorImageStatus};
+use smithay::output::Output;
+use smithay::reexports::wayland_server::backend::ClientId;
+use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer;
+use smithay::reexports::wayland_server::protocol::wl_shm;
+use smithay::reexports::wayland_server::{Client, Resource};
+use smithay::utils::{Logical, Physical, Point, Rectangle, Size, Transform};
+use smithay::wayland::buffer::{buffer_type, BufferType};
+use smithay::wayland::compositor::with_states;
+use smithay::wayland::dmabuf::get_dmabuf;
+use smithay::wayland::shm::{with_buffer_contents, with_buffer_contents_mut};
+
+use crate::cursor::xcursor_size;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::render_elements::{
+    cursor_render_elements, OutputRenderElement, FLUTTER_FRAME_TRANSFORM,
+};
+use crate::{Backend, ClientState};
+
+use super::{monitors_bounding_box, ServerState};
+
+/// Captures are copied from an RGBA render, into the little-endian ARGB layout of these formats.
+pub const SHM_FORMATS: [wl_shm::Format; 2] = [wl_shm::Format::Xrgb8888, wl_shm::Format::Argb8888];
+pub const DMABUF_FORMATS: [Fourcc; 2] = [Fourcc::Xrgb8888, Fourcc::Argb8888];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenCapturePermission {
+    /// The client never tried to capture the screen.
+    #[default]
+    Unknown,
+    /// The shell hasn't answered yet, captures wait for it.
+    Pending,
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    #[error("Nothing was rendered yet")]
+    NothingRendered,
+    #[error("The buffer doesn't match the advertised size and formats")]
+    BufferConstraints,
+    #[error("Failed to render the capture: {0}")]
+    Render(#[from] GlesError),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureRegion {
+    pub output: Output,
+    /// Relative to the top left corner of the monitor, the whole monitor when `None`.
+    pub region: Option<Rectangle<i32, Logical>>,
+    pub paint_cursor: bool,
+}
+
+impl CaptureRegion {
+    /// Logical coordinates in the monitor layout, follows the monitor when the shell moves it.
+    pub fn geometry(&self) -> Rectangle<i32, Logical> {
+        let output_geometry = monitors_bounding_box(&[self.output.clone()]);
+        match self.region {
+            Some(region) => {
+                Rectangle::from_loc_and_size(output_geometry.loc + region.loc, region.size)
+                    .intersection(output_geometry)
+                    .unwrap_or_default()
+            }
+            None => output_geometry,
+        }
+    }
+
+    /// Captures have the resolution of the monitor.
+    pub fn scale(&self) -> f64 {
+        self.output.current_scale().fractional_scale()
+    }
+
+    pub fn buffer_size(&self) -> Size<i32, Physical> {
+        self.geometry()
+            .size
+            .to_f64()
+            .to_physical(self.scale())
+            .to_i32_round()
+    }
+
+    /// The part of the damage of the Flutter view that is inside the capture, in buffer pixels.
+    /// Empty when nothing changed in the capture.
+    pub fn buffer_damage(
+        &self,
+        damage: &[Rectangle<i32, Logical>],
+    ) -> Vec<Rectangle<i32, Physical>> {
+        let geometry = self.geometry();
+        let buffer = Rectangle::from_loc_and_size((0, 0), self.buffer_size());
+        damage
+            .iter()
+            .filter_map(|rect| rect.intersection(geometry))
+            .map(|rect| {
+                Rectangle::<i32, Logical>::from_loc_and_size(rect.loc - geometry.loc, rect.size)
+                    .to_f64()
+                    .to_physical(self.scale())
+                    .to_i32_up()
+            })
+            .filter_map(|rect| rect.intersection(buffer))
+            .filter(|rect| !rect.is_empty())
+            .collect()
+    }
+}
+
+pub struct DmabufConstraints {
+    /// `dev_t` of the render node, in native endianness.
+    pub device: Vec<u8>,
+    pub formats: Vec<(Fourcc, Vec<u64>)>,
+}
+
+/// Splits a `CLOCK_MONOTONIC` timestamp the way the capture protocols send it.
+pub fn split_timestamp(time: Duration) -> (u32, u32, u32) {
+    let secs = time.as_secs();
+    ((secs >> 32) as u32, secs as u32, time.subsec_nanos())
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// Asks the shell the first time a client tries to capture the screen.
+    /// XWayland clients have no way to capture the screen through Wayland.
+    pub fn screen_capture_permission(&mut self, client: &Client) -> ScreenCapturePermission {
+        let Some(client_state) = client.get_data::<ClientState>() else {
+            return ScreenCapturePermission::Denied;
+        };
+        let mut permission = client_state.screen_capture_permission.lock().unwrap();
+        if *permission == ScreenCapturePermission::Unknown {
+            *permission = ScreenCapturePermission::Pending;
+
+            let request_id = self.next_screen_capture_permission_request_id;
+            self.next_screen_capture_permission_request_id += 1;
+            self.screen_capture_permission_requests
+                .insert(request_id, client.clone());
+
+            let pid = client
+                .get_credentials(&self.display_handle)
+                .ok()
+                .map(|credentials| credentials.pid);
+            self.invoke_platform_method(
+                "screen_capture_permission_request",
+                json!({
+                    "requestId": request_id,
+                    "pid": pid,
+                }),
+            );
+        }
+        *permission
+    }
+
+    /// The shell is told to drop the permission request of the client, if it hadn't answered yet.
+    pub fn client_disconnected(&mut self, client_id: ClientId) {
+        let request_ids = self
+            .screen_capture_permission_requests
+            .iter()
+            .filter(|(_, client)| client.id() == client_id)
+            .map(|(request_id, _)| *request_id)
+            .collect::<Vec<_>>();
+        for request_id in request_ids {
+            self.screen_capture_permission_requests.remove(&request_id);
+            self.invoke_platform_method(
+                "screen_capture_permission_request_cancelled",
+                json!({ "requestId": request_id }),
+            );
+        }
+    }
+
+    pub fn answer_screen_capture_permission(
+        &mut self,
+        request_id: u64,
+        allowed: bool,
+    ) -> Result<(), PlatformChannelError> {
+        let client = self
+            .screen_capture_permission_requests
+            .remove(&request_id)
+            .ok_or(PlatformChannelError::UnknownPermissionRequest(request_id))?;
+
+        if let Some(client_state) = client.get_data::<ClientState>() {
+            *client_state.screen_capture_permission.lock().unwrap() = if allowed {
+                ScreenCapturePermission::Allowed
+            } else {
+                ScreenCapturePermission::Denied
+            };
+        }
+
+        // Captures waiting for the answer either complete or fail now.
+        self.process_screen_captures();
+        Ok(())
+    }
+
+    /// Called by the backends with the damage of every frame Flutter presents,
+    /// in physical pixels of the Flutter view.
+    pub fn flutter_frame_presented(&mut self, damage: &[Rectangle<i32, Physical>]) {
+        let damage = damage
+            .iter()
+            .map(|rect| rect.to_f64().to_logical(self.pixel_ratio).to_i32_up())
+            .collect::<Vec<_>>();
+        self.wlr_screencopy_state.add_damage(&damage);
+        self.image_copy_capture_state.add_damage(&damage);
+        self.process_screen_captures();
+    }
+
+    /// Captures with the cursor are damaged where the cursor was and where it is now.
+    pub fn cursor_moved(&mut self, old_position: (f64, f64)) {
+        let damage = [old_position, self.mouse_position]
+            .into_iter()
+            .filter_map(|position| self.cursor_geometry(Point::from(position)))
+            .collect::<Vec<_>>();
+        if damage.is_empty() {
+            return;
+        }
+        self.wlr_screencopy_state.add_cursor_damage(&damage);
+        self.image_copy_capture_state.add_cursor_damage(&damage);
+        self.process_screen_captures();
+    }
+
+    /// The area covered by the cursor at `position`.
+    fn cursor_geometry(&self, position: Point<f64, Logical>) -> Option<Rectangle<i32, Logical>> {
+        let position = position.to_i32_round();
+        match self.cursor_image_status() {
+            CursorImageStatus::Hidden => None,
+            // The hotspot is somewhere in the image, which can be larger than the nominal size.
+            CursorImageStatus::Named(_) => {
+                let size = 2 * xcursor_size() as i32;
+                Some(Rectangle::from_loc_and_size(
+                    position - Point::from((size, size)),
+                    (2 * size, 2 * size),
+                ))
+            }
+            CursorImageStatus::Surface(surface) => {
+                let hotspot = with_states(&surface, |states| {
+                    states
+                        .data_map
+                        .get::<Mutex<CursorImageAttributes>>()
+                        .map(|attributes| attributes.lock().unwrap().hotspot)
+                        .unwrap_or_default()
+                });
+                Some(bbox_from_surface_tree(&surface, position - hotspot))
+            }
+        }
+    }
+
+    pub fn process_screen_captures(&mut self) {
+        self.process_wlr_screencopy_frames();
+        self.process_image_copy_capture_frames();
+    }
+
+    /// The monitor was unplugged or turned off.
+    pub fn is_capture_gone(&self, capture: &CaptureRegion) -> bool {
+        !self
+            .backend_data
+            .get_monitor_layout()
+            .contains(&capture.output)
+    }
+
+    /// `None` when there is no renderer yet or its render node is unknown.
+    pub fn capture_dmabuf_constraints(&self) -> Option<DmabufConstraints> {
+        let egl_context = self.gles_renderer.as_ref()?.egl_context();
+        let render_node = EGLDevice::device_for_display(egl_context.display())
+            .ok()?
+            .try_get_render_node()
+            .ok()??;
+
+        let formats = DMABUF_FORMATS
+            .iter()
+            .map(|code| {
+                let modifiers = egl_context
+                    .dmabuf_render_formats()
+                    .iter()
+                    .filter(|format| format.code == *code)
+                    .map(|format| u64::from(format.modifier))
+                    .collect::<Vec<_>>();
+                (*code, modifiers)
+            })
+            .filter(|(_, modifiers)| !modifiers.is_empty())
+            .collect();
+
+        Some(DmabufConstraints {
+            device: render_node.dev_id().to_ne_bytes().to_vec(),
+            formats,
+        })
+    }
+
+    /// Renders the last Flutter frame, and the cursor when asked, into a client buffer.
+    pub fn render_capture(
+        &mut self,
+        capture: &CaptureRegion,
+        buffer: &WlBuffer,
+    ) -> Result<(), CaptureError> {
+        let flutter_texture =
+            BackendData::last_flutter_frame(self).ok_or(CaptureError::NothingRendered)?;
+
+        let geometry = capture.geometry();
+        let scale = capture.scale();
+        let buffer_size = capture.buffer_size();
+        let layout_size = monitors_bounding_box(&self.backend_data.get_monitor_layout()).size;
+        let cursor_image_status = match self.cursor_image_status() {
+            _ if !capture.paint_cursor => CursorImageStatus::Hidden,
+            // Only the DRM backend resets the cursor when the client destroys its surface.
+            CursorImageStatus::Surface(surface) if !surface.alive() => {
+                CursorImageStatus::Named(CursorIcon::Default)
+            }
+            cursor_image_status => cursor_image_status,
+        };
+        let direct_scanout_surface = self.direct_scanout_surface(&capture.output);
+        let monitor_location = monitors_bounding_box(&[capture.output.clone()]).loc;
+
+        let gles_renderer = self.gles_renderer.as_mut().unwrap();
+        let mut elements = cursor_render_elements(
+            gles_renderer,
+            &mut self.cursor_textures,
+            cursor_image_status,
+            Point::from(self.mouse_position) - geometry.loc.to_f64(),
+            scale,
+            self.clock.now().into(),
+        );
+        // Flutter doesn't see the buffers of a client on a plane, they are drawn above its frame.
+        if let Some((_, wl_surface)) = direct_scanout_surface {
+            elements.extend(
+                render_elements_from_surface_tree::<_, WaylandSurfaceRenderElement<GlesRenderer>>(
+                    gles_renderer,
+                    &wl_surface,
+                    (monitor_location - geometry.loc)
+                        .to_f64()
+                        .to_physical(scale)
+                        .to_i32_round(),
+                    scale,
+                    1.0,
+                    Kind::Unspecified,
+                )
+                .into_iter()
+                .map(OutputRenderElement::from),
+            );
+        }
+        elements.push(OutputRenderElement::from(
+            TextureRenderElement::from_static_texture(
+                Id::new(),
+                gles_renderer.id(),
+                Point::<f64, Logical>::from((-geometry.loc.x as f64, -geometry.loc.y as f64))
+                    .to_physical(scale),
+                flutter_texture,
+                1,
+                FLUTTER_FRAME_TRANSFORM,
+                None,
+                None,
+                Some(layout_size),
+                None,
+                Kind::Unspecified,
+            ),
+        ));
+
+        match buffer_type(buffer) {
+            Some(BufferType::Dma) => {
+                let dmabuf = get_dmabuf(buffer)
+                    .map_err(|_| CaptureError::BufferConstraints)?
+                    .clone();
+                let size = dmabuf.size();
+                if (size.w, size.h) != (buffer_size.w, buffer_size.h)
+                    || !DMABUF_FORMATS.contains(&dmabuf.format().code)
+                {
+                    return Err(CaptureError::BufferConstraints);
+                }
+
+                gles_renderer.bind(dmabuf)?;
+                let result = render_elements(gles_renderer, buffer_size, scale, &elements)
+                    .and_then(|()| {
+                        // The client reads the buffer as soon as it receives the ready event.
+                        gles_renderer.with_context(|gl| unsafe { gl.Finish() })?;
+                        Ok(())
+                    });
+                gles_renderer.unbind()?;
+                result
+            }
+            Some(BufferType::Shm) => {
+                let (len, data) = with_buffer_contents(buffer, |_, len, data| (len, data))
+                    .map_err(|_| CaptureError::BufferConstraints)?;
+                let stride = buffer_size.w as usize * 4;
+                if (data.width, data.height) != (buffer_size.w, buffer_size.h)
+                    || !SHM_FORMATS.contains(&data.format)
+                    || (data.stride as usize) < stride
+                    || data.offset as usize + data.stride as usize * data.height as usize > len
+                {
+                    return Err(CaptureError::BufferConstraints);
+                }
+
+                let texture: GlesTexture = Offscreen::<GlesTexture>::create_buffer(
+                    gles_renderer,
+                    Fourcc::Abgr8888,
+                    (buffer_size.w, buffer_size.h).into(),
+                )?;
+                gles_renderer.bind(texture)?;
+                let result = render_elements(gles_renderer, buffer_size, scale, &elements)
+                    .and_then(|()| {
+                        let mapping = gles_renderer.copy_framebuffer(
+                            Rectangle::from_loc_and_size((0, 0), (buffer_size.w, buffer_size.h)),
+                            Fourcc::Abgr8888,
+                        )?;
+                        let flipped = mapping.flipped();
+                        let pixels = gles_renderer.map_texture(&mapping)?;
+
+                        with_buffer_contents_mut(buffer, |ptr, len, data| {
+                            let shm = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+                            for row in 0..data.height as usize {
+                                let source_row = if flipped {
+                                    data.height as usize - 1 - row
+                                } else {
+                                    row
+                                };
+                                let source = &pixels[source_row * stride..][..stride];
+                                let destination = &mut shm
+                                    [data.offset as usize + row * data.stride as usize..][..stride];
+                                for (destination, source) in
+                                    destination.chunks_exact_mut(4).zip(source.chunks_exact(4))
+                                {
+                                    destination.copy_from_slice(&[
+                                        source[2], source[1], source[0], source[3],
+                                    ]);
+                                }
+                            }
+                        })
+                        .map_err(|_| CaptureError::BufferConstraints)
+                    });
+                gles_renderer.unbind()?;
+                result
+            }
+            _ => Err(CaptureError::BufferConstraints),
+        }
+    }
+}
+
+/// The capture is rendered from scratch, the buffers of the clients have no known age.
+fn render_elements(
+    gles_renderer: &mut GlesRenderer,
+    size: Size<i32, Physical>,
+    scale: f64,
+    elements: &[OutputRenderElement],
+) -> Result<(), CaptureError> {
+    let mut damage_tracker = OutputDamageTracker::new(size, scale, Transform::Normal);
+    damage_tracker
+        .render_output(gles_renderer, 0, elements, [0.0, 0.0, 0.0, 1.0])
+        .map_err(|err| match err {
+            damage::Error::Rendering(err) => CaptureError::Render(err),
+            damage::Error::OutputNoMode(_) => unreachable!("The damage tracker has a fixed size"),
+        })?;
+    Ok(())
+}
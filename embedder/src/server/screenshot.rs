@@ -0,0 +1,416 @@
+//! Screenshots of the monitors, a region of the screen or a single window, saved as PNG.
+//!
+//! Monitors and regions are read back from the last frame Flutter rendered,
+//! so every backend gives the same picture. Windows are drawn from the last buffer
+//! their client committed, without subsurfaces and popups.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::renderer::damage::{self, OutputDamageTracker};
+use smithay::backend::renderer::element::texture::TextureRenderElement;
+use smithay::backend::renderer::element::{Id, Kind};
+use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
+use smithay::backend::renderer::{Bind, ExportMem, Offscreen, Renderer, Texture, Unbind};
+use smithay::utils::{
+    Buffer as BufferCoords, Logical, Physical, Point, Rectangle, Size, Transform,
+};
+use smithay::wayland::compositor::{with_states, SurfaceAttributes};
+use smithay::wayland::shell::xdg::SurfaceCachedState;
+use smithay::wayland::viewporter::ViewportCachedState;
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::render_elements::flutter_frame_rect_in_texture;
+use crate::Backend;
+
+use super::{monitors_bounding_box, ServerState};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ScreenshotTarget {
+    /// All the monitors, as laid out by the shell.
+    All,
+    Monitor {
+        name: String,
+    },
+    /// Logical coordinates in the monitor layout.
+    Region {
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    },
+    /// A toplevel or any other surface, cropped to its window geometry.
+    Surface {
+        #[serde(rename = "surfaceId")]
+        surface_id: u64,
+    },
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureScreenshotPayload {
+    /// Where the PNG file is written.
+    pub path: PathBuf,
+    pub target: ScreenshotTarget,
+}
+
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    /// RGBA pixels, rows from top to bottom.
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    pub fn save(&self, path: &Path) -> Result<(), PlatformChannelError> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn flip_vertically(&mut self) {
+        let stride = self.width as usize * 4;
+        let rows = self.pixels.chunks_exact(stride).rev().flatten().copied();
+        self.pixels = rows.collect();
+    }
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    pub fn save_screenshot(
+        &mut self,
+        path: &Path,
+        target: &ScreenshotTarget,
+    ) -> Result<(), PlatformChannelError> {
+        self.capture_screenshot(target)?.save(path)
+    }
+
+    pub fn capture_screenshot(
+        &mut self,
+        target: &ScreenshotTarget,
+    ) -> Result<Screenshot, PlatformChannelError> {
+        let monitors = self.backend_data.get_monitor_layout();
+        match target {
+            ScreenshotTarget::All => self.capture_flutter_region(monitors_bounding_box(&monitors)),
+            ScreenshotTarget::Monitor { name } => {
+                let monitor = monitors
+                    .iter()
+                    .find(|monitor| monitor.name() == *name)
+                    .ok_or_else(|| PlatformChannelError::MonitorDoesntExist(name.clone()))?;
+                self.capture_flutter_region(monitors_bounding_box(&[monitor.clone()]))
+            }
+            ScreenshotTarget::Region {
+                x,
+                y,
+                width,
+                height,
+            } => self
+                .capture_flutter_region(Rectangle::from_loc_and_size((*x, *y), (*width, *height))),
+            ScreenshotTarget::Surface { surface_id } => self.capture_surface(*surface_id),
+        }
+    }
+
+    /// The Flutter view starts at (0, 0) of the monitor layout and is rendered at `pixel_ratio`.
+    fn capture_flutter_region(
+        &mut self,
+        region: Rectangle<i32, Logical>,
+    ) -> Result<Screenshot, PlatformChannelError> {
+        let texture =
+            BackendData::last_flutter_frame(self).ok_or(PlatformChannelError::NothingRendered)?;
+        let region = flutter_frame_region(region, self.pixel_ratio, texture.size())
+            .ok_or(PlatformChannelError::EmptyRegion)?;
+
+        let gles_renderer = self.gles_renderer.as_mut().unwrap();
+        let mut screenshot = read_texture(
+            gles_renderer,
+            &texture,
+            flutter_frame_rect_in_texture(region, texture.size()),
+        )?;
+        screenshot.flip_vertically();
+        Ok(screenshot)
+    }
+
+    /// The surface is drawn the way the shell shows it, with its viewport and buffer transform,
+    /// at the resolution of its buffer.
+    fn capture_surface(&mut self, surface_id: u64) -> Result<Screenshot, PlatformChannelError> {
+        let surface = self
+            .surfaces
+            .get(&surface_id)
+            .ok_or(PlatformChannelError::SurfaceDoesntExist(surface_id))?;
+
+        let texture = self
+            .texture_ids_per_surface_id
+            .get(&surface_id)
+            .and_then(|texture_ids| texture_ids.last())
+            .and_then(|(texture_id, _)| self.texture_swapchains.get(texture_id))
+            .and_then(|swapchain| swapchain.newest.clone())
+            .ok_or(PlatformChannelError::NothingRendered)?;
+
+        let buffer = with_states(surface, |surface_data| {
+            let attributes = surface_data.cached_state.current::<SurfaceAttributes>();
+            SurfaceBuffer {
+                size: texture.size(),
+                scale: attributes.buffer_scale,
+                transform: attributes.buffer_transform.into(),
+                viewport: *surface_data.cached_state.current::<ViewportCachedState>(),
+                geometry: surface_data
+                    .cached_state
+                    .current::<SurfaceCachedState>()
+                    .geometry,
+            }
+        });
+        let (region, scale) = buffer
+            .capture_region()
+            .ok_or(PlatformChannelError::EmptyRegion)?;
+
+        let gles_renderer = self.gles_renderer.as_mut().unwrap();
+        let element = TextureRenderElement::from_static_texture(
+            Id::new(),
+            gles_renderer.id(),
+            Point::<f64, Logical>::from((-region.loc.x as f64, -region.loc.y as f64))
+                .to_physical(scale),
+            texture,
+            buffer.scale,
+            buffer.transform,
+            None,
+            buffer.viewport.src,
+            Some(buffer.surface_size()),
+            None,
+            Kind::Unspecified,
+        );
+        let size = region.size.to_f64().to_physical(scale).to_i32_round();
+        render_screenshot(gles_renderer, size, scale, &[element])
+    }
+}
+
+/// The physical rectangle of the Flutter frame showing a logical region of the monitor layout,
+/// `None` when the region is outside of the frame.
+fn flutter_frame_region(
+    region: Rectangle<i32, Logical>,
+    pixel_ratio: f64,
+    frame_size: Size<i32, BufferCoords>,
+) -> Option<Rectangle<i32, BufferCoords>> {
+    let region = region.to_f64().to_physical(pixel_ratio).to_i32_round();
+    Rectangle::<i32, BufferCoords>::from_loc_and_size(
+        (region.loc.x, region.loc.y),
+        (region.size.w, region.size.h),
+    )
+    .intersection(Rectangle::from_loc_and_size((0, 0), frame_size))
+    .filter(|region| !region.is_empty())
+}
+
+/// What a surface shows of its buffer.
+struct SurfaceBuffer {
+    size: Size<i32, BufferCoords>,
+    scale: i32,
+    transform: Transform,
+    viewport: ViewportCachedState,
+    geometry: Option<Rectangle<i32, Logical>>,
+}
+
+impl SurfaceBuffer {
+    /// The part of the buffer the viewport shows, in surface coordinates before the viewport
+    /// scales it.
+    fn source(&self) -> Rectangle<f64, Logical> {
+        self.viewport.src.unwrap_or_else(|| {
+            let size = self.size.to_logical(self.scale, self.transform);
+            Rectangle::from_loc_and_size((0.0, 0.0), size.to_f64())
+        })
+    }
+
+    fn surface_size(&self) -> Size<i32, Logical> {
+        self.viewport
+            .dst
+            .unwrap_or_else(|| self.source().size.to_i32_round())
+    }
+
+    /// The part of the surface in the screenshot, and the scale that keeps every pixel
+    /// of the buffer. The window geometry leaves out client-side shadows.
+    fn capture_region(&self) -> Option<(Rectangle<i32, Logical>, f64)> {
+        let surface = Rectangle::from_loc_and_size((0, 0), self.surface_size());
+        let region = self
+            .geometry
+            .unwrap_or(surface)
+            .intersection(surface)
+            .filter(|region| !region.is_empty())?;
+        let scale = self.scale as f64 * self.source().size.w / surface.size.w as f64;
+        Some((region, scale))
+    }
+}
+
+fn read_texture(
+    gles_renderer: &mut GlesRenderer,
+    texture: &GlesTexture,
+    region: Rectangle<i32, BufferCoords>,
+) -> Result<Screenshot, PlatformChannelError> {
+    let mapping = gles_renderer.copy_texture(texture, region, Fourcc::Abgr8888)?;
+    let pixels = gles_renderer.map_texture(&mapping)?.to_vec();
+    Ok(Screenshot {
+        width: region.size.w as u32,
+        height: region.size.h as u32,
+        pixels,
+    })
+}
+
+/// Draws the elements on a transparent texture of `size` and reads it back.
+fn render_screenshot(
+    gles_renderer: &mut GlesRenderer,
+    size: Size<i32, Physical>,
+    scale: f64,
+    elements: &[TextureRenderElement<GlesTexture>],
+) -> Result<Screenshot, PlatformChannelError> {
+    let texture: GlesTexture = Offscreen::<GlesTexture>::create_buffer(
+        gles_renderer,
+        Fourcc::Abgr8888,
+        (size.w, size.h).into(),
+    )
+    .map_err(PlatformChannelError::Render)?;
+    gles_renderer
+        .bind(texture)
+        .map_err(PlatformChannelError::Render)?;
+    let result = render_and_read(gles_renderer, size, scale, elements);
+    gles_renderer
+        .unbind()
+        .map_err(PlatformChannelError::Render)?;
+    result
+}
+
+fn render_and_read(
+    gles_renderer: &mut GlesRenderer,
+    size: Size<i32, Physical>,
+    scale: f64,
+    elements: &[TextureRenderElement<GlesTexture>],
+) -> Result<Screenshot, PlatformChannelError> {
+    let mut damage_tracker = OutputDamageTracker::new(size, scale, Transform::Normal);
+    damage_tracker
+        .render_output(gles_renderer, 0, elements, [0.0, 0.0, 0.0, 0.0])
+        .map_err(|err| match err {
+            damage::Error::Rendering(err) => PlatformChannelError::Render(err),
+            damage::Error::OutputNoMode(_) => unreachable!("The damage tracker has a fixed size"),
+        })?;
+
+    let mapping = gles_renderer.copy_framebuffer(
+        Rectangle::from_loc_and_size((0, 0), (size.w, size.h)),
+        Fourcc::Abgr8888,
+    )?;
+    let mut screenshot = Screenshot {
+        width: size.w as u32,
+        height: size.h as u32,
+        pixels: gles_renderer.map_texture(&mapping)?.to_vec(),
+    };
+    if mapping.flipped() {
+        screenshot.flip_vertically();
+    }
+    Ok(screenshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(size: (i32, i32), scale: i32, transform: Transform) -> SurfaceBuffer {
+        SurfaceBuffer {
+            size: size.into(),
+            scale,
+            transform,
+            viewport: ViewportCachedState::default(),
+            geometry: None,
+        }
+    }
+
+    #[test]
+    fn regions_are_read_from_the_flutter_frame() {
+        let frame_size = (3840, 2160).into();
+        let region = flutter_frame_region(
+            Rectangle::from_loc_and_size((100, 50), (200, 100)),
+            2.0,
+            frame_size,
+        );
+        assert_eq!(
+            region,
+            Some(Rectangle::from_loc_and_size((200, 100), (400, 200)))
+        );
+        // The rows of the frame go from bottom to top.
+        assert_eq!(
+            flutter_frame_rect_in_texture(region.unwrap(), frame_size),
+            Rectangle::from_loc_and_size((200, 2160 - 100 - 200), (400, 200))
+        );
+
+        let clipped = flutter_frame_region(
+            Rectangle::from_loc_and_size((1800, 1000), (500, 500)),
+            2.0,
+            frame_size,
+        );
+        assert_eq!(
+            clipped,
+            Some(Rectangle::from_loc_and_size((3600, 2000), (240, 160)))
+        );
+        let outside = flutter_frame_region(
+            Rectangle::from_loc_and_size((2000, 0), (100, 100)),
+            2.0,
+            frame_size,
+        );
+        assert_eq!(outside, None);
+    }
+
+    #[test]
+    fn surfaces_are_captured_at_the_resolution_of_their_buffer() {
+        let mut surface = buffer((200, 100), 2, Transform::Normal);
+        assert_eq!(
+            surface.capture_region(),
+            Some((Rectangle::from_loc_and_size((0, 0), (100, 50)), 2.0))
+        );
+        surface.geometry = Some(Rectangle::from_loc_and_size((10, 10), (200, 30)));
+        assert_eq!(
+            surface.capture_region(),
+            Some((Rectangle::from_loc_and_size((10, 10), (90, 30)), 2.0))
+        );
+
+        // A buffer rendered sideways.
+        let surface = buffer((100, 200), 1, Transform::_90);
+        assert_eq!(surface.surface_size(), (200, 100).into());
+
+        // A 1.5 scale client renders 150x75 pixels for a 100x50 surface.
+        let mut surface = buffer((150, 75), 1, Transform::Normal);
+        surface.viewport.dst = Some((100, 50).into());
+        assert_eq!(
+            surface.capture_region(),
+            Some((Rectangle::from_loc_and_size((0, 0), (100, 50)), 1.5))
+        );
+        // A video cropped to its middle.
+        surface.viewport.src = Some(Rectangle::from_loc_and_size((25.0, 0.0), (100.0, 75.0)));
+        assert_eq!(surface.capture_region().unwrap().1, 1.0);
+    }
+
+    #[test]
+    fn screenshots_are_saved_as_png() {
+        let mut screenshot = Screenshot {
+            width: 1,
+            height: 2,
+            pixels: vec![255, 0, 0, 255, 0, 0, 255, 255],
+        };
+        screenshot.flip_vertically();
+        assert_eq!(screenshot.pixels, [0, 0, 255, 255, 255, 0, 0, 255]);
+
+        let path = std::env::temp_dir().join(format!(
+            "veshell-screenshot-test-{}.png",
+            std::process::id()
+        ));
+        screenshot.save(&path).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((info.width, info.height), (1, 2));
+        assert_eq!(pixels, screenshot.pixels);
+    }
+}
@@ -0,0 +1,1837 @@
+//! Drives `ServerState` with real Wayland clients connected over a socket pair.
+//!
+//! There is no Flutter engine and no renderer, so every message the compositor
+//! would send to the shell is recorded in `ServerState::platform_messages` instead.
+
+use std::fs::File;
+use std::os::fd::AsFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+
+use serde_json::Value;
+use smithay::backend::input::{
+    KeyState, TabletToolCapabilities, TabletToolDescriptor, TabletToolType,
+};
+use smithay::input::pointer::{CursorIcon, CursorImageStatus, MotionEvent};
+use smithay::output::{Mode, Output, PhysicalProperties, Subpixel};
+use smithay::reexports::calloop::EventLoop;
+use smithay::reexports::wayland_server::Display;
+use smithay::utils::{Transform, SERIAL_COUNTER};
+use smithay::wayland::tablet_manager::TabletDescriptor;
+use wayland_client::protocol::{
+    wl_buffer, wl_callback, wl_compositor, wl_output, wl_pointer, wl_registry, wl_seat, wl_shm,
+    wl_shm_pool, wl_subcompositor, wl_subsurface, wl_surface,
+};
+use wayland_client::{
+    delegate_noop, event_created_child, Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+};
+use wayland_protocols::wp::cursor_shape::v1::client::{
+    wp_cursor_shape_device_v1, wp_cursor_shape_manager_v1,
+};
+use wayland_protocols::xdg::activation::v1::client::{xdg_activation_token_v1, xdg_activation_v1};
+use wayland_protocols::xdg::decoration::zv1::client::{
+    zxdg_decoration_manager_v1, zxdg_toplevel_decoration_v1,
+};
+use wayland_protocols::xdg::shell::client::{
+    xdg_popup, xdg_positioner, xdg_surface, xdg_toplevel, xdg_wm_base,
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1, zwlr_foreign_toplevel_manager_v1,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
+use wayland_protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1, zwlr_screencopy_manager_v1,
+};
+
+use crate::flutter_engine::wayland_messages::DecorationMode;
+use crate::focus::PointerFocusTarget;
+use crate::headless_backend::HeadlessBackend;
+use crate::input_handling::{
+    forget_tablet_tools, next_tablet_tool_device_id, send_stylus_axes, TabletTool,
+};
+use crate::keyboard::repeat_settings::KeyRepeatSettings;
+use crate::{Backend, ClientState};
+
+use super::key_bindings::{load_key_bindings_from, KeyBindingAction};
+use super::screenshot::ScreenshotTarget;
+use super::window_state::{SetWindowStatePayload, TiledEdges};
+use super::{monitors_bounding_box, scale_from_f64, ServerState};
+
+/// Where the tests save settings, instead of `~/.config`.
+fn test_config_home() -> PathBuf {
+    std::env::temp_dir().join(format!("veshell-tests-{}", std::process::id()))
+}
+
+/// Tells apart the files backing the buffers of tests running at the same time.
+static BUFFER_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+static TEST_ENVIRONMENT: Once = Once::new();
+
+/// Gives up if the compositor hasn't answered a `wl_display.sync` after this many iterations.
+const MAX_ROUNDTRIP_ITERATIONS: usize = 100;
+
+struct TestServer {
+    event_loop: EventLoop<'static, ServerState<HeadlessBackend>>,
+    state: ServerState<HeadlessBackend>,
+}
+
+impl TestServer {
+    fn new() -> Self {
+        // Tests run on several threads, the environment is only changed before the first server.
+        TEST_ENVIRONMENT.call_once(|| {
+            // The listening socket is never used by the tests, but it still needs a place to live.
+            if std::env::var_os("XDG_RUNTIME_DIR").is_none() {
+                std::env::set_var("XDG_RUNTIME_DIR", std::env::temp_dir());
+            }
+            // Settings saved by the tests must not end up with the ones of the user.
+            std::env::set_var("XDG_CONFIG_HOME", test_config_home());
+        });
+
+        let event_loop = EventLoop::try_new().unwrap();
+        let display = Display::new().unwrap();
+        let state = ServerState::new(
+            display,
+            event_loop.handle(),
+            HeadlessBackend::new(vec![]),
+            None,
+        );
+
+        Self { event_loop, state }
+    }
+
+    fn connect_client(&mut self) -> TestClient {
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        self.state
+            .display_handle
+            .insert_client(
+                server_stream,
+                Arc::new(ClientState::new(self.state.tx_client_disconnected.clone())),
+            )
+            .unwrap();
+
+        let connection = Connection::from_socket(client_stream).unwrap();
+        let event_queue = connection.new_event_queue();
+        let mut client = TestClient {
+            connection,
+            event_queue,
+            state: TestClientState::default(),
+        };
+
+        let qh = client.event_queue.handle();
+        client.connection.display().get_registry(&qh, ());
+        self.roundtrip(&mut client);
+        // Globals are bound while handling the registry events, so the binds need one more trip.
+        self.roundtrip(&mut client);
+
+        client
+    }
+
+    /// Exchanges messages until the compositor has processed every request sent so far
+    /// and the client has handled every event sent back.
+    fn roundtrip(&mut self, client: &mut TestClient) {
+        client.state.sync_done = false;
+        client
+            .connection
+            .display()
+            .sync(&client.event_queue.handle(), ());
+
+        for _ in 0..MAX_ROUNDTRIP_ITERATIONS {
+            client.connection.flush().unwrap();
+            self.event_loop
+                .dispatch(Some(Duration::ZERO), &mut self.state)
+                .unwrap();
+            self.state.display_handle.flush_clients().unwrap();
+
+            // The socket is non-blocking, reading fails with WouldBlock when there is nothing to read.
+            if let Some(guard) = client.event_queue.prepare_read() {
+                let _ = guard.read();
+            }
+            client
+                .event_queue
+                .dispatch_pending(&mut client.state)
+                .unwrap();
+
+            if client.state.sync_done {
+                return;
+            }
+        }
+        panic!("The compositor didn't answer the roundtrip");
+    }
+
+    /// Plugs in a monitor clients can bind.
+    fn add_monitor(&mut self, name: &str, w: i32, h: i32) {
+        let output = virtual_output(name, w, h);
+        self.state.backend_data.outputs.push(output.clone());
+        HeadlessBackend::update_monitor_layout(&mut self.state);
+        output.create_global::<ServerState<HeadlessBackend>>(&self.state.display_handle);
+    }
+
+    fn take_messages(&mut self) -> Vec<(String, Value)> {
+        std::mem::take(&mut self.state.platform_messages)
+    }
+}
+
+struct TestClient {
+    connection: Connection,
+    event_queue: EventQueue<TestClientState>,
+    state: TestClientState,
+}
+
+impl TestClient {
+    fn qh(&self) -> QueueHandle<TestClientState> {
+        self.event_queue.handle()
+    }
+
+    fn create_surface(&self) -> wl_surface::WlSurface {
+        self.state
+            .compositor
+            .as_ref()
+            .unwrap()
+            .create_surface(&self.qh(), ())
+    }
+
+    fn create_toplevel(
+        &self,
+    ) -> (
+        wl_surface::WlSurface,
+        xdg_surface::XdgSurface,
+        xdg_toplevel::XdgToplevel,
+    ) {
+        let surface = self.create_surface();
+        let xdg_surface =
+            self.state
+                .wm_base
+                .as_ref()
+                .unwrap()
+                .get_xdg_surface(&surface, &self.qh(), ());
+        let toplevel = xdg_surface.get_toplevel(&self.qh(), ());
+        (surface, xdg_surface, toplevel)
+    }
+
+    /// Attaches a blank buffer and commits, which maps toplevels once they acked a configure.
+    fn attach_buffer(&self, surface: &wl_surface::WlSurface) {
+        let (width, height) = (64, 64);
+        let stride = width * 4;
+        let path = std::env::temp_dir().join(format!(
+            "veshell-tests-buffer-{}-{}",
+            std::process::id(),
+            BUFFER_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.set_len((stride * height) as u64).unwrap();
+
+        let shm = self.state.shm.as_ref().unwrap();
+        let pool = shm.create_pool(file.as_fd(), stride * height, &self.qh(), ());
+        let buffer = pool.create_buffer(
+            0,
+            width,
+            height,
+            stride,
+            wl_shm::Format::Argb8888,
+            &self.qh(),
+            (),
+        );
+        pool.destroy();
+        surface.attach(Some(&buffer), 0, 0);
+        surface.commit();
+    }
+}
+
+#[derive(Default)]
+struct TestClientState {
+    compositor: Option<wl_compositor::WlCompositor>,
+    subcompositor: Option<wl_subcompositor::WlSubcompositor>,
+    shm: Option<wl_shm::WlShm>,
+    wm_base: Option<xdg_wm_base::XdgWmBase>,
+    layer_shell: Option<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+    decoration_manager: Option<zxdg_decoration_manager_v1::ZxdgDecorationManagerV1>,
+    decoration_mode: Option<zxdg_toplevel_decoration_v1::Mode>,
+    output: Option<wl_output::WlOutput>,
+    screencopy_manager: Option<zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1>,
+    screencopy_buffer_size: Option<(u32, u32)>,
+    foreign_toplevels: Vec<TestForeignToplevel>,
+    activation: Option<xdg_activation_v1::XdgActivationV1>,
+    activation_token: Option<String>,
+    seat: Option<wl_seat::WlSeat>,
+    cursor_shape_manager: Option<wp_cursor_shape_manager_v1::WpCursorShapeManagerV1>,
+    /// The serial of the last `wl_pointer.enter`, needed to set the cursor.
+    pointer_enter_serial: Option<u32>,
+    /// The `states` of the last toplevel configure.
+    toplevel_states: Vec<u8>,
+    toplevel_configures: usize,
+    frames_done: usize,
+    sync_done: bool,
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        else {
+            return;
+        };
+
+        match interface.as_str() {
+            "wl_compositor" => {
+                state.compositor = Some(registry.bind(name, version.min(4), qh, ()));
+            }
+            "wl_subcompositor" => {
+                state.subcompositor = Some(registry.bind(name, 1, qh, ()));
+            }
+            "wl_shm" => {
+                state.shm = Some(registry.bind(name, 1, qh, ()));
+            }
+            "xdg_wm_base" => {
+                state.wm_base = Some(registry.bind(name, version.min(3), qh, ()));
+            }
+            "zxdg_decoration_manager_v1" => {
+                state.decoration_manager = Some(registry.bind(name, 1, qh, ()));
+            }
+            "zwlr_layer_shell_v1" => {
+                state.layer_shell = Some(registry.bind(name, version.min(4), qh, ()));
+            }
+            "wl_output" => {
+                state.output = Some(registry.bind(name, version.min(4), qh, ()));
+            }
+            "zwlr_screencopy_manager_v1" => {
+                state.screencopy_manager = Some(registry.bind(name, version.min(3), qh, ()));
+            }
+            "xdg_activation_v1" => {
+                state.activation = Some(registry.bind(name, 1, qh, ()));
+            }
+            "wl_seat" => {
+                state.seat = Some(registry.bind(name, version.min(5), qh, ()));
+            }
+            "wp_cursor_shape_manager_v1" => {
+                state.cursor_shape_manager = Some(registry.bind(name, 1, qh, ()));
+            }
+            "zwlr_foreign_toplevel_manager_v1" => {
+                registry
+                    .bind::<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, _, _>(
+                        name,
+                        version.min(3),
+                        qh,
+                        (),
+                    );
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<wl_callback::WlCallback, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &wl_callback::WlCallback,
+        event: wl_callback::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.sync_done = true;
+        }
+    }
+}
+
+/// User data of the `wl_surface.frame` callbacks, told apart from the `wl_display.sync` ones.
+struct FrameCallback;
+
+impl Dispatch<wl_callback::WlCallback, FrameCallback> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &wl_callback::WlCallback,
+        event: wl_callback::Event,
+        _: &FrameCallback,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            state.frames_done += 1;
+        }
+    }
+}
+
+impl Dispatch<xdg_wm_base::XdgWmBase, ()> for TestClientState {
+    fn event(
+        _: &mut Self,
+        wm_base: &xdg_wm_base::XdgWmBase,
+        event: xdg_wm_base::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<xdg_surface::XdgSurface, ()> for TestClientState {
+    fn event(
+        _: &mut Self,
+        xdg_surface: &xdg_surface::XdgSurface,
+        event: xdg_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+        }
+    }
+}
+
+impl Dispatch<xdg_toplevel::XdgToplevel, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &xdg_toplevel::XdgToplevel,
+        event: xdg_toplevel::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_toplevel::Event::Configure { states, .. } = event {
+            state.toplevel_states = states;
+            state.toplevel_configures += 1;
+        }
+    }
+}
+
+impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, ()> for TestClientState {
+    fn event(
+        _: &mut Self,
+        layer_surface: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+        event: zwlr_layer_surface_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_layer_surface_v1::Event::Configure { serial, .. } = event {
+            layer_surface.ack_configure(serial);
+        }
+    }
+}
+
+impl Dispatch<zxdg_toplevel_decoration_v1::ZxdgToplevelDecorationV1, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &zxdg_toplevel_decoration_v1::ZxdgToplevelDecorationV1,
+        event: zxdg_toplevel_decoration_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zxdg_toplevel_decoration_v1::Event::Configure { mode } = event {
+            state.decoration_mode = mode.into_result().ok();
+        }
+    }
+}
+
+impl Dispatch<zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
+        event: zwlr_screencopy_frame_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_screencopy_frame_v1::Event::Buffer { width, height, .. } = event {
+            state.screencopy_buffer_size = Some((width, height));
+        }
+    }
+}
+
+impl Dispatch<xdg_activation_token_v1::XdgActivationTokenV1, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &xdg_activation_token_v1::XdgActivationTokenV1,
+        event: xdg_activation_token_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_activation_token_v1::Event::Done { token } = event {
+            state.activation_token = Some(token);
+        }
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for TestClientState {
+    fn event(
+        state: &mut Self,
+        _: &wl_pointer::WlPointer,
+        event: wl_pointer::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_pointer::Event::Enter { serial, .. } = event {
+            state.pointer_enter_serial = Some(serial);
+        }
+    }
+}
+
+/// What a taskbar knows about a window.
+struct TestForeignToplevel {
+    handle: zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1,
+    title: Option<String>,
+    states: Vec<u8>,
+    outputs: Vec<wl_output::WlOutput>,
+    closed: bool,
+}
+
+impl Dispatch<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, ()>
+    for TestClientState
+{
+    fn event(
+        state: &mut Self,
+        _: &zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } = event {
+            state.foreign_toplevels.push(TestForeignToplevel {
+                handle: toplevel,
+                title: None,
+                states: vec![],
+                outputs: vec![],
+                closed: false,
+            });
+        }
+    }
+
+    event_created_child!(TestClientState, zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1, ()>
+    for TestClientState
+{
+    fn event(
+        state: &mut Self,
+        handle: &zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let Some(toplevel) = state
+            .foreign_toplevels
+            .iter_mut()
+            .find(|toplevel| toplevel.handle.id() == handle.id())
+        else {
+            return;
+        };
+        match event {
+            zwlr_foreign_toplevel_handle_v1::Event::Title { title } => toplevel.title = Some(title),
+            zwlr_foreign_toplevel_handle_v1::Event::State { state } => toplevel.states = state,
+            zwlr_foreign_toplevel_handle_v1::Event::OutputEnter { output } => {
+                toplevel.outputs.push(output)
+            }
+            zwlr_foreign_toplevel_handle_v1::Event::Closed => toplevel.closed = true,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(TestClientState: wl_compositor::WlCompositor);
+delegate_noop!(TestClientState: wl_subcompositor::WlSubcompositor);
+delegate_noop!(TestClientState: wl_subsurface::WlSubsurface);
+delegate_noop!(TestClientState: wl_shm_pool::WlShmPool);
+delegate_noop!(TestClientState: xdg_positioner::XdgPositioner);
+delegate_noop!(TestClientState: zwlr_layer_shell_v1::ZwlrLayerShellV1);
+delegate_noop!(TestClientState: zxdg_decoration_manager_v1::ZxdgDecorationManagerV1);
+delegate_noop!(TestClientState: zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1);
+delegate_noop!(TestClientState: xdg_activation_v1::XdgActivationV1);
+delegate_noop!(TestClientState: wp_cursor_shape_manager_v1::WpCursorShapeManagerV1);
+delegate_noop!(TestClientState: wp_cursor_shape_device_v1::WpCursorShapeDeviceV1);
+delegate_noop!(TestClientState: ignore wl_seat::WlSeat);
+delegate_noop!(TestClientState: ignore wl_shm::WlShm);
+delegate_noop!(TestClientState: ignore wl_buffer::WlBuffer);
+delegate_noop!(TestClientState: ignore wl_surface::WlSurface);
+delegate_noop!(TestClientState: ignore wl_output::WlOutput);
+delegate_noop!(TestClientState: ignore xdg_popup::XdgPopup);
+
+fn find_message<'a>(messages: &'a [(String, Value)], method: &str) -> &'a Value {
+    messages
+        .iter()
+        .find(|(name, _)| name == method)
+        .map(|(_, arguments)| arguments)
+        .unwrap_or_else(|| panic!("No {method} message in {messages:?}"))
+}
+
+fn find_last_message<'a>(messages: &'a [(String, Value)], method: &str) -> &'a Value {
+    messages
+        .iter()
+        .rev()
+        .find(|(name, _)| name == method)
+        .map(|(_, arguments)| arguments)
+        .unwrap_or_else(|| panic!("No {method} message in {messages:?}"))
+}
+
+fn surface_id_of(arguments: &Value) -> u64 {
+    arguments["surfaceId"].as_u64().unwrap()
+}
+
+#[test]
+fn new_surface_is_registered() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let _surface = client.create_surface();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let surface_id = surface_id_of(find_message(&messages, "new_surface"));
+    assert!(server.state.surfaces.contains_key(&surface_id));
+}
+
+#[test]
+fn toplevel_lifecycle() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (surface, xdg_surface, toplevel) = client.create_toplevel();
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let surface_id = surface_id_of(find_message(&messages, "new_surface"));
+    assert_eq!(
+        surface_id_of(find_message(&messages, "new_toplevel")),
+        surface_id
+    );
+    assert!(server.state.xdg_toplevels.contains_key(&surface_id));
+
+    // The first commit only triggers the initial configure.
+    let commit = find_last_message(&messages, "commit_surface");
+    assert_eq!(commit["role"]["type"], "xdgSurface");
+    assert!(commit["role"]["role"].is_null());
+
+    // The configure has been acked during the roundtrip, the surface is now mapped.
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let commit = find_last_message(&messages, "commit_surface");
+    assert_eq!(surface_id_of(commit), surface_id);
+    assert_eq!(commit["role"]["role"]["type"], "xdgToplevel");
+    assert_eq!(commit["textureId"], -1);
+
+    toplevel.destroy();
+    xdg_surface.destroy();
+    surface.destroy();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    assert_eq!(
+        surface_id_of(find_message(&messages, "destroy_toplevel")),
+        surface_id
+    );
+    assert_eq!(
+        surface_id_of(find_message(&messages, "destroy_surface")),
+        surface_id
+    );
+    assert!(!server.state.xdg_toplevels.contains_key(&surface_id));
+    assert!(!server.state.surfaces.contains_key(&surface_id));
+    assert!(!server
+        .state
+        .texture_ids_per_surface_id
+        .contains_key(&surface_id));
+}
+
+#[test]
+fn decoration_mode_is_negotiated() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (surface, _xdg_surface, toplevel) = client.create_toplevel();
+    let decoration = client
+        .state
+        .decoration_manager
+        .as_ref()
+        .unwrap()
+        .get_toplevel_decoration(&toplevel, &client.qh(), ());
+    decoration.set_mode(zxdg_toplevel_decoration_v1::Mode::ServerSide);
+    surface.commit();
+    server.roundtrip(&mut client);
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let commit = find_last_message(&messages, "commit_surface");
+    let surface_id = surface_id_of(commit);
+    assert_eq!(commit["role"]["role"]["decorationMode"], "serverSide");
+    assert_eq!(
+        client.state.decoration_mode,
+        Some(zxdg_toplevel_decoration_v1::Mode::ServerSide)
+    );
+
+    // The shell draws no frame around this window, the client has to.
+    server
+        .state
+        .set_decoration_mode(surface_id, DecorationMode::ClientSide);
+    server.roundtrip(&mut client);
+
+    assert_eq!(
+        client.state.decoration_mode,
+        Some(zxdg_toplevel_decoration_v1::Mode::ClientSide)
+    );
+
+    // The shell's choice holds, the client's new request is only reported.
+    decoration.set_mode(zxdg_toplevel_decoration_v1::Mode::ServerSide);
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let commit = find_last_message(&messages, "commit_surface");
+    assert_eq!(commit["role"]["role"]["decorationMode"], "serverSide");
+    assert_eq!(
+        client.state.decoration_mode,
+        Some(zxdg_toplevel_decoration_v1::Mode::ClientSide)
+    );
+}
+
+#[test]
+fn popup_lifecycle() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (parent_surface, parent_xdg_surface, _parent_toplevel) = client.create_toplevel();
+    parent_surface.commit();
+    server.roundtrip(&mut client);
+    parent_surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let parent_id = surface_id_of(find_message(&messages, "new_toplevel"));
+
+    let wm_base = client.state.wm_base.clone().unwrap();
+    let positioner = wm_base.create_positioner(&client.qh(), ());
+    positioner.set_size(100, 100);
+    positioner.set_anchor_rect(0, 0, 1, 1);
+
+    let surface = client.create_surface();
+    let xdg_surface = wm_base.get_xdg_surface(&surface, &client.qh(), ());
+    let popup = xdg_surface.get_popup(Some(&parent_xdg_surface), &positioner, &client.qh(), ());
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let new_popup = find_message(&messages, "new_popup");
+    let surface_id = surface_id_of(new_popup);
+    assert_eq!(new_popup["parent"], parent_id);
+    assert!(server.state.xdg_popups.contains_key(&surface_id));
+
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let commit = find_last_message(&messages, "commit_surface");
+    assert_eq!(surface_id_of(commit), surface_id);
+    assert_eq!(commit["role"]["role"]["type"], "xdgPopup");
+    assert_eq!(commit["role"]["role"]["parent"], parent_id);
+
+    popup.destroy();
+    xdg_surface.destroy();
+    surface.destroy();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    assert_eq!(
+        surface_id_of(find_message(&messages, "destroy_popup")),
+        surface_id
+    );
+    assert!(!server.state.xdg_popups.contains_key(&surface_id));
+    assert!(!server.state.surfaces.contains_key(&surface_id));
+    assert!(server.state.xdg_toplevels.contains_key(&parent_id));
+}
+
+#[test]
+fn subsurface_lifecycle() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let parent = client.create_surface();
+    let surface = client.create_surface();
+    let subsurface = client.state.subcompositor.as_ref().unwrap().get_subsurface(
+        &surface,
+        &parent,
+        &client.qh(),
+        (),
+    );
+    subsurface.set_position(10, 20);
+    surface.commit();
+    parent.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let new_subsurface = find_message(&messages, "new_subsurface");
+    let surface_id = surface_id_of(new_subsurface);
+    let parent_id = new_subsurface["parent"].as_u64().unwrap();
+    assert!(server.state.subsurfaces.contains_key(&surface_id));
+
+    // Committing the parent also commits the subsurface.
+    let subsurface_commit = messages
+        .iter()
+        .rev()
+        .find(|(name, arguments)| {
+            name == "commit_surface" && surface_id_of(arguments) == surface_id
+        })
+        .map(|(_, arguments)| arguments)
+        .unwrap();
+    assert_eq!(subsurface_commit["role"]["type"], "subsurface");
+    assert_eq!(subsurface_commit["role"]["parent"], parent_id);
+    assert_eq!(subsurface_commit["role"]["position"]["x"], 10);
+    assert_eq!(subsurface_commit["role"]["position"]["y"], 20);
+
+    let parent_commit = find_last_message(&messages, "commit_surface");
+    assert_eq!(surface_id_of(parent_commit), parent_id);
+    assert_eq!(
+        parent_commit["subsurfacesAbove"],
+        serde_json::json!([surface_id])
+    );
+
+    subsurface.destroy();
+    surface.destroy();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    assert_eq!(
+        surface_id_of(find_message(&messages, "destroy_surface")),
+        surface_id
+    );
+    assert!(!server.state.subsurfaces.contains_key(&surface_id));
+    assert!(!server.state.surfaces.contains_key(&surface_id));
+    assert!(server.state.surfaces.contains_key(&parent_id));
+}
+
+#[test]
+fn layer_surface_lifecycle() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let surface = client.create_surface();
+    let layer_surface = client
+        .state
+        .layer_shell
+        .as_ref()
+        .unwrap()
+        .get_layer_surface(
+            &surface,
+            None,
+            zwlr_layer_shell_v1::Layer::Top,
+            "panel".to_string(),
+            &client.qh(),
+            (),
+        );
+    layer_surface.set_size(0, 30);
+    layer_surface.set_anchor(
+        zwlr_layer_surface_v1::Anchor::Top
+            | zwlr_layer_surface_v1::Anchor::Left
+            | zwlr_layer_surface_v1::Anchor::Right,
+    );
+    layer_surface.set_exclusive_zone(30);
+    layer_surface.set_margin(1, 2, 3, 4);
+    layer_surface
+        .set_keyboard_interactivity(zwlr_layer_surface_v1::KeyboardInteractivity::OnDemand);
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let new_layer_surface = find_message(&messages, "new_layer_surface");
+    let surface_id = surface_id_of(new_layer_surface);
+    assert_eq!(new_layer_surface["namespace"], "panel");
+    assert_eq!(new_layer_surface["layer"], "top");
+    assert!(server.state.layer_surfaces.contains_key(&surface_id));
+
+    // The first commit only triggers the initial configure.
+    assert!(find_last_message(&messages, "commit_surface")["role"].is_null());
+
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let role = &find_last_message(&messages, "commit_surface")["role"];
+    assert_eq!(role["type"], "layerSurface");
+    assert_eq!(role["layer"], "top");
+    assert_eq!(
+        role["anchor"],
+        serde_json::json!({"top": true, "bottom": false, "left": true, "right": true})
+    );
+    assert_eq!(role["exclusiveZone"], 30);
+    assert_eq!(
+        role["margin"],
+        serde_json::json!({"top": 1, "right": 2, "bottom": 3, "left": 4})
+    );
+    assert_eq!(role["keyboardInteractivity"], "onDemand");
+    assert_eq!(role["desiredSize"]["height"], 30);
+
+    layer_surface.destroy();
+    surface.destroy();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    assert_eq!(
+        surface_id_of(find_message(&messages, "destroy_layer_surface")),
+        surface_id
+    );
+    assert!(!server.state.layer_surfaces.contains_key(&surface_id));
+}
+
+#[test]
+fn layer_surfaces_get_frame_callbacks() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let surface = client.create_surface();
+    let layer_surface = client
+        .state
+        .layer_shell
+        .as_ref()
+        .unwrap()
+        .get_layer_surface(
+            &surface,
+            None,
+            zwlr_layer_shell_v1::Layer::Background,
+            "wallpaper".to_string(),
+            &client.qh(),
+            (),
+        );
+    layer_surface.set_size(200, 30);
+    surface.commit();
+    server.roundtrip(&mut client);
+
+    surface.frame(&client.qh(), FrameCallback);
+    client.attach_buffer(&surface);
+    server.roundtrip(&mut client);
+    assert_eq!(client.state.frames_done, 0);
+
+    // What the backends do once a frame was shown.
+    server.state.send_frame_callbacks();
+    server.roundtrip(&mut client);
+    assert_eq!(client.state.frames_done, 1);
+}
+
+fn virtual_output(name: &str, w: i32, h: i32) -> Output {
+    let output = Output::new(
+        name.to_string(),
+        PhysicalProperties {
+            size: (0, 0).into(),
+            subpixel: Subpixel::Unknown,
+            make: "Veshell".into(),
+            model: "test".into(),
+        },
+    );
+    let mode = Mode {
+        size: (w, h).into(),
+        refresh: 60_000,
+    };
+    output.change_current_state(Some(mode), None, None, None);
+    output
+}
+
+#[test]
+fn monitor_scale_resizes_the_view() {
+    let mut server = TestServer::new();
+    let first = virtual_output("first", 1920, 1080);
+    let second = virtual_output("second", 3840, 2160);
+    server.state.backend_data.outputs = vec![first.clone(), second.clone()];
+
+    second.change_current_state(None, None, Some(scale_from_f64(2.0)), None);
+    HeadlessBackend::update_monitor_layout(&mut server.state);
+
+    assert_eq!(second.current_location(), (1920, 0).into());
+    assert_eq!(server.state.pixel_ratio, 2.0);
+    // The view spans 3840x1080 logical pixels and renders at the highest scale.
+    let size = server.state.update_window_metrics();
+    assert_eq!(size, (7680, 2160).into());
+
+    second.change_current_state(None, None, Some(scale_from_f64(1.5)), None);
+    HeadlessBackend::update_monitor_layout(&mut server.state);
+
+    assert_eq!(server.state.pixel_ratio, 1.5);
+    assert_eq!(
+        monitors_bounding_box(&[first, second]).size,
+        (1920 + 2560, 1440).into()
+    );
+}
+
+#[test]
+fn clients_render_at_the_scale_of_their_monitor() {
+    let mut server = TestServer::new();
+    let first = virtual_output("first", 1920, 1080);
+    let second = virtual_output("second", 3840, 2160);
+    second.change_current_state(None, None, Some(scale_from_f64(2.0)), None);
+    server.state.backend_data.outputs = vec![first, second];
+    HeadlessBackend::update_monitor_layout(&mut server.state);
+    let mut client = server.connect_client();
+
+    let (surface, _xdg_surface, _toplevel) = client.create_toplevel();
+    let child = client.create_surface();
+    client
+        .state
+        .subcompositor
+        .as_ref()
+        .unwrap()
+        .get_subsurface(&child, &surface, &client.qh(), ());
+    surface.commit();
+    let panel = client.create_surface();
+    let layer_surface = client
+        .state
+        .layer_shell
+        .as_ref()
+        .unwrap()
+        .get_layer_surface(
+            &panel,
+            None,
+            zwlr_layer_shell_v1::Layer::Top,
+            "panel".to_string(),
+            &client.qh(),
+            (),
+        );
+    layer_surface.set_size(200, 30);
+    panel.commit();
+    server.roundtrip(&mut client);
+    // Only mapped windows are placed by the shell.
+    client.attach_buffer(&surface);
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let surface_id = surface_id_of(find_message(&messages, "new_toplevel"));
+    let child_id = surface_id_of(find_message(&messages, "new_subsurface"));
+    let panel_id = surface_id_of(find_message(&messages, "new_layer_surface"));
+    let preferred_scale = |server: &TestServer, surface_id| {
+        let surface = server.state.surfaces.get(&surface_id).unwrap();
+        server.state.preferred_scale(surface)
+    };
+
+    // Flutter renders at the highest scale, clients at the one of their monitor.
+    assert_eq!(server.state.pixel_ratio, 2.0);
+    // Panels without a monitor go on the first one.
+    assert_eq!(preferred_scale(&server, panel_id), 1.0);
+    // Windows the shell didn't place yet follow the sharpest monitor.
+    assert_eq!(preferred_scale(&server, surface_id), 2.0);
+
+    server
+        .state
+        .set_foreign_toplevel_state(surface_id, false, false, Some(vec!["first".to_string()]))
+        .unwrap();
+    assert_eq!(preferred_scale(&server, surface_id), 1.0);
+    assert_eq!(preferred_scale(&server, child_id), 1.0);
+
+    server
+        .state
+        .set_foreign_toplevel_state(surface_id, false, false, Some(vec!["second".to_string()]))
+        .unwrap();
+    assert_eq!(preferred_scale(&server, child_id), 2.0);
+}
+
+#[test]
+fn configure_monitor_places_and_transforms_monitors() {
+    let mut server = TestServer::new();
+    let first = virtual_output("first", 1920, 1080);
+    let second = virtual_output("second", 1280, 720);
+    server.state.backend_data.outputs = vec![first.clone(), second.clone()];
+    HeadlessBackend::update_monitor_layout(&mut server.state);
+
+    let configuration = serde_json::from_value(serde_json::json!({
+        "monitorName": "second",
+        "position": { "x": -720, "y": 100 },
+        "transform": "rotate90",
+        "modeIndex": 0,
+    }))
+    .unwrap();
+    HeadlessBackend::configure_monitor(&mut server.state, configuration).unwrap();
+
+    // The rotated monitor is 720 pixels wide, the other one goes to its right,
+    // then the layout is moved to start at (0, 0).
+    assert_eq!(second.current_transform(), Transform::_90);
+    assert_eq!(second.current_location(), (0, 0).into());
+    assert_eq!(first.current_location(), (720, 0).into());
+    assert_eq!(
+        server.state.update_window_metrics(),
+        (720 + 1920, 1280).into()
+    );
+}
+
+#[test]
+fn configure_monitor_rejects_invalid_configurations() {
+    let mut server = TestServer::new();
+    server.state.backend_data.outputs = vec![virtual_output("first", 1920, 1080)];
+    HeadlessBackend::update_monitor_layout(&mut server.state);
+
+    let mut configure = |arguments: Value| {
+        let configuration = serde_json::from_value(arguments).unwrap();
+        HeadlessBackend::configure_monitor(&mut server.state, configuration)
+            .unwrap_err()
+            .code()
+    };
+
+    assert_eq!(
+        configure(serde_json::json!({ "monitorName": "missing" })),
+        "monitor_doesnt_exist"
+    );
+    assert_eq!(
+        configure(serde_json::json!({ "monitorName": "first", "modeIndex": 1 })),
+        "invalid_mode"
+    );
+    assert_eq!(
+        configure(serde_json::json!({ "monitorName": "first", "scale": 0.0 })),
+        "invalid_scale"
+    );
+}
+
+#[test]
+fn direct_scanout_needs_a_capable_backend() {
+    let mut server = TestServer::new();
+    server.state.backend_data.outputs = vec![virtual_output("first", 1920, 1080)];
+    let mut client = server.connect_client();
+
+    let _surface = client.create_surface();
+    server.roundtrip(&mut client);
+    let messages = server.take_messages();
+    let surface_id = surface_id_of(find_message(&messages, "new_surface"));
+
+    let error = server
+        .state
+        .set_direct_scanout(surface_id + 1, Some("first".to_string()))
+        .unwrap_err();
+    assert_eq!(error.code(), "surface_doesnt_exist");
+
+    // Virtual outputs don't have planes.
+    let error = server
+        .state
+        .set_direct_scanout(surface_id, Some("first".to_string()))
+        .unwrap_err();
+    assert_eq!(error.code(), "unsupported");
+    assert!(!server.state.has_direct_scanout(surface_id));
+
+    server.state.set_direct_scanout(surface_id, None).unwrap();
+}
+
+#[test]
+fn keyboard_layout_must_exist() {
+    let mut server = TestServer::new();
+    let (layouts, active) = server.state.keyboard_layouts();
+    assert!(!layouts.is_empty());
+    assert_eq!(active, 0);
+
+    let error = server
+        .state
+        .set_keyboard_layout(layouts.len() as u32)
+        .unwrap_err();
+    assert_eq!(error.code(), "keyboard_layout_doesnt_exist");
+
+    // Switching to the active layout doesn't bother the shell.
+    server.state.set_keyboard_layout(0).unwrap();
+    let messages = server.take_messages();
+    assert!(messages
+        .iter()
+        .all(|(method, _)| method != "keyboard_layout_changed"));
+}
+
+#[test]
+fn key_repeat_settings_are_validated() {
+    let mut server = TestServer::new();
+    let previous = server.state.key_repeat;
+
+    let error = server
+        .state
+        .change_keyboard_repeat_info(KeyRepeatSettings {
+            enabled: true,
+            delay: 300,
+            rate: 0,
+        })
+        .unwrap_err();
+    assert_eq!(error.code(), "invalid_repeat_rate");
+    assert_eq!(server.state.key_repeat, previous);
+}
+
+#[test]
+fn key_bindings_are_parsed_and_replaced() {
+    let mut server = TestServer::new();
+    server.state.key_bindings.clear();
+
+    let error = server
+        .state
+        .register_key_binding("Ctrl+Hyper+T", KeyBindingAction::Exit)
+        .unwrap_err();
+    assert_eq!(error.code(), "invalid_keys");
+
+    let action = KeyBindingAction::Flutter {
+        name: "launcher".to_string(),
+    };
+    server
+        .state
+        .register_key_binding("Super+space", KeyBindingAction::Exit)
+        .unwrap();
+    server
+        .state
+        .register_key_binding("super+Space", action.clone())
+        .unwrap();
+    assert_eq!(server.state.key_bindings.len(), 1);
+    assert_eq!(server.state.key_bindings[0].action, action);
+
+    server.state.unregister_key_binding("Super+space").unwrap();
+    let error = server
+        .state
+        .unregister_key_binding("Super+space")
+        .unwrap_err();
+    assert_eq!(error.code(), "key_binding_doesnt_exist");
+}
+
+#[test]
+fn broken_key_bindings_file_falls_back_to_the_defaults() {
+    let path = test_config_home().join("broken_key_bindings.json");
+    std::fs::create_dir_all(test_config_home()).unwrap();
+
+    std::fs::write(
+        &path,
+        r#"[{ "keys": "Super+T", "action": { "type": "spawn" } }]"#,
+    )
+    .unwrap();
+    let key_bindings = load_key_bindings_from(&path);
+    assert_eq!(key_bindings.len(), 1);
+    assert_eq!(key_bindings[0].action, KeyBindingAction::Exit);
+
+    std::fs::write(
+        &path,
+        r#"[{ "keys": "Super+T", "action": { "type": "spawn", "command": "foot" } }]"#,
+    )
+    .unwrap();
+    let key_bindings = load_key_bindings_from(&path);
+    assert_eq!(
+        key_bindings[0].action,
+        KeyBindingAction::Spawn {
+            command: "foot".to_string()
+        }
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn bound_keys_are_swallowed() {
+    let mut server = TestServer::new();
+    server
+        .state
+        .register_key_binding(
+            "Escape",
+            KeyBindingAction::Flutter {
+                name: "overview".to_string(),
+            },
+        )
+        .unwrap();
+    server.take_messages();
+
+    // There is no Flutter engine, a key forwarded to it would panic.
+    let key_code = input_linux::sys::KEY_ESC as u32;
+    server
+        .state
+        .handle_key_event(key_code, KeyState::Pressed, 0);
+    assert!(server.state.intercepted_keys.contains(&key_code));
+    server
+        .state
+        .handle_key_event(key_code, KeyState::Released, 0);
+    assert!(server.state.intercepted_keys.is_empty());
+
+    let messages = server.take_messages();
+    assert_eq!(
+        find_message(&messages, "key_binding_action")["name"],
+        "overview"
+    );
+}
+
+#[test]
+fn bindings_with_shift_match_the_unshifted_key() {
+    let mut server = TestServer::new();
+    server
+        .state
+        .register_key_binding(
+            "Super+Shift+1",
+            KeyBindingAction::Flutter {
+                name: "move_to_workspace_1".to_string(),
+            },
+        )
+        .unwrap();
+    server.take_messages();
+
+    // Modifiers not bound to anything would go to Flutter, they only update the keyboard state.
+    let keyboard = server.state.keyboard.clone();
+    for key in [
+        input_linux::sys::KEY_LEFTMETA,
+        input_linux::sys::KEY_LEFTSHIFT,
+    ] {
+        keyboard.input_intercept::<_, _>(
+            &mut server.state,
+            key as u32,
+            KeyState::Pressed,
+            |_, _, _| (),
+        );
+    }
+
+    // Shift turns the key into `exclam`.
+    let key_code = input_linux::sys::KEY_1 as u32;
+    server
+        .state
+        .handle_key_event(key_code, KeyState::Pressed, 0);
+    assert!(server.state.intercepted_keys.contains(&key_code));
+
+    let messages = server.take_messages();
+    assert_eq!(
+        find_message(&messages, "key_binding_action")["name"],
+        "move_to_workspace_1"
+    );
+}
+
+#[test]
+fn screenshot_targets_must_exist() {
+    let mut server = TestServer::new();
+
+    let error = |server: &mut TestServer, target| {
+        let result = server.state.capture_screenshot(&target);
+        result.err().unwrap().code()
+    };
+    assert_eq!(
+        error(
+            &mut server,
+            ScreenshotTarget::Monitor {
+                name: "HDMI-A-1".to_string()
+            }
+        ),
+        "monitor_doesnt_exist"
+    );
+    assert_eq!(
+        error(&mut server, ScreenshotTarget::Surface { surface_id: 42 }),
+        "surface_doesnt_exist"
+    );
+    assert_eq!(
+        error(&mut server, ScreenshotTarget::All),
+        "nothing_rendered"
+    );
+}
+
+#[test]
+fn screen_capture_waits_for_the_shell_permission() {
+    let mut server = TestServer::new();
+    server.add_monitor("first", 1920, 1080);
+    let mut client = server.connect_client();
+    server.take_messages();
+
+    let manager = client.state.screencopy_manager.clone().unwrap();
+    let wl_output = client.state.output.clone().unwrap();
+    let _frame = manager.capture_output(1, &wl_output, &client.qh(), ());
+    server.roundtrip(&mut client);
+
+    assert_eq!(client.state.screencopy_buffer_size, Some((1920, 1080)));
+    let messages = server.take_messages();
+    let request_id = find_message(&messages, "screen_capture_permission_request")["requestId"]
+        .as_u64()
+        .unwrap();
+
+    let error = server
+        .state
+        .answer_screen_capture_permission(request_id + 1, true)
+        .unwrap_err();
+    assert_eq!(error.code(), "unknown_permission_request");
+    server
+        .state
+        .answer_screen_capture_permission(request_id, true)
+        .unwrap();
+
+    // The answer holds for the next captures of the client.
+    let _frame = manager.capture_output(0, &wl_output, &client.qh(), ());
+    server.roundtrip(&mut client);
+    assert!(server
+        .take_messages()
+        .iter()
+        .all(|(method, _)| method != "screen_capture_permission_request"));
+}
+
+#[test]
+fn screen_capture_permission_requests_are_dropped_with_their_client() {
+    let mut server = TestServer::new();
+    server.add_monitor("first", 1920, 1080);
+    let mut client = server.connect_client();
+
+    let manager = client.state.screencopy_manager.clone().unwrap();
+    let wl_output = client.state.output.clone().unwrap();
+    let _frame = manager.capture_output(0, &wl_output, &client.qh(), ());
+    server.roundtrip(&mut client);
+    let request_id = find_message(&server.take_messages(), "screen_capture_permission_request")
+        ["requestId"]
+        .as_u64()
+        .unwrap();
+
+    drop(client);
+    for _ in 0..MAX_ROUNDTRIP_ITERATIONS {
+        server
+            .event_loop
+            .dispatch(Some(Duration::ZERO), &mut server.state)
+            .unwrap();
+    }
+
+    assert!(server.state.screen_capture_permission_requests.is_empty());
+    let messages = server.take_messages();
+    assert_eq!(
+        find_message(&messages, "screen_capture_permission_request_cancelled")["requestId"],
+        request_id
+    );
+    assert_eq!(
+        server
+            .state
+            .answer_screen_capture_permission(request_id, true)
+            .unwrap_err()
+            .code(),
+        "unknown_permission_request"
+    );
+}
+
+#[test]
+fn foreign_toplevels_follow_the_windows() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (surface, xdg_surface, toplevel) = client.create_toplevel();
+    toplevel.set_title("Terminal".to_string());
+    surface.commit();
+    server.roundtrip(&mut client);
+    // Windows are only listed once they show something.
+    assert!(client.state.foreign_toplevels.is_empty());
+
+    client.attach_buffer(&surface);
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let surface_id = surface_id_of(find_message(&messages, "new_toplevel"));
+    assert_eq!(client.state.foreign_toplevels.len(), 1);
+    assert_eq!(
+        client.state.foreign_toplevels[0].title.as_deref(),
+        Some("Terminal")
+    );
+
+    // Requests are left to the shell.
+    client.state.foreign_toplevels[0].handle.close();
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let request = find_message(&messages, "foreign_toplevel_request");
+    assert_eq!(surface_id_of(request), surface_id);
+    assert_eq!(request["action"], "close");
+
+    let error = server
+        .state
+        .set_foreign_toplevel_state(surface_id + 1, true, false, None)
+        .unwrap_err();
+    assert_eq!(error.code(), "surface_doesnt_exist");
+    server
+        .state
+        .set_foreign_toplevel_state(surface_id, true, false, None)
+        .unwrap();
+    // Taskbars see the state the shell gives to the window.
+    server
+        .state
+        .set_window_state(&SetWindowStatePayload {
+            surface_id,
+            maximized: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+    server.roundtrip(&mut client);
+
+    let states = [
+        zwlr_foreign_toplevel_handle_v1::State::Maximized as u32,
+        zwlr_foreign_toplevel_handle_v1::State::Activated as u32,
+    ];
+    assert_eq!(
+        client.state.foreign_toplevels[0].states,
+        states
+            .into_iter()
+            .flat_map(u32::to_ne_bytes)
+            .collect::<Vec<_>>()
+    );
+
+    toplevel.destroy();
+    xdg_surface.destroy();
+    server.roundtrip(&mut client);
+
+    assert!(client.state.foreign_toplevels[0].closed);
+}
+
+#[test]
+fn taskbars_binding_a_monitor_later_learn_the_windows_on_it() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (surface, _xdg_surface, _toplevel) = client.create_toplevel();
+    surface.commit();
+    server.roundtrip(&mut client);
+    client.attach_buffer(&surface);
+    server.roundtrip(&mut client);
+    assert!(client.state.foreign_toplevels[0].outputs.is_empty());
+
+    server.add_monitor("first", 1920, 1080);
+    server.roundtrip(&mut client);
+    // The output is bound while handling the registry event, so the bind needs one more trip.
+    server.roundtrip(&mut client);
+
+    let wl_output = client.state.output.clone().unwrap();
+    assert_eq!(client.state.foreign_toplevels[0].outputs, [wl_output]);
+}
+
+#[test]
+fn activation_needs_a_valid_token() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let (surface, _xdg_surface, _toplevel) = client.create_toplevel();
+    surface.commit();
+    server.roundtrip(&mut client);
+    let surface_id = surface_id_of(find_message(&server.take_messages(), "new_toplevel"));
+    let activation = client.state.activation.clone().unwrap();
+
+    // Without a serial of a user interaction, the client can't give itself the focus.
+    let token = activation.get_activation_token(&client.qh(), ());
+    token.set_surface(&surface);
+    token.commit();
+    server.roundtrip(&mut client);
+    let client_token = client.state.activation_token.take().unwrap();
+    activation.activate(client_token, &surface);
+    server.roundtrip(&mut client);
+    assert!(server
+        .take_messages()
+        .iter()
+        .all(|(method, _)| method != "activation_requested"));
+
+    let launcher_token = server.state.create_activation_token();
+    activation.activate(launcher_token.clone(), &surface);
+    server.roundtrip(&mut client);
+
+    let messages = server.take_messages();
+    let request = find_message(&messages, "activation_requested");
+    assert_eq!(surface_id_of(request), surface_id);
+    assert_eq!(request["token"], launcher_token);
+    assert_eq!(request["stale"], false);
+
+    // Tokens only activate once.
+    activation.activate(launcher_token, &surface);
+    server.roundtrip(&mut client);
+    assert!(server
+        .take_messages()
+        .iter()
+        .all(|(method, _)| method != "activation_requested"));
+}
+
+fn has_toplevel_state(states: &[u8], state: xdg_toplevel::State) -> bool {
+    states
+        .chunks_exact(4)
+        .any(|chunk| u32::from_ne_bytes(chunk.try_into().unwrap()) == state as u32)
+}
+
+#[test]
+fn window_state_requests_are_left_to_the_shell() {
+    let mut server = TestServer::new();
+    server.add_monitor("first", 1920, 1080);
+    let mut client = server.connect_client();
+
+    let (surface, _xdg_surface, toplevel) = client.create_toplevel();
+    surface.commit();
+    server.roundtrip(&mut client);
+    let surface_id = surface_id_of(find_message(&server.take_messages(), "new_toplevel"));
+
+    // Windows aren't maximized until the shell says so.
+    surface.commit();
+    server.roundtrip(&mut client);
+    assert!(!has_toplevel_state(
+        &client.state.toplevel_states,
+        xdg_toplevel::State::Maximized
+    ));
+
+    let configures = client.state.toplevel_configures;
+    toplevel.set_maximized();
+    toplevel.set_fullscreen(client.state.output.as_ref());
+    server.roundtrip(&mut client);
+    // Each request is answered, even before the shell decides.
+    assert_eq!(client.state.toplevel_configures, configures + 2);
+
+    let messages = server.take_messages();
+    assert_eq!(
+        surface_id_of(find_message(&messages, "maximize_request")),
+        surface_id
+    );
+    let fullscreen_request = find_message(&messages, "fullscreen_request");
+    assert_eq!(surface_id_of(fullscreen_request), surface_id);
+    assert_eq!(fullscreen_request["monitor"], "first");
+    assert!(!has_toplevel_state(
+        &client.state.toplevel_states,
+        xdg_toplevel::State::Maximized
+    ));
+
+    server
+        .state
+        .set_window_state(&SetWindowStatePayload {
+            surface_id,
+            maximized: Some(true),
+            tiled_edges: Some(TiledEdges {
+                left: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+    server.roundtrip(&mut client);
+
+    let states = &client.state.toplevel_states;
+    assert!(has_toplevel_state(states, xdg_toplevel::State::Maximized));
+    assert!(has_toplevel_state(states, xdg_toplevel::State::TiledLeft));
+    assert!(!has_toplevel_state(states, xdg_toplevel::State::Fullscreen));
+
+    let error = server
+        .state
+        .set_window_state(&SetWindowStatePayload {
+            surface_id,
+            fullscreen: Some(true),
+            monitor: Some("second".to_string()),
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert_eq!(error.code(), "monitor_doesnt_exist");
+    let error = server
+        .state
+        .set_window_state(&SetWindowStatePayload {
+            surface_id: surface_id + 1,
+            ..Default::default()
+        })
+        .unwrap_err();
+    assert_eq!(error.code(), "surface_doesnt_exist");
+}
+
+#[test]
+fn clients_choose_the_cursor_over_their_surfaces() {
+    let mut server = TestServer::new();
+    server.state.flutter_cursor_image_status = CursorImageStatus::Named(CursorIcon::Text);
+    server.state.client_cursor_image_status = CursorImageStatus::Hidden;
+
+    server.state.surface_id_under_cursor = None;
+    assert!(matches!(
+        server.state.cursor_image_status(),
+        CursorImageStatus::Named(CursorIcon::Text)
+    ));
+
+    server.state.surface_id_under_cursor = Some(1);
+    assert!(matches!(
+        server.state.cursor_image_status(),
+        CursorImageStatus::Hidden
+    ));
+}
+
+/// Moves the pointer over the surface, like the shell does with `pointer_hover`.
+fn hover(server: &mut TestServer, client: &mut TestClient, surface_id: u64) {
+    let surface = server.state.surfaces.get(&surface_id).unwrap().clone();
+    let pointer = server.state.pointer.clone();
+    server.state.surface_id_under_cursor = Some(surface_id);
+    pointer.motion(
+        &mut server.state,
+        Some((PointerFocusTarget::from(surface), (0, 0).into())),
+        &MotionEvent {
+            location: (5.0, 5.0).into(),
+            serial: SERIAL_COUNTER.next_serial(),
+            time: 0,
+        },
+    );
+    pointer.frame(&mut server.state);
+    server.roundtrip(client);
+}
+
+#[test]
+fn clients_set_the_cursor_shape_over_their_surfaces() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let surface = client.create_surface();
+    surface.commit();
+    let pointer = client
+        .state
+        .seat
+        .as_ref()
+        .unwrap()
+        .get_pointer(&client.qh(), ());
+    server.roundtrip(&mut client);
+    let messages = server.take_messages();
+    hover(
+        &mut server,
+        &mut client,
+        surface_id_of(find_message(&messages, "new_surface")),
+    );
+
+    let cursor_shape_device = client
+        .state
+        .cursor_shape_manager
+        .as_ref()
+        .unwrap()
+        .get_pointer(&pointer, &client.qh(), ());
+    cursor_shape_device.set_shape(
+        client.state.pointer_enter_serial.unwrap(),
+        wp_cursor_shape_device_v1::Shape::Wait,
+    );
+    server.roundtrip(&mut client);
+
+    assert!(matches!(
+        server.state.cursor_image_status(),
+        CursorImageStatus::Named(CursorIcon::Wait)
+    ));
+}
+
+#[test]
+fn cursor_surfaces_are_not_committed_to_the_shell() {
+    let mut server = TestServer::new();
+    let mut client = server.connect_client();
+
+    let surface = client.create_surface();
+    surface.commit();
+    let pointer = client
+        .state
+        .seat
+        .as_ref()
+        .unwrap()
+        .get_pointer(&client.qh(), ());
+    server.roundtrip(&mut client);
+    let messages = server.take_messages();
+    hover(
+        &mut server,
+        &mut client,
+        surface_id_of(find_message(&messages, "new_surface")),
+    );
+
+    let cursor_surface = client.create_surface();
+    let cursor_subsurface = client.create_surface();
+    client.state.subcompositor.as_ref().unwrap().get_subsurface(
+        &cursor_subsurface,
+        &cursor_surface,
+        &client.qh(),
+        (),
+    );
+    pointer.set_cursor(
+        client.state.pointer_enter_serial.unwrap(),
+        Some(&cursor_surface),
+        1,
+        1,
+    );
+    cursor_subsurface.commit();
+    cursor_surface.commit();
+    server.roundtrip(&mut client);
+
+    assert!(matches!(
+        server.state.cursor_image_status(),
+        CursorImageStatus::Surface(_)
+    ));
+    let messages = server.take_messages();
+    assert!(!messages.iter().any(|(name, _)| name == "commit_surface"));
+}
+
+fn pen(hardware_serial: u64) -> TabletToolDescriptor {
+    TabletToolDescriptor {
+        tool_type: TabletToolType::Pen,
+        hardware_serial,
+        hardware_id_wacom: 0,
+        capabilities: TabletToolCapabilities::PRESSURE | TabletToolCapabilities::TILT,
+    }
+}
+
+fn tablet(name: &str) -> TabletDescriptor {
+    TabletDescriptor {
+        name: name.to_string(),
+        usb_id: None,
+        syspath: None,
+    }
+}
+
+#[test]
+fn tablet_tool_axes_reach_the_shell_when_they_change() {
+    let mut server = TestServer::new();
+    let pen = pen(1);
+    let mut tablet_tool = TabletTool::new(0x10000, tablet("Tablet"), (10.0, 20.0));
+    tablet_tool.set_axes(0.5, (10.0, -5.0));
+    server.state.tablet_tools.insert(pen.clone(), tablet_tool);
+
+    send_stylus_axes(&mut server.state, &pen);
+    let messages = server.take_messages();
+    let axes = find_message(&messages, "tablet_tool_axes_changed");
+    assert_eq!(axes["device"], 0x10000);
+    assert_eq!(axes["pressure"], 0.5);
+    assert_eq!(axes["tiltX"], 10.0);
+    assert_eq!(axes["tiltY"], -5.0);
+
+    // Nothing new to tell.
+    send_stylus_axes(&mut server.state, &pen);
+    let tablet_tool = server.state.tablet_tools.get_mut(&pen).unwrap();
+    tablet_tool.set_axes(0.5, (10.0, -5.0));
+    send_stylus_axes(&mut server.state, &pen);
+    assert!(server.take_messages().is_empty());
+
+    let tablet_tool = server.state.tablet_tools.get_mut(&pen).unwrap();
+    tablet_tool.set_axes(0.75, (10.0, -5.0));
+    send_stylus_axes(&mut server.state, &pen);
+    let messages = server.take_messages();
+    assert_eq!(
+        find_message(&messages, "tablet_tool_axes_changed")["pressure"],
+        0.75
+    );
+}
+
+#[test]
+fn tablet_tools_are_forgotten_with_their_tablet() {
+    let mut server = TestServer::new();
+    server.state.tablet_tools.insert(
+        pen(1),
+        TabletTool::new(0x10000, tablet("First"), (0.0, 0.0)),
+    );
+    server.state.tablet_tools.insert(
+        pen(2),
+        TabletTool::new(0x10001, tablet("Second"), (0.0, 0.0)),
+    );
+
+    forget_tablet_tools(&mut server.state, &tablet("First"));
+
+    assert!(!server.state.tablet_tools.contains_key(&pen(1)));
+    assert!(server.state.tablet_tools.contains_key(&pen(2)));
+}
+
+#[test]
+fn tablet_tool_ids_are_not_reused() {
+    let mut server = TestServer::new();
+    let device = next_tablet_tool_device_id(&mut server.state);
+    server
+        .state
+        .tablet_tools
+        .insert(pen(1), TabletTool::new(device, tablet("First"), (0.0, 0.0)));
+
+    forget_tablet_tools(&mut server.state, &tablet("First"));
+
+    assert_ne!(next_tablet_tool_device_id(&mut server.state), device);
+}
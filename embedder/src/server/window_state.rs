@@ -0,0 +1,137 @@
+//! The states the shell gives to windows: maximized, fullscreen, tiled against
+//! other windows or the screen edges, and suspended when nothing of them is visible.
+//!
+//! Clients only ask to be maximized or fullscreen, the shell decides and answers with `set_window_state`.
+
+use smithay::output::Output;
+use smithay::reexports::wayland_protocols::xdg::shell::server::xdg_toplevel;
+use smithay::reexports::wayland_server::Resource;
+use smithay::wayland::shell::xdg::ToplevelSurface;
+use smithay::xwayland::X11Surface;
+
+use crate::platform_channel_error::PlatformChannelError;
+use crate::Backend;
+
+use super::ServerState;
+
+/// Missing states are left unchanged.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetWindowStatePayload {
+    pub surface_id: u64,
+    pub maximized: Option<bool>,
+    pub fullscreen: Option<bool>,
+    /// The monitor a fullscreen window covers,
+    /// the one the client asked for in its `fullscreen_request` when missing.
+    pub monitor: Option<String>,
+    pub tiled_edges: Option<TiledEdges>,
+    pub suspended: Option<bool>,
+}
+
+/// The edges of the window touching another window or the edge of the screen.
+#[derive(Debug, Default, Clone, Copy, serde::Deserialize)]
+pub struct TiledEdges {
+    #[serde(default)]
+    pub top: bool,
+    #[serde(default)]
+    pub bottom: bool,
+    #[serde(default)]
+    pub left: bool,
+    #[serde(default)]
+    pub right: bool,
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    pub fn set_window_state(
+        &mut self,
+        payload: &SetWindowStatePayload,
+    ) -> Result<(), PlatformChannelError> {
+        let monitor = payload
+            .monitor
+            .as_ref()
+            .map(|name| {
+                self.backend_data
+                    .get_monitor_layout()
+                    .into_iter()
+                    .find(|output| output.name() == *name)
+                    .ok_or_else(|| PlatformChannelError::MonitorDoesntExist(name.clone()))
+            })
+            .transpose()?;
+
+        if let Some(toplevel) = self.xdg_toplevels.get(&payload.surface_id) {
+            set_toplevel_state(toplevel, payload, monitor.as_ref());
+        } else {
+            // Tiling and suspending are xdg-shell only.
+            let x11_surface = self
+                .surfaces
+                .get(&payload.surface_id)
+                .and_then(|wl_surface| self.x11_surface_per_wl_surface.get(wl_surface))
+                .ok_or(PlatformChannelError::SurfaceDoesntExist(payload.surface_id))?;
+            set_x11_surface_state(x11_surface, payload);
+        }
+
+        self.change_foreign_toplevel_window_state(payload.surface_id, |window_state| {
+            if let Some(maximized) = payload.maximized {
+                window_state.maximized = maximized;
+            }
+            if let Some(fullscreen) = payload.fullscreen {
+                window_state.fullscreen = fullscreen;
+            }
+        });
+        Ok(())
+    }
+}
+
+fn set_toplevel_state(
+    toplevel: &ToplevelSurface,
+    payload: &SetWindowStatePayload,
+    monitor: Option<&Output>,
+) {
+    let fullscreen_output = monitor.and_then(|monitor| {
+        let client = toplevel.wl_surface().client()?;
+        monitor.client_outputs(&client).into_iter().next()
+    });
+
+    toplevel.with_pending_state(|state| {
+        let mut set = |xdg_state, value: Option<bool>| match value {
+            Some(true) => {
+                state.states.set(xdg_state);
+            }
+            Some(false) => {
+                state.states.unset(xdg_state);
+            }
+            None => {}
+        };
+
+        set(xdg_toplevel::State::Maximized, payload.maximized);
+        set(xdg_toplevel::State::Fullscreen, payload.fullscreen);
+        set(xdg_toplevel::State::Suspended, payload.suspended);
+        if let Some(edges) = payload.tiled_edges {
+            set(xdg_toplevel::State::TiledTop, Some(edges.top));
+            set(xdg_toplevel::State::TiledBottom, Some(edges.bottom));
+            set(xdg_toplevel::State::TiledLeft, Some(edges.left));
+            set(xdg_toplevel::State::TiledRight, Some(edges.right));
+        }
+
+        match payload.fullscreen {
+            // Otherwise the output of the client's request, stored by `fullscreen_request`.
+            Some(true) if fullscreen_output.is_some() => {
+                state.fullscreen_output = fullscreen_output
+            }
+            Some(false) => state.fullscreen_output = None,
+            _ => {}
+        }
+    });
+    // Also when nothing changed, the client waits for an answer to its request.
+    // States the client's version of xdg-shell doesn't know are filtered out by smithay.
+    toplevel.send_configure();
+}
+
+fn set_x11_surface_state(x11_surface: &X11Surface, payload: &SetWindowStatePayload) {
+    if let Some(maximized) = payload.maximized {
+        let _ = x11_surface.set_maximized(maximized);
+    }
+    if let Some(fullscreen) = payload.fullscreen {
+        let _ = x11_surface.set_fullscreen(fullscreen);
+    }
+}
@@ -0,0 +1,304 @@
+//! wlr-screencopy, the capture protocol of grim, wf-recorder, OBS and xdg-desktop-portal-wlr.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use smithay::output::Output;
+use smithay::reexports::wayland_protocols_wlr::screencopy::v1::server::{
+    zwlr_screencopy_frame_v1, zwlr_screencopy_manager_v1,
+};
+use smithay::reexports::wayland_server::backend::ClientId;
+use smithay::reexports::wayland_server::protocol::wl_buffer::WlBuffer;
+use smithay::reexports::wayland_server::{
+    Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+};
+use smithay::utils::{Logical, Rectangle};
+use tracing::warn;
+
+use crate::Backend;
+
+use super::screen_capture::{
+    split_timestamp, CaptureError, CaptureRegion, ScreenCapturePermission, SHM_FORMATS,
+};
+use super::ServerState;
+
+use zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1;
+use zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
+
+const VERSION: u32 = 3;
+
+pub struct WlrScreencopyState {
+    pending_copies: Vec<PendingCopy>,
+}
+
+/// A frame the client asked to copy into its buffer.
+struct PendingCopy {
+    frame: ZwlrScreencopyFrameV1,
+    buffer: WlBuffer,
+    /// What changed since `copy_with_damage`, the copy waits for the capture to be damaged.
+    /// `None` for a plain `copy`, done as soon as the client is allowed to capture.
+    damage: Option<Vec<Rectangle<i32, Logical>>>,
+}
+
+pub struct ScreencopyFrameData {
+    /// `None` when the monitor is gone or the region is outside of it, the frame failed.
+    capture: Option<CaptureRegion>,
+    copied: AtomicBool,
+}
+
+impl WlrScreencopyState {
+    pub fn new<BackendData: Backend + 'static>(
+        display_handle: &DisplayHandle,
+    ) -> WlrScreencopyState {
+        display_handle
+            .create_global::<ServerState<BackendData>, ZwlrScreencopyManagerV1, _>(VERSION, ());
+        WlrScreencopyState {
+            pending_copies: vec![],
+        }
+    }
+
+    pub fn add_damage(&mut self, damage: &[Rectangle<i32, Logical>]) {
+        for pending_copy in &mut self.pending_copies {
+            if let Some(copy_damage) = pending_copy.damage.as_mut() {
+                copy_damage.extend_from_slice(damage);
+            }
+        }
+    }
+
+    /// Only copies of frames with the cursor change when it moves.
+    pub fn add_cursor_damage(&mut self, damage: &[Rectangle<i32, Logical>]) {
+        for pending_copy in &mut self.pending_copies {
+            let paint_cursor = pending_copy
+                .frame
+                .data::<ScreencopyFrameData>()
+                .and_then(|data| data.capture.as_ref())
+                .map_or(false, |capture| capture.paint_cursor);
+            if let (true, Some(copy_damage)) = (paint_cursor, pending_copy.damage.as_mut()) {
+                copy_damage.extend_from_slice(damage);
+            }
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    pub fn process_wlr_screencopy_frames(&mut self) {
+        let pending_copies = std::mem::take(&mut self.wlr_screencopy_state.pending_copies);
+        self.wlr_screencopy_state.pending_copies = pending_copies
+            .into_iter()
+            .filter_map(|pending_copy| self.copy_screencopy_frame(pending_copy))
+            .collect();
+    }
+
+    /// Gives the copy back when it has to wait for the permission or for damage.
+    fn copy_screencopy_frame(&mut self, pending_copy: PendingCopy) -> Option<PendingCopy> {
+        let frame = &pending_copy.frame;
+        let client = frame.client()?;
+        match self.screen_capture_permission(&client) {
+            ScreenCapturePermission::Unknown | ScreenCapturePermission::Pending => {
+                return Some(pending_copy)
+            }
+            ScreenCapturePermission::Denied => {
+                frame.failed();
+                return None;
+            }
+            ScreenCapturePermission::Allowed => {}
+        }
+
+        let capture = frame
+            .data::<ScreencopyFrameData>()
+            .and_then(|data| data.capture.clone())?;
+        if self.is_capture_gone(&capture) {
+            frame.failed();
+            return None;
+        }
+
+        let damage = match &pending_copy.damage {
+            Some(damage) => {
+                let damage = capture.buffer_damage(damage);
+                if damage.is_empty() {
+                    return Some(pending_copy);
+                }
+                damage
+            }
+            None => vec![],
+        };
+
+        match self.render_capture(&capture, &pending_copy.buffer) {
+            Ok(()) => {}
+            Err(CaptureError::NothingRendered) => return Some(pending_copy),
+            Err(CaptureError::BufferConstraints) => {
+                frame.post_error(
+                    zwlr_screencopy_frame_v1::Error::InvalidBuffer,
+                    "The buffer doesn't match the advertised size and formats",
+                );
+                return None;
+            }
+            Err(err) => {
+                warn!("Failed to copy a screencopy frame: {err}");
+                frame.failed();
+                return None;
+            }
+        }
+
+        frame.flags(zwlr_screencopy_frame_v1::Flags::empty());
+        for rect in damage {
+            frame.damage(
+                rect.loc.x as u32,
+                rect.loc.y as u32,
+                rect.size.w as u32,
+                rect.size.h as u32,
+            );
+        }
+        let (tv_sec_hi, tv_sec_lo, tv_nsec) = split_timestamp(self.clock.now().into());
+        frame.ready(tv_sec_hi, tv_sec_lo, tv_nsec);
+        None
+    }
+
+    fn send_screencopy_buffer_constraints(
+        &mut self,
+        frame: &ZwlrScreencopyFrameV1,
+        capture: &CaptureRegion,
+    ) {
+        let size = capture.buffer_size();
+        for format in SHM_FORMATS {
+            frame.buffer(format, size.w as u32, size.h as u32, size.w as u32 * 4);
+        }
+        if frame.version() >= 3 {
+            if let Some(constraints) = self.capture_dmabuf_constraints() {
+                for (format, _) in constraints.formats {
+                    frame.linux_dmabuf(format as u32, size.w as u32, size.h as u32);
+                }
+            }
+            frame.buffer_done();
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> GlobalDispatch<ZwlrScreencopyManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn bind(
+        _state: &mut Self,
+        _handle: &DisplayHandle,
+        _client: &Client,
+        resource: New<ZwlrScreencopyManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ZwlrScreencopyManagerV1, ()>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        client: &Client,
+        _manager: &ZwlrScreencopyManagerV1,
+        request: zwlr_screencopy_manager_v1::Request,
+        _data: &(),
+        _handle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (frame, overlay_cursor, output, region) = match request {
+            zwlr_screencopy_manager_v1::Request::CaptureOutput {
+                frame,
+                overlay_cursor,
+                output,
+            } => (frame, overlay_cursor, output, None),
+            zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+                frame,
+                overlay_cursor,
+                output,
+                x,
+                y,
+                width,
+                height,
+            } => (
+                frame,
+                overlay_cursor,
+                output,
+                Some(Rectangle::from_loc_and_size((x, y), (width, height))),
+            ),
+            zwlr_screencopy_manager_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+
+        let capture = Output::from_resource(&output)
+            .map(|output| CaptureRegion {
+                output,
+                region,
+                paint_cursor: overlay_cursor != 0,
+            })
+            .filter(|capture| !state.is_capture_gone(capture) && !capture.geometry().is_empty());
+
+        let frame = data_init.init(
+            frame,
+            ScreencopyFrameData {
+                capture: capture.clone(),
+                copied: AtomicBool::new(false),
+            },
+        );
+
+        match capture {
+            Some(capture) => {
+                state.send_screencopy_buffer_constraints(&frame, &capture);
+                // The shell is asked while the client allocates its buffer.
+                state.screen_capture_permission(client);
+            }
+            None => frame.failed(),
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData>
+    for ServerState<BackendData>
+{
+    fn request(
+        state: &mut Self,
+        _client: &Client,
+        frame: &ZwlrScreencopyFrameV1,
+        request: zwlr_screencopy_frame_v1::Request,
+        data: &ScreencopyFrameData,
+        _handle: &DisplayHandle,
+        _data_init: &mut DataInit<'_, Self>,
+    ) {
+        let (buffer, with_damage) = match request {
+            zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+            zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+            zwlr_screencopy_frame_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+
+        if data.copied.swap(true, Ordering::SeqCst) {
+            frame.post_error(
+                zwlr_screencopy_frame_v1::Error::AlreadyUsed,
+                "The frame was already copied",
+            );
+            return;
+        }
+        if data.capture.is_none() {
+            // The failed event was already sent.
+            return;
+        }
+
+        state.wlr_screencopy_state.pending_copies.push(PendingCopy {
+            frame: frame.clone(),
+            buffer,
+            damage: with_damage.then(Vec::new),
+        });
+        state.process_wlr_screencopy_frames();
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        _client: ClientId,
+        frame: &ZwlrScreencopyFrameV1,
+        _data: &ScreencopyFrameData,
+    ) {
+        state
+            .wlr_screencopy_state
+            .pending_copies
+            .retain(|pending_copy| pending_copy.frame != *frame);
+    }
+}
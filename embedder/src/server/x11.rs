@@ -53,14 +53,12 @@ impl<BackendData: Backend> ServerState<BackendData> {
             })
         });
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "new_x11_surface",
-            Some(Box::new(json!(NewX11Surface {
+            json!(NewX11Surface {
                 x11_surface_id: Self::get_x11_surface_id(&surface),
                 override_redirect: surface.is_override_redirect(),
-            }))),
-            None,
+            }),
         );
     }
 
@@ -85,20 +83,18 @@ impl<BackendData: Backend> ServerState<BackendData> {
                 .map(|x11_surface| Self::get_x11_surface_id(&x11_surface))
         };
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "map_x11_surface",
-            Some(Box::new(json!(MapX11Surface {
+            json!(MapX11Surface {
                 x11_surface_id: Self::get_x11_surface_id(&surface),
                 geometry: surface.geometry().into(),
                 parent,
-            }))),
-            None,
+            }),
         );
     }
 }
 
-impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
+impl<BackendData: Backend + 'static> XwmHandler for ServerState<BackendData> {
     fn xwm_state(&mut self, _xwm: XwmId) -> &mut X11Wm {
         self.x11_wm.as_mut().unwrap()
     }
@@ -135,16 +131,15 @@ impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
         };
 
         self.x11_surface_per_wl_surface.remove(&wl_surface);
+        self.close_foreign_toplevel(get_surface_id(&wl_surface));
 
         let x11_surface_id = Self::get_x11_surface_id(&surface);
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "unmap_x11_surface",
-            Some(Box::new(json!({
+            json!({
                 "x11SurfaceId": x11_surface_id,
-            }))),
-            None,
+            }),
         );
 
         if !surface.is_override_redirect() {
@@ -155,13 +150,11 @@ impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
     fn destroyed_window(&mut self, xwm: XwmId, surface: X11Surface) {
         let x11_surface_id = Self::get_x11_surface_id(&surface);
 
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "destroy_x11_surface",
-            Some(Box::new(json!({
+            json!({
                 "x11SurfaceId": x11_surface_id,
-            }))),
-            None,
+            }),
         );
 
         self.x11_surface_per_x11_window.remove(&surface.window_id());
@@ -199,14 +192,12 @@ impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
     }
 
     fn property_notify(&mut self, xwm: XwmId, x11_surface: X11Surface, property: WmWindowProperty) {
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-
         // to simplify we sent all properties to flutter when any changes
         // TODO: split each property to channel
 
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "x11_properties_changed",
-            Some(Box::new(json!({
+            json!({
                 "x11SurfaceId": Self::get_x11_surface_id(&x11_surface),
                 "title": if !x11_surface.title().is_empty() { Some(x11_surface.title()) } else { None },
                 "windowClass": x11_surface.class(),
@@ -216,10 +207,16 @@ impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
                     None
                 },
                 "startupId": x11_surface.startup_id(),
-            }))),
-            None,
+            }),
         );
 
+        if let Some(wl_surface) = x11_surface.wl_surface() {
+            let surface_id = get_surface_id(&wl_surface);
+            self.set_foreign_toplevel_title(surface_id, non_empty(x11_surface.title()));
+            self.set_foreign_toplevel_app_id(surface_id, non_empty(x11_surface.class()));
+        }
+        self.activate_x11_surface_by_startup_id(&x11_surface);
+
         /* match property {
             WmWindowProperty::Title => {
                 let title = window.title();
@@ -334,7 +331,7 @@ impl<BackendData: Backend> XwmHandler for ServerState<BackendData> {
     }
 }
 
-impl<BackendData: Backend> XWaylandShellHandler for ServerState<BackendData> {
+impl<BackendData: Backend + 'static> XWaylandShellHandler for ServerState<BackendData> {
     fn xwayland_shell_state(&mut self) -> &mut XWaylandShellState {
         &mut self.xwayland_shell_state
     }
@@ -342,19 +339,34 @@ impl<BackendData: Backend> XWaylandShellHandler for ServerState<BackendData> {
     fn surface_associated(&mut self, _surface: WlSurface, _window: Window) {
         println!("surface {:?}", _surface);
         println!("window {:?}", _window);
-        let x11_surface = self.x11_surface_per_x11_window.get(&_window).unwrap();
-        let x11_surface_id = Self::get_x11_surface_id(x11_surface);
+        let x11_surface = self
+            .x11_surface_per_x11_window
+            .get(&_window)
+            .unwrap()
+            .clone();
+        let x11_surface_id = Self::get_x11_surface_id(&x11_surface);
         self.x11_surface_per_wl_surface
             .insert(_surface.clone(), x11_surface.clone());
-        let platform_method_channel = &mut self.flutter_engine_mut().platform_method_channel;
-
-        platform_method_channel.invoke_method(
+        self.invoke_platform_method(
             "surface_associated",
-            Some(Box::new(json!({
+            json!({
                 "surfaceId": get_surface_id(_surface.borrow()),
                 "x11SurfaceId": x11_surface_id,
-            }))),
-            None,
+            }),
         );
+
+        // Override redirect windows are menus and tooltips, not windows for taskbars.
+        if !x11_surface.is_override_redirect() {
+            self.open_foreign_toplevel(
+                get_surface_id(&_surface),
+                non_empty(x11_surface.title()),
+                non_empty(x11_surface.class()),
+            );
+            self.activate_x11_surface_by_startup_id(&x11_surface);
+        }
     }
 }
+
+fn non_empty(string: String) -> Option<String> {
+    (!string.is_empty()).then_some(string)
+}
@@ -0,0 +1,115 @@
+//! xdg-activation, how a window asks for the focus on behalf of the user,
+//! like a terminal opening a link in the browser or an app launched from the shell.
+//!
+//! The compositor only hands out tokens when the user interacted with the focused window,
+//! the shell decides whether an activation focuses the window or only marks it as urgent.
+
+use std::time::Duration;
+
+use serde_json::json;
+use smithay::delegate_xdg_activation;
+use smithay::input::Seat;
+use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
+use smithay::reexports::wayland_server::Resource;
+use smithay::wayland::xdg_activation::{
+    XdgActivationHandler, XdgActivationState, XdgActivationToken, XdgActivationTokenData,
+};
+use smithay::xwayland::X11Surface;
+
+use crate::Backend;
+
+use super::{get_surface_id, ServerState};
+
+/// Activations with an older token don't steal the focus, the shell should only mark the window as urgent.
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tokens nobody used by then are forgotten, some apps take a while to start.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+impl<BackendData: Backend + 'static> ServerState<BackendData> {
+    /// A token for an app launched by the shell,
+    /// given to it in `XDG_ACTIVATION_TOKEN` and `DESKTOP_STARTUP_ID`.
+    pub fn create_activation_token(&mut self) -> String {
+        self.forget_expired_activation_tokens();
+        let (token, _) = self.xdg_activation_state.create_external_token(None);
+        token.to_string()
+    }
+
+    fn forget_expired_activation_tokens(&mut self) {
+        self.xdg_activation_state
+            .retain_tokens(|_, data| data.timestamp.elapsed() < TOKEN_LIFETIME);
+    }
+
+    fn activation_requested(
+        &mut self,
+        surface: &WlSurface,
+        token: &XdgActivationToken,
+        token_data: &XdgActivationTokenData,
+    ) {
+        self.invoke_platform_method(
+            "activation_requested",
+            json!({
+                "surfaceId": get_surface_id(surface),
+                "token": token.to_string(),
+                "appId": token_data.app_id,
+                "stale": token_data.timestamp.elapsed() >= ACTIVATION_TIMEOUT,
+            }),
+        );
+    }
+
+    /// X11 apps launched with a token in `DESKTOP_STARTUP_ID` give it back in `_NET_STARTUP_ID`.
+    pub fn activate_x11_surface_by_startup_id(&mut self, x11_surface: &X11Surface) {
+        let (Some(startup_id), Some(wl_surface)) =
+            (x11_surface.startup_id(), x11_surface.wl_surface())
+        else {
+            return;
+        };
+        let token = XdgActivationToken::from(startup_id);
+        // Tokens only activate once.
+        if let Some(token_data) = self.xdg_activation_state.remove_token(&token) {
+            self.activation_requested(&wl_surface, &token, &token_data);
+        }
+    }
+}
+
+impl<BackendData: Backend + 'static> XdgActivationHandler for ServerState<BackendData> {
+    fn activation_state(&mut self) -> &mut XdgActivationState {
+        &mut self.xdg_activation_state
+    }
+
+    fn token_created(&mut self, _token: XdgActivationToken, data: XdgActivationTokenData) -> bool {
+        self.forget_expired_activation_tokens();
+
+        // The token must come from an input event the focused client received since it got the focus.
+        let Some((serial, seat)) = data.serial else {
+            return false;
+        };
+        if Seat::<Self>::from_resource(&seat).as_ref() != Some(&self.seat) {
+            return false;
+        }
+        let is_recent = self
+            .keyboard
+            .last_enter()
+            .map_or(false, |last_enter| serial.is_no_older_than(&last_enter));
+        let focused_client = self
+            .keyboard
+            .current_focus()
+            .and_then(|focus| focus.wl_surface())
+            .and_then(|surface| surface.client())
+            .map(|client| client.id());
+
+        is_recent && focused_client.is_some() && focused_client == data.client_id
+    }
+
+    fn request_activation(
+        &mut self,
+        token: XdgActivationToken,
+        token_data: XdgActivationTokenData,
+        surface: WlSurface,
+    ) {
+        self.xdg_activation_state.remove_token(&token);
+        self.activation_requested(&surface, &token, &token_data);
+    }
+}
+
+delegate_xdg_activation!(@<BackendData: Backend + 'static> ServerState<BackendData>);
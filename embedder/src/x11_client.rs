@@ -3,7 +3,7 @@ use std::sync::atomic::Ordering;
 use log::{error, warn};
 use smithay::backend::input::Event;
 use smithay::backend::renderer::gles::ffi::Gles2;
-use smithay::backend::renderer::gles::GlesRenderer;
+use smithay::backend::renderer::gles::{GlesRenderer, GlesTexture};
 use smithay::backend::renderer::{ImportDma, ImportEgl};
 use smithay::output::{Output, PhysicalProperties, Subpixel};
 use smithay::reexports::calloop::channel::Event::Msg;
@@ -15,7 +15,7 @@ use smithay::wayland::dmabuf::{DmabufFeedbackBuilder, DmabufState};
 use smithay::{
     backend::{
         allocator::{
-            dmabuf::DmabufAllocator,
+            dmabuf::{Dmabuf, DmabufAllocator},
             gbm::GbmAllocator,
             vulkan::{ImageUsageFlags, VulkanAllocator},
         },
@@ -35,8 +35,12 @@ use smithay::{
 use tracing::info;
 
 use crate::flutter_engine::FlutterEngine;
+use crate::gles_framebuffer_importer::{Framebuffer, FramebufferSource};
+use crate::input_device_configuration::InputDeviceConfiguration;
 use crate::input_handling::handle_input;
-use crate::{flutter_engine::EmbedderChannels, send_frames_surface_tree, Backend, ServerState};
+use crate::monitor_configuration::MonitorConfiguration;
+use crate::platform_channel_error::PlatformChannelError;
+use crate::{flutter_engine::EmbedderChannels, Backend, ServerState};
 
 pub fn run_x11_client() {
     let mut event_loop = EventLoop::try_new().unwrap();
@@ -169,6 +173,8 @@ pub fn run_x11_client() {
         X11Data {
             x11_surface,
             output,
+            current_buffer: None,
+            last_rendered_buffer: None,
         },
         Some(dmabuf_state),
     );
@@ -194,10 +200,7 @@ pub fn run_x11_client() {
 
     let size = window.size();
     tx_output_height.send(size.h).unwrap();
-    state
-        .flutter_engine_mut()
-        .send_window_metrics((size.w as u32, size.h as u32).into())
-        .unwrap();
+    state.update_window_metrics();
 
     // Mandatory formats by the Wayland spec.
     // TODO: Add more formats based on the GLES version.
@@ -229,12 +232,7 @@ pub fn run_x11_client() {
                     data.backend_data.output.set_preferred(mode);
 
                     let _ = tx_output_height.send(new_size.h);
-                    data.flutter_engine()
-                        .send_window_metrics((size.w as u32, size.h as u32).into())
-                        .unwrap();
-
-                    let monitors = data.backend_data.get_monitor_layout();
-                    data.flutter_engine_mut().monitor_layout_changed(monitors);
+                    X11Data::update_monitor_layout(data);
                 }
 
                 X11Event::PresentCompleted { .. } | X11Event::Refresh { .. } => {
@@ -244,22 +242,7 @@ pub fn run_x11_client() {
                     for baton in drained {
                         data.flutter_engine().on_vsync(baton, 144_000).unwrap();
                     }
-                    let start_time = std::time::Instant::now();
-                    for surface in data.xdg_shell_state.toplevel_surfaces() {
-                        send_frames_surface_tree(
-                            surface.wl_surface(),
-                            start_time.elapsed().as_millis() as u32,
-                        );
-                    }
-                    for surface in data.xdg_popups.values() {
-                        send_frames_surface_tree(
-                            surface.wl_surface(),
-                            start_time.elapsed().as_millis() as u32,
-                        );
-                    }
-                    for surface in data.x11_surface_per_wl_surface.keys() {
-                        send_frames_surface_tree(surface, start_time.elapsed().as_millis() as u32);
-                    }
+                    data.send_frame_callbacks();
                 }
 
                 X11Event::Input { event, .. } => handle_input::<X11Data>(&event, data),
@@ -286,8 +269,12 @@ pub fn run_x11_client() {
         .handle()
         .insert_source(rx_request_fbo, move |_, _, data| {
             match data.backend_data.x11_surface.buffer() {
-                Ok((dmabuf, _age)) => {
-                    let _ = data.tx_fbo.as_ref().unwrap().send(Some(dmabuf));
+                Ok((dmabuf, age)) => {
+                    data.backend_data.current_buffer = Some(dmabuf.clone());
+                    let _ = data.tx_fbo.as_ref().unwrap().send(Some(Framebuffer {
+                        source: FramebufferSource::Dmabuf(dmabuf),
+                        age,
+                    }));
                 }
                 Err(err) => {
                     error!("{err}");
@@ -299,12 +286,19 @@ pub fn run_x11_client() {
 
     event_loop
         .handle()
-        .insert_source(rx_present, move |_, _, data| {
+        .insert_source(rx_present, move |event, _, data| {
+            let Msg(damage) = event else {
+                return;
+            };
+
             data.is_next_flutter_frame_scheduled = true;
+            data.backend_data.last_rendered_buffer = data.backend_data.current_buffer.take();
             if let Err(err) = data.backend_data.x11_surface.submit() {
                 data.backend_data.x11_surface.reset_buffers();
                 warn!("Failed to submit buffer: {}. Retrying", err);
             };
+
+            data.flutter_frame_presented(&damage);
         })
         .unwrap();
 
@@ -327,6 +321,10 @@ pub fn run_x11_client() {
 pub struct X11Data {
     pub x11_surface: X11Surface,
     pub output: Output,
+    /// The buffer Flutter is rendering into.
+    current_buffer: Option<Dmabuf>,
+    /// The buffer of the last frame submitted to the X server.
+    last_rendered_buffer: Option<Dmabuf>,
 }
 
 impl Backend for X11Data {
@@ -337,4 +335,69 @@ impl Backend for X11Data {
     fn get_monitor_layout(&self) -> Vec<Output> {
         vec![self.output.clone()]
     }
+
+    fn update_monitor_layout(state: &mut ServerState<Self>) {
+        // The only monitor is the window, Flutter always renders at its size.
+        state.update_window_metrics();
+
+        let monitors = state.backend_data.get_monitor_layout();
+        state.flutter_engine_mut().monitor_layout_changed(monitors);
+    }
+
+    fn configure_monitor(
+        state: &mut ServerState<Self>,
+        configuration: MonitorConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        configuration.validate()?;
+        if configuration.monitor_name != state.backend_data.output.name() {
+            return Err(PlatformChannelError::MonitorDoesntExist(
+                configuration.monitor_name,
+            ));
+        }
+        // The mode follows the window size.
+        if configuration.position.is_some()
+            || configuration.mode_index.is_some()
+            || configuration.transform.is_some()
+            || configuration.enabled == Some(false)
+        {
+            return Err(PlatformChannelError::Unsupported(
+                "Only the scale of the X11 window can be changed",
+            ));
+        }
+
+        configuration.apply_to_output(&state.backend_data.output);
+        Self::update_monitor_layout(state);
+        Ok(())
+    }
+
+    fn supports_direct_scanout(&self) -> bool {
+        false
+    }
+
+    fn can_change_vt(&self) -> bool {
+        false
+    }
+
+    fn change_vt(&mut self, _vt: i32) {}
+
+    fn send_input_devices(_state: &mut ServerState<Self>) {}
+
+    fn configure_input_device(
+        _state: &mut ServerState<Self>,
+        _configuration: InputDeviceConfiguration,
+    ) -> Result<(), PlatformChannelError> {
+        Err(PlatformChannelError::Unsupported(
+            "Input devices can only be configured with libinput",
+        ))
+    }
+
+    fn last_flutter_frame(state: &mut ServerState<Self>) -> Option<GlesTexture> {
+        let dmabuf = state.backend_data.last_rendered_buffer.as_ref()?;
+        state
+            .gles_renderer
+            .as_mut()?
+            .import_dmabuf(dmabuf, None)
+            .map_err(|err| warn!("Failed to import the Flutter frame: {}", err))
+            .ok()
+    }
 }